@@ -3,7 +3,7 @@
 //! Defines the immutable block and block header structures.
 
 use serde::{Deserialize, Serialize};
-use crate::crypto::Hash;
+use crate::crypto::{Hash, SignatureCheck};
 use crate::validation::Transaction;
 
 /// Block header containing all metadata
@@ -97,6 +97,21 @@ impl Block {
     pub fn is_genesis(&self) -> bool {
         self.header.prev_hash == Hash::zero()
     }
+
+    /// Serialized size in bytes, limited by `MAX_BLOCK_WEIGHT`
+    pub fn weight(&self) -> u64 {
+        bincode::serialized_size(self).unwrap_or(u64::MAX)
+    }
+
+    /// Signature verifications needed to validate the block
+    pub fn sigops(&self) -> usize {
+        self.transactions.iter().map(|tx| tx.sigops()).sum()
+    }
+
+    /// Signatures of every input in the block, in block order
+    pub fn signature_checks(&self) -> Vec<SignatureCheck> {
+        self.transactions.iter().flat_map(|tx| tx.signature_checks()).collect()
+    }
 }
 
 #[cfg(test)]
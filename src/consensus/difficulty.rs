@@ -4,8 +4,8 @@
 
 use crate::constants::{BLOCK_TIME_TARGET, DIFFICULTY_ADJUSTMENT_INTERVAL};
 
-/// Minimum difficulty (easiest)
-const MIN_DIFFICULTY: u32 = 0x1d00ffff;
+/// Easiest target the network accepts, carried by the genesis block
+pub const POW_LIMIT: u32 = 0x1e00ffff;
 
 /// Maximum adjustment factor (4x in either direction per period)
 const MAX_ADJUSTMENT_FACTOR: u64 = 4;
@@ -19,6 +19,7 @@ const MAX_ADJUSTMENT_FACTOR: u64 = 4;
 /// * `current_difficulty` - Current compact difficulty target
 /// * `first_block_time` - Timestamp of first block in adjustment period
 /// * `last_block_time` - Timestamp of last block in adjustment period
+/// * `pow_limit` - Easiest compact target of the chain (its genesis target)
 /// 
 /// # Returns
 /// New compact difficulty target
@@ -26,6 +27,7 @@ pub fn calculate_next_difficulty(
     current_difficulty: u32,
     first_block_time: u64,
     last_block_time: u64,
+    pow_limit: u32,
 ) -> u32 {
     // Calculate actual time taken for the period
     let actual_time = last_block_time.saturating_sub(first_block_time);
@@ -41,39 +43,61 @@ pub fn calculate_next_difficulty(
     let current_target = compact_to_target(current_difficulty);
     let new_target = multiply_target(&current_target, actual_time, expected_time);
     
-    // Convert back to compact and ensure minimum difficulty
-    let new_compact = target_to_compact(&new_target);
-    
-    // Don't go below minimum difficulty
-    if new_compact > MIN_DIFFICULTY {
-        MIN_DIFFICULTY
+    // Don't go easier than the pow limit; targets compare as big-endian
+    // numbers, which compact values don't
+    if new_target > compact_to_target(pow_limit) {
+        pow_limit
     } else {
-        new_compact
+        target_to_compact(&new_target)
     }
 }
 
 /// Check if difficulty should be adjusted at this height
 pub fn should_adjust_difficulty(height: u64) -> bool {
-    height > 0 && height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0
+    height > 0 && height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
 }
 
 /// Get the height of the first block in the current adjustment period
 pub fn get_period_start_height(height: u64) -> u64 {
-    if height < DIFFICULTY_ADJUSTMENT_INTERVAL {
-        0
-    } else {
-        height - DIFFICULTY_ADJUSTMENT_INTERVAL
+    height.saturating_sub(DIFFICULTY_ADJUSTMENT_INTERVAL)
+}
+
+/// Difficulty target required for the block at `height`
+/// 
+/// Carries `prev_difficulty` forward except on adjustment heights, where the
+/// target is recomputed from the timestamps of the first and last block of
+/// the closing period, never easier than `pow_limit`. `timestamp_at` looks
+/// up main-chain block timestamps.
+pub fn expected_difficulty(
+    height: u64,
+    prev_difficulty: u32,
+    pow_limit: u32,
+    timestamp_at: impl Fn(u64) -> Option<u64>,
+) -> u32 {
+    if !should_adjust_difficulty(height) {
+        return prev_difficulty;
+    }
+
+    let first_block_time = timestamp_at(get_period_start_height(height));
+    let last_block_time = timestamp_at(height - 1);
+
+    match (first_block_time, last_block_time) {
+        (Some(first), Some(last)) => calculate_next_difficulty(prev_difficulty, first, last, pow_limit),
+        _ => prev_difficulty,
     }
 }
 
 /// Convert compact difficulty to 256-bit target
-fn compact_to_target(compact: u32) -> [u8; 32] {
+/// 
+/// This is the single definition of the PoW target: the miner searches
+/// for hashes at or below it and block validation enforces the same bound.
+pub fn compact_to_target(compact: u32) -> [u8; 32] {
     let exponent = (compact >> 24) as usize;
     let mantissa = compact & 0x007FFFFF;
     
     let mut target = [0u8; 32];
     
-    if exponent == 0 {
+    if exponent == 0 || exponent > 32 {
         return target;
     }
     
@@ -145,20 +169,37 @@ fn target_to_compact(target: &[u8; 32]) -> u32 {
 }
 
 /// Multiply target by a ratio (actual_time / expected_time)
+/// 
+/// Saturates at the largest 256-bit target.
 fn multiply_target(target: &[u8; 32], numerator: u64, denominator: u64) -> [u8; 32] {
-    // Simple implementation: convert to u128 for multiplication
-    // This is a simplified version - production would need arbitrary precision
-    
-    let mut result = [0u8; 32];
+    // Long multiplication, least significant byte first; the u64 factor
+    // widens the product by up to 8 bytes
+    let mut product = [0u8; 40];
     let mut carry: u128 = 0;
-    
     for i in (0..32).rev() {
         let val = (target[i] as u128) * (numerator as u128) + carry;
-        let new_val = val / (denominator as u128);
-        carry = (val % (denominator as u128)) << 8;
-        result[i] = (new_val & 0xFF) as u8;
+        product[i + 8] = (val & 0xFF) as u8;
+        carry = val >> 8;
     }
-    
+    for byte in product[..8].iter_mut().rev() {
+        *byte = (carry & 0xFF) as u8;
+        carry >>= 8;
+    }
+
+    // Long division, most significant byte first
+    let mut quotient = [0u8; 40];
+    let mut remainder: u128 = 0;
+    for i in 0..40 {
+        let val = (remainder << 8) | product[i] as u128;
+        quotient[i] = (val / denominator as u128) as u8;
+        remainder = val % denominator as u128;
+    }
+
+    if quotient[..8].iter().any(|&byte| byte != 0) {
+        return [0xFF; 32];
+    }
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&quotient[8..]);
     result
 }
 
@@ -184,6 +225,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_expected_difficulty_carries_forward_within_period() {
+        let current = 0x1c00ffff;
+        let timestamps = |h: u64| Some(h * BLOCK_TIME_TARGET);
+
+        assert_eq!(expected_difficulty(1, current, POW_LIMIT, timestamps), current);
+        assert_eq!(
+            expected_difficulty(DIFFICULTY_ADJUSTMENT_INTERVAL - 1, current, POW_LIMIT, timestamps),
+            current
+        );
+    }
+
+    #[test]
+    fn test_expected_difficulty_retargets_at_interval() {
+        let current = 0x1c00ffff;
+        // Blocks came twice as fast as the target
+        let timestamps = |h: u64| Some(h * BLOCK_TIME_TARGET / 2);
+
+        let height = DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let expected = calculate_next_difficulty(
+            current,
+            timestamps(get_period_start_height(height)).unwrap(),
+            timestamps(height - 1).unwrap(),
+            POW_LIMIT,
+        );
+
+        assert_eq!(expected_difficulty(height, current, POW_LIMIT, timestamps), expected);
+        assert_ne!(expected, current);
+    }
+
     #[test]
     fn test_difficulty_increases_when_blocks_too_fast() {
         // When blocks come faster than expected, difficulty should not decrease
@@ -191,12 +262,11 @@ mod tests {
         let expected_time = BLOCK_TIME_TARGET * DIFFICULTY_ADJUSTMENT_INTERVAL;
         let actual_time = expected_time / 2; // Blocks came twice as fast
         
-        let new_difficulty = calculate_next_difficulty(current, 0, actual_time);
+        let new_difficulty = calculate_next_difficulty(current, 0, actual_time, POW_LIMIT);
         
-        // New difficulty should not be easier than MIN_DIFFICULTY
-        // And should not be radically different (within 4x)
-        assert!(new_difficulty != 0, "Difficulty should not be zero");
-        assert!(new_difficulty <= MIN_DIFFICULTY, "Should not exceed MIN_DIFFICULTY");
+        // Half the period means half the target, exactly
+        assert_eq!(new_difficulty, 0x1b7fff80);
+        assert!(compact_to_target(new_difficulty) < compact_to_target(current));
     }
 
     #[test]
@@ -206,11 +276,27 @@ mod tests {
         let expected_time = BLOCK_TIME_TARGET * DIFFICULTY_ADJUSTMENT_INTERVAL;
         let actual_time = expected_time * 2; // Blocks came twice as slow
         
-        let new_difficulty = calculate_next_difficulty(current, 0, actual_time);
+        let new_difficulty = calculate_next_difficulty(current, 0, actual_time, POW_LIMIT);
         
-        // Should still be a valid difficulty
-        assert!(new_difficulty != 0, "Difficulty should not be zero");
-        // Should be capped at MIN_DIFFICULTY if going too easy
-        assert!(new_difficulty <= MIN_DIFFICULTY, "Should not exceed MIN_DIFFICULTY");
+        // Twice the period means twice the target, still within the limit
+        assert_eq!(new_difficulty, 0x1c01fffe);
+        assert!(compact_to_target(new_difficulty) > compact_to_target(current));
+    }
+
+    #[test]
+    fn test_difficulty_clamped_to_pow_limit() {
+        let expected_time = BLOCK_TIME_TARGET * DIFFICULTY_ADJUSTMENT_INTERVAL;
+
+        // Already at the limit, slow blocks can't make it any easier
+        assert_eq!(calculate_next_difficulty(POW_LIMIT, 0, expected_time * 4, POW_LIMIT), POW_LIMIT);
+
+        // Just under the limit, a full 4x step is cut off at it
+        let current = 0x1d7fffff;
+        assert!(compact_to_target(current) < compact_to_target(POW_LIMIT));
+        assert_eq!(calculate_next_difficulty(current, 0, expected_time * 4, POW_LIMIT), POW_LIMIT);
+
+        // A test chain with an easier limit retargets freely below it
+        let regtest_limit = 0x207fffff;
+        assert_eq!(calculate_next_difficulty(regtest_limit, 0, expected_time / 2, regtest_limit), 0x203fffff);
     }
 }
@@ -2,10 +2,10 @@
 //! 
 //! Pure functions for validating blocks and chains.
 
-use crate::consensus::{Block, BlockHeader};
-use crate::crypto::{Hash, compute_merkle_root};
+use crate::consensus::{Block, BlockHeader, compact_to_target};
+use crate::crypto::{Hash, compute_merkle_root, verify_batch, SignatureCache};
 use crate::validation::Transaction;
-use crate::storage::UTXOSet;
+use crate::storage::{NonceSet, UTXOSet};
 use thiserror::Error;
 
 /// Validation errors
@@ -17,8 +17,12 @@ pub enum ValidationError {
     InvalidMerkleRoot,
     #[error("Invalid previous hash")]
     InvalidPrevHash,
-    #[error("Invalid timestamp")]
-    InvalidTimestamp,
+    #[error("Block parent is unknown")]
+    UnknownParent,
+    #[error("Invalid timestamp: {0}")]
+    InvalidTimestamp(String),
+    #[error("Invalid chain id: {got} (expected {expected})")]
+    InvalidChainId { got: u8, expected: u8 },
     #[error("Invalid transaction: {0}")]
     InvalidTransaction(String),
     #[error("Invalid block reward")]
@@ -27,16 +31,36 @@ pub enum ValidationError {
     InvalidDifficulty,
     #[error("Double spend detected")]
     DoubleSpend,
+    #[error("Transaction {0} spends the same input more than once")]
+    DuplicateInput(Hash),
     #[error("Supply exceeded")]
     SupplyExceeded,
+    #[error("Block weight {0} exceeds the maximum")]
+    BlockTooLarge(u64),
+    #[error("Block needs {0} signature checks, more than the maximum")]
+    TooManySigops(usize),
+    #[error("Block must contain exactly one coinbase as its first transaction")]
+    InvalidCoinbase,
+    #[error("Coinbase output {0}:{1} spent before maturity")]
+    ImmatureCoinbaseSpend(Hash, u32),
+    #[error("Transaction {0} is not final (locked until {1})")]
+    NonFinalTransaction(Hash, u32),
+    #[error("Transaction {0} has nonce {2}, expected {1}")]
+    InvalidNonce(Hash, u64, u64),
+    #[error("Block at height {0} conflicts with a checkpoint")]
+    CheckpointMismatch(u64),
+    #[error("Storage error: {0}")]
+    Storage(String),
 }
 
 /// Validate proof of work
 /// 
-/// The block hash must be less than the target derived from difficulty_target
+/// The block hash must not exceed the target derived from difficulty_target.
+/// The target is decoded with `compact_to_target`, the same function the
+/// miner uses, so a block the miner accepts is a block validation accepts.
 pub fn validate_pow(header: &BlockHeader) -> Result<(), ValidationError> {
     let hash = header.hash();
-    let target = difficulty_to_target(header.difficulty_target);
+    let target = compact_to_target(header.difficulty_target);
     
     if hash_to_u256(&hash) > target {
         return Err(ValidationError::InvalidPoW);
@@ -60,14 +84,92 @@ pub fn validate_merkle_root(block: &Block) -> Result<(), ValidationError> {
     Ok(())
 }
 
+/// Context-free header checks: chain id and proof of work
+/// 
+/// Enough to reject a header before it is stored, without its body.
+pub fn check_header(header: &BlockHeader) -> Result<(), ValidationError> {
+    // Replay protection
+    if header.chain_id != crate::constants::CHAIN_ID {
+        return Err(ValidationError::InvalidChainId {
+            got: header.chain_id,
+            expected: crate::constants::CHAIN_ID,
+        });
+    }
+
+    validate_pow(header)
+}
+
+/// Check a block hash against the checkpoint for its height, if any
+pub fn check_checkpoint(height: u64, hash: &Hash, checkpoints: &[(u64, &str)]) -> Result<(), ValidationError> {
+    for (checkpoint_height, checkpoint_hash) in checkpoints {
+        if *checkpoint_height == height && hash.to_hex() != *checkpoint_hash {
+            return Err(ValidationError::CheckpointMismatch(height));
+        }
+    }
+    Ok(())
+}
+
+/// Context-free block checks
+///
+/// Everything that can be verified without knowing the parent: chain id,
+/// proof of work, merkle root, weight and signature budget, and coinbase
+/// placement. Run this before a block is indexed, so unmined, oversized or
+/// malformed blocks never enter the index.
+pub fn check_block(block: &Block) -> Result<(), ValidationError> {
+    check_header(&block.header)?;
+
+    // Validate merkle root
+    validate_merkle_root(block)?;
+
+    // Size and signature budget, so every valid block can be relayed and
+    // checked in bounded time
+    let weight = block.weight();
+    if weight > crate::constants::MAX_BLOCK_WEIGHT {
+        return Err(ValidationError::BlockTooLarge(weight));
+    }
+    let sigops = block.sigops();
+    if sigops > crate::constants::MAX_BLOCK_SIGOPS {
+        return Err(ValidationError::TooManySigops(sigops));
+    }
+
+    // Exactly one coinbase, and it must come first
+    match block.transactions.first() {
+        Some(tx) if tx.is_coinbase() => {}
+        _ => return Err(ValidationError::InvalidCoinbase),
+    }
+    if block.transactions.iter().skip(1).any(|tx| tx.is_coinbase()) {
+        return Err(ValidationError::InvalidCoinbase);
+    }
+
+    Ok(())
+}
+
+/// Median of a set of block timestamps (0 if empty)
+pub fn median_time(timestamps: &[u64]) -> u64 {
+    if timestamps.is_empty() {
+        return 0;
+    }
+    let mut times = timestamps.to_vec();
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
 /// Validate a block against the current chain state
+/// 
+/// `median_time_past` is the median timestamp of the 11 blocks before it,
+/// and `nonces` the confirmed sender nonces at its parent. Signatures already
+/// in `sig_cache` are not verified again.
+#[allow(clippy::too_many_arguments)]
 pub fn validate_block(
     block: &Block,
     prev_block_hash: &Hash,
     expected_difficulty: u32,
     utxo_set: &UTXOSet,
+    nonces: &NonceSet,
     current_height: u64,
+    median_time_past: u64,
     total_issued: u64,
+    sig_cache: &SignatureCache,
 ) -> Result<(), ValidationError> {
     // Check previous hash
     if block.header.prev_hash != *prev_block_hash {
@@ -79,63 +181,136 @@ pub fn validate_block(
         return Err(ValidationError::InvalidDifficulty);
     }
     
-    // Validate PoW
-    validate_pow(&block.header)?;
-    
-    // Validate merkle root
-    validate_merkle_root(block)?;
+    // Chain id, PoW, merkle root, coinbase placement
+    check_block(block)?;
     
     // Validate all transactions
-    validate_transactions(&block.transactions, utxo_set)?;
+    let total_fees = validate_transactions(&block.transactions, utxo_set, nonces, current_height, median_time_past)?;
     
     // Validate block reward
-    validate_block_reward(block, current_height, total_issued)?;
+    validate_block_reward(block, total_fees, current_height, total_issued)?;
+
+    // Verify every input signature, in parallel, once the cheap checks pass
+    validate_signatures(block, sig_cache)?;
     
     Ok(())
 }
 
-/// Validate all transactions in a block
+/// Validate all transactions in a block at `height`
+/// 
+/// Inputs resolve against `utxo_set` and the outputs of earlier transactions
+/// in the same block, so in-block chains of dependent transactions are
+/// allowed. Each sender's nonces must continue from `nonces` without gaps,
+/// in block order. Returns the total fees of the block.
 fn validate_transactions(
     transactions: &[Transaction],
     utxo_set: &UTXOSet,
-) -> Result<(), ValidationError> {
+    nonces: &NonceSet,
+    height: u64,
+    median_time_past: u64,
+) -> Result<u64, ValidationError> {
     use std::collections::HashSet;
     let mut spent_outputs = HashSet::new();
+    let mut block_outputs = UTXOSet::new();
+    let mut block_nonces = std::collections::HashMap::new();
+    let mut total_fees = 0u64;
     
     for tx in transactions {
-        // Skip coinbase validation for now (first tx)
+        // Coinbase is checked by validate_block_reward
         if tx.is_coinbase() {
+            block_outputs.apply_transaction(tx, height);
             continue;
         }
         
-        // Check for double spends within the block
+        // Lock time must have passed
+        if !tx.is_final(height, median_time_past) {
+            return Err(ValidationError::NonFinalTransaction(tx.hash(), tx.lock_time));
+        }
+
+        if tx.has_duplicate_inputs() {
+            return Err(ValidationError::DuplicateInput(tx.hash()));
+        }
+
+        // Sender nonces continue the confirmed sequence, one per transaction
+        if let Some(sender) = tx.sender() {
+            let expected = block_nonces.get(&sender).copied().unwrap_or_else(|| nonces.get(&sender));
+            if tx.nonce != expected {
+                return Err(ValidationError::InvalidNonce(tx.hash(), expected, tx.nonce));
+            }
+            block_nonces.insert(sender, expected + 1);
+        }
+
+        // Resolve inputs, checking for double spends within the block
+        let mut inputs = UTXOSet::new();
         for input in &tx.inputs {
-            let outpoint = (input.prev_tx_hash.clone(), input.output_index);
-            if spent_outputs.contains(&outpoint) {
+            let outpoint = (input.prev_tx_hash, input.output_index);
+            if !spent_outputs.insert(outpoint) {
                 return Err(ValidationError::DoubleSpend);
             }
-            spent_outputs.insert(outpoint);
             
-            // Check UTXO exists
-            if !utxo_set.contains(&input.prev_tx_hash, input.output_index) {
+            // Check UTXO exists and, for coinbase outputs, is mature
+            let Some(utxo) = block_outputs.get(&outpoint.0, outpoint.1).or_else(|| utxo_set.get(&outpoint.0, outpoint.1)) else {
                 return Err(ValidationError::InvalidTransaction(
-                    "Input UTXO does not exist".to_string()
+                    format!("Input UTXO {}:{} does not exist", input.prev_tx_hash, input.output_index)
                 ));
+            };
+            if !utxo.is_mature(height) {
+                return Err(ValidationError::ImmatureCoinbaseSpend(input.prev_tx_hash, input.output_index));
             }
+            inputs.add(outpoint.0, outpoint.1, utxo.clone());
         }
         
-        // Validate transaction signature
-        if let Err(e) = tx.verify_signatures(utxo_set) {
+        // Inputs must belong to their signers (signatures are verified
+        // for the whole block by validate_signatures)
+        if let Err(e) = tx.verify_input_owners(&inputs) {
             return Err(ValidationError::InvalidTransaction(e));
         }
+
+        // Inputs must cover outputs
+        let (Some(input_val), Some(output_val)) = (tx.total_input_value(&inputs), tx.total_output_value()) else {
+            return Err(ValidationError::InvalidTransaction(
+                format!("Amounts of transaction {} overflow", tx.hash())
+            ));
+        };
+        if input_val < output_val {
+            return Err(ValidationError::InvalidTransaction(
+                format!("Insufficient input in transaction {}: {} < {}", tx.hash(), input_val, output_val)
+            ));
+        }
+        total_fees = total_fees.checked_add(input_val - output_val).ok_or_else(|| {
+            ValidationError::InvalidTransaction(format!("Block fees overflow at transaction {}", tx.hash()))
+        })?;
+
+        // Outputs become spendable by later transactions in the block
+        block_outputs.apply_transaction(tx, height);
     }
     
-    Ok(())
+    Ok(total_fees)
+}
+
+/// Verify the signatures of every input in a block
+///
+/// Checks missing from `sig_cache` run on worker threads (see
+/// `crypto::verify_batch`).
+pub fn validate_signatures(block: &Block, sig_cache: &SignatureCache) -> Result<(), ValidationError> {
+    let checks = block.signature_checks();
+    verify_batch(&checks, sig_cache).map_err(|index| {
+        // Checks follow the inputs of non-coinbase transactions in block order
+        let input = block.transactions.iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| &tx.inputs)
+            .nth(index)
+            .expect("one check per input");
+        ValidationError::InvalidTransaction(
+            format!("Invalid signature for input {}:{}", input.prev_tx_hash, input.output_index)
+        )
+    })
 }
 
 /// Validate block reward
 fn validate_block_reward(
     block: &Block,
+    total_fees: u64,
     height: u64,
     total_issued: u64,
 ) -> Result<(), ValidationError> {
@@ -151,14 +326,12 @@ fn validate_block_reward(
         return Err(ValidationError::InvalidBlockReward);
     }
     
-    let coinbase_amount: u64 = coinbase.outputs.iter().map(|o| o.amount).sum();
+    let coinbase_amount = coinbase.total_output_value().ok_or(ValidationError::InvalidBlockReward)?;
     
-    // Coinbase can include fees, so it should be >= expected reward
-    // For now, we just check it doesn't exceed reward (fees handled separately)
+    // Coinbase may claim the subsidy plus the fees of the block
     if coinbase_amount > expected_reward {
         // Check if excess is from fees
-        let total_fees = calculate_total_fees(block);
-        if coinbase_amount > expected_reward + total_fees {
+        if coinbase_amount > expected_reward.saturating_add(total_fees) {
             return Err(ValidationError::InvalidBlockReward);
         }
     }
@@ -166,52 +339,6 @@ fn validate_block_reward(
     Ok(())
 }
 
-/// Calculate total fees in a block
-fn calculate_total_fees(block: &Block) -> u64 {
-    // Fees = sum of inputs - sum of outputs (excluding coinbase)
-    block.transactions.iter()
-        .skip(1) // Skip coinbase
-        .map(|tx| {
-            let input_sum: u64 = tx.inputs.iter().map(|_| 0u64).sum(); // Would need UTXO lookup
-            let output_sum: u64 = tx.outputs.iter().map(|o| o.amount).sum();
-            input_sum.saturating_sub(output_sum)
-        })
-        .sum()
-}
-
-/// Convert difficulty target to full 256-bit target
-fn difficulty_to_target(compact: u32) -> [u8; 32] {
-    let exponent = (compact >> 24) as usize;
-    let mantissa = compact & 0x00FFFFFF;
-    
-    let mut target = [0u8; 32];
-    
-    if exponent == 0 || exponent > 32 {
-        return target;
-    }
-    
-    if exponent <= 3 {
-        let shift = 8 * (3 - exponent);
-        let value = mantissa >> shift;
-        target[31] = (value & 0xFF) as u8;
-        target[30] = ((value >> 8) & 0xFF) as u8;
-        target[29] = ((value >> 16) & 0xFF) as u8;
-    } else {
-        let byte_index = 32usize.saturating_sub(exponent);
-        if byte_index < 32 {
-            target[byte_index] = (mantissa & 0xFF) as u8;
-        }
-        if byte_index + 1 < 32 {
-            target[byte_index + 1] = ((mantissa >> 8) & 0xFF) as u8;
-        }
-        if byte_index + 2 < 32 {
-            target[byte_index + 2] = ((mantissa >> 16) & 0xFF) as u8;
-        }
-    }
-    
-    target
-}
-
 /// Convert hash to comparable value
 fn hash_to_u256(hash: &Hash) -> [u8; 32] {
     hash.0
@@ -252,16 +379,30 @@ pub fn validate_chain(
     let mut total_issued: u64 = 0;
     let mut prev_hash = Hash::zero();
     let mut prev_difficulty = blocks[0].header.difficulty_target;
+    let pow_limit = blocks[0].header.difficulty_target;
+    let mut nonces = NonceSet::new();
+    let sig_cache = SignatureCache::default();
 
     for (height, block) in blocks.iter().enumerate() {
+        // Retarget on adjustment heights
+        let difficulty = crate::consensus::expected_difficulty(height as u64, prev_difficulty, pow_limit, |h| {
+            blocks.get(h as usize).map(|b| b.header.timestamp)
+        });
+
         // Validate block
+        let timestamps: Vec<u64> = blocks[height.saturating_sub(11)..height].iter()
+            .map(|b| b.header.timestamp)
+            .collect();
         validate_block(
             block,
             &prev_hash,
-            prev_difficulty,
+            difficulty,
             utxo_set,
+            &nonces,
             height as u64,
+            median_time(&timestamps),
             total_issued,
+            &sig_cache,
         )?;
 
         // Calculate work for this block
@@ -272,9 +413,10 @@ pub fn validate_chain(
         let reward = crate::consensus::calculate_block_reward(height as u64, total_issued);
         total_issued = total_issued.saturating_add(reward);
 
-        // Apply block to UTXO set
+        // Apply block to UTXO and nonce sets
         for tx in &block.transactions {
             utxo_set.apply_transaction(tx, height as u64);
+            nonces.apply_transaction(tx);
         }
 
         // Update for next iteration
@@ -301,21 +443,26 @@ pub fn compare_chains(chain_a: &ChainValidationResult, chain_b: &ChainValidation
 /// Calculate work from difficulty target
 /// 
 /// Work = 2^256 / (target + 1)
-/// Higher exponent = easier target = less work
-/// Lower exponent = harder target = more work
-fn calculate_work(compact_difficulty: u32) -> u128 {
-    let exponent = (compact_difficulty >> 24) as u32;
-    let mantissa = (compact_difficulty & 0x00FFFFFF) as u128;
+/// Approximated from the top 128 bits of the target so it fits in a u128.
+/// Harder (smaller) target = more work
+pub fn calculate_work(compact_difficulty: u32) -> u128 {
+    let target = compact_to_target(compact_difficulty);
     
-    if mantissa == 0 || exponent == 0 {
+    // Zero target is invalid and carries no work
+    if target == [0u8; 32] {
         return 0;
     }
     
-    // Work is inversely proportional to target
-    // Smaller exponent = harder = more work
-    // We calculate: 2^32 * (256 - exponent * 8) / mantissa
-    let work_factor = (32u32.saturating_sub(exponent)) as u128;
-    ((1u128 << 32) * work_factor) / mantissa
+    let mut high_bytes = [0u8; 16];
+    high_bytes.copy_from_slice(&target[0..16]);
+    let high = u128::from_be_bytes(high_bytes);
+    
+    // Target below 2^128: more work than we can represent
+    if high == 0 {
+        return u128::MAX;
+    }
+    
+    u128::MAX / high.saturating_add(1)
 }
 
 #[cfg(test)]
@@ -325,10 +472,24 @@ mod tests {
     use crate::crypto::hash_bytes;
     use crate::validation::Transaction;
 
+    #[test]
+    fn test_checkpoint_mismatch() {
+        let hash = hash_bytes(b"block");
+        let hex = hash.to_hex();
+        let checkpoints = [(5, hex.as_str())];
+
+        assert!(check_checkpoint(5, &hash, &checkpoints).is_ok());
+        assert!(check_checkpoint(6, &hash_bytes(b"other"), &checkpoints).is_ok());
+        assert!(matches!(
+            check_checkpoint(5, &hash_bytes(b"other"), &checkpoints),
+            Err(ValidationError::CheckpointMismatch(5))
+        ));
+    }
+
     #[test]
     fn test_difficulty_to_target() {
         // Bitcoin-style compact target
-        let target = difficulty_to_target(0x1d00ffff);
+        let target = compact_to_target(0x1d00ffff);
         assert!(target[0] == 0x00);
     }
 
@@ -378,6 +539,10 @@ mod tests {
         
         // Harder difficulty (smaller exponent) = more work
         assert!(hard > easy, "hard={} should be > easy={}", hard, easy);
+
+        // Even the easiest valid target carries some work
+        assert!(calculate_work(0x207fffff) > 0);
+        assert_eq!(calculate_work(0), 0);
     }
 
     #[test]
@@ -398,5 +563,20 @@ mod tests {
         assert!(compare_chains(&chain_a, &chain_b));
         assert!(!compare_chains(&chain_b, &chain_a));
     }
+
+    #[test]
+    fn test_pow_target_decoding() {
+        // The mantissa is big-endian: 0x1d00ffff is 0x00ffff shifted so its
+        // top byte lands at index 32 - 0x1d
+        let mut expected = [0u8; 32];
+        expected[4] = 0xff;
+        expected[5] = 0xff;
+        assert_eq!(compact_to_target(0x1d00ffff), expected);
+
+        // The sign bit makes the target zero, which no hash meets
+        assert_eq!(compact_to_target(0x1d800000), [0u8; 32]);
+        let header = BlockHeader::new(1, 0x01, Hash::zero(), Hash::zero(), 0, 0x1d800000, 0);
+        assert!(matches!(validate_pow(&header), Err(ValidationError::InvalidPoW)));
+    }
 }
 
@@ -88,7 +88,7 @@ pub fn build_merkle_proof(hashes: &[Hash], index: usize) -> Option<MerkleProof>
         }
         
         // Get sibling
-        let sibling_index = if current_index % 2 == 0 {
+        let sibling_index = if current_index.is_multiple_of(2) {
             current_index + 1
         } else {
             current_index - 1
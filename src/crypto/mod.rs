@@ -1,9 +1,12 @@
-//! Cryptography module - BLAKE3 hashing, Schnorr signatures, Merkle trees
+//! Cryptography module - BLAKE3 hashing, Schnorr signatures, Merkle trees,
+//! signature cache
 
 mod hash;
 mod schnorr;
 mod merkle;
+mod sigcache;
 
 pub use hash::*;
 pub use schnorr::*;
 pub use merkle::*;
+pub use sigcache::*;
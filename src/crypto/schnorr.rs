@@ -110,7 +110,7 @@ mod privkey_serde {
         }
         let mut arr = [0u8; 32];
         arr.copy_from_slice(&bytes);
-        SigningKey::from_bytes(arr.as_slice().into())
+        SigningKey::from_bytes(arr.as_slice())
             .map_err(|_| serde::de::Error::custom("Invalid private key bytes"))
     }
 }
@@ -139,7 +139,7 @@ impl PrivateKey {
     /// Sign a message hash
     pub fn sign(&self, message: &Hash) -> Result<SchnorrSignature, SignatureError> {
         let signature: Signature = self.0.sign(&message.0);
-        Ok(SchnorrSignature(signature.to_bytes().into()))
+        Ok(SchnorrSignature(signature.to_bytes()))
     }
 
     /// Export to bytes
@@ -172,6 +172,16 @@ impl PublicKey {
         verifying_key.verify(&message.0, &sig).is_ok()
     }
 
+    /// 20-byte address hash, zero-padded to a full `Hash`
+    ///
+    /// The form outputs are locked to and addresses encode.
+    pub fn pubkey_hash(&self) -> Hash {
+        let full_hash = super::hash_bytes(&self.0);
+        let mut addr_hash = [0u8; 32];
+        addr_hash[0..20].copy_from_slice(&full_hash.0[0..20]);
+        Hash(addr_hash)
+    }
+
     /// Convert to address with checksum
     pub fn to_address(&self) -> String {
         // Address = "RH" + Base58Check(BLAKE3(pubkey)[0:20])
@@ -0,0 +1,206 @@
+//! Signature cache and parallel batch verification
+//!
+//! Schnorr verification dominates the cost of validating a block. Every
+//! successful check is remembered in a `SignatureCache`, so transactions
+//! verified when they entered the mempool are not verified again when their
+//! block connects. Blocks verify all remaining signatures at once, spread
+//! over worker threads.
+//!
+//! k256 has no batch Schnorr verification API, so each signature is still
+//! checked on its own; the speedup comes from running checks concurrently.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+
+use super::{hash_bytes, Hash, PublicKey, SchnorrSignature};
+
+/// Default number of verified signatures remembered
+pub const DEFAULT_SIG_CACHE_SIZE: usize = 100_000;
+
+/// Below this many uncached checks, verify on the calling thread
+const PARALLEL_THRESHOLD: usize = 16;
+
+/// Most worker threads used for one batch
+const MAX_VERIFY_THREADS: usize = 8;
+
+/// One signature to verify: `public_key` signed `message` with `signature`
+#[derive(Debug, Clone)]
+pub struct SignatureCheck {
+    pub public_key: PublicKey,
+    pub message: Hash,
+    pub signature: SchnorrSignature,
+}
+
+impl SignatureCheck {
+    /// Cache key committing to the key, message and signature
+    fn cache_key(&self) -> Hash {
+        let mut bytes = Vec::with_capacity(32 + 32 + 64);
+        bytes.extend_from_slice(&self.public_key.0);
+        bytes.extend_from_slice(&self.message.0);
+        bytes.extend_from_slice(&self.signature.0);
+        hash_bytes(&bytes)
+    }
+}
+
+/// Bounded set of signatures known to be valid
+///
+/// Shared between threads; the oldest entries are evicted first.
+#[derive(Debug)]
+pub struct SignatureCache {
+    /// Maximum number of entries held
+    capacity: usize,
+    inner: Mutex<CacheEntries>,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    /// Cache keys of verified signatures
+    valid: HashSet<Hash>,
+    /// Insertion order, oldest first
+    order: VecDeque<Hash>,
+}
+
+impl SignatureCache {
+    /// Create a cache holding at most `capacity` signatures
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Check if a signature has already been verified
+    pub fn contains(&self, check: &SignatureCheck) -> bool {
+        self.inner.lock().unwrap().valid.contains(&check.cache_key())
+    }
+
+    /// Remember a verified signature
+    pub fn insert(&self, check: &SignatureCheck) {
+        let key = check.cache_key();
+        let mut entries = self.inner.lock().unwrap();
+        if !entries.valid.insert(key) {
+            return;
+        }
+        entries.order.push_back(key);
+        while entries.order.len() > self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.valid.remove(&oldest);
+            }
+        }
+    }
+
+    /// Verify a single signature, consulting and filling the cache
+    pub fn verify(&self, check: &SignatureCheck) -> bool {
+        if self.contains(check) {
+            return true;
+        }
+        if !check.public_key.verify(&check.message, &check.signature) {
+            return false;
+        }
+        self.insert(check);
+        true
+    }
+
+    /// Number of cached signatures
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().valid.len()
+    }
+
+    /// Check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIG_CACHE_SIZE)
+    }
+}
+
+/// Verify every check, skipping those already in `cache`
+///
+/// Uncached checks are split across worker threads when there are enough of
+/// them. Valid signatures are added to the cache. Returns the index of the
+/// first invalid check.
+pub fn verify_batch(checks: &[SignatureCheck], cache: &SignatureCache) -> Result<(), usize> {
+    let pending: Vec<usize> = (0..checks.len())
+        .filter(|&i| !cache.contains(&checks[i]))
+        .collect();
+
+    let verify_all = |indices: &[usize]| -> Option<usize> {
+        indices.iter().copied().find(|&i| !cache.verify(&checks[i]))
+    };
+
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_VERIFY_THREADS)
+        .min(pending.len() / PARALLEL_THRESHOLD);
+
+    let failed = if threads <= 1 {
+        verify_all(&pending)
+    } else {
+        let chunk_size = pending.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = pending.chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || verify_all(chunk)))
+                .collect();
+            workers.into_iter()
+                .filter_map(|worker| worker.join().expect("signature worker panicked"))
+                .min()
+        })
+    };
+
+    match failed {
+        Some(index) => Err(index),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+
+    fn checks(count: usize) -> Vec<SignatureCheck> {
+        let key = PrivateKey::generate();
+        (0..count as u32)
+            .map(|i| {
+                let message = hash_bytes(&i.to_le_bytes());
+                SignatureCheck {
+                    public_key: key.public_key(),
+                    message,
+                    signature: key.sign(&message).unwrap(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let cache = SignatureCache::new(2);
+        let checks = checks(3);
+        for check in &checks {
+            assert!(cache.verify(check));
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&checks[0]));
+        assert!(cache.contains(&checks[2]));
+    }
+
+    #[test]
+    fn test_verify_batch_reports_first_failure() {
+        let cache = SignatureCache::default();
+        let mut checks = checks(PARALLEL_THRESHOLD * 4);
+        assert_eq!(verify_batch(&checks, &cache), Ok(()));
+        assert_eq!(cache.len(), checks.len());
+
+        // Invalid signatures are reported and never cached
+        checks[40].message = Hash::zero();
+        checks[50].signature = checks[51].signature.clone();
+        assert_eq!(verify_batch(&checks, &cache), Err(40));
+        assert!(!cache.contains(&checks[40]));
+        assert!(!cache.contains(&checks[50]));
+    }
+}
@@ -233,9 +233,10 @@ pub const WALLET_HTML: &str = r#"<!DOCTYPE html>
             }
             try {
                 const balance = await rpc('getbalance', [currentAddress]);
+                const format = amount => amount.toLocaleString(undefined, {minimumFractionDigits: 2, maximumFractionDigits: 8});
                 document.getElementById('balance').innerHTML = 
-                    balance.toLocaleString(undefined, {minimumFractionDigits: 2, maximumFractionDigits: 8}) + 
-                    ' <span class="balance-unit">RH</span>';
+                    format(balance.spendable) + ' <span class="balance-unit">RH</span>' +
+                    (balance.immature > 0 ? ' <span class="balance-unit">(+' + format(balance.immature) + ' immature)</span>' : '');
             } catch (e) {
                 console.error('Failed to get balance:', e);
             }
@@ -15,6 +15,7 @@ pub mod wallet;
 pub mod node;
 pub mod rpc;
 pub mod explorer;
+pub mod mempool;
 
 /// Protocol constants - HARD-CODED, NEVER CONFIGURABLE
 pub mod constants {
@@ -84,6 +85,21 @@ pub mod constants {
     /// Maximum depth of chain reorganization allowed (in blocks)
     /// Prevents reorgs deeper than this unless explicitly authorized
     pub const MAX_REORG_DEPTH: u64 = 10;
+
+    /// Blocks a coinbase output must wait before it can be spent
+    /// Kept well above MAX_REORG_DEPTH so a reorg cannot erase spent rewards
+    pub const COINBASE_MATURITY: u64 = 100;
+
+    /// Transaction lock times below this are block heights, others are Unix timestamps
+    pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+    /// Maximum serialized size of a block in bytes
+    /// Half of the P2P MAX_MESSAGE_SIZE, so every valid block can be relayed
+    pub const MAX_BLOCK_WEIGHT: u64 = 2_000_000;
+
+    /// Maximum signature verifications per block (one per non-coinbase input)
+    /// Bounds the time needed to validate a block
+    pub const MAX_BLOCK_SIGOPS: usize = 10_000;
     
     /// Chain ID for replay protection
     /// Mainnet = 0x01, Testnet = 0x00
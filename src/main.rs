@@ -4,17 +4,14 @@
 //! RH is the short form used in addresses and logos.
 
 use rh_core::node::{create_genesis_block, GenesisInfo};
-use rh_core::storage::{ChainState, UTXO, db::BlockChainDB};
+use rh_core::storage::{ChainState, db::BlockChainDB};
 use rh_core::mining::{Miner, MiningResult};
 use rh_core::wallet::Wallet;
-use rh_core::p2p::{Message, PeerManager, VersionMessage, PROTOCOL_VERSION, NETWORK_MAGIC, InvItem, InvType};
-use rh_core::crypto::Hash;
+use rh_core::p2p::{NetworkService, PeerManager, SyncManager, MAX_ADDED_NODES, PEERS_FILE};
 use rh_core::rpc::{start_rpc_server, RpcState};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
 use tokio::time::sleep;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -22,6 +19,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let connect_addr = args.iter()
         .position(|a| a == "--connect")
         .and_then(|i| args.get(i + 1));
+
+    // --addnode may be given several times
+    let added_nodes: Vec<&String> = args.iter()
+        .enumerate()
+        .filter(|(_, a)| *a == "--addnode")
+        .filter_map(|(i, _)| args.get(i + 1))
+        .collect();
     
     let p2p_port: u16 = args.iter()
         .position(|a| a == "--p2p-port")
@@ -84,25 +88,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                           let mut state = ChainState::new(&genesis);
                           match BlockChainDB::open(&db_path) {
                               Ok(new_db) => {
-                                  // Save genesis block and metadata
-                                  let _ = new_db.save_block(&genesis);
-                                  let _ = new_db.update_metadata(&state.tip_hash, state.height, state.total_issued);
-                                  
-                                  // Save genesis UTXOs
-                                  let mut new_utxos = Vec::new();
-                                  for tx in &genesis.transactions {
-                                      for (i, output) in tx.outputs.iter().enumerate() {
-                                          new_utxos.push((
-                                              (tx.hash(), i as u32),
-                                              UTXO {
-                                                  amount: output.amount,
-                                                  pubkey_hash: output.pubkey_hash,
-                                                  height: 0,
-                                              }
-                                          ));
-                                      }
-                                  }
-                                  let _ = new_db.update_utxos(&[], &new_utxos);
+                                  // Save genesis block, UTXOs and metadata
+                                  let _ = new_db.save_genesis(&genesis);
                                   
                                   state.set_db(new_db);
                                   println!("📦 Database recreated at {}", &db_path);
@@ -126,25 +113,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut state = ChainState::new(&genesis);
         match BlockChainDB::open(&db_path) {
             Ok(db) => {
-                // Save genesis block and metadata
-                let _ = db.save_block(&genesis);
-                let _ = db.update_metadata(&state.tip_hash, state.height, state.total_issued);
-                
-                // Save genesis UTXOs
-                let mut new_utxos = Vec::new();
-                for tx in &genesis.transactions {
-                    for (i, output) in tx.outputs.iter().enumerate() {
-                        new_utxos.push((
-                            (tx.hash(), i as u32),
-                            UTXO {
-                                amount: output.amount,
-                                pubkey_hash: output.pubkey_hash,
-                                height: 0,
-                            }
-                        ));
-                    }
-                }
-                let _ = db.update_utxos(&[], &new_utxos);
+                // Save genesis block, UTXOs and metadata
+                let _ = db.save_genesis(&genesis);
                 
                 state.set_db(db);
                 println!("📦 Database created at {}", &db_path);
@@ -154,7 +124,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Arc::new(Mutex::new(state))
     };
 
-    let peer_manager = Arc::new(Mutex::new(PeerManager::new(25)));
+    // --connect keeps the node to that one peer; --addnode peers get
+    // reserved slots next to the automatic outbound peers
+    let mut peer_manager = PeerManager::new(25);
+    if connect_addr.is_some() {
+        peer_manager = peer_manager.with_max_outbound(0);
+    }
+    for addr_str in connect_addr.into_iter().chain(added_nodes) {
+        let addr = addr_str.parse::<std::net::SocketAddr>()?;
+        if !peer_manager.add_node(addr) {
+            eprintln!("⚠️  Ignoring --addnode {}: at most {} added nodes", addr, MAX_ADDED_NODES);
+        }
+    }
+    let peer_manager = Arc::new(Mutex::new(peer_manager));
+    let sync_manager = Arc::new(Mutex::new(SyncManager::new()));
 
     // ... (existing display logic) ...
     {
@@ -237,11 +220,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         chain_state: chain_state.clone(),
         wallet: wallet.clone(),
         peer_manager: peer_manager.clone(),
+        sync_manager: sync_manager.clone(),
         miner_address: shared_miner_address,
         miner_pubkey_hash: shared_miner_pubkey_hash,
     });
     tokio::spawn(start_rpc_server(rpc_state, rpc_port));
 
+    // P2P service: listener, outbound peers, message handling and sync
+    let network = NetworkService::new(chain_state.clone(), peer_manager.clone(), sync_manager.clone())
+        .with_miner(miner.clone())
+        .with_peers_file(std::path::Path::new(&db_path).join(PEERS_FILE));
+    network.start_sync();
+    network.start_peer_sweep();
+
     // Create a flag to signal shutdown to mining task
     let shutdown_flag = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shutdown_flag_clone = shutdown_flag.clone();
@@ -250,6 +241,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let miner_state = chain_state.clone();
     let miner_instance = miner.clone();
     let pm_mining = peer_manager.clone();
+    let network_mining = network.clone();
     
     tokio::spawn(async move {
         let peer_manager = pm_mining;
@@ -258,11 +250,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             if shutdown_flag_clone.load(std::sync::atomic::Ordering::Relaxed) {
                 break;
             }
-            // Check if we are syncing (any peer has higher height)
+            // Check if we are syncing (any peer or known header is ahead of our tip)
             let is_syncing = {
                 let pm = peer_manager.lock().unwrap();
                 let state = miner_state.lock().unwrap();
-                pm.get_peers_with_height(state.height).len() > 0
+                !pm.get_peers_with_height(state.height).is_empty() || state.best_header_height() > state.height
             };
 
             if is_syncing {
@@ -274,8 +266,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             // Construct block template (requires lock)
             let block_template = {
                 let state = miner_state.lock().unwrap();
-                let txs = state.get_mempool_transactions();
-                miner_instance.assemble_block(&state, txs)
+                miner_instance.assemble_block(&state)
             };
             
             // Create a channel to receive results from workers
@@ -329,17 +320,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 );
 
                 // Broadcast new block to peers
-                let inv_msg = Message::Inv(vec![InvItem {
-                    inv_type: InvType::Block,
-                    hash: block.hash(),
-                }]);
-                
-                // We'll need a way to reach connected peers. 
-                // For this ceremony version, we'll store peer senders in PeerManager.
-                {
-                    let pm = peer_manager.lock().unwrap();
-                    pm.broadcast_message(&inv_msg);
-                }
+                drop(state);
+                network_mining.announce_block(block.hash());
             } else {
                 // All workers stopped without success (interrupted)
                 sleep(Duration::from_millis(100)).await;
@@ -348,392 +330,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // P2P Listener
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", p2p_port)).await?;
-
-    // Outbound connection if requested, otherwise connect to seed nodes
-    if let Some(addr_str) = connect_addr {
-        let state = chain_state.clone();
-        let pm = peer_manager.clone();
-        let m_instance = miner.clone();
-        let addr = addr_str.parse::<std::net::SocketAddr>()?;
-        
-        tokio::spawn(async move {
-            println!("Connecting to peer: {}...", addr);
-            match TcpStream::connect(addr).await {
-                Ok(stream) => {
-                    let _ = handle_peer(stream, addr, state, pm, m_instance).await;
-                },
-                Err(e) => eprintln!("Failed to connect to {}: {}", addr, e),
+    network.listen(std::net::SocketAddr::from(([0, 0, 0, 0], p2p_port))).await?;
+
+    // Seed nodes are only needed while the address table is empty
+    let need_seeds = connect_addr.is_none() && peer_manager.lock().unwrap().addrman.is_empty();
+    if need_seeds {
+        println!("📡 No known peers. Resolving seed nodes...");
+        for seed in rh_core::constants::SEED_NODES {
+            match tokio::net::lookup_host(seed).await {
+                Ok(addrs) => peer_manager.lock().unwrap().add_peers(&addrs.collect::<Vec<_>>()),
+                Err(e) => eprintln!("Failed to resolve seed node {}: {}", seed, e),
             }
-        });
-    } else {
-        // Auto-connect to seed nodes for peer discovery
-        println!("📡 No manual peer specified. Connecting to seed nodes...");
-        for seed_addr_str in rh_core::constants::SEED_NODES {
-            let state = chain_state.clone();
-            let pm = peer_manager.clone();
-            let m_instance = miner.clone();
-            let seed_str = seed_addr_str.to_string();
-            
-            tokio::spawn(async move {
-                if let Ok(addr) = seed_str.parse::<std::net::SocketAddr>() {
-                    match TcpStream::connect(addr).await {
-                        Ok(stream) => {
-                            println!("🌱 Connected to seed node: {}", addr);
-                            let _ = handle_peer(stream, addr, state, pm, m_instance).await;
-                        },
-                        Err(e) => eprintln!("Failed to connect to seed node {}: {}", addr, e),
-                    }
-                } else {
-                    eprintln!("Invalid seed node address: {}", seed_str);
-                }
-            });
         }
     }
 
-    tokio::select! {
-        _ = async {
-            loop {
-                match listener.accept().await {
-                    Ok((socket, addr)) => {
-                        let state = chain_state.clone();
-                        let pm = peer_manager.clone();
-                        let m_instance = miner.clone();
-                        tokio::spawn(async move {
-                            let _ = handle_peer(socket, addr, state, pm, m_instance).await;
-                        });
-                    }
-                    Err(e) => eprintln!("Connection error: {}", e),
-                }
-            }
-        } => {},
-        _ = tokio::signal::ctrl_c() => {
-            println!("\nShutdown signal received. Waiting for pending operations...");
-            shutdown_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-            miner.stop();
-            
-            // Give mining/block application time to complete (max 30 seconds)
-            for _ in 0..30 {
-                let state = chain_state.lock().unwrap();
-                // If we're not in the middle of block operations, safe to exit
-                drop(state);
-                sleep(Duration::from_millis(100)).await;
-            }
-            
-            println!("Stopping node...");
-        }
-    }
-
-    Ok(())
-}
+    // Outbound peers: added nodes, known peers and the address table
+    network.start_connection_manager();
 
-async fn handle_peer(
-    mut stream: TcpStream, 
-    addr: std::net::SocketAddr, 
-    chain_state: Arc<Mutex<ChainState>>,
-    peer_manager: Arc<Mutex<PeerManager>>,
-    miner: Miner,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("🤝 Peer connected: {}", addr);
-
-    // 1. Initial Handshake
-    let local_height = { chain_state.lock().unwrap().height };
-    let version_msg = Message::Version(VersionMessage {
-        version: PROTOCOL_VERSION,
-        best_height: local_height,
-        from_addr: "127.0.0.1:8333".parse()?,
-        to_addr: addr,
-        nonce: rand::random(),
-        user_agent: "roho-v1.4".to_string(),
-    });
+    // Run until Ctrl+C
+    tokio::signal::ctrl_c().await?;
+    println!("\nShutdown signal received. Waiting for pending operations...");
+    shutdown_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+    miner.stop();
     
-    send_message(&mut stream, &version_msg).await?;
-
-    // Create a channel for outbound messages to this peer
-    let (peer_tx, mut peer_rx) = tokio::sync::mpsc::channel::<Message>(100);
-
-    // Split stream for concurrent read/write
-    let (mut reader, mut writer) = stream.into_split();
+    // Give mining/block application time to complete (max 30 seconds)
+    for _ in 0..30 {
+        let state = chain_state.lock().unwrap();
+        // If we're not in the middle of block operations, safe to exit
+        drop(state);
+        sleep(Duration::from_millis(100)).await;
+    }
 
-    // Outbound message task
-    tokio::spawn(async move {
-        while let Some(msg) = peer_rx.recv().await {
-            let bytes = msg.to_bytes();
-            if let Err(e) = writer.write_all(&bytes).await {
-                eprintln!("Failed to send to {}: {}", addr, e);
-                break;
-            }
+    // Keep pending transactions and fee statistics for the next start
+    {
+        let state = chain_state.lock().unwrap();
+        match state.save_mempool() {
+            Ok(count) => println!("💾 Saved {} mempool transactions", count),
+            Err(e) => eprintln!("⚠️  Failed to save mempool: {}", e),
         }
-    });
-
-    // 2. Message Loop
-    loop {
-        match read_message_stream(&mut reader).await {
-            Ok(msg) => {
-                match msg {
-                    Message::Version(v) => {
-                        println!("👋 Peer version: {} (Height: {})", v.user_agent, v.best_height);
-                        let _ = peer_tx.send(Message::VerAck).await;
-                        
-                        // Register peer in manager
-                        {
-                            let mut pm = peer_manager.lock().unwrap();
-                            pm.add_peer(addr);
-                            pm.peer_connected(addr, v.version, v.best_height, peer_tx.clone());
-                        }
-
-                        // If they are ahead, request block hashes
-                        if v.best_height > local_height {
-                            let locators = {
-                                let state = chain_state.lock().unwrap();
-                                let mut l = Vec::new();
-                                l.push(state.tip_hash);
-                                l
-                            };
-                            let _ = peer_tx.send(Message::GetBlocks(rh_core::p2p::GetBlocksMessage {
-                                block_locators: locators,
-                                stop_hash: rh_core::crypto::Hash::zero(),
-                            })).await;
-                        }
-                    },
-                    Message::Inv(items) => {
-                        for item in items {
-                            match item.inv_type {
-                                InvType::Block => {
-                                    let _ = peer_tx.send(Message::GetData(vec![item])).await;
-                                }
-                                InvType::Transaction => {
-                                    // Only request if we don't have it in mempool
-                                    let has_tx = {
-                                        let state = chain_state.lock().unwrap();
-                                        state.get_mempool_transactions().iter().any(|tx| tx.hash() == item.hash)
-                                    };
-                                    if !has_tx {
-                                        let _ = peer_tx.send(Message::GetData(vec![item])).await;
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Message::GetData(items) => {
-                        for item in items {
-                            match item.inv_type {
-                                InvType::Block => {
-                                    let block = {
-                                        let state = chain_state.lock().unwrap();
-                                        state.get_block(&item.hash).cloned()
-                                    };
-                                    if let Some(b) = block {
-                                        let _ = peer_tx.send(Message::Block(b)).await;
-                                    }
-                                }
-                                InvType::Transaction => {
-                                    let tx = {
-                                        let state = chain_state.lock().unwrap();
-                                        state.get_mempool_transactions().into_iter().find(|tx| tx.hash() == item.hash)
-                                    };
-                                    if let Some(t) = tx {
-                                        let _ = peer_tx.send(Message::Tx(t)).await;
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    Message::Block(block) => {
-                        let (result, request_missing) = {
-                            let mut state = chain_state.lock().unwrap();
-                            let block_hash = block.hash();
-                            
-                            // 1. Index the block (even if it's on a side chain)
-                            state.index_block(&block);
-
-                            if block.header.prev_hash == state.tip_hash {
-                                // 2. Direct connection to current tip
-                                match state.apply_block(&block) {
-                                    Ok(_) => (Some(state.get_stats()), None),
-                                    Err(e) => {
-                                        eprintln!("❌ Invalid block #{} from peer: {}", state.height + 1, e);
-                                        (None, None)
-                                    }
-                                }
-                            } else {
-                                // 3. Fork detection / Higher chain?
-                                let peer_height = state.get_block_height(&block_hash).unwrap_or(0);
-                                if peer_height > state.height {
-                                     match state.reorganize(block_hash) {
-                                         Ok(_) => {
-                                             println!("✅ Successfully reorganized to better chain height {}", state.height);
-                                             (Some(state.get_stats()), None)
-                                         }
-                                         Err(_) => {
-                                             // If we are missing intermediate blocks, request them
-                                             if state.get_block_header(&block.header.prev_hash).is_none() {
-                                                 let locator = rh_core::p2p::build_block_locator(&[state.height], |h| state.get_block_hash_at_height(h));
-                                                 (None, Some(locator))
-                                             } else {
-                                                 (None, None)
-                                             }
-                                         }
-                                     }
-                                } else {
-                                    (None, None)
-                                }
-                            }
-                        };
-
-                        if let Some(locator) = request_missing {
-                             println!("❓ Received potentially better block from peer with unknown parent. Requesting history...");
-                             let _ = peer_tx.send(Message::GetBlocks(rh_core::p2p::GetBlocksMessage {
-                                block_locators: locator,
-                                stop_hash: Hash::zero(),
-                            })).await;
-                        }
-                        
-                        if let Some(stats) = result {
-                            println!("📦 Applied block #{} from peer", stats.height);
-                            
-                            // Update peer height in manager
-                            {
-                                let mut pm = peer_manager.lock().unwrap();
-                                pm.update_peer_height(&addr, stats.height);
-                                pm.broadcast_message(&Message::Inv(vec![InvItem {
-                                    inv_type: InvType::Block,
-                                    hash: block.hash(),
-                                }]));
-                            }
-                            
-                            miner.stop(); // Interrupt to start on new tip
-                        }
-                    },
-                    Message::Tx(tx) => {
-                        let added = {
-                            let mut state = chain_state.lock().unwrap();
-                            match state.add_to_mempool(tx.clone()) {
-                                Ok(_) => true,
-                                Err(e) => {
-                                    eprintln!("❌ Failed to add relay tx {} to mempool: {}", tx.hash(), e);
-                                    false
-                                }
-                            }
-                        };
-
-                        if added {
-                            println!("📥 Relaying transaction: {}", tx.hash());
-                            // Gossip to others
-                            let pm = peer_manager.lock().unwrap();
-                            pm.broadcast_message(&Message::Inv(vec![InvItem {
-                                inv_type: InvType::Transaction,
-                                hash: tx.hash(),
-                            }]));
-                        }
-                    },
-                    Message::GetBlocks(req) => {
-                        let inv_items = {
-                            let state = chain_state.lock().unwrap();
-                            let mut start_height = 0;
-                            
-                            // 1. Find the first common block from locators
-                            for hash in &req.block_locators {
-                                if let Some(h) = state.get_block_height(hash) {
-                                    start_height = h + 1;
-                                    break;
-                                }
-                            }
-
-                            // 2. Collect up to 500 block hashes after common point
-                            let mut items = Vec::new();
-                            let max_height = state.height;
-                            for h in start_height..=std::cmp::min(start_height + 500, max_height) {
-                                if let Some(hash) = state.get_block_hash_at_height(h) {
-                                    items.push(InvItem {
-                                        inv_type: InvType::Block,
-                                        hash,
-                                    });
-                                    if hash == req.stop_hash {
-                                        break;
-                                    }
-                                }
-                            }
-                            items
-                        };
-
-                        if !inv_items.is_empty() {
-                            let _ = peer_tx.send(Message::Inv(inv_items)).await;
-                        }
-                    },
-                    Message::GetHeaders(req) => {
-                        let headers = {
-                            let state = chain_state.lock().unwrap();
-                            let mut start_height = 0;
-
-                            for hash in &req.block_locators {
-                                if let Some(h) = state.get_block_height(hash) {
-                                    start_height = h + 1;
-                                    break;
-                                }
-                            }
-
-                            let mut items = Vec::new();
-                            let max_height = state.height;
-                            for h in start_height..=std::cmp::min(start_height + 2000, max_height) {
-                                if let Some(hash) = state.get_block_hash_at_height(h) {
-                                    if let Some(header) = state.get_block_header(&hash) {
-                                        items.push(header.clone());
-                                        if hash == req.stop_hash {
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                            items
-                        };
-
-                        if !headers.is_empty() {
-                            let _ = peer_tx.send(Message::Headers(headers)).await;
-                        }
-                    },
-                    _ => {}
-                }
-            },
-            Err(_) => {
-                println!("🔌 Peer disconnected: {}", addr);
-                let mut pm = peer_manager.lock().unwrap();
-                pm.peer_disconnected(&addr);
-                break;
-            }
+        if let Err(e) = state.save_fee_estimates() {
+            eprintln!("⚠️  Failed to save fee estimates: {}", e);
         }
     }
-    
-    Ok(())
-}
-
-async fn send_message(stream: &mut TcpStream, msg: &Message) -> tokio::io::Result<()> {
-    let bytes = msg.to_bytes();
-    stream.write_all(&bytes).await
-}
-
-async fn read_message_stream(stream: &mut tokio::net::tcp::OwnedReadHalf) -> Result<Message, String> {
-    let mut magic = [0u8; 4];
-    stream.read_exact(&mut magic).await.map_err(|e| e.to_string())?;
-    if magic != NETWORK_MAGIC {
-        return Err("Invalid magic".to_string());
+    match network.save_peers() {
+        Ok(count) => println!("💾 Saved {} peer addresses", count),
+        Err(e) => eprintln!("⚠️  Failed to save peer addresses: {}", e),
     }
-
-    let mut len_bytes = [0u8; 4];
-    stream.read_exact(&mut len_bytes).await.map_err(|e| e.to_string())?;
-    let len = u32::from_le_bytes(len_bytes) as usize;
     
-    if len > 4 * 1024 * 1024 {
-        return Err("Message too large".to_string());
-    }
-
-    let mut payload = vec![0u8; len];
-    stream.read_exact(&mut payload).await.map_err(|e| e.to_string())?;
+    println!("Stopping node...");
 
-    let mut full_msg = Vec::with_capacity(8 + len);
-    full_msg.extend_from_slice(&magic);
-    full_msg.extend_from_slice(&len_bytes);
-    full_msg.extend_from_slice(&payload);
-    
-    Message::from_bytes(&full_msg)
+    Ok(())
 }
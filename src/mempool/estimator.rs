@@ -0,0 +1,211 @@
+//! Fee estimation from observed confirmation times
+//!
+//! Every transaction entering the mempool is filed in a fee rate bucket
+//! together with the height it was seen at. When a block connects, the
+//! transactions it mines record how many blocks they waited; transactions
+//! that leave the pool any other way count as never confirming. Counts
+//! decay each block so the estimate follows the current fee market.
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::crypto::Hash;
+use crate::validation::Transaction;
+use super::FeeRate;
+
+/// Longest confirmation target that can be estimated (blocks)
+pub const MAX_CONFIRM_TARGET: usize = 48;
+
+/// Lowest bucket boundary (sat/byte)
+const MIN_BUCKET_FEE_RATE: f64 = 1.0;
+
+/// Highest bucket boundary (sat/byte)
+const MAX_BUCKET_FEE_RATE: f64 = 10_000.0;
+
+/// Ratio between consecutive bucket boundaries
+const BUCKET_SPACING: f64 = 1.25;
+
+/// Per-block decay applied to all counts (half-life of ~350 blocks)
+const DECAY: f64 = 0.998;
+
+/// Share of a bucket's transactions that must confirm within the target
+const SUCCESS_THRESHOLD: f64 = 0.85;
+
+/// Decayed sample count a bucket needs before it is trusted
+const MIN_SAMPLES: f64 = 2.0;
+
+/// Confirmation statistics per fee rate bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeEstimator {
+    /// Lower fee rate bound of each bucket (sat/byte)
+    bounds: Vec<f64>,
+    /// Transactions that left the pool, mined or not, per bucket
+    tx_count: Vec<f64>,
+    /// `confirmed[t - 1][b]`: transactions in bucket `b` mined within `t` blocks
+    confirmed: Vec<Vec<f64>>,
+    /// Sum of the fee rates of mined transactions, per bucket
+    fee_rate_sum: Vec<f64>,
+    /// Mined transactions, per bucket
+    mined_count: Vec<f64>,
+    /// Pool transactions being followed: hash -> (height seen, fee rate)
+    #[serde(skip)]
+    tracked: HashMap<Hash, (u64, f64)>,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeeEstimator {
+    /// Create an estimator with no history
+    pub fn new() -> Self {
+        let mut bounds = vec![0.0];
+        let mut bound = MIN_BUCKET_FEE_RATE;
+        while bound <= MAX_BUCKET_FEE_RATE {
+            bounds.push(bound);
+            bound *= BUCKET_SPACING;
+        }
+        let buckets = bounds.len();
+        Self {
+            bounds,
+            tx_count: vec![0.0; buckets],
+            confirmed: vec![vec![0.0; buckets]; MAX_CONFIRM_TARGET],
+            fee_rate_sum: vec![0.0; buckets],
+            mined_count: vec![0.0; buckets],
+            tracked: HashMap::new(),
+        }
+    }
+
+    fn bucket(&self, fee_rate: f64) -> usize {
+        self.bounds.partition_point(|bound| *bound <= fee_rate).saturating_sub(1)
+    }
+
+    /// Start following a transaction that entered the pool at `height`
+    pub fn track(&mut self, hash: Hash, fee_rate: FeeRate, height: u64) {
+        let rate = fee_rate.fee as f64 / fee_rate.size.max(1) as f64;
+        self.tracked.insert(hash, (height, rate));
+    }
+
+    /// Height a followed transaction entered the pool at
+    pub fn seen_height(&self, hash: &Hash) -> Option<u64> {
+        self.tracked.get(hash).map(|(height, _)| *height)
+    }
+
+    /// Record the transactions mined by the block at `height`
+    ///
+    /// Decays the history first, so each block weighs slightly less than
+    /// the one after it.
+    pub fn process_block(&mut self, height: u64, transactions: &[Transaction]) {
+        for count in self.tx_count.iter_mut()
+            .chain(self.fee_rate_sum.iter_mut())
+            .chain(self.mined_count.iter_mut())
+            .chain(self.confirmed.iter_mut().flatten())
+        {
+            *count *= DECAY;
+        }
+
+        for tx in transactions {
+            let Some((seen, rate)) = self.tracked.remove(&tx.hash()) else {
+                continue;
+            };
+            let bucket = self.bucket(rate);
+            let waited = height.saturating_sub(seen).max(1) as usize;
+            self.tx_count[bucket] += 1.0;
+            self.mined_count[bucket] += 1.0;
+            self.fee_rate_sum[bucket] += rate;
+            for target in waited..=MAX_CONFIRM_TARGET {
+                self.confirmed[target - 1][bucket] += 1.0;
+            }
+        }
+    }
+
+    /// Record a transaction leaving the pool without being mined
+    pub fn remove(&mut self, hash: &Hash) {
+        if let Some((_, rate)) = self.tracked.remove(hash) {
+            let bucket = self.bucket(rate);
+            self.tx_count[bucket] += 1.0;
+        }
+    }
+
+    /// Fee rate (sat/byte) likely to confirm within `target` blocks
+    ///
+    /// Walks down from the most expensive bucket and stops at the first one
+    /// that confirms too rarely; returns the average fee rate mined from
+    /// the cheapest bucket that still made it. `None` without enough data.
+    pub fn estimate(&self, target: usize) -> Option<u64> {
+        let target = target.clamp(1, MAX_CONFIRM_TARGET);
+        let mut best = None;
+        for bucket in (0..self.bounds.len()).rev() {
+            let count = self.tx_count[bucket];
+            if count < MIN_SAMPLES {
+                continue;
+            }
+            if self.confirmed[target - 1][bucket] / count < SUCCESS_THRESHOLD {
+                break;
+            }
+            best = Some(bucket);
+        }
+        let bucket = best?;
+        let average = self.fee_rate_sum[bucket] / self.mined_count[bucket];
+        Some((average.round() as u64).max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash_bytes;
+
+    fn mined(fee_rate: u64, waited: u64, estimator: &mut FeeEstimator, height: &mut u64) {
+        let tx = Transaction::coinbase(fee_rate, hash_bytes(&height.to_le_bytes()));
+        estimator.track(tx.hash(), FeeRate::new(fee_rate * 100, 100), *height);
+        *height += waited;
+        estimator.process_block(*height, &[tx]);
+    }
+
+    #[test]
+    fn test_estimate_follows_confirmation_times() {
+        let mut estimator = FeeEstimator::new();
+        let mut height = 0;
+        assert_eq!(estimator.estimate(1), None);
+
+        // High fee rates confirm in the next block, low ones take 10
+        for _ in 0..20 {
+            mined(50, 1, &mut estimator, &mut height);
+            mined(5, 10, &mut estimator, &mut height);
+        }
+
+        let fast = estimator.estimate(1).unwrap();
+        let slow = estimator.estimate(10).unwrap();
+        assert!(fast > 5 && fast <= 50, "fast estimate {}", fast);
+        assert!(slow <= 5, "slow estimate {}", slow);
+    }
+
+    #[test]
+    fn test_unconfirmed_removals_count_as_failures() {
+        let mut estimator = FeeEstimator::new();
+        let mut height = 0;
+        for i in 0..20u64 {
+            mined(50, 1, &mut estimator, &mut height);
+            // Most cheap transactions are evicted instead of mined
+            let evicted = hash_bytes(&i.to_le_bytes());
+            estimator.track(evicted, FeeRate::new(500, 100), height);
+            estimator.remove(&evicted);
+            mined(5, 1, &mut estimator, &mut height);
+        }
+        assert!(estimator.estimate(1).unwrap() > 5);
+    }
+
+    #[test]
+    fn test_stats_survive_serialization() {
+        let mut estimator = FeeEstimator::new();
+        let mut height = 0;
+        for _ in 0..10 {
+            mined(20, 2, &mut estimator, &mut height);
+        }
+        let bytes = bincode::serialize(&estimator).unwrap();
+        let restored: FeeEstimator = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(restored.estimate(2), estimator.estimate(2));
+    }
+}
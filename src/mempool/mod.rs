@@ -0,0 +1,9 @@
+//! Mempool module - Unconfirmed transactions waiting to be mined
+
+mod pool;
+mod rbf;
+mod estimator;
+
+pub use pool::*;
+pub use rbf::*;
+pub use estimator::*;
@@ -0,0 +1,857 @@
+//! Dependency-aware transaction pool
+//!
+//! Transactions are indexed by the outpoints they spend, so a transaction
+//! may spend outputs of other unconfirmed transactions. A transaction also
+//! depends on its sender's transaction with the previous nonce, since
+//! consensus mines a sender's nonces in order. Every entry tracks
+//! the totals of its ancestor package (what a miner must include to mine it)
+//! and of its descendant package (what is lost if it is evicted).
+//!
+//! Entries are also indexed by sender nonce and kept in fee rate order, so
+//! admission, eviction and block templates never scan the whole pool.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use crate::consensus::Block;
+use crate::crypto::Hash;
+use crate::storage::{UTXOKey, UTXOSet, UTXO};
+use crate::validation::Transaction;
+
+/// Maximum in-pool ancestors of a transaction, itself included
+pub const MAX_ANCESTORS: usize = 25;
+
+/// Maximum in-pool descendants of a transaction, itself included
+pub const MAX_DESCENDANTS: usize = 25;
+
+/// Fee per byte kept as a fraction, so comparisons never round
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRate {
+    pub fee: u64,
+    pub size: u64,
+}
+
+impl FeeRate {
+    pub fn new(fee: u64, size: u64) -> Self {
+        Self { fee, size }
+    }
+
+    /// Whole satoshis per byte (rounded down)
+    pub fn per_byte(&self) -> u64 {
+        self.fee / self.size.max(1)
+    }
+}
+
+impl Ord for FeeRate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = self.fee as u128 * other.size.max(1) as u128;
+        let rhs = other.fee as u128 * self.size.max(1) as u128;
+        lhs.cmp(&rhs)
+    }
+}
+
+impl PartialOrd for FeeRate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRate {}
+
+/// Index key ordering entries by a fee rate, ties broken by hash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct RateKey(FeeRate, [u8; 32]);
+
+/// A transaction in the pool with its cached package totals
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    /// Fee paid (inputs minus outputs)
+    pub fee: u64,
+    /// Serialized size in bytes
+    pub size: u64,
+    /// Pubkey hash of the first input's signer
+    pub sender: Option<Hash>,
+    /// Unix time the transaction entered the pool
+    pub time: u64,
+    /// In-pool transactions whose outputs this one spends
+    pub parents: HashSet<Hash>,
+    /// In-pool transactions spending this one's outputs
+    pub children: HashSet<Hash>,
+    /// Fee of this entry plus all in-pool ancestors
+    pub ancestor_fee: u64,
+    /// Size of this entry plus all in-pool ancestors
+    pub ancestor_size: u64,
+    /// Number of in-pool ancestors, this entry included
+    pub ancestor_count: usize,
+    /// Fee of this entry plus all in-pool descendants
+    pub descendant_fee: u64,
+    /// Size of this entry plus all in-pool descendants
+    pub descendant_size: u64,
+    /// Number of in-pool descendants, this entry included
+    pub descendant_count: usize,
+}
+
+impl MempoolEntry {
+    /// Fee rate of this transaction alone
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.fee, self.size)
+    }
+
+    /// Fee rate of the package needed to mine this transaction
+    pub fn ancestor_fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.ancestor_fee, self.ancestor_size)
+    }
+
+    /// Fee rate of the package lost by evicting this transaction
+    pub fn descendant_fee_rate(&self) -> FeeRate {
+        FeeRate::new(self.descendant_fee, self.descendant_size)
+    }
+}
+
+/// Pool of unconfirmed transactions
+#[derive(Debug, Default)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    /// Outpoint -> pool transaction spending it
+    spent_by: HashMap<UTXOKey, Hash>,
+    /// Sender pubkey hash -> nonce -> pool transaction
+    by_sender: HashMap<Hash, BTreeMap<u64, Hash>>,
+    /// Entries ordered by ancestor fee rate, for block templates
+    by_ancestor_rate: BTreeSet<RateKey>,
+    /// Entries ordered by descendant fee rate, for eviction
+    by_descendant_rate: BTreeSet<RateKey>,
+    /// Entries ordered by arrival time, for expiry
+    by_time: BTreeSet<(u64, [u8; 32])>,
+    /// Sum of entry sizes
+    total_bytes: u64,
+}
+
+impl Mempool {
+    /// Create an empty pool
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total serialized size of all transactions
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Check if a transaction is in the pool
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Get a pool entry
+    pub fn get(&self, hash: &Hash) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Iterate over all entries (unordered)
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Pool transaction spending an outpoint, if any
+    pub fn spender(&self, outpoint: &UTXOKey) -> Option<Hash> {
+        self.spent_by.get(outpoint).copied()
+    }
+
+    /// Pool transaction from `sender` (pubkey hash) with the given nonce
+    pub fn find_by_nonce(&self, sender: &Hash, nonce: u64) -> Option<Hash> {
+        self.by_sender.get(sender)?.get(&nonce).copied()
+    }
+
+    /// Check if `sender` (pubkey hash) has any transaction in the pool
+    pub fn has_sender(&self, sender: &Hash) -> bool {
+        self.by_sender.contains_key(sender)
+    }
+
+    /// Lowest nonce `sender` (pubkey hash) uses in the pool
+    pub fn first_nonce(&self, sender: &Hash) -> Option<u64> {
+        self.by_sender.get(sender)?.keys().next().copied()
+    }
+
+    /// Highest nonce `sender` (pubkey hash) uses in the pool
+    pub fn last_nonce(&self, sender: &Hash) -> Option<u64> {
+        self.by_sender.get(sender)?.keys().next_back().copied()
+    }
+
+    /// Look up the outputs a transaction spends
+    ///
+    /// Inputs resolve against the confirmed `utxo_set` first, then against
+    /// outputs of pool transactions, which are reported at `next_height`.
+    /// Returns a set holding just those outputs.
+    pub fn resolve_inputs(&self, tx: &Transaction, utxo_set: &UTXOSet, next_height: u64) -> Result<UTXOSet, String> {
+        let mut inputs = UTXOSet::new();
+        for input in &tx.inputs {
+            let (hash, index) = (input.prev_tx_hash, input.output_index);
+            let utxo = match utxo_set.get(&hash, index) {
+                Some(utxo) => utxo.clone(),
+                None => {
+                    let output = self.entries.get(&hash)
+                        .and_then(|entry| entry.tx.outputs.get(index as usize))
+                        .ok_or_else(|| format!("UTXO not found: {}:{}", hash, index))?;
+                    UTXO {
+                        amount: output.amount,
+                        pubkey_hash: output.pubkey_hash,
+                        height: next_height,
+                        is_coinbase: false,
+                    }
+                }
+            };
+            inputs.add(hash, index, utxo);
+        }
+        Ok(inputs)
+    }
+
+    /// In-pool ancestors of a transaction (not including itself)
+    pub fn ancestors(&self, hash: &Hash) -> HashSet<Hash> {
+        self.walk(hash, |entry| &entry.parents)
+    }
+
+    /// In-pool descendants of a transaction (not including itself)
+    pub fn descendants(&self, hash: &Hash) -> HashSet<Hash> {
+        self.walk(hash, |entry| &entry.children)
+    }
+
+    fn walk(&self, hash: &Hash, next: impl Fn(&MempoolEntry) -> &HashSet<Hash>) -> HashSet<Hash> {
+        let mut found = HashSet::new();
+        let mut stack: Vec<Hash> = self.entries.get(hash).map(|e| next(e).iter().copied().collect()).unwrap_or_default();
+        while let Some(h) = stack.pop() {
+            if found.insert(h) {
+                if let Some(entry) = self.entries.get(&h) {
+                    stack.extend(next(entry).iter().copied());
+                }
+            }
+        }
+        found
+    }
+
+    /// In-pool parents of a transaction that is not (yet) in the pool
+    ///
+    /// The transactions whose outputs it spends, and the one using its
+    /// sender's previous nonce.
+    fn parents_of(&self, tx: &Transaction) -> HashSet<Hash> {
+        let previous_nonce = tx.sender()
+            .zip(tx.nonce.checked_sub(1))
+            .and_then(|(sender, nonce)| self.find_by_nonce(&sender, nonce));
+        tx.inputs.iter()
+            .map(|input| input.prev_tx_hash)
+            .filter(|prev| self.entries.contains_key(prev))
+            .chain(previous_nonce)
+            .collect()
+    }
+
+    /// In-pool children of a transaction that is not (yet) in the pool
+    ///
+    /// Only a transaction returned by a reorg has any: those spending its
+    /// outputs, and the one using its sender's next nonce.
+    fn children_of(&self, tx: &Transaction, hash: &Hash) -> HashSet<Hash> {
+        let next_nonce = tx.sender()
+            .and_then(|sender| self.find_by_nonce(&sender, tx.nonce + 1));
+        (0..tx.outputs.len() as u32)
+            .filter_map(|index| self.spender(&(*hash, index)))
+            .chain(next_nonce)
+            .collect()
+    }
+
+    /// In-pool ancestors a transaction would have if it were added
+    pub fn ancestors_of(&self, tx: &Transaction) -> HashSet<Hash> {
+        let parents = self.parents_of(tx);
+        let mut ancestors = parents.clone();
+        for parent in &parents {
+            ancestors.extend(self.ancestors(parent));
+        }
+        ancestors
+    }
+
+    /// Check the ancestor and descendant limits for a new transaction
+    pub fn check_limits(&self, tx: &Transaction) -> Result<(), String> {
+        let ancestors = self.ancestors_of(tx);
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Err(format!("Too many unconfirmed ancestors ({} > {})", ancestors.len() + 1, MAX_ANCESTORS));
+        }
+        if let Some(full) = ancestors.iter().find(|a| self.entries[*a].descendant_count + 1 > MAX_DESCENDANTS) {
+            return Err(format!("Unconfirmed ancestor {} has too many descendants (max {})", full, MAX_DESCENDANTS));
+        }
+        Ok(())
+    }
+
+    /// Drop an entry from the fee rate indexes
+    fn unindex(&mut self, hash: &Hash) {
+        if let Some(entry) = self.entries.get(hash) {
+            self.by_ancestor_rate.remove(&RateKey(entry.ancestor_fee_rate(), hash.0));
+            self.by_descendant_rate.remove(&RateKey(entry.descendant_fee_rate(), hash.0));
+        }
+    }
+
+    /// Add an entry to the fee rate indexes
+    fn reindex(&mut self, hash: &Hash) {
+        if let Some(entry) = self.entries.get(hash) {
+            self.by_ancestor_rate.insert(RateKey(entry.ancestor_fee_rate(), hash.0));
+            self.by_descendant_rate.insert(RateKey(entry.descendant_fee_rate(), hash.0));
+        }
+    }
+
+    /// Change an entry's package totals, keeping the indexes in step
+    fn update(&mut self, hash: &Hash, f: impl FnOnce(&mut MempoolEntry)) {
+        self.unindex(hash);
+        if let Some(entry) = self.entries.get_mut(hash) {
+            f(entry);
+        }
+        self.reindex(hash);
+    }
+
+    /// Add a validated transaction paying `fee`, received at Unix `time`
+    ///
+    /// Fails if the transaction would exceed the ancestor or descendant
+    /// limits. Conflicts must have been resolved by the caller. Pool entries
+    /// already spending the transaction (it was returned by a reorg) become
+    /// its descendants, which may take its package past the descendant limit.
+    pub fn insert(&mut self, tx: Transaction, fee: u64, time: u64) -> Result<Hash, String> {
+        self.check_limits(&tx)?;
+        let hash = tx.hash();
+        let size = bincode::serialized_size(&tx).unwrap_or(0);
+        let parents = self.parents_of(&tx);
+        let ancestors = self.ancestors_of(&tx);
+        let children = self.children_of(&tx, &hash);
+        let sender = tx.sender();
+
+        let mut entry = MempoolEntry {
+            tx,
+            fee,
+            size,
+            sender,
+            time,
+            parents,
+            children: HashSet::new(),
+            ancestor_fee: fee,
+            ancestor_size: size,
+            ancestor_count: ancestors.len() + 1,
+            descendant_fee: fee,
+            descendant_size: size,
+            descendant_count: 1,
+        };
+        for ancestor in &ancestors {
+            let a = &self.entries[ancestor];
+            entry.ancestor_fee = entry.ancestor_fee.saturating_add(a.fee);
+            entry.ancestor_size += a.size;
+            self.update(ancestor, |a| {
+                a.descendant_fee = a.descendant_fee.saturating_add(fee);
+                a.descendant_size += size;
+                a.descendant_count += 1;
+            });
+        }
+        for parent in &entry.parents {
+            self.entries.get_mut(parent).unwrap().children.insert(hash);
+        }
+        for input in &entry.tx.inputs {
+            self.spent_by.insert((input.prev_tx_hash, input.output_index), hash);
+        }
+
+        if let Some(sender) = sender {
+            self.by_sender.entry(sender).or_default().insert(entry.tx.nonce, hash);
+        }
+
+        self.by_time.insert((time, hash.0));
+        self.total_bytes += size;
+        self.entries.insert(hash, entry);
+        self.reindex(&hash);
+
+        if !children.is_empty() {
+            for child in &children {
+                self.entries.get_mut(child).unwrap().parents.insert(hash);
+            }
+            self.entries.get_mut(&hash).unwrap().children = children;
+            self.refresh_packages(&hash);
+        }
+        Ok(hash)
+    }
+
+    /// Recompute the package totals of everything related to `hash`
+    ///
+    /// Needed when an entry is linked under existing children, which gains
+    /// each of its descendants new ancestors at once.
+    fn refresh_packages(&mut self, hash: &Hash) {
+        let mut affected = self.ancestors(hash);
+        affected.extend(self.descendants(hash));
+        affected.insert(*hash);
+
+        for h in &affected {
+            let ancestors = self.ancestors(h);
+            let descendants = self.descendants(h);
+            let entry = &self.entries[h];
+            let (mut ancestor_fee, mut ancestor_size) = (entry.fee, entry.size);
+            for a in &ancestors {
+                ancestor_fee = ancestor_fee.saturating_add(self.entries[a].fee);
+                ancestor_size += self.entries[a].size;
+            }
+            let (mut descendant_fee, mut descendant_size) = (entry.fee, entry.size);
+            for d in &descendants {
+                descendant_fee = descendant_fee.saturating_add(self.entries[d].fee);
+                descendant_size += self.entries[d].size;
+            }
+            self.update(h, |e| {
+                e.ancestor_fee = ancestor_fee;
+                e.ancestor_size = ancestor_size;
+                e.ancestor_count = ancestors.len() + 1;
+                e.descendant_fee = descendant_fee;
+                e.descendant_size = descendant_size;
+                e.descendant_count = descendants.len() + 1;
+            });
+        }
+    }
+
+    /// Remove one transaction, leaving its descendants in the pool
+    ///
+    /// Only correct when the transaction was mined (its outputs now exist on
+    /// chain); otherwise use `remove_with_descendants`.
+    fn remove_entry(&mut self, hash: &Hash) -> Option<Transaction> {
+        let ancestors = self.ancestors(hash);
+        let descendants = self.descendants(hash);
+        self.unindex(hash);
+        let entry = self.entries.remove(hash)?;
+
+        for ancestor in &ancestors {
+            self.update(ancestor, |a| {
+                a.descendant_fee = a.descendant_fee.saturating_sub(entry.fee);
+                a.descendant_size -= entry.size;
+                a.descendant_count -= 1;
+            });
+        }
+        for descendant in &descendants {
+            self.update(descendant, |d| {
+                d.ancestor_fee = d.ancestor_fee.saturating_sub(entry.fee);
+                d.ancestor_size -= entry.size;
+                d.ancestor_count -= 1;
+            });
+        }
+        for parent in &entry.parents {
+            self.entries.get_mut(parent).unwrap().children.remove(hash);
+        }
+        for child in &entry.children {
+            self.entries.get_mut(child).unwrap().parents.remove(hash);
+        }
+        for input in &entry.tx.inputs {
+            self.spent_by.remove(&(input.prev_tx_hash, input.output_index));
+        }
+        if let Some(sender) = entry.sender {
+            if let Some(nonces) = self.by_sender.get_mut(&sender) {
+                if nonces.get(&entry.tx.nonce) == Some(hash) {
+                    nonces.remove(&entry.tx.nonce);
+                }
+                if nonces.is_empty() {
+                    self.by_sender.remove(&sender);
+                }
+            }
+        }
+
+        self.by_time.remove(&(entry.time, hash.0));
+        self.total_bytes -= entry.size;
+        Some(entry.tx)
+    }
+
+    /// Remove a transaction together with everything spending its outputs
+    pub fn remove_with_descendants(&mut self, hash: &Hash) -> Vec<Transaction> {
+        if !self.entries.contains_key(hash) {
+            return Vec::new();
+        }
+        let mut package: Vec<Hash> = self.descendants(hash).into_iter().collect();
+        package.push(*hash);
+
+        // Leaves first, so no entry outlives its parent
+        package.sort_by_key(|h| std::cmp::Reverse(self.entries[h].ancestor_count));
+        package.iter().filter_map(|h| self.remove_entry(h)).collect()
+    }
+
+    /// Remove the transactions mined in `block` and everything conflicting with it
+    ///
+    /// Conflicts spend the same outputs or reuse a mined sender nonce.
+    /// Children of mined transactions stay, now spending confirmed outputs.
+    /// Returns all removed transactions.
+    pub fn remove_for_block(&mut self, block: &Block) -> Vec<Transaction> {
+        let mut removed = Vec::new();
+
+        // Block order puts parents before children
+        for tx in &block.transactions {
+            removed.extend(self.remove_entry(&tx.hash()));
+        }
+
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            for input in &tx.inputs {
+                if let Some(conflict) = self.spender(&(input.prev_tx_hash, input.output_index)) {
+                    removed.extend(self.remove_with_descendants(&conflict));
+                }
+            }
+            if let Some(conflict) = tx.sender().and_then(|sender| self.find_by_nonce(&sender, tx.nonce)) {
+                removed.extend(self.remove_with_descendants(&conflict));
+            }
+        }
+
+        removed
+    }
+
+    /// Remove transactions that arrived before Unix time `cutoff`
+    ///
+    /// Descendants go too, however recent. Returns all removed transactions.
+    pub fn expire(&mut self, cutoff: u64) -> Vec<Transaction> {
+        let stale: Vec<Hash> = self.by_time.range(..(cutoff, [0u8; 32]))
+            .map(|(_, hash)| Hash(*hash))
+            .collect();
+        stale.iter().flat_map(|hash| self.remove_with_descendants(hash)).collect()
+    }
+
+    /// All transactions with their arrival times
+    ///
+    /// Ordered by ancestor count, then oldest first. A child always has
+    /// more ancestors than its parent, so parents precede their children
+    /// even when they arrived later (returned by a reorg), and the list can
+    /// be replayed through `insert` in order.
+    pub fn dump(&self) -> Vec<(Transaction, u64)> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| (entry.ancestor_count, entry.time));
+        entries.into_iter().map(|entry| (entry.tx.clone(), entry.time)).collect()
+    }
+
+    /// All transactions in block template order, best ancestor fee rate first
+    pub fn select_packages(&self) -> Vec<Transaction> {
+        self.select_packages_within(u64::MAX, usize::MAX)
+    }
+
+    /// Transactions for a block template, best ancestor fee rate first
+    ///
+    /// Each step takes the transaction whose not-yet-selected ancestor
+    /// package pays the best fee rate, and adds that whole package if it
+    /// fits in the remaining `max_size` bytes and `max_sigops` signature
+    /// checks. Every transaction comes after its in-pool parents.
+    pub fn select_packages_within(&self, max_size: u64, max_sigops: usize) -> Vec<Transaction> {
+        let (mut size_left, mut sigops_left) = (max_size, max_sigops);
+        let mut selected: HashSet<Hash> = HashSet::with_capacity(self.entries.len());
+        let mut order = Vec::with_capacity(self.entries.len());
+        // Ancestor totals of entries with some ancestors already selected,
+        // which take them out of `by_ancestor_rate` order
+        let mut modified: HashMap<Hash, (u64, u64)> = HashMap::new();
+        let mut modified_rates: BTreeSet<RateKey> = BTreeSet::new();
+        let mut unmodified = self.by_ancestor_rate.iter().rev().peekable();
+
+        loop {
+            while unmodified.peek().is_some_and(|key| {
+                let hash = Hash(key.1);
+                selected.contains(&hash) || modified.contains_key(&hash)
+            }) {
+                unmodified.next();
+            }
+
+            let best = match (unmodified.peek().copied().copied(), modified_rates.last().copied()) {
+                (None, None) => break,
+                (Some(a), Some(b)) if b > a => {
+                    modified_rates.remove(&b);
+                    b
+                }
+                (Some(a), _) => {
+                    unmodified.next();
+                    a
+                }
+                (None, Some(b)) => {
+                    modified_rates.remove(&b);
+                    b
+                }
+            };
+            let best = Hash(best.1);
+
+            let mut package: Vec<Hash> = self.ancestors(&best).into_iter()
+                .filter(|h| !selected.contains(h))
+                .collect();
+            package.push(best);
+            package.sort_by_key(|h| self.entries[h].ancestor_count);
+
+            // Packages that do not fit are dropped; their descendants'
+            // packages contain them, so they never fit either
+            let size: u64 = package.iter().map(|h| self.entries[h].size).sum();
+            let sigops: usize = package.iter().map(|h| self.entries[h].tx.sigops()).sum();
+            if size > size_left || sigops > sigops_left {
+                continue;
+            }
+            size_left -= size;
+            sigops_left -= sigops;
+
+            for hash in package {
+                let entry = &self.entries[&hash];
+                for descendant in self.descendants(&hash) {
+                    if selected.contains(&descendant) {
+                        continue;
+                    }
+                    let d = &self.entries[&descendant];
+                    let totals = modified.entry(descendant).or_insert((d.ancestor_fee, d.ancestor_size));
+                    modified_rates.remove(&RateKey(FeeRate::new(totals.0, totals.1), descendant.0));
+                    totals.0 = totals.0.saturating_sub(entry.fee);
+                    totals.1 -= entry.size;
+                    modified_rates.insert(RateKey(FeeRate::new(totals.0, totals.1), descendant.0));
+                }
+                if let Some(&(fee, size)) = modified.get(&hash) {
+                    modified_rates.remove(&RateKey(FeeRate::new(fee, size), hash.0));
+                }
+                selected.insert(hash);
+                order.push(entry.tx.clone());
+            }
+        }
+
+        order
+    }
+
+    /// The package that is cheapest to lose, for eviction
+    ///
+    /// Returns the transaction with the lowest descendant fee rate, skipping
+    /// those in `protected`.
+    pub fn worst_package(&self, protected: &HashSet<Hash>) -> Option<(Hash, FeeRate)> {
+        self.by_descendant_rate.iter()
+            .map(|key| (Hash(key.1), key.0))
+            .find(|(hash, _)| !protected.contains(hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{hash_bytes, PublicKey, SchnorrSignature};
+    use crate::validation::{TxInput, TxOutput};
+
+    fn tx(inputs: &[UTXOKey], outputs: usize, tag: &[u8]) -> Transaction {
+        Transaction::new(
+            inputs.iter()
+                .map(|(hash, index)| TxInput {
+                    prev_tx_hash: *hash,
+                    output_index: *index,
+                    signature: SchnorrSignature([0u8; 64]),
+                    public_key: PublicKey([0u8; 32]),
+                })
+                .collect(),
+            (0..outputs).map(|_| TxOutput { amount: 1000, pubkey_hash: hash_bytes(tag) }).collect(),
+        )
+    }
+
+    #[test]
+    fn test_chain_tracks_packages() {
+        let mut pool = Mempool::new();
+        let a = tx(&[(hash_bytes(b"confirmed"), 0)], 2, b"a");
+        let b = tx(&[(a.hash(), 0)], 1, b"b");
+        let c = tx(&[(a.hash(), 1), (b.hash(), 0)], 1, b"c");
+
+        pool.insert(a.clone(), 100, 0).unwrap();
+        pool.insert(b.clone(), 200, 0).unwrap();
+        pool.insert(c.clone(), 300, 0).unwrap();
+
+        let entry_a = pool.get(&a.hash()).unwrap();
+        assert_eq!((entry_a.descendant_fee, entry_a.descendant_count), (600, 3));
+        let entry_c = pool.get(&c.hash()).unwrap();
+        assert_eq!((entry_c.ancestor_fee, entry_c.ancestor_count), (600, 3));
+        assert_eq!(entry_c.parents.len(), 2);
+        assert_eq!(pool.spender(&(b.hash(), 0)), Some(c.hash()));
+
+        // Evicting the root takes the whole package
+        assert_eq!(pool.remove_with_descendants(&a.hash()).len(), 3);
+        assert!(pool.is_empty());
+        assert_eq!(pool.total_bytes(), 0);
+        assert_eq!(pool.spender(&(b.hash(), 0)), None);
+    }
+
+    #[test]
+    fn test_mined_parent_leaves_child() {
+        let mut pool = Mempool::new();
+        let a = tx(&[(hash_bytes(b"confirmed"), 0)], 1, b"a");
+        let b = tx(&[(a.hash(), 0)], 1, b"b");
+        let rival = tx(&[(hash_bytes(b"confirmed"), 1)], 1, b"rival");
+        pool.insert(a.clone(), 100, 0).unwrap();
+        pool.insert(b.clone(), 200, 0).unwrap();
+        pool.insert(rival.clone(), 50, 0).unwrap();
+
+        // A block mines `a` and double-spends `rival`
+        let conflict = tx(&[(hash_bytes(b"confirmed"), 1)], 1, b"other");
+        let block = Block::new(
+            crate::consensus::BlockHeader::new(1, 0x01, Hash::zero(), Hash::zero(), 0, 0x207fffff, 0),
+            vec![Transaction::coinbase(1, hash_bytes(b"miner")), a.clone(), conflict],
+        );
+        assert_eq!(pool.remove_for_block(&block).len(), 2);
+
+        let entry_b = pool.get(&b.hash()).unwrap();
+        assert!(entry_b.parents.is_empty());
+        assert_eq!((entry_b.ancestor_fee, entry_b.ancestor_count), (200, 1));
+        assert!(!pool.contains(&rival.hash()));
+    }
+
+    #[test]
+    fn test_expire_removes_descendants() {
+        let mut pool = Mempool::new();
+        let old = tx(&[(hash_bytes(b"confirmed"), 0)], 1, b"old");
+        let child = tx(&[(old.hash(), 0)], 1, b"child");
+        let fresh = tx(&[(hash_bytes(b"confirmed"), 1)], 1, b"fresh");
+        pool.insert(old.clone(), 100, 10).unwrap();
+        pool.insert(child.clone(), 100, 50).unwrap();
+        pool.insert(fresh.clone(), 100, 50).unwrap();
+
+        assert!(pool.expire(10).is_empty());
+        let expired: HashSet<Hash> = pool.expire(11).iter().map(|t| t.hash()).collect();
+        assert_eq!(expired, HashSet::from([old.hash(), child.hash()]));
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&fresh.hash()));
+    }
+
+    #[test]
+    fn test_ancestor_limit() {
+        let mut pool = Mempool::new();
+        let mut prev = (hash_bytes(b"confirmed"), 0);
+        for i in 0..MAX_ANCESTORS {
+            let t = tx(&[prev], 1, &[i as u8]);
+            prev = (t.hash(), 0);
+            pool.insert(t, 100, 0).unwrap();
+        }
+        assert!(pool.insert(tx(&[prev], 1, b"too deep"), 100, 0).is_err());
+    }
+
+    #[test]
+    fn test_select_packages_by_ancestor_fee_rate() {
+        let mut pool = Mempool::new();
+        // A cheap parent with a child paying for both (CPFP)
+        let parent = tx(&[(hash_bytes(b"p"), 0)], 1, b"parent");
+        let child = tx(&[(parent.hash(), 0)], 1, b"child");
+        // A medium transaction on its own
+        let single = tx(&[(hash_bytes(b"s"), 0)], 1, b"single");
+
+        pool.insert(parent.clone(), 0, 0).unwrap();
+        pool.insert(child.clone(), 10_000, 0).unwrap();
+        pool.insert(single.clone(), 2_000, 0).unwrap();
+
+        let order: Vec<Hash> = pool.select_packages().iter().map(|t| t.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), child.hash(), single.hash()]);
+    }
+
+    #[test]
+    fn test_select_packages_within_limits() {
+        let mut pool = Mempool::new();
+        let parent = tx(&[(hash_bytes(b"p"), 0)], 1, b"parent");
+        let child = tx(&[(parent.hash(), 0)], 1, b"child");
+        let single = tx(&[(hash_bytes(b"s"), 0)], 1, b"single");
+        pool.insert(parent.clone(), 0, 0).unwrap();
+        pool.insert(child.clone(), 10_000, 0).unwrap();
+        pool.insert(single.clone(), 2_000, 0).unwrap();
+        let size = |t: &Transaction| bincode::serialized_size(t).unwrap();
+
+        // The best package does not fit, the next one does
+        let order: Vec<Hash> = pool.select_packages_within(size(&parent) + size(&child) - 1, usize::MAX)
+            .iter().map(|t| t.hash()).collect();
+        assert_eq!(order, vec![single.hash()]);
+
+        // Two signature checks: the package only
+        let order: Vec<Hash> = pool.select_packages_within(u64::MAX, 2)
+            .iter().map(|t| t.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), child.hash()]);
+    }
+
+    #[test]
+    fn test_sender_nonce_index() {
+        let mut pool = Mempool::new();
+        let mut first = tx(&[(hash_bytes(b"confirmed"), 0)], 1, b"first");
+        first.inputs[0].public_key = PublicKey([7u8; 32]);
+        let mut second = tx(&[(first.hash(), 0)], 1, b"second");
+        second.inputs[0].public_key = PublicKey([7u8; 32]);
+        second.nonce = 1;
+        let sender = PublicKey([7u8; 32]).pubkey_hash();
+
+        pool.insert(first.clone(), 100, 0).unwrap();
+        pool.insert(second.clone(), 100, 0).unwrap();
+        assert_eq!(pool.find_by_nonce(&sender, 1), Some(second.hash()));
+        assert_eq!(pool.find_by_nonce(&sender, 2), None);
+        assert_eq!(pool.last_nonce(&sender), Some(1));
+
+        // The next nonce depends on the previous one without spending it
+        let mut third = tx(&[(hash_bytes(b"unrelated"), 0)], 1, b"third");
+        third.inputs[0].public_key = PublicKey([7u8; 32]);
+        third.nonce = 2;
+        pool.insert(third.clone(), 100, 0).unwrap();
+        assert_eq!(pool.get(&third.hash()).unwrap().parents, HashSet::from([second.hash()]));
+
+        assert_eq!(pool.remove_with_descendants(&first.hash()).len(), 3);
+        assert!(!pool.has_sender(&sender));
+    }
+
+    /// Fill a pool with `n` entries in parent/child pairs, build a template
+    /// and evict a tenth of the packages, lowest descendant fee rate first.
+    /// Returns the time taken by the inserts and the template.
+    fn exercise_pool(n: u64) -> std::time::Duration {
+        use std::time::Instant;
+
+        // Pairs of parent and child, fees rising with the index
+        let mut txs = Vec::new();
+        for i in 0..n / 2 {
+            let parent = tx(&[(hash_bytes(&i.to_le_bytes()), 0)], 1, b"parent");
+            let child = tx(&[(parent.hash(), 0)], 1, b"child");
+            txs.push((parent, 1_000 + i));
+            txs.push((child, 2 * (1_000 + i)));
+        }
+
+        let start = Instant::now();
+        let mut pool = Mempool::new();
+        for (t, fee) in txs {
+            pool.insert(t, fee, 0).unwrap();
+        }
+        let template = pool.select_packages();
+        let elapsed = start.elapsed();
+        assert_eq!(template.len() as u64, n);
+
+        let mut last_rate = None;
+        for _ in 0..n / 20 {
+            let (worst, rate) = pool.worst_package(&HashSet::new()).unwrap();
+            assert!(last_rate.is_none_or(|last| rate >= last));
+            last_rate = Some(rate);
+            assert_eq!(pool.remove_with_descendants(&worst).len(), 2);
+        }
+        assert_eq!(pool.len() as u64, n - n / 10);
+        elapsed
+    }
+
+    /// A few thousand entries insert and build a template in milliseconds
+    /// with the indexes, even in debug builds; scanning the whole pool per
+    /// insert takes orders of magnitude longer. The bound leaves ample room
+    /// for slow machines.
+    #[test]
+    fn test_pool_operations_scale() {
+        let elapsed = exercise_pool(4_000);
+        assert!(
+            elapsed < std::time::Duration::from_secs(5),
+            "4000 inserts and a template took {:?}",
+            elapsed
+        );
+    }
+
+    /// The same at tens of thousands of entries, where per-operation scans
+    /// of the whole pool take minutes; with the indexes it takes well under
+    /// a second in release builds. Ignored by default; run it with
+    /// `cargo test --release bench_large_pool -- --ignored`.
+    #[test]
+    #[ignore]
+    fn bench_large_pool() {
+        let elapsed = exercise_pool(20_000);
+        assert!(
+            elapsed < std::time::Duration::from_secs(30),
+            "20000 inserts and a template took {:?}",
+            elapsed
+        );
+    }
+}
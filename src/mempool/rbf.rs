@@ -0,0 +1,148 @@
+//! Replace-by-fee policy
+//!
+//! A transaction that conflicts with pool entries (spends the same outputs,
+//! or reuses its sender's pending nonce) replaces them when all of these hold:
+//!
+//! 1. The conflicts plus all their descendants number at most
+//!    `MAX_REPLACEMENT_EVICTIONS`.
+//! 2. It spends no output of a transaction it would evict.
+//! 3. Its fee rate is higher than that of every direct conflict.
+//! 4. Its absolute fee covers the fees of everything evicted, plus
+//!    `INCREMENTAL_RELAY_FEE` for each of its own bytes, so every
+//!    replacement pays for the bandwidth it uses to relay.
+//!
+//! Any transaction may be replaced; there is no opt-in flag.
+
+use std::collections::HashSet;
+use crate::crypto::Hash;
+use crate::validation::Transaction;
+use super::{FeeRate, Mempool};
+
+/// Maximum pool transactions a single replacement may evict
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+
+/// Extra fee a replacement pays per byte on top of what it evicts
+pub const INCREMENTAL_RELAY_FEE: u64 = 1;
+
+impl Mempool {
+    /// Check `tx` paying `fee` against the replacement rules
+    ///
+    /// `conflicts` are the pool transactions it directly replaces. Returns
+    /// every transaction that would be evicted (conflicts and descendants).
+    pub fn check_replacement(
+        &self,
+        tx: &Transaction,
+        fee: u64,
+        conflicts: &HashSet<Hash>,
+    ) -> Result<HashSet<Hash>, String> {
+        let mut evicted = HashSet::new();
+        for conflict in conflicts {
+            evicted.insert(*conflict);
+            evicted.extend(self.descendants(conflict));
+            if evicted.len() > MAX_REPLACEMENT_EVICTIONS {
+                return Err(format!(
+                    "Replacement would evict more than {} transactions",
+                    MAX_REPLACEMENT_EVICTIONS
+                ));
+            }
+        }
+        if evicted.is_empty() {
+            return Ok(evicted);
+        }
+
+        if tx.inputs.iter().any(|input| evicted.contains(&input.prev_tx_hash)) {
+            return Err("Replacement transaction cannot spend outputs of a transaction it replaces".to_string());
+        }
+
+        let size = bincode::serialized_size(tx).unwrap_or(0);
+        let fee_rate = FeeRate::new(fee, size);
+        for conflict in conflicts.iter().filter_map(|hash| self.get(hash)) {
+            if fee_rate <= conflict.fee_rate() {
+                return Err("Replacement transaction must have higher fee rate".to_string());
+            }
+        }
+
+        let evicted_fees: u64 = evicted.iter()
+            .filter_map(|hash| self.get(hash))
+            .map(|entry| entry.fee)
+            .sum();
+        let required = evicted_fees.saturating_add(INCREMENTAL_RELAY_FEE * size);
+        if fee < required {
+            return Err(format!(
+                "Replacement fee {} too low: must be at least {} (replaced fees {} plus {} sat/byte)",
+                fee, required, evicted_fees, INCREMENTAL_RELAY_FEE
+            ));
+        }
+
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{hash_bytes, PublicKey, SchnorrSignature};
+    use crate::storage::UTXOKey;
+    use crate::validation::{TxInput, TxOutput};
+
+    fn tx(inputs: &[UTXOKey], tag: &[u8]) -> Transaction {
+        Transaction::new(
+            inputs.iter()
+                .map(|(hash, index)| TxInput {
+                    prev_tx_hash: *hash,
+                    output_index: *index,
+                    signature: SchnorrSignature([0u8; 64]),
+                    public_key: PublicKey([0u8; 32]),
+                })
+                .collect(),
+            vec![TxOutput { amount: 1000, pubkey_hash: hash_bytes(tag) }],
+        )
+    }
+
+    #[test]
+    fn test_replacement_rules() {
+        let mut pool = Mempool::new();
+        let (x, y) = ((hash_bytes(b"x"), 0), (hash_bytes(b"y"), 0));
+        let a = tx(&[x], b"a");
+        let b = tx(&[y], b"b");
+        let child = tx(&[(a.hash(), 0)], b"child");
+        pool.insert(a.clone(), 1_000, 0).unwrap();
+        pool.insert(b.clone(), 1_000, 0).unwrap();
+        pool.insert(child.clone(), 1_000, 0).unwrap();
+
+        // Replaces both a and b, which also evicts a's child
+        let replacement = tx(&[x, y], b"replacement");
+        let size = bincode::serialized_size(&replacement).unwrap();
+        let conflicts = HashSet::from([a.hash(), b.hash()]);
+
+        let err = pool.check_replacement(&replacement, 3_000, &conflicts).unwrap_err();
+        assert!(err.contains("too low"));
+        let evicted = pool.check_replacement(&replacement, 3_000 + size, &conflicts).unwrap();
+        assert_eq!(evicted, HashSet::from([a.hash(), b.hash(), child.hash()]));
+
+        // Spending what it evicts is never allowed
+        let spends_child = tx(&[x, (child.hash(), 0)], b"bad");
+        assert!(pool.check_replacement(&spends_child, 1_000_000, &HashSet::from([a.hash()])).is_err());
+    }
+
+    #[test]
+    fn test_replacement_eviction_limit() {
+        let mut pool = Mempool::new();
+        let root = tx(&[(hash_bytes(b"x"), 0)], b"root");
+        pool.insert(root.clone(), 1_000, 0).unwrap();
+        // Descendant limits keep one package small, so conflict with many
+        // independent transactions instead
+        let mut conflicts = HashSet::from([root.hash()]);
+        let mut inputs = vec![(hash_bytes(b"x"), 0)];
+        for i in 0..MAX_REPLACEMENT_EVICTIONS as u32 {
+            let t = tx(&[(hash_bytes(b"fan"), i)], b"fan");
+            conflicts.insert(t.hash());
+            inputs.push((hash_bytes(b"fan"), i));
+            pool.insert(t, 1_000, 0).unwrap();
+        }
+
+        let replacement = tx(&inputs, b"replacement");
+        let err = pool.check_replacement(&replacement, u64::MAX, &conflicts).unwrap_err();
+        assert!(err.contains("evict more than"));
+    }
+}
@@ -2,10 +2,10 @@
 //! 
 //! Assembles candidate blocks and performs PoW.
 
-use crate::consensus::{Block, BlockHeader, calculate_block_reward};
+use crate::consensus::{Block, BlockHeader, calculate_block_reward, compact_to_target};
 use crate::crypto::{Hash, compute_merkle_root};
 use crate::validation::Transaction;
-use crate::storage::ChainState;
+use crate::storage::{ChainState, UTXOSet};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -54,23 +54,45 @@ impl Miner {
         self.stop_signal.store(false, Ordering::SeqCst);
     }
 
-    /// Assemble a candidate block
-    pub fn assemble_block(
-        &self,
-        chain_state: &ChainState,
-        transactions: Vec<Transaction>,
-    ) -> Block {
+    /// Assemble a candidate block from the mempool
+    /// 
+    /// Packages are taken best ancestor fee rate first, for as long as they
+    /// fit in `MAX_BLOCK_WEIGHT` and `MAX_BLOCK_SIGOPS` next to the coinbase.
+    pub fn assemble_block(&self, chain_state: &ChainState) -> Block {
         let height = chain_state.height + 1;
         let subsidy = calculate_block_reward(height, chain_state.total_issued);
+        let miner_pkh = *self.miner_pubkey_hash.lock().unwrap();
+
+        // The coinbase amount does not change its size, so the space it and
+        // the header take is known before any transaction is picked
+        let empty = Block::new(
+            BlockHeader::new(1, crate::constants::CHAIN_ID, chain_state.tip_hash, Hash::zero(), 0, 0, 0),
+            vec![Transaction::coinbase(subsidy, miner_pkh)],
+        );
+        let transactions = chain_state.mempool.select_packages_within(
+            crate::constants::MAX_BLOCK_WEIGHT.saturating_sub(empty.weight()),
+            crate::constants::MAX_BLOCK_SIGOPS,
+        );
         
         // Calculate transaction fees
+        // Inputs may spend outputs of earlier transactions in the same block
         let mut total_fees = 0u64;
+        let mut block_outputs = UTXOSet::new();
         for tx in &transactions {
-            total_fees = total_fees.saturating_add(tx.fee(&chain_state.utxo_set));
+            let input_value = tx.inputs.iter()
+                .filter_map(|input| {
+                    block_outputs.get(&input.prev_tx_hash, input.output_index)
+                        .or_else(|| chain_state.utxo_set.get(&input.prev_tx_hash, input.output_index))
+                })
+                .try_fold(0u64, |total, utxo| total.checked_add(utxo.amount));
+            // Pool entries passed the overflow checks on admission
+            let fee = input_value.zip(tx.total_output_value())
+                .map_or(0, |(input_value, output_value)| input_value.saturating_sub(output_value));
+            total_fees = total_fees.saturating_add(fee);
+            block_outputs.apply_transaction(tx, height);
         }
 
         // Create coinbase transaction (subsidy + fees)
-        let miner_pkh = *self.miner_pubkey_hash.lock().unwrap();
         let coinbase = Transaction::coinbase(subsidy.saturating_add(total_fees), miner_pkh);
 
         // Combine coinbase with other transactions
@@ -94,7 +116,7 @@ impl Miner {
             chain_state.tip_hash,
             merkle_root,
             timestamp,
-            chain_state.difficulty,
+            chain_state.difficulty, // retargeted target for the next block
             0, // nonce starts at 0
         );
 
@@ -106,7 +128,7 @@ impl Miner {
     /// This performs the PoW loop, incrementing the nonce until
     /// a valid hash is found or mining is interrupted.
     pub fn mine_block(&self, mut block: Block) -> MiningResult {
-        let target = difficulty_to_target(block.header.difficulty_target);
+        let target = compact_to_target(block.header.difficulty_target);
 
         loop {
             // Check stop signal
@@ -145,7 +167,7 @@ impl Miner {
     where
         F: FnMut(u64), // nonce count
     {
-        let target = difficulty_to_target(block.header.difficulty_target);
+        let target = compact_to_target(block.header.difficulty_target);
         let mut iterations = 0u64;
 
         loop {
@@ -162,7 +184,7 @@ impl Miner {
             block.header.nonce = block.header.nonce.wrapping_add(1);
             iterations += 1;
 
-            if iterations % progress_interval == 0 {
+            if iterations.is_multiple_of(progress_interval) {
                 callback(iterations);
             }
 
@@ -176,52 +198,13 @@ impl Miner {
     }
 }
 
-/// Convert compact difficulty to 256-bit target
-fn difficulty_to_target(compact: u32) -> [u8; 32] {
-    let exponent = (compact >> 24) as usize;
-    let mantissa = compact & 0x007FFFFF;
-
-    let mut target = [0u8; 32];
-
-    if exponent == 0 || exponent > 32 {
-        return target;
-    }
-
-    let negative = (compact & 0x00800000) != 0;
-    if negative {
-        return target;
-    }
-
-    if exponent <= 3 {
-        let value = mantissa >> (8 * (3 - exponent));
-        target[31] = (value & 0xFF) as u8;
-        if exponent >= 2 {
-            target[30] = ((value >> 8) & 0xFF) as u8;
-        }
-        if exponent >= 3 {
-            target[29] = ((value >> 16) & 0xFF) as u8;
-        }
-    } else {
-        let start = 32 - exponent;
-        target[start] = ((mantissa >> 16) & 0xFF) as u8;
-        if start + 1 < 32 {
-            target[start + 1] = ((mantissa >> 8) & 0xFF) as u8;
-        }
-        if start + 2 < 32 {
-            target[start + 2] = (mantissa & 0xFF) as u8;
-        }
-    }
-
-    target
-}
-
 /// Compare hash to target (hash <= target)
 fn compare_to_target(hash: &Hash, target: &[u8; 32]) -> bool {
-    for i in 0..32 {
-        if hash.0[i] < target[i] {
+    for (h, t) in hash.0.iter().zip(target.iter()) {
+        if h < t {
             return true;
         }
-        if hash.0[i] > target[i] {
+        if h > t {
             return false;
         }
     }
@@ -254,9 +237,8 @@ mod tests {
             vec![]
         );
         let chain_state = ChainState::new(&genesis);
-        let transactions = vec![];
 
-        let block = miner.assemble_block(&chain_state, transactions);
+        let block = miner.assemble_block(&chain_state);
 
         assert_eq!(block.header.version, 1);
         assert_eq!(block.header.prev_hash, chain_state.tip_hash);
@@ -266,6 +248,46 @@ mod tests {
         assert!(block.transactions[0].is_coinbase());
     }
 
+    #[test]
+    fn test_assemble_block_respects_sigop_limit() {
+        use crate::constants::{MAX_BLOCK_SIGOPS, MAX_BLOCK_WEIGHT};
+        use crate::crypto::{PublicKey, SchnorrSignature};
+        use crate::validation::{TxInput, TxOutput};
+
+        let miner = Miner::new(Arc::new(Mutex::new(hash_bytes(b"miner"))));
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), Hash::zero(), 0, 0x207fffff, 0),
+            vec![],
+        );
+        let mut chain_state = ChainState::new(&genesis);
+
+        // Three independent transactions, any two of which use most of the
+        // signature budget; fees are not checked by the pool itself
+        let inputs = MAX_BLOCK_SIGOPS / 2 - 100;
+        let mut txs = Vec::new();
+        for (i, fee) in [(0u32, 1_000u64), (1, 3_000), (2, 2_000)] {
+            let tx = Transaction::new(
+                (0..inputs as u32)
+                    .map(|index| TxInput {
+                        prev_tx_hash: hash_bytes(&i.to_le_bytes()),
+                        output_index: index,
+                        signature: SchnorrSignature([0u8; 64]),
+                        public_key: PublicKey([i as u8; 32]),
+                    })
+                    .collect(),
+                vec![TxOutput { amount: 1, pubkey_hash: Hash::zero() }],
+            );
+            chain_state.mempool.insert(tx.clone(), fee, 0).unwrap();
+            txs.push(tx);
+        }
+
+        let block = miner.assemble_block(&chain_state);
+        let mined: Vec<Hash> = block.transactions.iter().skip(1).map(|tx| tx.hash()).collect();
+        assert_eq!(mined, vec![txs[1].hash(), txs[2].hash()]);
+        assert!(block.sigops() <= MAX_BLOCK_SIGOPS);
+        assert!(block.weight() <= MAX_BLOCK_WEIGHT);
+    }
+
     #[test]
     fn test_miner_stop_signal() {
         let miner = Miner::new(Arc::new(Mutex::new(Hash::zero()))); // Updated here
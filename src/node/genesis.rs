@@ -8,8 +8,8 @@ use crate::crypto::{Hash, compute_merkle_root, hash_bytes};
 use crate::validation::Transaction;
 use crate::constants::{FOUNDER_ALLOCATION, GENESIS_TIMESTAMP, FOUNDER_ADDRESS, CONSTITUTION_HASH};
 
-/// Initial difficulty target (easy for genesis)
-const GENESIS_DIFFICULTY: u32 = 0x1e00ffff;
+/// Initial difficulty target, the easiest the network allows
+const GENESIS_DIFFICULTY: u32 = crate::consensus::POW_LIMIT;
 
 /// Genesis block version
 const GENESIS_VERSION: u32 = 1;
@@ -0,0 +1,567 @@
+//! Address manager
+//!
+//! Remembers the addresses of peers we could connect to: those gossiped in
+//! `addr` messages, those inbound peers listen on and those we dialed
+//! ourselves. The table is saved to `peers.dat`, so a restarted node finds
+//! the network again without the seed nodes.
+//!
+//! As in Bitcoin's addrman, an address starts in a "new" bucket and moves to
+//! a "tried" bucket once we connected to it. Buckets are picked by hashing
+//! the address's network group (and, for new addresses, the group of the
+//! peer that told us about it) with a secret key, so a single peer or a
+//! single address range can only ever fill a few buckets.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::crypto::hash_bytes;
+use super::NetAddress;
+
+/// Buckets holding addresses we have not connected to yet
+pub const NEW_BUCKET_COUNT: usize = 256;
+
+/// Buckets holding addresses we have connected to
+pub const TRIED_BUCKET_COUNT: usize = 64;
+
+/// Addresses per bucket
+pub const BUCKET_SIZE: usize = 64;
+
+/// New buckets that addresses from one source group can spread over
+pub const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+
+/// Tried buckets that one address group can spread over
+pub const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+/// Most addresses in one `addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// Share of the table sent in answer to `getaddr` (percent)
+const GETADDR_PERCENT: usize = 23;
+
+/// Addresses always shared when we know that many, so small networks bootstrap
+const GETADDR_MIN: usize = 8;
+
+/// Addresses not heard of for this long are dropped (seconds)
+pub const ADDR_HORIZON: u64 = 30 * 24 * 3600;
+
+/// Timestamps further in the future than this are reset to now (seconds)
+const MAX_TIMESTAMP_DRIFT: u64 = 10 * 60;
+
+/// Failed attempts after which a never-reached address is given up
+const MAX_RETRIES: u32 = 3;
+
+/// Failed attempts in a row after which a once-reached address is given up...
+const MAX_FAILURES: u32 = 10;
+
+/// ...if its last successful connection is older than this (seconds)
+const MIN_FAIL_AGE: u64 = 7 * 24 * 3600;
+
+/// File the address table is saved to, in the data directory
+pub const PEERS_FILE: &str = "peers.dat";
+
+/// Format version of the peers file
+const PEERS_FILE_VERSION: u32 = 1;
+
+/// Current time in unix seconds
+pub(crate) fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Network group of an address: its /16 for IPv4, its /32 for IPv6
+///
+/// IPv4-mapped IPv6 addresses share the group of the IPv4 address.
+pub fn network_group(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            vec![4, octets[0], octets[1]]
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => network_group(IpAddr::V4(v4)),
+            None => {
+                let octets = v6.octets();
+                vec![6, octets[0], octets[1], octets[2], octets[3]]
+            }
+        },
+    }
+}
+
+/// What we know about one address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddrInfo {
+    /// Address, services and when it was last heard of
+    pub address: NetAddress,
+    /// Network group of the peer that told us about it
+    pub source_group: Vec<u8>,
+    /// Last connection attempt (unix seconds, 0 = never)
+    pub last_try: u64,
+    /// Last successful connection (unix seconds, 0 = never)
+    pub last_success: u64,
+    /// Failed connection attempts since the last success
+    pub attempts: u32,
+    /// Held in a tried bucket
+    pub tried: bool,
+}
+
+impl AddrInfo {
+    fn new(address: NetAddress, source_group: Vec<u8>) -> Self {
+        Self {
+            address,
+            source_group,
+            last_try: 0,
+            last_success: 0,
+            attempts: 0,
+            tried: false,
+        }
+    }
+
+    /// Check if the address is not worth keeping or sharing
+    pub fn is_terrible(&self, now: u64) -> bool {
+        // Tried in the last minute: give the attempt a chance to finish
+        if self.last_try > 0 && now.saturating_sub(self.last_try) < 60 {
+            return false;
+        }
+        if self.address.timestamp + ADDR_HORIZON < now {
+            return true;
+        }
+        if self.last_success == 0 {
+            return self.attempts >= MAX_RETRIES;
+        }
+        self.attempts >= MAX_FAILURES && now.saturating_sub(self.last_success) > MIN_FAIL_AGE
+    }
+
+    /// Relative chance of being selected, lower after each failed attempt
+    fn chance(&self) -> f64 {
+        0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+/// Persistent table of peer addresses in new and tried buckets
+#[derive(Debug)]
+pub struct AddrMan {
+    /// Secret bucket placement key, kept across restarts
+    key: [u8; 32],
+    /// Every known address; each sits in exactly one bucket
+    entries: HashMap<SocketAddr, AddrInfo>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+}
+
+/// On-disk form of the table; buckets are rebuilt on load
+#[derive(Serialize, Deserialize)]
+struct PeersFile {
+    version: u32,
+    key: [u8; 32],
+    entries: Vec<AddrInfo>,
+}
+
+impl AddrMan {
+    /// Create an empty table with a fresh random key
+    pub fn new() -> Self {
+        Self::with_key(rand::random())
+    }
+
+    fn with_key(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            entries: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT],
+        }
+    }
+
+    /// Hash `parts` with the secret key
+    fn keyed_hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut bytes = self.key.to_vec();
+        for part in parts {
+            bytes.push(part.len() as u8);
+            bytes.extend_from_slice(part);
+        }
+        let hash = hash_bytes(&bytes);
+        u64::from_le_bytes(hash.0[..8].try_into().unwrap())
+    }
+
+    /// New bucket of an address learned from a peer in `source_group`
+    ///
+    /// Addresses from one source group land in at most
+    /// `NEW_BUCKETS_PER_SOURCE_GROUP` buckets.
+    fn new_bucket(&self, addr: &SocketAddr, source_group: &[u8]) -> usize {
+        let group = network_group(addr.ip());
+        let slot = self.keyed_hash(&[b"new", &group, source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.keyed_hash(&[b"new", source_group, &slot.to_le_bytes()]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Tried bucket of an address
+    ///
+    /// Addresses from one group land in at most `TRIED_BUCKETS_PER_GROUP`
+    /// buckets.
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        let group = network_group(addr.ip());
+        let slot = self.keyed_hash(&[b"tried", addr.to_string().as_bytes()]) % TRIED_BUCKETS_PER_GROUP;
+        (self.keyed_hash(&[b"tried", &group, &slot.to_le_bytes()]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Learn about `address` from the peer at `source`
+    ///
+    /// Timestamps from the future are reset to `now`; addresses older than
+    /// `ADDR_HORIZON` are ignored. Returns true if the address was new to us.
+    pub fn add(&mut self, mut address: NetAddress, source: IpAddr, now: u64) -> bool {
+        if address.addr.port() == 0 || address.addr.ip().is_unspecified() {
+            return false;
+        }
+        if address.timestamp > now + MAX_TIMESTAMP_DRIFT {
+            address.timestamp = now;
+        }
+        if address.timestamp + ADDR_HORIZON < now {
+            return false;
+        }
+
+        if let Some(info) = self.entries.get_mut(&address.addr) {
+            info.address.timestamp = info.address.timestamp.max(address.timestamp);
+            info.address.services |= address.services;
+            return false;
+        }
+
+        self.insert_new(AddrInfo::new(address, network_group(source)), now)
+    }
+
+    /// Place an entry in its new bucket
+    ///
+    /// A full bucket evicts a terrible entry, or else the one heard of
+    /// longest ago if `info` is fresher. Returns false if `info` was dropped.
+    fn insert_new(&mut self, mut info: AddrInfo, now: u64) -> bool {
+        info.tried = false;
+        let bucket = self.new_bucket(&info.address.addr, &info.source_group);
+
+        if self.new_buckets[bucket].len() >= BUCKET_SIZE {
+            let (index, replaceable) = self.new_buckets[bucket].iter()
+                .map(|addr| &self.entries[addr])
+                .enumerate()
+                .max_by_key(|(_, entry)| (entry.is_terrible(now), Reverse(entry.address.timestamp)))
+                .map(|(index, entry)| {
+                    (index, entry.is_terrible(now) || entry.address.timestamp < info.address.timestamp)
+                })
+                .unwrap();
+            if !replaceable {
+                return false;
+            }
+            let evicted = self.new_buckets[bucket].swap_remove(index);
+            self.entries.remove(&evicted);
+        }
+
+        self.new_buckets[bucket].push(info.address.addr);
+        self.entries.insert(info.address.addr, info);
+        true
+    }
+
+    /// Place an entry in its tried bucket
+    ///
+    /// A full bucket moves its least recently connected entry back to new.
+    fn insert_tried(&mut self, mut info: AddrInfo, now: u64) {
+        info.tried = true;
+        let bucket = self.tried_bucket(&info.address.addr);
+
+        if self.tried_buckets[bucket].len() >= BUCKET_SIZE {
+            let index = self.tried_buckets[bucket].iter()
+                .enumerate()
+                .min_by_key(|(_, addr)| self.entries[*addr].last_success)
+                .map(|(index, _)| index)
+                .unwrap();
+            let evicted = self.tried_buckets[bucket].swap_remove(index);
+            if let Some(evicted) = self.entries.remove(&evicted) {
+                self.insert_new(evicted, now);
+            }
+        }
+
+        self.tried_buckets[bucket].push(info.address.addr);
+        self.entries.insert(info.address.addr, info);
+    }
+
+    /// Take an entry out of the table
+    fn remove(&mut self, addr: &SocketAddr) -> Option<AddrInfo> {
+        let info = self.entries.remove(addr)?;
+        if info.tried {
+            let bucket = self.tried_bucket(addr);
+            self.tried_buckets[bucket].retain(|a| a != addr);
+        } else {
+            let bucket = self.new_bucket(addr, &info.source_group);
+            self.new_buckets[bucket].retain(|a| a != addr);
+        }
+        Some(info)
+    }
+
+    /// Record a connection attempt to `addr`
+    pub fn mark_attempt(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some(info) = self.entries.get_mut(addr) {
+            info.last_try = now;
+            info.attempts += 1;
+        }
+    }
+
+    /// Record a successful connection to `addr`, moving it to a tried bucket
+    pub fn mark_good(&mut self, addr: SocketAddr, services: u64, now: u64) {
+        let mut info = self.remove(&addr).unwrap_or_else(|| {
+            AddrInfo::new(NetAddress { addr, services, timestamp: now }, network_group(addr.ip()))
+        });
+        info.address.services = services;
+        info.address.timestamp = now;
+        info.last_try = now;
+        info.last_success = now;
+        info.attempts = 0;
+        self.insert_tried(info, now);
+    }
+
+    /// Pick an address to dial
+    ///
+    /// New and tried addresses are equally likely when both exist; within a
+    /// table, addresses that failed recently are picked less often.
+    pub fn select(&self) -> Option<SocketAddr> {
+        let mut rng = rand::thread_rng();
+        let use_tried = self.tried_count() > 0 && (self.new_count() == 0 || rng.gen_bool(0.5));
+        let buckets = if use_tried { &self.tried_buckets } else { &self.new_buckets };
+        let candidates: Vec<&SocketAddr> = buckets.iter().flatten().collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        // Rejection sampling, relaxed on every miss so it always ends
+        let mut factor = 1.0;
+        loop {
+            let addr = candidates[rng.gen_range(0..candidates.len())];
+            if rng.gen::<f64>() < self.entries[addr].chance() * factor {
+                return Some(*addr);
+            }
+            factor *= 1.2;
+        }
+    }
+
+    /// Addresses to answer a `getaddr` with, in random order
+    ///
+    /// Only a share of the table is returned, so a peer cannot scrape it all
+    /// at once.
+    pub fn get_addresses(&self, now: u64) -> Vec<NetAddress> {
+        let mut addresses: Vec<NetAddress> = self.entries.values()
+            .filter(|info| !info.is_terrible(now))
+            .map(|info| info.address)
+            .collect();
+        let count = (addresses.len() * GETADDR_PERCENT / 100)
+            .max(addresses.len().min(GETADDR_MIN))
+            .min(MAX_ADDR_PER_MESSAGE);
+        addresses.shuffle(&mut rand::thread_rng());
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// Get what we know about an address
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.entries.get(addr)
+    }
+
+    /// Number of known addresses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if no addresses are known
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of addresses in new buckets
+    pub fn new_count(&self) -> usize {
+        self.new_buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Number of addresses in tried buckets
+    pub fn tried_count(&self) -> usize {
+        self.tried_buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Save the table to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let file = PeersFile {
+            version: PEERS_FILE_VERSION,
+            key: self.key,
+            entries: self.entries.values().cloned().collect(),
+        };
+        let bytes = bincode::serialize(&file)
+            .map_err(std::io::Error::other)?;
+        let mut file = File::create(path)?;
+        file.write_all(&bytes)
+    }
+
+    /// Load a table saved with `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let file: PeersFile = bincode::deserialize(&bytes)
+            .map_err(std::io::Error::other)?;
+        if file.version != PEERS_FILE_VERSION {
+            return Err(std::io::Error::other(format!("Unsupported peers file version {}", file.version)));
+        }
+
+        let now = unix_time();
+        let mut addrman = Self::with_key(file.key);
+        for info in file.entries {
+            if info.tried {
+                addrman.insert_tried(info, now);
+            } else {
+                addrman.insert_new(info, now);
+            }
+        }
+        Ok(addrman)
+    }
+}
+
+impl Default for AddrMan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn address(ip: [u8; 4], port: u16, timestamp: u64) -> NetAddress {
+        NetAddress {
+            addr: SocketAddr::from((ip, port)),
+            services: crate::p2p::NODE_NETWORK,
+            timestamp,
+        }
+    }
+
+    fn source(ip: [u8; 4]) -> IpAddr {
+        IpAddr::from(ip)
+    }
+
+    #[test]
+    fn test_network_groups() {
+        assert_eq!(network_group("1.2.3.4".parse().unwrap()), network_group("1.2.200.1".parse().unwrap()));
+        assert_ne!(network_group("1.2.3.4".parse().unwrap()), network_group("1.3.3.4".parse().unwrap()));
+        assert_eq!(network_group("::ffff:1.2.3.4".parse().unwrap()), network_group("1.2.9.9".parse().unwrap()));
+        assert_eq!(network_group("2001:db8::1".parse().unwrap()), network_group("2001:db8:ffff::1".parse().unwrap()));
+        assert_ne!(network_group("2001:db8::1".parse().unwrap()), network_group("2001:db9::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_add_and_timestamps() {
+        let mut addrman = AddrMan::new();
+        assert!(addrman.add(address([1, 2, 3, 4], 8333, NOW - 100), source([5, 6, 7, 8]), NOW));
+        assert!(!addrman.add(address([1, 2, 3, 4], 8333, NOW), source([9, 9, 9, 9]), NOW));
+        assert_eq!(addrman.get(&SocketAddr::from(([1, 2, 3, 4], 8333))).unwrap().address.timestamp, NOW);
+
+        // Future timestamps are clamped, stale and unroutable addresses ignored
+        assert!(addrman.add(address([1, 2, 3, 5], 8333, NOW + 86_400), source([5, 6, 7, 8]), NOW));
+        assert_eq!(addrman.get(&SocketAddr::from(([1, 2, 3, 5], 8333))).unwrap().address.timestamp, NOW);
+        assert!(!addrman.add(address([1, 2, 3, 6], 8333, NOW - ADDR_HORIZON - 1), source([5, 6, 7, 8]), NOW));
+        assert!(!addrman.add(address([0, 0, 0, 0], 8333, NOW), source([5, 6, 7, 8]), NOW));
+        assert!(!addrman.add(address([1, 2, 3, 7], 0, NOW), source([5, 6, 7, 8]), NOW));
+
+        assert_eq!((addrman.len(), addrman.new_count(), addrman.tried_count()), (2, 2, 0));
+    }
+
+    #[test]
+    fn test_one_source_cannot_fill_the_table() {
+        let mut addrman = AddrMan::new();
+
+        // One address range from one source fits in a single bucket
+        for i in 0..1000u16 {
+            addrman.add(address([10, 20, (i >> 8) as u8, i as u8], 8333, NOW), source([5, 6, 7, 8]), NOW);
+        }
+        assert_eq!(addrman.len(), BUCKET_SIZE);
+
+        // Many ranges from one source group spread over a bounded set of buckets
+        let mut addrman = AddrMan::new();
+        for i in 0..20_000u32 {
+            let b = i.to_be_bytes();
+            addrman.add(address([b[1].wrapping_add(11), b[2], b[3], 1], 8333, NOW - 60), source([5, 6, 7, 8]), NOW);
+        }
+        assert!(addrman.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize * BUCKET_SIZE);
+
+        // Another source still gets its addresses in, evicting an older
+        // entry if it lands in one of the flooded buckets
+        assert!(addrman.add(address([99, 1, 1, 1], 8333, NOW), source([77, 7, 7, 7]), NOW));
+    }
+
+    #[test]
+    fn test_mark_good_moves_to_tried() {
+        let mut addrman = AddrMan::new();
+        let addr = SocketAddr::from(([1, 2, 3, 4], 8333));
+        addrman.add(address([1, 2, 3, 4], 8333, NOW - 100), source([5, 6, 7, 8]), NOW);
+
+        addrman.mark_attempt(&addr, NOW);
+        assert_eq!(addrman.get(&addr).unwrap().attempts, 1);
+        addrman.mark_good(addr, crate::p2p::NODE_NETWORK, NOW + 1);
+
+        let info = addrman.get(&addr).unwrap();
+        assert!(info.tried);
+        assert_eq!((info.attempts, info.last_success, info.address.timestamp), (0, NOW + 1, NOW + 1));
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (0, 1));
+        assert_eq!(addrman.select(), Some(addr));
+
+        // Addresses we dialed without hearing of them first are tried too
+        addrman.mark_good(SocketAddr::from(([4, 3, 2, 1], 8333)), 0, NOW);
+        assert_eq!(addrman.tried_count(), 2);
+    }
+
+    #[test]
+    fn test_failing_addresses_become_terrible() {
+        let mut addrman = AddrMan::new();
+        let addr = SocketAddr::from(([1, 2, 3, 4], 8333));
+        addrman.add(address([1, 2, 3, 4], 8333, NOW), source([5, 6, 7, 8]), NOW);
+        addrman.add(address([1, 2, 3, 5], 8333, NOW), source([5, 6, 7, 8]), NOW);
+
+        for _ in 0..MAX_RETRIES {
+            addrman.mark_attempt(&addr, NOW);
+        }
+        assert!(!addrman.get(&addr).unwrap().is_terrible(NOW + 1));
+        assert!(addrman.get(&addr).unwrap().is_terrible(NOW + 120));
+
+        let shared = addrman.get_addresses(NOW + 120);
+        assert_eq!(shared.len(), 1);
+        assert_ne!(shared[0].addr, addr);
+    }
+
+    #[test]
+    fn test_get_addresses_shares_part_of_the_table() {
+        let mut addrman = AddrMan::new();
+        for i in 0..200u8 {
+            addrman.add(address([i, 1, 1, 1], 8333, NOW), source([i, 2, 2, 2]), NOW);
+        }
+        assert_eq!(addrman.len(), 200);
+        assert_eq!(addrman.get_addresses(NOW).len(), 200 * GETADDR_PERCENT / 100);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut addrman = AddrMan::new();
+        for i in 1..50u8 {
+            addrman.add(address([i, 1, 1, 1], 8333, NOW), source([i, 2, 2, 2]), NOW);
+        }
+        addrman.mark_good(SocketAddr::from(([7, 1, 1, 1], 8333)), crate::p2p::NODE_NETWORK, NOW);
+
+        let path = std::env::temp_dir().join(format!("rh_peers_test_{}.dat", rand::random::<u64>()));
+        addrman.save(&path).unwrap();
+        let loaded = AddrMan::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.key, addrman.key);
+        assert_eq!((loaded.new_count(), loaded.tried_count()), (addrman.new_count(), addrman.tried_count()));
+        let info = loaded.get(&SocketAddr::from(([7, 1, 1, 1], 8333))).unwrap();
+        assert!(info.tried);
+        assert_eq!(info.last_success, NOW);
+    }
+}
@@ -1,9 +1,15 @@
 //! P2P networking module - Peer discovery and message propagation
 
+mod addrman;
+mod node;
 mod peer;
 mod protocol;
 mod seeds;
+mod sync;
 
+pub use addrman::*;
+pub use node::*;
 pub use peer::*;
 pub use protocol::*;
 pub use seeds::*;
+pub use sync::*;
@@ -0,0 +1,940 @@
+//! Network service
+//!
+//! Owns the P2P side of a node: the listener, outbound dialing, one task per
+//! peer and the dispatch of every message against `ChainState`,
+//! `PeerManager` and `SyncManager`. The binary only wires it up, so tests and
+//! tooling can run several nodes in one process.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use crate::consensus::{check_block, validate_signatures, Block, BlockHeader, ValidationError};
+use crate::crypto::{Hash, SignatureCache};
+use crate::mining::Miner;
+use crate::storage::ChainState;
+use crate::validation::Transaction;
+use super::addrman::unix_time;
+use super::{
+    AddrMan, GetBlocksMessage, GetHeadersMessage, Handshake, HandshakeError, InvItem, InvType,
+    Message, NetAddress, PeerManager, RejectMessage, SyncManager, VersionMessage, LOCAL_SERVICES,
+    MAX_ADDR_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE, MAX_MESSAGE_SIZE, NETWORK_MAGIC, NODE_NETWORK,
+    PROTOCOL_VERSION,
+};
+
+/// Error type of a peer connection task
+type PeerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Both ends of a peer's outbound message queue
+type PeerQueue = (mpsc::Sender<Message>, mpsc::Receiver<Message>);
+
+/// Outbound message queue length per peer
+const PEER_QUEUE_SIZE: usize = 100;
+
+/// Time a new peer has to complete the version handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often connected peers are pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(120);
+
+/// A peer that leaves a ping unanswered this long is disconnected
+pub const PING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Disconnected peers not seen for this long are forgotten
+pub const STALE_PEER_TIMEOUT: Duration = Duration::from_secs(3 * 3600);
+
+/// How often stale peers are swept and the address table saved
+const STALE_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// `addr` messages up to this size are announcements worth relaying;
+/// larger ones answer a `getaddr`
+const MAX_ADDR_RELAY_SIZE: usize = 10;
+
+/// Only addresses heard of this recently are relayed (seconds)
+const ADDR_RELAY_MAX_AGE: u64 = 600;
+
+/// Peers each fresh address is relayed to
+const ADDR_RELAY_PEERS: usize = 2;
+
+/// How often the sync task hands out header and block requests
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the connection manager fills free outbound slots
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a dial may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the worst outbound peer is considered for replacement
+pub const ROTATION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Addresses drawn from the table per free outbound slot before giving up
+/// until the next round
+const SELECT_TRIES_PER_SLOT: usize = 10;
+
+/// P2P service of a node
+///
+/// Cheap to clone; every clone drives the same node.
+#[derive(Clone)]
+pub struct NetworkService {
+    chain_state: Arc<Mutex<ChainState>>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    sync_manager: Arc<Mutex<SyncManager>>,
+    /// Blocks are signature-checked against this before the chain state is locked
+    sig_cache: Arc<SignatureCache>,
+    /// Local miner, interrupted whenever a peer moves our tip
+    miner: Option<Miner>,
+    /// Nonce sent in all our version messages, to detect self-connections
+    local_nonce: u64,
+    /// Time a new peer has to complete the version handshake
+    handshake_timeout: Duration,
+    /// Keepalive ping interval and the time a ping may go unanswered
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    /// Port we accept peers on, advertised in our version (0 = not listening)
+    listen_port: Arc<AtomicU16>,
+    /// Where the address table is saved
+    peers_file: Option<PathBuf>,
+    /// How often a poorly performing outbound peer is replaced
+    rotation_interval: Duration,
+}
+
+impl NetworkService {
+    /// Create a service for a node's shared state
+    pub fn new(
+        chain_state: Arc<Mutex<ChainState>>,
+        peer_manager: Arc<Mutex<PeerManager>>,
+        sync_manager: Arc<Mutex<SyncManager>>,
+    ) -> Self {
+        let sig_cache = chain_state.lock().unwrap().sig_cache.clone();
+        Self {
+            chain_state,
+            peer_manager,
+            sync_manager,
+            sig_cache,
+            miner: None,
+            local_nonce: rand::random(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            ping_interval: PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT,
+            listen_port: Arc::new(AtomicU16::new(0)),
+            peers_file: None,
+            rotation_interval: ROTATION_INTERVAL,
+        }
+    }
+
+    /// Change how often poorly performing outbound peers are replaced
+    pub fn with_rotation_interval(mut self, interval: Duration) -> Self {
+        self.rotation_interval = interval;
+        self
+    }
+
+    /// Load the address table from `path` and save it back there
+    ///
+    /// A missing or unreadable file starts an empty table.
+    pub fn with_peers_file(mut self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            match AddrMan::load(&path) {
+                Ok(addrman) => {
+                    println!("📇 Loaded {} peer addresses from {}", addrman.len(), path.display());
+                    self.peer_manager.lock().unwrap().addrman = addrman;
+                }
+                Err(e) => eprintln!("⚠️  Failed to load peer addresses from {}: {}", path.display(), e),
+            }
+        }
+        self.peers_file = Some(path);
+        self
+    }
+
+    /// Save the address table to the peers file, if one is set
+    ///
+    /// Returns the number of addresses saved.
+    pub fn save_peers(&self) -> std::io::Result<usize> {
+        let Some(path) = &self.peers_file else {
+            return Ok(0);
+        };
+        let pm = self.peer_manager.lock().unwrap();
+        pm.addrman.save(path)?;
+        Ok(pm.addrman.len())
+    }
+
+    /// Change how long new peers have to complete the handshake
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Interrupt `miner` when a block from a peer changes the tip
+    pub fn with_miner(mut self, miner: Miner) -> Self {
+        self.miner = Some(miner);
+        self
+    }
+
+    /// Change how often peers are pinged and how long they have to answer
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping_interval = interval;
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Accept inbound peers on `addr` in the background
+    ///
+    /// Returns the bound address (useful with port 0).
+    pub async fn listen(&self, addr: SocketAddr) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        self.listen_port.store(local_addr.port(), Ordering::Relaxed);
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        // Take the slot before accepting the next connection
+                        let Some(queue) = service.register_peer(addr, true) else {
+                            continue;
+                        };
+                        let service = service.clone();
+                        tokio::spawn(async move {
+                            let _ = service.handle_peer(socket, addr, queue).await;
+                        });
+                    }
+                    Err(e) => eprintln!("Connection error: {}", e),
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    /// Dial a peer and run its connection in the background
+    ///
+    /// A failed dial counts against the peer's retry backoff.
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<()> {
+        {
+            let mut pm = self.peer_manager.lock().unwrap();
+            pm.connection_attempt(addr, Instant::now());
+            pm.addrman.mark_attempt(&addr, unix_time());
+        }
+        let dial = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        let stream = match dial {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.peer_manager.lock().unwrap().connection_failed(&addr);
+                return Err(e);
+            }
+        };
+        let Some(queue) = self.register_peer(addr, false) else {
+            // Free the slot `connection_attempt` took
+            self.peer_manager.lock().unwrap().connection_failed(&addr);
+            return Ok(());
+        };
+        let service = self.clone();
+        tokio::spawn(async move {
+            let _ = service.handle_peer(stream, addr, queue).await;
+        });
+        Ok(())
+    }
+
+    /// Run the headers-first sync task: request headers, hand out block
+    /// bodies, drop stalling peers
+    pub fn start_sync(&self) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(SYNC_INTERVAL).await;
+                service.sync_step();
+            }
+        });
+    }
+
+    /// Periodically forget disconnected peers that have not been seen for
+    /// `STALE_PEER_TIMEOUT`, and save the address table
+    pub fn start_peer_sweep(&self) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(STALE_SWEEP_INTERVAL).await;
+                service.peer_manager.lock().unwrap().remove_stale_peers(STALE_PEER_TIMEOUT);
+                if let Err(e) = service.save_peers() {
+                    eprintln!("⚠️  Failed to save peer addresses: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Keep outbound connections up: redial added nodes, fill free outbound
+    /// slots from known peers and the address table, and periodically
+    /// replace the worst-performing outbound peer
+    pub fn start_connection_manager(&self) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut next_rotation = Instant::now() + service.rotation_interval;
+            loop {
+                service.connection_step();
+                if Instant::now() >= next_rotation {
+                    service.rotation_step();
+                    next_rotation = Instant::now() + service.rotation_interval;
+                }
+                sleep(CONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    /// Dial added nodes and enough peers to fill the outbound slots
+    fn connection_step(&self) {
+        let dial = {
+            let mut pm = self.peer_manager.lock().unwrap();
+            let now = Instant::now();
+            let mut dial = pm.added_nodes_to_connect(now);
+
+            // Known peers first (seeds, earlier connections), then the table
+            let free = pm.max_outbound().saturating_sub(pm.outbound_count());
+            let mut outbound = pm.get_peers_to_connect(free, now);
+            for _ in 0..free * SELECT_TRIES_PER_SLOT {
+                if outbound.len() >= free {
+                    break;
+                }
+                match pm.addrman.select() {
+                    Some(addr) if !outbound.contains(&addr) && !dial.contains(&addr) && pm.can_dial(&addr, now) => {
+                        outbound.push(addr);
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            dial.extend(outbound);
+
+            // Take the slots now, so the next round does not dial them again
+            for addr in &dial {
+                pm.connection_attempt(*addr, now);
+            }
+            dial
+        };
+
+        for addr in dial {
+            let service = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = service.connect(addr).await {
+                    eprintln!("Failed to connect to {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Disconnect the worst-performing outbound peer, if it performs poorly
+    /// and all outbound slots are taken; the next connection round fills
+    /// its slot with another address
+    fn rotation_step(&self) {
+        let mut pm = self.peer_manager.lock().unwrap();
+        if pm.outbound_count() < pm.max_outbound() {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(addr) = pm.rotation_candidate(now) {
+            println!("🔄 Replacing poorly performing peer {}", addr);
+            pm.rotate_out(&addr, now);
+        }
+    }
+
+    /// Send the next round of sync requests to connected peers
+    fn sync_step(&self) {
+        let peers: Vec<(SocketAddr, u64)> = {
+            let pm = self.peer_manager.lock().unwrap();
+            pm.get_connected_peers().iter().map(|p| (p.addr, p.best_height)).collect()
+        };
+        if peers.is_empty() {
+            return;
+        }
+
+        let requests = {
+            let state = self.chain_state.lock().unwrap();
+            let mut sync = self.sync_manager.lock().unwrap();
+            let now = Instant::now();
+            for addr in sync.check_stalls(now) {
+                println!("🐢 Peer {} stalled the download, reassigning its requests", addr);
+            }
+            let mut requests = sync.blocks_requests(&state, &peers, now);
+            requests.extend(sync.headers_request(&state, &peers, now));
+            requests
+        };
+
+        let pm = self.peer_manager.lock().unwrap();
+        for (addr, msg) in requests {
+            pm.send_to(&addr, msg);
+        }
+    }
+
+    /// Announce a block (e.g. one we mined) to every connected peer
+    pub fn announce_block(&self, hash: Hash) {
+        self.peer_manager.lock().unwrap().broadcast_message(&Message::Inv(vec![InvItem {
+            inv_type: InvType::Block,
+            hash,
+        }]));
+    }
+
+    /// Announce a transaction to every connected peer
+    pub fn announce_transaction(&self, hash: Hash) {
+        self.peer_manager.lock().unwrap().broadcast_message(&Message::Inv(vec![InvItem {
+            inv_type: InvType::Transaction,
+            hash,
+        }]));
+    }
+
+    /// Our version message to `addr`
+    fn version_message(&self, addr: SocketAddr) -> Message {
+        Message::Version(VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: LOCAL_SERVICES,
+            best_height: self.chain_state.lock().unwrap().height,
+            from_addr: SocketAddr::from(([0, 0, 0, 0], self.listen_port.load(Ordering::Relaxed))),
+            to_addr: addr,
+            nonce: self.local_nonce,
+            user_agent: "roho-v1.4".to_string(),
+        })
+    }
+
+    /// Register a new connection with the peer manager
+    ///
+    /// Refuses banned peers, and inbound ones once every inbound slot is
+    /// taken. Checking and taking the slot under one lock keeps a burst of
+    /// accepted connections from overfilling them. Returns the peer's
+    /// message queue.
+    fn register_peer(&self, addr: SocketAddr, inbound: bool) -> Option<PeerQueue> {
+        let mut pm = self.peer_manager.lock().unwrap();
+        if inbound && !pm.accepts_inbound() {
+            println!("🚫 Refusing inbound peer {}: no free slots", addr);
+            return None;
+        }
+
+        let (peer_tx, peer_rx) = mpsc::channel::<Message>(PEER_QUEUE_SIZE);
+        if !pm.peer_connecting(addr, inbound, peer_tx.clone()) {
+            println!("🚫 Refusing banned peer {}", addr);
+            return None;
+        }
+        Some((peer_tx, peer_rx))
+    }
+
+    /// Run one peer connection until it closes
+    async fn handle_peer(self, stream: TcpStream, addr: SocketAddr, queue: PeerQueue) -> PeerResult {
+        println!("🤝 Peer connected: {}", addr);
+        let (peer_tx, mut peer_rx) = queue;
+
+        // Split stream for concurrent read/write
+        let (mut reader, mut writer) = stream.into_split();
+
+        // Outbound message task; ends once every sender is dropped
+        tokio::spawn(async move {
+            while let Some(msg) = peer_rx.recv().await {
+                let bytes = msg.to_bytes();
+                if let Err(e) = writer.write_all(&bytes).await {
+                    eprintln!("Failed to send to {}: {}", addr, e);
+                    break;
+                }
+            }
+        });
+
+        // 1. Initial Handshake: both sides open with their version
+        let _ = peer_tx.send(self.version_message(addr)).await;
+
+        // 2. Keepalive pings, flagging the peer if it stops answering
+        let unresponsive = Arc::new(Notify::new());
+        let keepalive = self.spawn_keepalive(addr, peer_tx.clone(), unresponsive.clone());
+
+        // 3. Message Loop, until the peer leaves or we drop it
+        let disconnect = self.peer_manager.lock().unwrap()
+            .get_peer(&addr)
+            .map(|peer| peer.disconnect.clone())
+            .unwrap_or_default();
+        let result = self.message_loop(&mut reader, addr, &peer_tx, &unresponsive, &disconnect).await;
+        keepalive.abort();
+        match &result {
+            Ok(()) => println!("🔌 Peer disconnected: {}", addr),
+            Err(e) => println!("🔌 Disconnecting peer {}: {}", addr, e),
+        }
+        self.sync_manager.lock().unwrap().peer_disconnected(&addr);
+        self.peer_manager.lock().unwrap().peer_disconnected(&addr);
+        result
+    }
+
+    /// Ping the peer every `ping_interval` once it completed the handshake
+    ///
+    /// Wakes `unresponsive` if a ping goes unanswered for `ping_timeout`.
+    fn spawn_keepalive(&self, addr: SocketAddr, peer_tx: mpsc::Sender<Message>, unresponsive: Arc<Notify>) -> JoinHandle<()> {
+        let service = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(service.ping_interval).await;
+
+                let nonce = {
+                    let mut pm = service.peer_manager.lock().unwrap();
+                    let Some(peer) = pm.get_peer(&addr) else {
+                        return;
+                    };
+                    if peer.handshake != Handshake::Complete {
+                        continue;
+                    }
+
+                    let now = Instant::now();
+                    match peer.ping_pending {
+                        Some((_, sent)) if now.duration_since(sent) >= service.ping_timeout => {
+                            unresponsive.notify_one();
+                            return;
+                        }
+                        Some(_) => continue,
+                        None => {
+                            let nonce = rand::random();
+                            pm.ping_sent(&addr, nonce, now);
+                            nonce
+                        }
+                    }
+                };
+
+                if peer_tx.send(Message::Ping(nonce)).await.is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Read and handle messages until the peer closes the connection, breaks
+    /// the protocol or stops answering pings, or `disconnect` is notified
+    async fn message_loop(
+        &self,
+        reader: &mut OwnedReadHalf,
+        addr: SocketAddr,
+        peer_tx: &mpsc::Sender<Message>,
+        unresponsive: &Notify,
+        disconnect: &Notify,
+    ) -> PeerResult {
+        let handshake_deadline = tokio::time::Instant::now() + self.handshake_timeout;
+        loop {
+            let handshaked = self.peer_manager.lock().unwrap()
+                .get_peer(&addr)
+                .is_some_and(|peer| peer.handshake == Handshake::Complete);
+
+            let read = tokio::select! {
+                read = read_message(reader) => read,
+                _ = tokio::time::sleep_until(handshake_deadline), if !handshaked => {
+                    return Err(HandshakeError::Timeout.into());
+                }
+                _ = unresponsive.notified() => return Err("Ping timed out".into()),
+                _ = disconnect.notified() => return Err("Disconnect requested".into()),
+            };
+            let Ok(msg) = read else {
+                return Ok(());
+            };
+            self.handle_message(msg, addr, peer_tx).await?;
+        }
+    }
+
+    /// Process one message from `addr`, replying through `peer_tx`
+    async fn handle_message(&self, msg: Message, addr: SocketAddr, peer_tx: &mpsc::Sender<Message>) -> PeerResult {
+        // Only the handshake itself is accepted until it completes
+        let handshake = self.peer_manager.lock().unwrap().handshake_message(&addr, &msg, self.local_nonce);
+        if let Err(e) = handshake {
+            if let Some(code) = e.reject_code() {
+                let _ = peer_tx.send(Message::Reject(RejectMessage {
+                    message_type: msg.command().to_string(),
+                    code,
+                    reason: e.to_string(),
+                    data_hash: None,
+                })).await;
+            }
+            return Err(e.into());
+        }
+
+        match msg {
+            Message::Version(v) => {
+                println!("👋 Peer version: {} (Height: {})", v.user_agent, v.best_height);
+                let _ = peer_tx.send(Message::VerAck).await;
+
+                // An inbound full node tells us the port it listens on; the
+                // address is fresh, so it is relayed like an announcement
+                let inbound = self.peer_manager.lock().unwrap().get_peer(&addr).is_some_and(|p| p.inbound);
+                if inbound && v.from_addr.port() != 0 && v.services & NODE_NETWORK != 0 {
+                    let listen_addr = NetAddress {
+                        addr: SocketAddr::new(addr.ip(), v.from_addr.port()),
+                        services: v.services,
+                        timestamp: unix_time(),
+                    };
+                    self.learn_addresses(vec![listen_addr], addr);
+                }
+            }
+            Message::VerAck => {
+                // If they are ahead, the sync task will pick them for headers and blocks
+                println!("✅ Handshake complete with {}", addr);
+
+                // Peers we dialed are proven reachable; ask them for more
+                let mut pm = self.peer_manager.lock().unwrap();
+                if let Some(peer) = pm.get_peer(&addr).filter(|p| !p.inbound) {
+                    let services = peer.services;
+                    pm.addrman.mark_good(addr, services, unix_time());
+                    pm.getaddr_sent(&addr);
+                    pm.send_to(&addr, Message::GetAddr);
+                }
+            }
+            Message::GetAddr => {
+                let addresses = {
+                    let mut pm = self.peer_manager.lock().unwrap();
+                    pm.answer_getaddr(&addr).then(|| pm.addrman.get_addresses(unix_time()))
+                };
+                if let Some(addresses) = addresses.filter(|a| !a.is_empty()) {
+                    let _ = peer_tx.send(Message::Addr(addresses)).await;
+                }
+            }
+            Message::Addr(addresses) => self.handle_addr(addresses, addr),
+            Message::Ping(nonce) => {
+                let _ = peer_tx.send(Message::Pong(nonce)).await;
+            }
+            Message::Pong(nonce) => {
+                self.peer_manager.lock().unwrap().pong_received(&addr, nonce, Instant::now());
+            }
+            Message::Inv(items) => {
+                for item in items {
+                    match item.inv_type {
+                        InvType::Block => {
+                            let _ = peer_tx.send(Message::GetData(vec![item])).await;
+                        }
+                        InvType::Transaction => {
+                            // Only request if we don't have it in mempool
+                            let has_tx = {
+                                let state = self.chain_state.lock().unwrap();
+                                state.mempool.contains(&item.hash)
+                            };
+                            if !has_tx {
+                                let _ = peer_tx.send(Message::GetData(vec![item])).await;
+                            }
+                        }
+                    }
+                }
+            }
+            Message::GetData(items) => {
+                for item in items {
+                    match item.inv_type {
+                        InvType::Block => {
+                            let block = {
+                                let state = self.chain_state.lock().unwrap();
+                                state.get_block(&item.hash)
+                            };
+                            if let Some(b) = block {
+                                let _ = peer_tx.send(Message::Block(b)).await;
+                            }
+                        }
+                        InvType::Transaction => {
+                            let tx = {
+                                let state = self.chain_state.lock().unwrap();
+                                state.mempool.get(&item.hash).map(|entry| entry.tx.clone())
+                            };
+                            if let Some(t) = tx {
+                                let _ = peer_tx.send(Message::Tx(t)).await;
+                            }
+                        }
+                    }
+                }
+            }
+            Message::Block(block) => self.handle_block(block, addr, peer_tx).await?,
+            Message::Tx(tx) => self.handle_tx(tx, addr, peer_tx),
+            Message::GetBlocks(req) => {
+                let inv_items = self.block_inventory(&req);
+                if !inv_items.is_empty() {
+                    let _ = peer_tx.send(Message::Inv(inv_items)).await;
+                }
+            }
+            Message::GetHeaders(req) => {
+                let headers = self.headers_after(&req);
+                if !headers.is_empty() {
+                    let _ = peer_tx.send(Message::Headers(headers)).await;
+                }
+            }
+            Message::Headers(headers) => {
+                let announced_height = {
+                    let pm = self.peer_manager.lock().unwrap();
+                    pm.get_connected_peers().iter()
+                        .find(|p| p.addr == addr)
+                        .map(|p| p.best_height)
+                        .unwrap_or(0)
+                };
+
+                let result = {
+                    let mut state = self.chain_state.lock().unwrap();
+                    let mut sync = self.sync_manager.lock().unwrap();
+                    let result = sync.on_headers(addr, announced_height, &headers, &mut state, Instant::now());
+                    (result, state.best_header_height())
+                };
+
+                match result {
+                    (Ok(next), best_header_height) => {
+                        if !headers.is_empty() {
+                            println!("📑 Synced headers to height {}", best_header_height);
+                        }
+                        if let Some(get_headers) = next {
+                            let _ = peer_tx.send(get_headers).await;
+                        }
+                    }
+                    (Err(ValidationError::UnknownParent), _) => {
+                        // Headers that don't connect (e.g. an unsolicited announcement); not an offence
+                    }
+                    (Err(e), _) => {
+                        eprintln!("❌ Rejected headers from peer {}: {}", addr, e);
+                        self.peer_manager.lock().unwrap().report_misbehavior(&addr, 100);
+                    }
+                }
+            }
+            Message::Reject(reject) => {
+                println!("⛔ Peer {} rejected our {} ({:?}): {}", addr, reject.message_type, reject.code, reject.reason);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Index and, if it belongs to the best chain, connect a block from a peer
+    async fn handle_block(&self, block: Block, addr: SocketAddr, peer_tx: &mpsc::Sender<Message>) -> PeerResult {
+        // Verify signatures on the blocking pool first, so connecting the
+        // block under the lock hits the cache. Only blocks passing the
+        // context-free checks are worth it: their proof of work is valid
+        // and their signature count bounded.
+        let verified = match check_block(&block) {
+            Ok(()) => {
+                let (checked, cache) = (block.clone(), self.sig_cache.clone());
+                tokio::task::spawn_blocking(move || validate_signatures(&checked, &cache)).await?
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = verified {
+            eprintln!("❌ Rejected block {} from peer {}: {}", block.hash(), addr, e);
+            self.peer_manager.lock().unwrap().report_misbehavior(&addr, 100);
+            return Ok(());
+        }
+
+        let (result, request_missing) = {
+            let mut state = self.chain_state.lock().unwrap();
+            let block_hash = block.hash();
+            self.sync_manager.lock().unwrap().block_received(&block_hash);
+
+            // 1. Index the block (even if it's on a side chain)
+            //    Unmined or malformed blocks are dropped here.
+            match state.index_block(&block) {
+                Ok(()) => {
+                    // 2. Connect as far along the best header chain as bodies allow
+                    //    (a reorg only happens if that chain has more cumulative work)
+                    let extends_tip = block.header.prev_hash == state.tip_hash;
+                    match state.activate_best_header() {
+                        Ok(true) => {
+                            if !extends_tip {
+                                println!("✅ Successfully reorganized to better chain height {}", state.height);
+                            }
+                            (Some(state.get_stats()), None)
+                        }
+                        Ok(false) => (None, None),
+                        Err(e) => {
+                            eprintln!("❌ Invalid block {} from peer: {}", block_hash, e);
+                            (None, None)
+                        }
+                    }
+                }
+                Err(ValidationError::UnknownParent) => {
+                    // 3. We are missing intermediate headers, request them
+                    (None, Some(SyncManager::get_headers(&state)))
+                }
+                Err(e) => {
+                    eprintln!("❌ Rejected block {} from peer {}: {}", block_hash, addr, e);
+                    drop(state);
+                    self.peer_manager.lock().unwrap().report_misbehavior(&addr, 100);
+                    return Ok(());
+                }
+            }
+        };
+
+        if let Some(get_headers) = request_missing {
+            println!("❓ Received potentially better block from peer with unknown parent. Requesting headers...");
+            let _ = peer_tx.send(get_headers).await;
+        }
+
+        if let Some(stats) = result {
+            println!("📦 Applied block #{} from peer", stats.height);
+
+            // Update peer height in manager
+            self.peer_manager.lock().unwrap().update_peer_height(&addr, stats.height);
+            self.announce_block(block.hash());
+
+            if let Some(miner) = &self.miner {
+                miner.stop(); // Interrupt to start on new tip
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take in an `addr` message from a peer, within its rate limit
+    fn handle_addr(&self, addresses: Vec<NetAddress>, addr: SocketAddr) {
+        let mut pm = self.peer_manager.lock().unwrap();
+        if addresses.len() > MAX_ADDR_PER_MESSAGE {
+            eprintln!("❌ Peer {} sent {} addresses in one message", addr, addresses.len());
+            pm.report_misbehavior(&addr, 20);
+            return;
+        }
+
+        let allowed = pm.take_addr_tokens(&addr, addresses.len(), Instant::now());
+        if allowed < addresses.len() {
+            println!("🐢 Rate-limited {} of {} addresses from {}", addresses.len() - allowed, addresses.len(), addr);
+        }
+        drop(pm);
+
+        if addresses.len() <= MAX_ADDR_RELAY_SIZE {
+            self.learn_addresses(addresses.into_iter().take(allowed).collect(), addr);
+        } else {
+            let now = unix_time();
+            let mut pm = self.peer_manager.lock().unwrap();
+            for address in addresses.into_iter().take(allowed) {
+                pm.addrman.add(address, addr.ip(), now);
+            }
+        }
+    }
+
+    /// Add announced addresses from `source` to the table and relay the
+    /// fresh ones we did not know to a few other peers
+    fn learn_addresses(&self, addresses: Vec<NetAddress>, source: SocketAddr) {
+        let now = unix_time();
+        let mut pm = self.peer_manager.lock().unwrap();
+        for address in addresses {
+            if !pm.addrman.add(address, source.ip(), now) {
+                continue;
+            }
+            // Relay the stored entry, whose timestamp is no longer in the future
+            let Some(stored) = pm.addrman.get(&address.addr).map(|info| info.address) else {
+                continue;
+            };
+            if stored.timestamp + ADDR_RELAY_MAX_AGE >= now {
+                for target in pm.addr_relay_targets(&source, ADDR_RELAY_PEERS) {
+                    pm.send_to(&target, Message::Addr(vec![stored]));
+                }
+            }
+        }
+    }
+
+    /// Add a relayed transaction to the mempool and gossip it on
+    fn handle_tx(&self, tx: Transaction, addr: SocketAddr, peer_tx: &mpsc::Sender<Message>) {
+        let added = {
+            let mut state = self.chain_state.lock().unwrap();
+            match state.add_to_mempool(tx.clone()) {
+                Ok(replaced) => {
+                    if !replaced.is_empty() {
+                        println!("🔁 Transaction {} replaced {} mempool transactions", tx.hash(), replaced.len());
+                    }
+                    true
+                }
+                Err(e) => {
+                    eprintln!("❌ Failed to add relay tx {} from {} to mempool: {}", tx.hash(), addr, e);
+                    let _ = peer_tx.try_send(Message::Reject(RejectMessage {
+                        message_type: "tx".to_string(),
+                        code: e.code,
+                        reason: e.reason,
+                        data_hash: Some(tx.hash()),
+                    }));
+                    false
+                }
+            }
+        };
+
+        if added {
+            println!("📥 Relaying transaction: {}", tx.hash());
+            self.announce_transaction(tx.hash());
+        }
+    }
+
+    /// Up to 500 active-chain block hashes after the locator's fork point
+    fn block_inventory(&self, req: &GetBlocksMessage) -> Vec<InvItem> {
+        let state = self.chain_state.lock().unwrap();
+        let mut start_height = 0;
+
+        // 1. Find the first common block from locators
+        for hash in &req.block_locators {
+            if let Some(h) = state.get_block_height(hash) {
+                start_height = h + 1;
+                break;
+            }
+        }
+
+        // 2. Collect up to 500 block hashes after common point
+        let mut items = Vec::new();
+        let max_height = state.height;
+        for h in start_height..=std::cmp::min(start_height + 500, max_height) {
+            if let Some(hash) = state.get_block_hash_at_height(h) {
+                items.push(InvItem {
+                    inv_type: InvType::Block,
+                    hash,
+                });
+                if hash == req.stop_hash {
+                    break;
+                }
+            }
+        }
+        items
+    }
+
+    /// Active-chain headers after the locator's fork point
+    fn headers_after(&self, req: &GetHeadersMessage) -> Vec<BlockHeader> {
+        let state = self.chain_state.lock().unwrap();
+        let mut start_height = 0;
+
+        for hash in &req.block_locators {
+            if let Some(h) = state.get_block_height(hash) {
+                start_height = h + 1;
+                break;
+            }
+        }
+
+        let mut items = Vec::new();
+        let max_height = state.height;
+        let end_height = start_height + MAX_HEADERS_PER_MESSAGE as u64;
+        for h in start_height..std::cmp::min(end_height, max_height + 1) {
+            if let Some(hash) = state.get_block_hash_at_height(h) {
+                if let Some(header) = state.get_block_header(&hash) {
+                    items.push(header.clone());
+                    if hash == req.stop_hash {
+                        break;
+                    }
+                }
+            }
+        }
+        items
+    }
+}
+
+/// Read one framed message (magic, length, payload)
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message, String> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic).await.map_err(|e| e.to_string())?;
+    if magic != NETWORK_MAGIC {
+        return Err("Invalid magic".to_string());
+    }
+
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes).await.map_err(|e| e.to_string())?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+
+    if len > MAX_MESSAGE_SIZE {
+        return Err("Message too large".to_string());
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.map_err(|e| e.to_string())?;
+
+    let mut full_msg = Vec::with_capacity(8 + len);
+    full_msg.extend_from_slice(&magic);
+    full_msg.extend_from_slice(&len_bytes);
+    full_msg.extend_from_slice(&payload);
+
+    Message::from_bytes(&full_msg)
+}
@@ -5,14 +5,44 @@
 
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use rand::seq::IteratorRandom;
+use thiserror::Error;
+use tokio::sync::Notify;
+use super::{AddrMan, Message, RejectCode, MAX_ADDR_PER_MESSAGE, MIN_PROTOCOL_VERSION};
+
+/// Addresses a peer may send us per second, on average
+pub const ADDR_RATE_PER_SECOND: f64 = 0.1;
+
+/// Burst of addresses a peer may send beyond the average rate
+pub const MAX_ADDR_BURST: f64 = MAX_ADDR_PER_MESSAGE as f64;
+
+/// Outbound peers kept by default, not counting added nodes
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+
+/// Most operator-added (`--addnode`) peers
+pub const MAX_ADDED_NODES: usize = 8;
+
+/// Failed dials after which a known peer is no longer tried
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+/// Wait before redialing a peer after one failure; doubles with each
+/// further failure
+pub const RETRY_BACKOFF_BASE: Duration = Duration::from_secs(10);
+
+/// Longest wait before redialing a peer
+pub const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Outbound peers answering pings slower than this are replaced
+pub const SLOW_PEER_PING: Duration = Duration::from_secs(2);
 
 /// Peer connection state
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerState {
     /// Not yet connected
     Disconnected,
-    /// Connection in progress
+    /// Socket open, version handshake in progress
     Connecting,
     /// Fully connected and handshake complete
     Connected,
@@ -20,23 +50,91 @@ pub enum PeerState {
     Banned,
 }
 
+/// Progress of the version handshake on a connection
+///
+/// Both sides send `Version` as soon as the socket opens and answer the
+/// other's `Version` with `VerAck`. No other message is accepted until our
+/// `Version` has been acknowledged and theirs received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// Waiting for the peer's `Version`
+    AwaitingVersion,
+    /// Their `Version` acknowledged, waiting for their `VerAck`
+    AwaitingVerAck,
+    /// Both sides exchanged `Version` and `VerAck`
+    Complete,
+}
+
+/// Handshake violations; each one ends the connection
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("Received {0} before the version handshake completed")]
+    NotHandshaked(&'static str),
+    #[error("Received verack before version")]
+    UnexpectedVerAck,
+    #[error("Duplicate {0} message")]
+    Duplicate(&'static str),
+    #[error("Protocol version {0} is older than the minimum {MIN_PROTOCOL_VERSION}")]
+    ObsoleteVersion(u32),
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Handshake timed out")]
+    Timeout,
+    #[error("Peer is not registered")]
+    UnknownPeer,
+}
+
+impl HandshakeError {
+    /// Code of the `reject` to send before disconnecting, if any
+    pub fn reject_code(&self) -> Option<RejectCode> {
+        match self {
+            HandshakeError::ObsoleteVersion(_) => Some(RejectCode::Obsolete),
+            HandshakeError::Duplicate(_) => Some(RejectCode::Duplicate),
+            HandshakeError::NotHandshaked(_) | HandshakeError::UnexpectedVerAck => Some(RejectCode::Malformed),
+            HandshakeError::SelfConnection | HandshakeError::Timeout | HandshakeError::UnknownPeer => None,
+        }
+    }
+}
+
 /// Information about a peer
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
     /// Peer's network address
     pub addr: SocketAddr,
+    /// The peer dialed us
+    pub inbound: bool,
     /// Current connection state
     pub state: PeerState,
     /// Last seen timestamp
     pub last_seen: Instant,
-    /// Number of failed connection attempts
+    /// Number of failed connection attempts since the last handshake
     pub failed_attempts: u32,
+    /// When we last dialed the peer
+    pub last_attempt: Option<Instant>,
     /// Best known block height
     pub best_height: u64,
     /// Protocol version
     pub version: u32,
+    /// Service bits from the peer's version message
+    pub services: u64,
+    /// Version handshake progress on the current connection
+    pub handshake: Handshake,
     /// Misbehavior score (100 = ban)
     pub misbehavior_score: u32,
+    /// Nonce and send time of our unanswered ping
+    pub ping_pending: Option<(u64, Instant)>,
+    /// Round-trip time of the last answered ping
+    pub ping_rtt: Option<Duration>,
+    /// Fastest round-trip time seen
+    pub min_ping_rtt: Option<Duration>,
+    /// Addresses the peer may still send before it is rate-limited
+    pub addr_tokens: f64,
+    /// When `addr_tokens` was last refilled
+    pub addr_tokens_updated: Instant,
+    /// We already answered a `getaddr` on this connection
+    pub getaddr_answered: bool,
+    /// Wakes the connection task to close the current connection
+    pub disconnect: Arc<Notify>,
     /// Channel to send messages to this peer
     pub sender: Option<tokio::sync::mpsc::Sender<crate::p2p::Message>>,
 }
@@ -46,16 +144,61 @@ impl PeerInfo {
     pub fn new(addr: SocketAddr) -> Self {
         Self {
             addr,
+            inbound: false,
             state: PeerState::Disconnected,
             last_seen: Instant::now(),
             failed_attempts: 0,
+            last_attempt: None,
             best_height: 0,
             version: 0,
+            services: 0,
+            handshake: Handshake::AwaitingVersion,
             misbehavior_score: 0,
+            ping_pending: None,
+            ping_rtt: None,
+            min_ping_rtt: None,
+            addr_tokens: 1.0,
+            addr_tokens_updated: Instant::now(),
+            getaddr_answered: false,
+            disconnect: Arc::new(Notify::new()),
             sender: None,
         }
     }
 
+    /// Advance the handshake with a `Version` or `VerAck` from the peer
+    ///
+    /// `local_nonce` is the nonce we put in our own version messages; seeing
+    /// it come back means we dialed ourselves. Returns true once the
+    /// handshake is complete.
+    pub fn handshake_step(&mut self, msg: &Message, local_nonce: u64) -> Result<bool, HandshakeError> {
+        match (self.handshake, msg) {
+            (Handshake::AwaitingVersion, Message::Version(v)) => {
+                if v.nonce == local_nonce {
+                    return Err(HandshakeError::SelfConnection);
+                }
+                if v.version < MIN_PROTOCOL_VERSION {
+                    return Err(HandshakeError::ObsoleteVersion(v.version));
+                }
+                self.version = v.version;
+                self.services = v.services;
+                self.best_height = v.best_height;
+                self.handshake = Handshake::AwaitingVerAck;
+                Ok(false)
+            }
+            (Handshake::AwaitingVersion, Message::VerAck) => Err(HandshakeError::UnexpectedVerAck),
+            (Handshake::AwaitingVerAck, Message::VerAck) => {
+                self.handshake = Handshake::Complete;
+                self.state = PeerState::Connected;
+                self.failed_attempts = 0;
+                self.touch();
+                Ok(true)
+            }
+            (_, Message::Version(_) | Message::VerAck) => Err(HandshakeError::Duplicate(msg.command())),
+            (Handshake::Complete, _) => Ok(true),
+            (_, other) => Err(HandshakeError::NotHandshaked(other.command())),
+        }
+    }
+
     /// Update last seen time
     pub fn touch(&mut self) {
         self.last_seen = Instant::now();
@@ -74,6 +217,53 @@ impl PeerInfo {
         self.misbehavior_score >= 100
     }
 
+    /// Take up to `count` addresses from the peer's rate limit at `now`
+    ///
+    /// Tokens refill at `ADDR_RATE_PER_SECOND` up to `MAX_ADDR_BURST`.
+    /// Returns how many of the addresses may be processed.
+    pub fn take_addr_tokens(&mut self, count: usize, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.addr_tokens_updated).as_secs_f64();
+        self.addr_tokens = (self.addr_tokens + elapsed * ADDR_RATE_PER_SECOND).min(MAX_ADDR_BURST);
+        self.addr_tokens_updated = now;
+
+        let allowed = (self.addr_tokens.floor() as usize).min(count);
+        self.addr_tokens -= allowed as f64;
+        allowed
+    }
+
+    /// Earliest time the peer may be dialed again, if it failed before
+    ///
+    /// The wait doubles with every failed attempt, up to `MAX_RETRY_BACKOFF`.
+    pub fn retry_at(&self) -> Option<Instant> {
+        let last_attempt = self.last_attempt?;
+        if self.failed_attempts == 0 {
+            return None;
+        }
+        let backoff = RETRY_BACKOFF_BASE
+            .saturating_mul(1 << (self.failed_attempts - 1).min(16))
+            .min(MAX_RETRY_BACKOFF);
+        Some(last_attempt + backoff)
+    }
+
+    /// Check if the peer's retry backoff has passed at `now`
+    pub fn can_retry(&self, now: Instant) -> bool {
+        self.retry_at().is_none_or(|at| now >= at)
+    }
+
+    /// Check if a connection to the peer is open or being opened
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, PeerState::Connecting | PeerState::Connected)
+    }
+
+    /// Check if the peer performs poorly enough to be replaced: it
+    /// misbehaved, or answers pings slowly
+    pub fn is_poor(&self, now: Instant) -> bool {
+        let waiting = self.ping_pending.map(|(_, sent)| now.saturating_duration_since(sent));
+        self.misbehavior_score > 0
+            || self.ping_rtt.is_some_and(|rtt| rtt > SLOW_PEER_PING)
+            || waiting.is_some_and(|wait| wait > SLOW_PEER_PING)
+    }
+
     /// Check if connection has timed out
     pub fn is_stale(&self, timeout: Duration) -> bool {
         self.last_seen.elapsed() > timeout
@@ -81,31 +271,99 @@ impl PeerInfo {
 }
 
 /// Peer manager
+///
+/// Connection slots: `max_outbound` peers we pick ourselves, one per added
+/// node, and inbound peers in whatever is left of `max_connections`.
 #[derive(Debug, Default)]
 pub struct PeerManager {
     /// Known peers
     peers: HashMap<SocketAddr, PeerInfo>,
+    /// Addresses learned from gossip and past connections
+    pub addrman: AddrMan,
     /// Connected peer addresses
     connected: HashSet<SocketAddr>,
     /// Maximum number of connections
     max_connections: usize,
+    /// Outbound peers to keep, not counting added nodes
+    max_outbound: usize,
+    /// Operator-specified peers, always kept connected in reserved slots
+    added_nodes: Vec<SocketAddr>,
 }
 
 impl PeerManager {
     /// Create a new peer manager
+    ///
+    /// Up to `DEFAULT_MAX_OUTBOUND` of the connections, and at most half,
+    /// are outbound.
     pub fn new(max_connections: usize) -> Self {
         Self {
             peers: HashMap::new(),
+            addrman: AddrMan::new(),
             connected: HashSet::new(),
             max_connections,
+            max_outbound: DEFAULT_MAX_OUTBOUND.min(max_connections / 2),
+            added_nodes: Vec::new(),
         }
     }
 
+    /// Change the number of outbound peers to keep
+    pub fn with_max_outbound(mut self, max_outbound: usize) -> Self {
+        self.max_outbound = max_outbound.min(self.max_connections);
+        self
+    }
+
+    /// Number of outbound peers to keep, not counting added nodes
+    pub fn max_outbound(&self) -> usize {
+        self.max_outbound
+    }
+
+    /// Inbound connections accepted, after reserving outbound and added
+    /// node slots
+    pub fn max_inbound(&self) -> usize {
+        self.max_connections.saturating_sub(self.max_outbound + self.added_nodes.len())
+    }
+
+    /// Reserve a slot for an operator-specified peer
+    ///
+    /// Returns false if `MAX_ADDED_NODES` are already added.
+    pub fn add_node(&mut self, addr: SocketAddr) -> bool {
+        if self.added_nodes.contains(&addr) {
+            return true;
+        }
+        if self.added_nodes.len() >= MAX_ADDED_NODES {
+            return false;
+        }
+        self.added_nodes.push(addr);
+        self.add_peer(addr);
+        true
+    }
+
+    /// Operator-specified peers
+    pub fn added_nodes(&self) -> &[SocketAddr] {
+        &self.added_nodes
+    }
+
+    /// Number of open or opening outbound connections, not counting added
+    /// nodes
+    pub fn outbound_count(&self) -> usize {
+        self.peers.values()
+            .filter(|p| !p.inbound && p.is_active() && !self.added_nodes.contains(&p.addr))
+            .count()
+    }
+
+    /// Number of open or opening inbound connections
+    pub fn inbound_count(&self) -> usize {
+        self.peers.values().filter(|p| p.inbound && p.is_active()).count()
+    }
+
+    /// Check if another inbound connection fits
+    pub fn accepts_inbound(&self) -> bool {
+        self.inbound_count() < self.max_inbound()
+    }
+
     /// Add a new peer address
     pub fn add_peer(&mut self, addr: SocketAddr) {
-        if !self.peers.contains_key(&addr) {
-            self.peers.insert(addr, PeerInfo::new(addr));
-        }
+        self.peers.entry(addr).or_insert_with(|| PeerInfo::new(addr));
     }
 
     /// Add multiple peer addresses
@@ -115,10 +373,47 @@ impl PeerManager {
         }
     }
 
-    /// Mark peer as connected
+    /// Register a new connection whose handshake is about to start
+    ///
+    /// Returns false for banned peers, which must be disconnected.
+    pub fn peer_connecting(&mut self, addr: SocketAddr, inbound: bool, sender: tokio::sync::mpsc::Sender<crate::p2p::Message>) -> bool {
+        let peer = self.peers.entry(addr).or_insert_with(|| PeerInfo::new(addr));
+        if peer.should_ban() {
+            return false;
+        }
+        peer.inbound = inbound;
+        peer.disconnect = Arc::new(Notify::new());
+        peer.state = PeerState::Connecting;
+        peer.handshake = Handshake::AwaitingVersion;
+        peer.ping_pending = None;
+        peer.addr_tokens = 1.0;
+        peer.addr_tokens_updated = Instant::now();
+        peer.getaddr_answered = false;
+        peer.sender = Some(sender);
+        peer.touch();
+        true
+    }
+
+    /// Feed a message from a connecting or connected peer to its handshake
+    ///
+    /// Returns true if the peer has completed the handshake, i.e. `msg` may
+    /// be processed. See `PeerInfo::handshake_step`. Every received message
+    /// passes through here, so it also refreshes `last_seen`.
+    pub fn handshake_message(&mut self, addr: &SocketAddr, msg: &Message, local_nonce: u64) -> Result<bool, HandshakeError> {
+        let peer = self.peers.get_mut(addr).ok_or(HandshakeError::UnknownPeer)?;
+        peer.touch();
+        let complete = peer.handshake_step(msg, local_nonce)?;
+        if complete {
+            self.connected.insert(*addr);
+        }
+        Ok(complete)
+    }
+
+    /// Mark peer as connected, skipping the handshake
     pub fn peer_connected(&mut self, addr: SocketAddr, version: u32, best_height: u64, sender: tokio::sync::mpsc::Sender<crate::p2p::Message>) {
         if let Some(peer) = self.peers.get_mut(&addr) {
             peer.state = PeerState::Connected;
+            peer.handshake = Handshake::Complete;
             peer.version = version;
             peer.best_height = best_height;
             peer.failed_attempts = 0;
@@ -139,6 +434,77 @@ impl PeerManager {
         }
     }
 
+    /// Send a message to one connected peer
+    ///
+    /// Returns false if the peer is unknown or its queue is full.
+    pub fn send_to(&self, addr: &SocketAddr, msg: crate::p2p::Message) -> bool {
+        self.peers.get(addr)
+            .and_then(|peer| peer.sender.as_ref())
+            .is_some_and(|sender| sender.try_send(msg).is_ok())
+    }
+
+    /// Record a ping sent to a peer at `now`
+    pub fn ping_sent(&mut self, addr: &SocketAddr, nonce: u64, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.ping_pending = Some((nonce, now));
+        }
+    }
+
+    /// Match a pong against the outstanding ping
+    ///
+    /// Returns the round-trip time, or None if the nonce is not the one we
+    /// are waiting for.
+    pub fn pong_received(&mut self, addr: &SocketAddr, nonce: u64, now: Instant) -> Option<Duration> {
+        let peer = self.peers.get_mut(addr)?;
+        let (expected, sent) = peer.ping_pending?;
+        if nonce != expected {
+            return None;
+        }
+
+        let rtt = now.saturating_duration_since(sent);
+        peer.ping_pending = None;
+        peer.ping_rtt = Some(rtt);
+        peer.min_ping_rtt = Some(peer.min_ping_rtt.map_or(rtt, |min| min.min(rtt)));
+        Some(rtt)
+    }
+
+    /// Apply the `addr` rate limit of a peer that sent `count` addresses
+    ///
+    /// Returns how many of them may be processed.
+    pub fn take_addr_tokens(&mut self, addr: &SocketAddr, count: usize, now: Instant) -> usize {
+        self.peers.get_mut(addr).map_or(0, |peer| peer.take_addr_tokens(count, now))
+    }
+
+    /// Record a `getaddr` sent to a peer, allowing it a full `addr` answer
+    pub fn getaddr_sent(&mut self, addr: &SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.addr_tokens += MAX_ADDR_PER_MESSAGE as f64;
+        }
+    }
+
+    /// Check if a peer's `getaddr` should be answered
+    ///
+    /// Only inbound peers are answered, once per connection, so outbound
+    /// peers cannot fingerprint us through our address table.
+    pub fn answer_getaddr(&mut self, addr: &SocketAddr) -> bool {
+        match self.peers.get_mut(addr) {
+            Some(peer) if peer.inbound && !peer.getaddr_answered => {
+                peer.getaddr_answered = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Up to `count` random connected peers other than `exclude`, to relay
+    /// addresses to
+    pub fn addr_relay_targets(&self, exclude: &SocketAddr, count: usize) -> Vec<SocketAddr> {
+        self.connected.iter()
+            .filter(|addr| *addr != exclude)
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), count)
+    }
+
     /// Update peer's best known height
     pub fn update_peer_height(&mut self, addr: &SocketAddr, height: u64) {
         if let Some(peer) = self.peers.get_mut(addr) {
@@ -150,13 +516,31 @@ impl PeerManager {
     }
 
     /// Mark peer as disconnected
+    ///
+    /// Drops its message queue, so the connection's writer closes once
+    /// queued messages are flushed. An outbound connection that closes
+    /// before the handshake completes counts as a failed attempt.
     pub fn peer_disconnected(&mut self, addr: &SocketAddr) {
         if let Some(peer) = self.peers.get_mut(addr) {
-            peer.state = PeerState::Disconnected;
+            if !peer.inbound && peer.handshake != Handshake::Complete {
+                peer.failed_attempts += 1;
+            }
+            if peer.state != PeerState::Banned {
+                peer.state = PeerState::Disconnected;
+            }
+            peer.sender = None;
         }
         self.connected.remove(addr);
     }
 
+    /// Record that we are dialing a peer at `now`
+    pub fn connection_attempt(&mut self, addr: SocketAddr, now: Instant) {
+        let peer = self.peers.entry(addr).or_insert_with(|| PeerInfo::new(addr));
+        peer.inbound = false;
+        peer.state = PeerState::Connecting;
+        peer.last_attempt = Some(now);
+    }
+
     /// Mark connection attempt failed
     pub fn connection_failed(&mut self, addr: &SocketAddr) {
         if let Some(peer) = self.peers.get_mut(addr) {
@@ -165,11 +549,31 @@ impl PeerManager {
         }
     }
 
+    /// Ask the connection task of a peer to close the connection
+    pub fn disconnect_peer(&self, addr: &SocketAddr) {
+        if let Some(peer) = self.peers.get(addr) {
+            peer.disconnect.notify_one();
+        }
+    }
+
+    /// Disconnect a poorly performing peer to make room for another
+    ///
+    /// The peer is backed off like after a failed attempt, so its slot goes
+    /// to someone else.
+    pub fn rotate_out(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.failed_attempts += 1;
+            peer.last_attempt = Some(now);
+            peer.disconnect.notify_one();
+        }
+    }
+
     /// Ban a peer
     pub fn ban_peer(&mut self, addr: &SocketAddr) {
         if let Some(peer) = self.peers.get_mut(addr) {
             peer.state = PeerState::Banned;
             peer.misbehavior_score = 100;
+            peer.disconnect.notify_one();
         }
         self.connected.remove(addr);
     }
@@ -179,31 +583,68 @@ impl PeerManager {
         if let Some(peer) = self.peers.get_mut(addr) {
             peer.add_misbehavior(points);
             if peer.should_ban() {
+                peer.disconnect.notify_one();
                 self.connected.remove(addr);
             }
         }
     }
 
-    /// Get peers to connect to
-    pub fn get_peers_to_connect(&self, count: usize) -> Vec<SocketAddr> {
-        if self.connected.len() >= self.max_connections {
-            return vec![];
-        }
+    /// Check if `addr` may be dialed at `now`: not connected, not banned,
+    /// and past its retry backoff
+    ///
+    /// Addresses only seen as the source of an inbound connection are
+    /// never dialed; their port is the remote's outgoing one, not a listener.
+    pub fn can_dial(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.peers.get(addr).is_none_or(|p| {
+            !p.inbound
+                && !p.should_ban()
+                && p.state == PeerState::Disconnected
+                && p.failed_attempts < MAX_FAILED_ATTEMPTS
+                && p.can_retry(now)
+        })
+    }
 
-        let remaining = self.max_connections - self.connected.len();
-        let count = count.min(remaining);
+    /// Get known peers (seeds and earlier outbound connections) to fill up
+    /// to `count` free outbound slots
+    pub fn get_peers_to_connect(&self, count: usize, now: Instant) -> Vec<SocketAddr> {
+        let free = self.max_outbound.saturating_sub(self.outbound_count());
 
         self.peers.values()
-            .filter(|p| {
-                p.state == PeerState::Disconnected 
-                && p.failed_attempts < 5 
-                && !p.should_ban()
-            })
-            .take(count)
+            .filter(|p| !self.added_nodes.contains(&p.addr) && self.can_dial(&p.addr, now))
+            .take(count.min(free))
             .map(|p| p.addr)
             .collect()
     }
 
+    /// Get added nodes that are not connected and past their backoff
+    ///
+    /// Added nodes are retried however often they failed.
+    pub fn added_nodes_to_connect(&self, now: Instant) -> Vec<SocketAddr> {
+        self.added_nodes.iter()
+            .filter(|addr| {
+                self.peers.get(addr).is_none_or(|p| {
+                    p.state == PeerState::Disconnected && p.can_retry(now)
+                })
+            })
+            .copied()
+            .collect()
+    }
+
+    /// The worst-performing outbound peer, if any performs poorly
+    ///
+    /// Added nodes are never rotated out.
+    pub fn rotation_candidate(&self, now: Instant) -> Option<SocketAddr> {
+        self.get_connected_peers().into_iter()
+            .filter(|p| !p.inbound && !self.added_nodes.contains(&p.addr) && p.is_poor(now))
+            .max_by_key(|p| (p.misbehavior_score, p.ping_rtt.unwrap_or_default()))
+            .map(|p| p.addr)
+    }
+
+    /// Get a known peer
+    pub fn get_peer(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
     /// Get all connected peers
     pub fn get_connected_peers(&self) -> Vec<&PeerInfo> {
         self.peers.values()
@@ -303,4 +744,246 @@ mod tests {
         pm.report_misbehavior(&addr, 60);
         assert_eq!(pm.connected_count(), 0); // Auto-banned
     }
+
+    fn version(version: u32, nonce: u64) -> Message {
+        Message::Version(crate::p2p::VersionMessage {
+            version,
+            services: crate::p2p::NODE_NETWORK,
+            best_height: 7,
+            from_addr: make_addr(8000),
+            to_addr: make_addr(8001),
+            nonce,
+            user_agent: "test".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_handshake_state_machine() {
+        let mut pm = PeerManager::new(10);
+        let addr = make_addr(8000);
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        assert!(pm.peer_connecting(addr, true, tx));
+
+        // Nothing but version is accepted first
+        assert_eq!(pm.handshake_message(&addr, &Message::GetAddr, 1), Err(HandshakeError::NotHandshaked("getaddr")));
+        assert_eq!(pm.handshake_message(&addr, &Message::VerAck, 1), Err(HandshakeError::UnexpectedVerAck));
+
+        assert_eq!(pm.handshake_message(&addr, &version(MIN_PROTOCOL_VERSION, 2), 1), Ok(false));
+        assert_eq!(pm.handshake_message(&addr, &Message::GetAddr, 1), Err(HandshakeError::NotHandshaked("getaddr")));
+        assert_eq!(pm.connected_count(), 0);
+
+        assert_eq!(pm.handshake_message(&addr, &Message::VerAck, 1), Ok(true));
+        assert_eq!(pm.handshake_message(&addr, &Message::GetAddr, 1), Ok(true));
+        assert_eq!(pm.handshake_message(&addr, &version(MIN_PROTOCOL_VERSION, 2), 1), Err(HandshakeError::Duplicate("version")));
+        let peer = pm.get_peer(&addr).unwrap();
+        assert_eq!((peer.state.clone(), peer.best_height, peer.services), (PeerState::Connected, 7, crate::p2p::NODE_NETWORK));
+        assert_eq!(pm.connected_count(), 1);
+    }
+
+    #[test]
+    fn test_ping_round_trip() {
+        let mut pm = PeerManager::new(10);
+        let addr = make_addr(8000);
+        pm.add_peer(addr);
+        let start = Instant::now();
+
+        // Unsolicited or mismatched pongs are ignored
+        assert_eq!(pm.pong_received(&addr, 7, start), None);
+        pm.ping_sent(&addr, 7, start);
+        assert_eq!(pm.pong_received(&addr, 8, start), None);
+
+        let rtt = pm.pong_received(&addr, 7, start + Duration::from_millis(80));
+        assert_eq!(rtt, Some(Duration::from_millis(80)));
+        pm.ping_sent(&addr, 9, start);
+        pm.pong_received(&addr, 9, start + Duration::from_millis(120));
+
+        let peer = pm.get_peer(&addr).unwrap();
+        assert_eq!(peer.ping_pending, None);
+        assert_eq!(peer.ping_rtt, Some(Duration::from_millis(120)));
+        assert_eq!(peer.min_ping_rtt, Some(Duration::from_millis(80)));
+    }
+
+    #[test]
+    fn test_addr_rate_limit() {
+        let mut pm = PeerManager::new(10);
+        let addr = make_addr(8000);
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        pm.peer_connecting(addr, false, tx);
+        let start = pm.get_peer(&addr).unwrap().addr_tokens_updated;
+
+        // One unsolicited address, then nothing until the bucket refills
+        assert_eq!(pm.take_addr_tokens(&addr, 5, start), 1);
+        assert_eq!(pm.take_addr_tokens(&addr, 5, start), 0);
+        assert_eq!(pm.take_addr_tokens(&addr, 5, start + Duration::from_secs(20)), 2);
+
+        // Asking for addresses allows a full answer, but never more
+        pm.getaddr_sent(&addr);
+        assert_eq!(pm.take_addr_tokens(&addr, 2 * MAX_ADDR_PER_MESSAGE, start + Duration::from_secs(20)), MAX_ADDR_PER_MESSAGE);
+        let later = start + Duration::from_secs(1_000_000);
+        assert_eq!(pm.take_addr_tokens(&addr, 2 * MAX_ADDR_PER_MESSAGE, later), MAX_ADDR_BURST as usize);
+    }
+
+    #[test]
+    fn test_getaddr_answered_once_for_inbound() {
+        let mut pm = PeerManager::new(10);
+        let (inbound, outbound) = (make_addr(8000), make_addr(8001));
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        pm.peer_connecting(inbound, true, tx.clone());
+        pm.peer_connecting(outbound, false, tx.clone());
+
+        assert!(pm.answer_getaddr(&inbound));
+        assert!(!pm.answer_getaddr(&inbound));
+        assert!(!pm.answer_getaddr(&outbound));
+
+        // A new connection may ask again
+        pm.peer_connecting(inbound, true, tx);
+        assert!(pm.answer_getaddr(&inbound));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let mut pm = PeerManager::new(10);
+        let addr = make_addr(8000);
+        let start = Instant::now();
+        assert!(pm.can_dial(&addr, start));
+
+        // Each failure doubles the wait
+        pm.connection_attempt(addr, start);
+        assert!(!pm.can_dial(&addr, start));
+        pm.connection_failed(&addr);
+        assert!(!pm.can_dial(&addr, start + RETRY_BACKOFF_BASE / 2));
+        assert!(pm.can_dial(&addr, start + RETRY_BACKOFF_BASE));
+        assert_eq!(pm.get_peers_to_connect(10, start + RETRY_BACKOFF_BASE), vec![addr]);
+
+        let retry = start + RETRY_BACKOFF_BASE;
+        pm.connection_attempt(addr, retry);
+        pm.connection_failed(&addr);
+        assert!(!pm.can_dial(&addr, retry + RETRY_BACKOFF_BASE));
+        assert!(pm.can_dial(&addr, retry + RETRY_BACKOFF_BASE * 2));
+
+        // Eventually the peer is given up, unless it is an added node
+        for _ in 2..MAX_FAILED_ATTEMPTS {
+            pm.connection_failed(&addr);
+        }
+        let later = retry + MAX_RETRY_BACKOFF;
+        assert!(!pm.can_dial(&addr, later));
+        assert!(pm.add_node(addr));
+        assert_eq!(pm.added_nodes_to_connect(later), vec![addr]);
+        assert!(pm.get_peers_to_connect(10, later).is_empty());
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let mut pm = PeerManager::new(10).with_max_outbound(3);
+        assert_eq!(pm.max_inbound(), 7);
+        assert!(pm.add_node(make_addr(9000)));
+        assert_eq!(pm.max_inbound(), 6);
+
+        // Added nodes and inbound peers do not take outbound slots
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        pm.peer_connecting(make_addr(9000), false, tx.clone());
+        for port in 0..6 {
+            pm.peer_connecting(make_addr(7000 + port), true, tx.clone());
+        }
+        assert_eq!((pm.outbound_count(), pm.inbound_count()), (0, 6));
+        assert!(!pm.accepts_inbound());
+
+        let now = Instant::now();
+        pm.add_peers(&[make_addr(8000), make_addr(8001), make_addr(8002), make_addr(8003)]);
+        assert_eq!(pm.get_peers_to_connect(10, now).len(), 3);
+        pm.connection_attempt(make_addr(8000), now);
+        pm.connection_attempt(make_addr(8001), now);
+        assert_eq!(pm.outbound_count(), 2);
+        assert_eq!(pm.get_peers_to_connect(10, now).len(), 1);
+    }
+
+    #[test]
+    fn test_inbound_peers_not_dialed() {
+        let mut pm = PeerManager::new(10);
+        let now = Instant::now();
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+
+        // An inbound connection comes and goes
+        let inbound = make_addr(51234);
+        pm.peer_connecting(inbound, true, tx);
+        pm.peer_disconnected(&inbound);
+        assert_eq!(pm.get_peer(&inbound).unwrap().state, PeerState::Disconnected);
+
+        // Only the seed is a dial candidate
+        let seed = make_addr(8000);
+        pm.add_peer(seed);
+        assert!(!pm.can_dial(&inbound, now + MAX_RETRY_BACKOFF));
+        assert_eq!(pm.get_peers_to_connect(10, now + MAX_RETRY_BACKOFF), vec![seed]);
+    }
+
+    #[test]
+    fn test_banned_peers_not_dialed() {
+        let mut pm = PeerManager::new(10);
+        let now = Instant::now();
+        let banned = make_addr(8000);
+        pm.add_peers(&[banned, make_addr(8001)]);
+
+        pm.report_misbehavior(&banned, 100);
+        assert!(!pm.can_dial(&banned, now));
+
+        // A dial refused on connect leaves the peer disconnected, but its
+        // ban still keeps it out of later rounds
+        pm.connection_attempt(banned, now);
+        pm.connection_failed(&banned);
+        assert_eq!(pm.get_peer(&banned).unwrap().state, PeerState::Disconnected);
+        let later = now + MAX_RETRY_BACKOFF;
+        assert!(!pm.can_dial(&banned, later));
+        assert_eq!(pm.get_peers_to_connect(10, later), vec![make_addr(8001)]);
+    }
+
+    #[test]
+    fn test_rotation_candidate() {
+        let mut pm = PeerManager::new(10);
+        let (good, slow, added, inbound) = (make_addr(8000), make_addr(8001), make_addr(8002), make_addr(8003));
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        pm.add_node(added);
+        for (addr, is_inbound) in [(good, false), (slow, false), (added, false), (inbound, true)] {
+            pm.peer_connecting(addr, is_inbound, tx.clone());
+            pm.handshake_message(&addr, &version(MIN_PROTOCOL_VERSION, 2), 1).unwrap();
+            pm.handshake_message(&addr, &Message::VerAck, 1).unwrap();
+        }
+
+        let now = Instant::now();
+        assert_eq!(pm.rotation_candidate(now), None);
+
+        // Slow or misbehaving outbound peers are replaced, added and inbound ones are not
+        for addr in [slow, added, inbound] {
+            pm.ping_sent(&addr, 1, now);
+            pm.pong_received(&addr, 1, now + SLOW_PEER_PING * 2);
+        }
+        assert_eq!(pm.rotation_candidate(now), Some(slow));
+        pm.report_misbehavior(&good, 10);
+        assert_eq!(pm.rotation_candidate(now), Some(good));
+
+        pm.rotate_out(&good, now);
+        assert!(!pm.can_dial(&good, now));
+    }
+
+    #[test]
+    fn test_handshake_rejects_incompatible_peers() {
+        let mut pm = PeerManager::new(10);
+        let addr = make_addr(8000);
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        pm.peer_connecting(addr, false, tx.clone());
+
+        let err = pm.handshake_message(&addr, &version(MIN_PROTOCOL_VERSION - 1, 2), 1).unwrap_err();
+        assert_eq!(err, HandshakeError::ObsoleteVersion(MIN_PROTOCOL_VERSION - 1));
+        assert_eq!(err.reject_code(), Some(RejectCode::Obsolete));
+
+        // Our own nonce coming back means we dialed ourselves
+        pm.peer_connecting(addr, false, tx.clone());
+        let err = pm.handshake_message(&addr, &version(MIN_PROTOCOL_VERSION, 1), 1).unwrap_err();
+        assert_eq!(err, HandshakeError::SelfConnection);
+        assert_eq!(err.reject_code(), None);
+
+        // Banned peers may not reconnect
+        pm.ban_peer(&addr);
+        pm.peer_disconnected(&addr);
+        assert!(!pm.peer_connecting(addr, true, tx));
+    }
 }
@@ -9,7 +9,19 @@ use crate::validation::Transaction;
 use std::net::SocketAddr;
 
 /// Protocol version
-pub const PROTOCOL_VERSION: u32 = 1;
+///
+/// Version 2 added service bits to the version handshake, version 3
+/// timestamps and service bits on `addr` entries.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Oldest protocol version we talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Service bit: the node stores and serves the full block chain
+pub const NODE_NETWORK: u64 = 1 << 0;
+
+/// Services this node offers
+pub const LOCAL_SERVICES: u64 = NODE_NETWORK;
 
 /// Network magic bytes (identifies RH network)
 pub const NETWORK_MAGIC: [u8; 4] = [0x52, 0x48, 0x43, 0x4E]; // "RHCN"
@@ -17,6 +29,9 @@ pub const NETWORK_MAGIC: [u8; 4] = [0x52, 0x48, 0x43, 0x4E]; // "RHCN"
 /// Maximum message size (4 MB)
 pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
 
+// A block of the maximum weight must fit in a single message
+const _: () = assert!(crate::constants::MAX_BLOCK_WEIGHT as usize <= MAX_MESSAGE_SIZE);
+
 /// P2P message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
@@ -31,7 +46,7 @@ pub enum Message {
     /// Request peer addresses
     GetAddr,
     /// Share peer addresses
-    Addr(Vec<SocketAddr>),
+    Addr(Vec<NetAddress>),
     /// Announce new block
     Inv(Vec<InvItem>),
     /// Request data
@@ -65,6 +80,9 @@ pub struct InvItem {
 }
 
 /// Version handshake message
+///
+/// Fields are encoded in order, so new ones go last: a version 1 decoder
+/// reads the fields it knows and ignores the rest.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VersionMessage {
     /// Protocol version
@@ -79,6 +97,50 @@ pub struct VersionMessage {
     pub nonce: u64,
     /// User agent string
     pub user_agent: String,
+    /// Service bits offered by the sender (see `NODE_NETWORK`)
+    pub services: u64,
+}
+
+/// Version message as sent by version 1 peers, before service bits
+#[derive(Deserialize)]
+struct LegacyVersionMessage {
+    version: u32,
+    best_height: u64,
+    from_addr: SocketAddr,
+    to_addr: SocketAddr,
+    nonce: u64,
+    user_agent: String,
+}
+
+/// The variant of `Message` a version 1 peer opens with
+#[derive(Deserialize)]
+enum LegacyMessage {
+    Version(LegacyVersionMessage),
+}
+
+impl From<LegacyVersionMessage> for VersionMessage {
+    fn from(v: LegacyVersionMessage) -> Self {
+        Self {
+            version: v.version,
+            best_height: v.best_height,
+            from_addr: v.from_addr,
+            to_addr: v.to_addr,
+            nonce: v.nonce,
+            user_agent: v.user_agent,
+            services: 0,
+        }
+    }
+}
+
+/// Peer address as gossiped in `addr` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetAddress {
+    /// Address the peer accepts connections on
+    pub addr: SocketAddr,
+    /// Service bits the peer offers
+    pub services: u64,
+    /// When the peer was last heard of (unix seconds)
+    pub timestamp: u64,
 }
 
 /// Get headers request
@@ -112,18 +174,8 @@ pub struct RejectMessage {
     pub data_hash: Option<Hash>,
 }
 
-/// Rejection codes
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub enum RejectCode {
-    Malformed = 0x01,
-    Invalid = 0x10,
-    Obsolete = 0x11,
-    Duplicate = 0x12,
-    NonStandard = 0x40,
-    Dust = 0x41,
-    InsufficientFee = 0x42,
-    Checkpoint = 0x43,
-}
+/// Rejection codes (defined with the mempool policy that produces them)
+pub use crate::validation::policy::RejectCode;
 
 impl Message {
     /// Serialize message to bytes
@@ -159,8 +211,16 @@ impl Message {
             return Err("Incomplete message".to_string());
         }
 
-        bincode::deserialize(&bytes[8..8 + length])
-            .map_err(|e| format!("Deserialization error: {}", e))
+        let payload = &bytes[8..8 + length];
+        bincode::deserialize(payload).or_else(|e| {
+            // A version 1 peer's version message is too short for the
+            // current layout; decode it anyway so the handshake can reject
+            // the peer as obsolete instead of dropping an unreadable message
+            match bincode::deserialize(payload) {
+                Ok(LegacyMessage::Version(v)) => Ok(Message::Version(v.into())),
+                Err(_) => Err(format!("Deserialization error: {}", e)),
+            }
+        })
     }
 
     /// Get the command name for this message
@@ -250,4 +310,57 @@ mod tests {
         let result = Message::from_bytes(&bytes);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_version_wire_compatibility() {
+        #[derive(Serialize, Deserialize)]
+        struct V1Version {
+            version: u32,
+            best_height: u64,
+            from_addr: SocketAddr,
+            to_addr: SocketAddr,
+            nonce: u64,
+            user_agent: String,
+        }
+        #[derive(Serialize, Deserialize)]
+        enum V1Message {
+            Version(V1Version),
+        }
+
+        let addr: SocketAddr = "127.0.0.1:8333".parse().unwrap();
+
+        // A version 1 peer's version decodes, with no services
+        let legacy = V1Message::Version(V1Version {
+            version: 1,
+            best_height: 7,
+            from_addr: addr,
+            to_addr: addr,
+            nonce: 42,
+            user_agent: "/rh:0.1/".to_string(),
+        });
+        let payload = bincode::serialize(&legacy).unwrap();
+        let mut bytes = NETWORK_MAGIC.to_vec();
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        match Message::from_bytes(&bytes).unwrap() {
+            Message::Version(v) => {
+                assert_eq!((v.version, v.best_height, v.nonce, v.services), (1, 7, 42, 0));
+                assert_eq!(v.user_agent, "/rh:0.1/");
+            }
+            _ => panic!("Wrong message type"),
+        }
+
+        // And a version 1 decoder reads our version, ignoring the services
+        let current = Message::Version(VersionMessage {
+            version: PROTOCOL_VERSION,
+            best_height: 9,
+            from_addr: addr,
+            to_addr: addr,
+            nonce: 43,
+            user_agent: "/rh:0.3/".to_string(),
+            services: LOCAL_SERVICES,
+        });
+        let V1Message::Version(v) = bincode::deserialize(&current.to_bytes()[8..]).unwrap();
+        assert_eq!((v.version, v.best_height, v.nonce), (PROTOCOL_VERSION, 9, 43));
+    }
 }
@@ -0,0 +1,400 @@
+//! Headers-first initial block download
+//!
+//! Header chains are downloaded from one peer at a time and validated (PoW,
+//! retargeted difficulty, checkpoints) before any body is requested. Bodies
+//! along the best header chain are then fetched in parallel from every peer
+//! that has them, inside a moving window above our tip. Requests that take
+//! too long are handed to other peers and the slow peer is benched.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::consensus::{BlockHeader, ValidationError};
+use crate::crypto::Hash;
+use crate::p2p::{build_block_locator, GetHeadersMessage, InvItem, InvType, Message};
+use crate::storage::ChainState;
+
+/// Maximum headers sent in one Headers message
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+/// How far above our tip block bodies are requested
+pub const BLOCK_DOWNLOAD_WINDOW: u64 = 1024;
+
+/// Maximum block requests outstanding per peer
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+
+/// A block request older than this marks its peer as stalling
+pub const BLOCK_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A headers request older than this marks its peer as stalling
+pub const HEADERS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a stalling peer is left out of downloads
+pub const STALL_PENALTY: Duration = Duration::from_secs(300);
+
+/// Current sync activity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPhase {
+    /// Caught up with every peer
+    Idle,
+    /// Downloading header chains
+    Headers,
+    /// Downloading block bodies behind the best header
+    Blocks,
+}
+
+/// Sync progress snapshot (for RPC)
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncProgress {
+    pub phase: SyncPhase,
+    /// Height of the best validated header
+    pub headers: u64,
+    /// Height of the active chain
+    pub blocks: u64,
+    /// Block bodies requested but not yet received
+    pub blocks_in_flight: usize,
+    /// Peer we are downloading headers from
+    pub headers_peer: Option<SocketAddr>,
+    /// Peers currently benched for stalling
+    pub stalled_peers: usize,
+}
+
+/// Headers-first download state machine
+///
+/// Pure bookkeeping: callers feed in peer heights and received messages,
+/// and send the requests it returns.
+#[derive(Debug, Default)]
+pub struct SyncManager {
+    /// Peer we asked for headers, and when
+    headers_peer: Option<(SocketAddr, Instant)>,
+    /// Announced height at which each peer ran out of new headers
+    headers_exhausted: HashMap<SocketAddr, u64>,
+    /// Requested block -> (peer, requested at)
+    in_flight: HashMap<Hash, (SocketAddr, Instant)>,
+    /// Stalling peers and when they were benched
+    stalled: HashMap<SocketAddr, Instant>,
+}
+
+impl SyncManager {
+    /// Create an idle sync manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pick a peer to download headers from, if any is ahead of us
+    ///
+    /// `peers` are (address, announced best height) of connected peers.
+    /// Returns None while a headers request is still outstanding.
+    pub fn headers_request(
+        &mut self,
+        chain: &ChainState,
+        peers: &[(SocketAddr, u64)],
+        now: Instant,
+    ) -> Option<(SocketAddr, Message)> {
+        if self.headers_peer.is_some() {
+            return None;
+        }
+
+        let best_height = chain.best_header_height();
+        let (addr, _) = peers.iter()
+            .filter(|(addr, height)| {
+                *height > best_height
+                    && !self.stalled.contains_key(addr)
+                    && self.headers_exhausted.get(addr).is_none_or(|exhausted| height > exhausted)
+            })
+            .max_by_key(|(_, height)| *height)?;
+
+        self.headers_peer = Some((*addr, now));
+        Some((*addr, Self::get_headers(chain)))
+    }
+
+    /// GetHeaders continuing from our best header chain
+    pub fn get_headers(chain: &ChainState) -> Message {
+        let locator = build_block_locator(&[chain.best_header_height()], |h| chain.get_header_hash_at_height(h));
+        Message::GetHeaders(GetHeadersMessage {
+            block_locators: locator,
+            stop_hash: Hash::zero(),
+        })
+    }
+
+    /// Validate and index a batch of headers from `from`
+    ///
+    /// A full batch means the peer has more, so the follow-up request to the
+    /// same peer is returned. Otherwise the peer is remembered as exhausted
+    /// at `announced_height` until it announces something higher.
+    pub fn on_headers(
+        &mut self,
+        from: SocketAddr,
+        announced_height: u64,
+        headers: &[BlockHeader],
+        chain: &mut ChainState,
+        now: Instant,
+    ) -> Result<Option<Message>, ValidationError> {
+        if self.headers_peer.is_some_and(|(addr, _)| addr == from) {
+            self.headers_peer = None;
+        }
+
+        for header in headers {
+            chain.accept_header(header)?;
+        }
+
+        if headers.len() < MAX_HEADERS_PER_MESSAGE {
+            self.headers_exhausted.insert(from, announced_height);
+            return Ok(None);
+        }
+        if self.headers_peer.is_some() {
+            return Ok(None);
+        }
+
+        self.headers_peer = Some((from, now));
+        Ok(Some(Self::get_headers(chain)))
+    }
+
+    /// Assign missing block bodies to peers
+    ///
+    /// Fills every non-stalled peer that has the blocks up to
+    /// `MAX_BLOCKS_IN_FLIGHT_PER_PEER`, lowest heights first. Returns the
+    /// GetData requests to send.
+    pub fn blocks_requests(
+        &mut self,
+        chain: &ChainState,
+        peers: &[(SocketAddr, u64)],
+        now: Instant,
+    ) -> Vec<(SocketAddr, Message)> {
+        let mut load: HashMap<SocketAddr, usize> = HashMap::new();
+        for (addr, _) in self.in_flight.values() {
+            *load.entry(*addr).or_default() += 1;
+        }
+
+        let mut requests: HashMap<SocketAddr, Vec<InvItem>> = HashMap::new();
+        for (height, hash) in chain.blocks_to_download(BLOCK_DOWNLOAD_WINDOW) {
+            if self.in_flight.contains_key(&hash) {
+                continue;
+            }
+
+            let peer = peers.iter()
+                .filter(|(addr, best)| *best >= height && !self.stalled.contains_key(addr))
+                .map(|(addr, _)| (*addr, load.get(addr).copied().unwrap_or(0)))
+                .filter(|(_, in_flight)| *in_flight < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .min_by_key(|(_, in_flight)| *in_flight);
+            let Some((addr, _)) = peer else {
+                continue;
+            };
+
+            *load.entry(addr).or_default() += 1;
+            self.in_flight.insert(hash, (addr, now));
+            requests.entry(addr).or_default().push(InvItem { inv_type: InvType::Block, hash });
+        }
+
+        requests.into_iter()
+            .map(|(addr, items)| (addr, Message::GetData(items)))
+            .collect()
+    }
+
+    /// A block body arrived
+    pub fn block_received(&mut self, hash: &Hash) {
+        self.in_flight.remove(hash);
+    }
+
+    /// Find peers sitting on requests for too long
+    ///
+    /// Their requests are released for other peers and they are benched for
+    /// `STALL_PENALTY`. Returns the newly benched peers.
+    pub fn check_stalls(&mut self, now: Instant) -> Vec<SocketAddr> {
+        self.stalled.retain(|_, since| now.duration_since(*since) < STALL_PENALTY);
+
+        let mut stalling: Vec<SocketAddr> = self.in_flight.values()
+            .filter(|(_, requested)| now.duration_since(*requested) > BLOCK_STALL_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        if let Some((addr, requested)) = self.headers_peer {
+            if now.duration_since(requested) > HEADERS_TIMEOUT {
+                stalling.push(addr);
+            }
+        }
+        stalling.sort();
+        stalling.dedup();
+
+        for addr in &stalling {
+            self.release_peer(addr);
+            self.stalled.insert(*addr, now);
+        }
+        stalling
+    }
+
+    /// Forget everything assigned to a disconnected peer
+    pub fn peer_disconnected(&mut self, addr: &SocketAddr) {
+        self.release_peer(addr);
+        self.headers_exhausted.remove(addr);
+    }
+
+    fn release_peer(&mut self, addr: &SocketAddr) {
+        self.in_flight.retain(|_, (peer, _)| peer != addr);
+        if self.headers_peer.is_some_and(|(peer, _)| peer == *addr) {
+            self.headers_peer = None;
+        }
+    }
+
+    /// Current progress
+    pub fn progress(&self, chain: &ChainState) -> SyncProgress {
+        let phase = if self.headers_peer.is_some() {
+            SyncPhase::Headers
+        } else if chain.best_header_height() > chain.height || !self.in_flight.is_empty() {
+            SyncPhase::Blocks
+        } else {
+            SyncPhase::Idle
+        };
+
+        SyncProgress {
+            phase,
+            headers: chain.best_header_height(),
+            blocks: chain.height,
+            blocks_in_flight: self.in_flight.len(),
+            headers_peer: self.headers_peer.map(|(addr, _)| addr),
+            stalled_peers: self.stalled.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{Block, validate_pow};
+    use crate::crypto::{compute_merkle_root, hash_bytes};
+    use crate::validation::Transaction;
+
+    const TEST_DIFFICULTY: u32 = 0x207fffff;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn mine(prev_hash: Hash, timestamp: u64) -> Block {
+        let coinbase = Transaction::coinbase(1, hash_bytes(&timestamp.to_le_bytes()));
+        let mut block = Block::new(
+            BlockHeader::new(1, 0x01, prev_hash, compute_merkle_root(&[coinbase.hash()]), timestamp, TEST_DIFFICULTY, 0),
+            vec![coinbase],
+        );
+        while validate_pow(&block.header).is_err() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    /// A chain state at genesis plus `n` blocks building on it
+    fn setup(n: u64) -> (ChainState, Vec<Block>) {
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![Transaction::coinbase(1, hash_bytes(b"founder"))],
+        );
+        let chain = ChainState::new(&genesis);
+        let mut blocks = Vec::new();
+        let mut prev = genesis.hash();
+        for i in 1..=n {
+            let block = mine(prev, 1234567890 + i);
+            prev = block.hash();
+            blocks.push(block);
+        }
+        (chain, blocks)
+    }
+
+    fn headers(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks.iter().map(|b| b.header.clone()).collect()
+    }
+
+    fn requested(requests: &[(SocketAddr, Message)], peer: SocketAddr) -> Vec<Hash> {
+        requests.iter()
+            .filter(|(a, _)| *a == peer)
+            .flat_map(|(_, msg)| match msg {
+                Message::GetData(items) => items.iter().map(|i| i.hash).collect(),
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_headers_requested_from_best_peer() {
+        let (chain, _) = setup(0);
+        let mut sync = SyncManager::new();
+        let now = Instant::now();
+        let peers = [(addr(1), 5), (addr(2), 9), (addr(3), 0)];
+
+        let (peer, msg) = sync.headers_request(&chain, &peers, now).unwrap();
+        assert_eq!(peer, addr(2));
+        assert!(matches!(msg, Message::GetHeaders(_)));
+
+        // Only one headers request at a time
+        assert!(sync.headers_request(&chain, &peers, now).is_none());
+        assert_eq!(sync.progress(&chain).phase, SyncPhase::Headers);
+    }
+
+    #[test]
+    fn test_headers_then_parallel_bodies() {
+        let (mut chain, blocks) = setup(40);
+        let mut sync = SyncManager::new();
+        let now = Instant::now();
+        let peers = [(addr(1), 40), (addr(2), 40)];
+
+        sync.headers_request(&chain, &peers, now).unwrap();
+        let next = sync.on_headers(addr(2), 40, &headers(&blocks), &mut chain, now).unwrap();
+        assert!(next.is_none());
+        assert_eq!(chain.best_header_height(), 40);
+        assert_eq!(chain.height, 0);
+
+        // Both peers get a full share, lowest heights first
+        let requests = sync.blocks_requests(&chain, &peers, now);
+        let (first, second) = (requested(&requests, addr(1)), requested(&requests, addr(2)));
+        assert_eq!(first.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert_eq!(second.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert!(!first.contains(&blocks[39].hash()));
+        assert_eq!(sync.progress(&chain).blocks_in_flight, 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+
+        // Bodies arriving out of order connect once the gap is filled
+        for block in blocks[..32].iter().rev() {
+            chain.index_block(block).unwrap();
+            sync.block_received(&block.hash());
+            chain.activate_best_header().unwrap();
+        }
+        assert_eq!(chain.height, 32);
+        assert_eq!(chain.tip_hash, blocks[31].hash());
+
+        // The rest are handed out as capacity frees up
+        let requests = sync.blocks_requests(&chain, &peers, now);
+        let total: usize = [addr(1), addr(2)].iter().map(|p| requested(&requests, *p).len()).sum();
+        assert_eq!(total, 8);
+    }
+
+    #[test]
+    fn test_invalid_header_rejected() {
+        let (mut chain, blocks) = setup(3);
+        let mut sync = SyncManager::new();
+
+        let mut bad = headers(&blocks);
+        bad[1].difficulty_target = 0x1f7fffff;
+        assert!(sync.on_headers(addr(1), 3, &bad, &mut chain, Instant::now()).is_err());
+        assert_eq!(chain.best_header_height(), 1);
+    }
+
+    #[test]
+    fn test_stalled_peer_released_and_benched() {
+        let (mut chain, blocks) = setup(4);
+        let mut sync = SyncManager::new();
+        let start = Instant::now();
+        sync.on_headers(addr(1), 4, &headers(&blocks), &mut chain, start).unwrap();
+
+        let requests = sync.blocks_requests(&chain, &[(addr(1), 4)], start);
+        assert_eq!(requested(&requests, addr(1)).len(), 4);
+
+        let later = start + BLOCK_STALL_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(sync.check_stalls(later), vec![addr(1)]);
+        assert_eq!(sync.progress(&chain).blocks_in_flight, 0);
+
+        // The work goes to another peer; the staller gets nothing
+        let peers = [(addr(1), 4), (addr(2), 4)];
+        let requests = sync.blocks_requests(&chain, &peers, later);
+        assert!(requested(&requests, addr(1)).is_empty());
+        assert_eq!(requested(&requests, addr(2)).len(), 4);
+    }
+}
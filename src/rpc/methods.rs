@@ -53,13 +53,14 @@ impl JsonRpcResponse {
     }
 }
 
-use crate::p2p::PeerManager;
+use crate::p2p::{PeerManager, SyncManager};
 
 /// RPC Handler State
 pub struct RpcState {
     pub chain_state: Arc<Mutex<ChainState>>,
     pub wallet: Arc<Mutex<Wallet>>,
     pub peer_manager: Arc<Mutex<PeerManager>>,
+    pub sync_manager: Arc<Mutex<SyncManager>>,
     pub miner_address: Arc<Mutex<String>>,
     pub miner_pubkey_hash: Arc<Mutex<Hash>>,
 }
@@ -73,11 +74,15 @@ pub fn handle_request(state: &RpcState, request: JsonRpcRequest) -> JsonRpcRespo
         "getbalance" => get_balance(state, request.id, request.params),
         "getnewaddress" => get_new_address(state, request.id),
         "getinfo" => get_info(state, request.id),
+        "getblockchaininfo" => get_blockchain_info(state, request.id),
         "getmineraddress" => get_miner_address(state, request.id),
         "createrawtransaction" => create_raw_transaction(state, request.id, request.params),
         "signrawtransaction" => sign_raw_transaction(state, request.id, request.params),
         "sendrawtransaction" => send_raw_transaction(state, request.id, request.params),
         "importprivkey" => import_priv_key(state, request.id, request.params),
+        "estimatesmartfee" => estimate_smart_fee(state, request.id, request.params),
+        "getnonce" => get_nonce(state, request.id, request.params),
+        "getpeerinfo" => get_peer_info(state, request.id),
         _ => JsonRpcResponse::error(
             request.id,
             -32601,
@@ -144,6 +149,7 @@ fn get_block(
                 "merkleroot": block.header.merkle_root.to_string(),
                 "time": block.header.timestamp,
                 "difficulty": block.header.difficulty_target,
+                "chainwork": format!("{:032x}", chain.get_chain_work(&hash).unwrap_or(0)),
                 "nonce": block.header.nonce,
                 "tx_count": block.transactions.len(),
             });
@@ -154,6 +160,10 @@ fn get_block(
 }
 
 /// Returns balance for a given address
+/// Params: [address]
+///
+/// Amounts are in RH. `spendable` can be spent in the next block,
+/// `immature` is coinbase rewards still waiting for maturity.
 fn get_balance(
     state: &RpcState,
     id: serde_json::Value,
@@ -174,13 +184,76 @@ fn get_balance(
     };
 
     let chain = state.chain_state.lock().unwrap();
-    let balance = chain.utxo_set.get_balance(&pubkey_hash);
+    let balance = crate::wallet::Balance::of(&pubkey_hash, &chain.utxo_set, chain.height);
     
-    println!("💰 Balance inquiry for {}: {} RH (hash: {})", address, balance as f64 / 100_000_000.0, pubkey_hash);
+    // Return balances in RH (divide by 10^8)
+    let to_rh = |amount: u64| amount as f64 / 100_000_000.0;
+    println!(
+        "💰 Balance inquiry for {}: {} RH spendable, {} RH immature (hash: {})",
+        address, to_rh(balance.spendable), to_rh(balance.immature), pubkey_hash
+    );
 
-    // Return balance in RH (divide by 10^8)
-    let balance_rh = balance as f64 / 100_000_000.0;
-    JsonRpcResponse::success(id, serde_json::json!(balance_rh))
+    JsonRpcResponse::success(id, serde_json::json!({
+        "spendable": to_rh(balance.spendable),
+        "immature": to_rh(balance.immature),
+    }))
+}
+
+/// Returns the nonces of an address
+/// Params: [address]
+///
+/// `confirmed` is the nonce its next transaction in a block must carry,
+/// `pending` the one to use after its mempool transactions.
+fn get_nonce(
+    state: &RpcState,
+    id: serde_json::Value,
+    params: Option<serde_json::Value>,
+) -> JsonRpcResponse {
+    let address = match params {
+        Some(serde_json::Value::Array(arr)) if !arr.is_empty() => {
+            arr[0].as_str().unwrap_or("").to_string()
+        }
+        Some(serde_json::Value::String(s)) => s,
+        _ => return JsonRpcResponse::error(id, -32602, "Invalid params: expected address".into()),
+    };
+
+    let pubkey_hash = match crate::wallet::address_to_pubkey_hash(&address) {
+        Ok(h) => h,
+        Err(_) => return JsonRpcResponse::error(id, -5, "Invalid address".into()),
+    };
+
+    let chain = state.chain_state.lock().unwrap();
+    JsonRpcResponse::success(id, serde_json::json!({
+        "confirmed": chain.nonces.get(&pubkey_hash),
+        "pending": chain.get_next_nonce(&pubkey_hash),
+    }))
+}
+
+/// Returns connected peers with their handshake data and ping latency
+///
+/// Times are in milliseconds; `pingwait` is set while a ping is unanswered.
+fn get_peer_info(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse {
+    let pm = state.peer_manager.lock().unwrap();
+    let millis = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+
+    let mut peers = pm.get_connected_peers();
+    peers.sort_by_key(|peer| peer.addr);
+    let info: Vec<serde_json::Value> = peers.into_iter()
+        .map(|peer| serde_json::json!({
+            "addr": peer.addr.to_string(),
+            "version": peer.version,
+            "services": format!("{:016x}", peer.services),
+            "inbound": peer.inbound,
+            "bestheight": peer.best_height,
+            "lastseen": peer.last_seen.elapsed().as_secs(),
+            "pingtime": peer.ping_rtt.map(millis),
+            "minping": peer.min_ping_rtt.map(millis),
+            "pingwait": peer.ping_pending.map(|(_, sent)| millis(sent.elapsed())),
+            "banscore": peer.misbehavior_score,
+        }))
+        .collect();
+
+    JsonRpcResponse::success(id, serde_json::json!(info))
 }
 
 /// Generates a new wallet address
@@ -205,6 +278,7 @@ fn get_info(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse {
         "blocks": stats.height,
         "tip": stats.tip_hash.to_string(),
         "difficulty": stats.difficulty,
+        "chainwork": format!("{:032x}", stats.chain_work),
         "total_issued": stats.total_issued as f64 / 100_000_000.0,
         "utxo_count": stats.utxo_count,
         "version": "1.5.0",
@@ -213,6 +287,63 @@ fn get_info(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse {
     JsonRpcResponse::success(id, info)
 }
 
+/// Returns chain and header sync status
+fn get_blockchain_info(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse {
+    let chain = state.chain_state.lock().unwrap();
+    let sync = state.sync_manager.lock().unwrap().progress(&chain);
+
+    let verification_progress = if sync.headers == 0 {
+        1.0
+    } else {
+        sync.blocks as f64 / sync.headers as f64
+    };
+
+    let info = serde_json::json!({
+        "blocks": sync.blocks,
+        "headers": sync.headers,
+        "bestblockhash": chain.tip_hash.to_string(),
+        "bestheaderhash": chain.best_header().to_string(),
+        "chainwork": format!("{:032x}", chain.chain_work()),
+        "initialblockdownload": sync.headers > sync.blocks,
+        "verificationprogress": verification_progress,
+        "sync": sync,
+    });
+
+    JsonRpcResponse::success(id, info)
+}
+
+/// Estimate the fee rate needed to confirm within a number of blocks
+/// Params: [target_blocks]
+fn estimate_smart_fee(
+    state: &RpcState,
+    id: serde_json::Value,
+    params: Option<serde_json::Value>,
+) -> JsonRpcResponse {
+    let target = match params {
+        Some(serde_json::Value::Array(arr)) if !arr.is_empty() => arr[0].as_u64(),
+        Some(serde_json::Value::Number(n)) => n.as_u64(),
+        _ => None,
+    };
+    let target = match target {
+        Some(t) if t >= 1 => (t as usize).min(crate::mempool::MAX_CONFIRM_TARGET),
+        _ => return JsonRpcResponse::error(id, -32602, "Invalid params: expected target_blocks >= 1".into()),
+    };
+
+    let chain = state.chain_state.lock().unwrap();
+    let result = match chain.fee_estimator.estimate(target) {
+        Some(fee_rate) => serde_json::json!({
+            "feerate": fee_rate,
+            "blocks": target,
+        }),
+        None => serde_json::json!({
+            "errors": ["Insufficient data or no feerate found"],
+            "blocks": target,
+        }),
+    };
+
+    JsonRpcResponse::success(id, result)
+}
+
 /// Returns the current miner address and potentially the private key for the web wallet
 fn get_miner_address(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse {
     let wallet = state.wallet.lock().unwrap();
@@ -228,20 +359,30 @@ fn get_miner_address(state: &RpcState, id: serde_json::Value) -> JsonRpcResponse
 }
 
 /// Create a raw transaction
-/// Params: [to_address, amount_rh]
+/// Params: [to_address, amount_rh, (optional) from_address, (optional) lock_time]
+/// 
+/// `lock_time` below 500,000,000 is a block height, otherwise a Unix timestamp;
+/// the transaction cannot be mined before it passes.
 fn create_raw_transaction(
     state: &RpcState,
     id: serde_json::Value,
     params: Option<serde_json::Value>,
 ) -> JsonRpcResponse {
-    let (to_address, amount_rh, from_address) = match params {
+    let (to_address, amount_rh, from_address, lock_time) = match params {
         Some(serde_json::Value::Array(arr)) if arr.len() >= 2 => {
             let to = arr[0].as_str().unwrap_or("").to_string();
             let amount = arr[1].as_f64().unwrap_or(0.0);
             let from = arr.get(2).and_then(|v| v.as_str()).map(|s| s.to_string());
-            (to, amount, from)
+            let lock_time = match arr.get(3) {
+                None | Some(serde_json::Value::Null) => 0,
+                Some(v) => match v.as_u64().and_then(|n| u32::try_from(n).ok()) {
+                    Some(n) => n,
+                    None => return JsonRpcResponse::error(id, -32602, "Invalid lock_time: expected a height or Unix timestamp".into()),
+                },
+            };
+            (to, amount, from, lock_time)
         }
-        _ => return JsonRpcResponse::error(id, -32602, "Invalid params: [to_address, amount, (optional) from_address]".into()),
+        _ => return JsonRpcResponse::error(id, -32602, "Invalid params: [to_address, amount, (optional) from_address, (optional) lock_time]".into()),
     };
 
     let amount_base = (amount_rh * 100_000_000.0) as u64;
@@ -270,7 +411,9 @@ fn create_raw_transaction(
     
     let mut selected_utxos = Vec::new();
     let mut total_selected = 0u64;
-    let fee = 1000; // Fixed fee for now
+    // Pay the node's fee estimate for the wallet's default confirmation target
+    let fee_rate = crate::wallet::Wallet::default_fee_rate(&chain.fee_estimator);
+    let mut fee = fee_rate * crate::wallet::estimate_tx_size(1, 2);
 
     for addr in &addresses {
         let pubkey_hash = match crate::wallet::address_to_pubkey_hash(addr) {
@@ -278,9 +421,11 @@ fn create_raw_transaction(
             Err(_) => continue,
         };
         let utxos = chain.utxo_set.get_by_pubkey_hash(&pubkey_hash);
-        for (key, utxo) in utxos {
+        // Coinbase rewards still maturing cannot be spent yet
+        for (key, utxo) in utxos.into_iter().filter(|(_, utxo)| utxo.is_mature(chain.height + 1)) {
             selected_utxos.push((key, utxo.clone()));
             total_selected += utxo.amount;
+            fee = fee_rate * crate::wallet::estimate_tx_size(selected_utxos.len(), 2);
             if total_selected >= amount_base + fee {
                 break;
             }
@@ -310,8 +455,8 @@ fn create_raw_transaction(
         pubkey_hash: recipient_hash,
     }];
 
-    // Change output
-    if total_selected > amount_base + fee {
+    // Change output, unless it is too small to relay (then it goes to the fee)
+    if total_selected - amount_base - fee >= crate::validation::policy::dust_threshold() {
         let change_addr = &addresses[0]; // Send change back to first address
         let change_hash = crate::wallet::address_to_pubkey_hash(change_addr).unwrap();
         outputs.push(crate::validation::TxOutput {
@@ -320,7 +465,10 @@ fn create_raw_transaction(
         });
     }
 
-    let tx = crate::validation::Transaction::new(inputs, outputs);
+    // Numbered after the owner of the first input, who signs it first
+    let sender = selected_utxos[0].1.pubkey_hash;
+    let mut tx = crate::validation::Transaction::new_with_nonce(inputs, outputs, chain.get_next_nonce(&sender));
+    tx.lock_time = lock_time;
     let tx_bytes = bincode::serialize(&tx).unwrap();
     
     JsonRpcResponse::success(id, serde_json::json!(hex::encode(tx_bytes)))
@@ -368,7 +516,7 @@ fn sign_raw_transaction(
     let signing_hash = tx.signing_hash();
     
     // Use the public field to initialize directly and avoid trait ambiguity
-    let key_bytes = match k256::schnorr::SigningKey::from_bytes(priv_key_bytes.as_slice().into()) {
+    let key_bytes = match k256::schnorr::SigningKey::from_bytes(priv_key_bytes.as_slice()) {
         Ok(k) => k,
         Err(_) => return JsonRpcResponse::error(id, -5, "Invalid private key".into()),
     };
@@ -414,18 +562,24 @@ fn send_raw_transaction(
     // Add to mempool
     let mut chain = state.chain_state.lock().unwrap();
     match chain.add_to_mempool(tx.clone()) {
-        Ok(_) => {
+        Ok(replaced) => {
             println!("📥 New transaction added to mempool: {}", tx.hash());
+            if !replaced.is_empty() {
+                println!("🔁 Replaced {} mempool transactions", replaced.len());
+            }
             
-            // Broadcast to peers
+            // Announce to peers; they fetch the body with GetData
             let pm = state.peer_manager.lock().unwrap();
-            pm.broadcast_message(&crate::p2p::Message::Tx(tx.clone()));
+            pm.broadcast_message(&crate::p2p::Message::Inv(vec![crate::p2p::InvItem {
+                inv_type: crate::p2p::InvType::Transaction,
+                hash: tx.hash(),
+            }]));
 
             JsonRpcResponse::success(id, serde_json::json!(tx.hash().to_string()))
         }
         Err(e) => {
             eprintln!("❌ Failed to add tx to mempool: {}", e);
-            JsonRpcResponse::error(id, -32603, e)
+            JsonRpcResponse::error(id, -32603, e.reason)
         }
     }
 }
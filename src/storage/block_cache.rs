@@ -0,0 +1,140 @@
+//! Bounded LRU cache of full blocks
+//!
+//! Only headers stay in memory for the whole chain. Block bodies live on
+//! disk, with the most recently used ones kept here.
+
+use std::collections::{BTreeMap, HashMap};
+use crate::consensus::Block;
+use crate::crypto::Hash;
+
+/// Least-recently-used block cache
+#[derive(Debug)]
+pub struct BlockCache {
+    /// Maximum number of blocks held (None = never evict)
+    capacity: Option<usize>,
+    /// Cached blocks with the tick of their last use
+    blocks: HashMap<Hash, (Block, u64)>,
+    /// Last-use tick -> hash, oldest first
+    recency: BTreeMap<u64, Hash>,
+    /// Monotonic use counter
+    tick: u64,
+}
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` blocks
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity.max(1)),
+            ..Self::unbounded()
+        }
+    }
+
+    /// Create a cache that never evicts (for nodes without a database)
+    pub fn unbounded() -> Self {
+        Self {
+            capacity: None,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Change the capacity, evicting the oldest blocks if needed
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = Some(capacity.max(1));
+        self.evict();
+    }
+
+    /// Get a block, marking it as recently used
+    pub fn get(&mut self, hash: &Hash) -> Option<Block> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (block, last_used) = self.blocks.get_mut(hash)?;
+        self.recency.remove(last_used);
+        self.recency.insert(tick, *hash);
+        *last_used = tick;
+        Some(block.clone())
+    }
+
+    /// Insert a block as the most recently used
+    pub fn insert(&mut self, block: Block) {
+        self.tick += 1;
+        let hash = block.hash();
+
+        if let Some((_, last_used)) = self.blocks.insert(hash, (block, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, hash);
+        self.evict();
+    }
+
+    /// Number of cached blocks
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    fn evict(&mut self) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+        while self.blocks.len() > capacity {
+            match self.recency.pop_first() {
+                Some((_, hash)) => {
+                    self.blocks.remove(&hash);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::BlockHeader;
+    use crate::validation::Transaction;
+    use crate::crypto::hash_bytes;
+
+    fn block(tag: &[u8]) -> Block {
+        Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(tag), 1234567890, 0x207fffff, 0),
+            vec![Transaction::coinbase(1, hash_bytes(tag))],
+        )
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let (a, b, c) = (block(b"a"), block(b"b"), block(b"c"));
+        let mut cache = BlockCache::new(2);
+
+        cache.insert(a.clone());
+        cache.insert(b.clone());
+        assert!(cache.get(&a.hash()).is_some()); // `b` is now the oldest
+        cache.insert(c.clone());
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&a.hash()).is_some());
+        assert!(cache.get(&b.hash()).is_none());
+        assert!(cache.get(&c.hash()).is_some());
+    }
+
+    #[test]
+    fn test_unbounded_until_capacity_set() {
+        let mut cache = BlockCache::unbounded();
+        for i in 0..10u8 {
+            cache.insert(block(&[i]));
+        }
+        assert_eq!(cache.len(), 10);
+
+        cache.set_capacity(3);
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&block(&[9]).hash()).is_some());
+        assert!(cache.get(&block(&[0]).hash()).is_none());
+    }
+}
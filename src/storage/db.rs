@@ -2,10 +2,15 @@
 //! 
 //! Handles saving and loading chain state to disk.
 
-use sled::{Db, Tree};
-use crate::consensus::Block;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, Transactional, Tree};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use crate::consensus::{Block, BlockHeader};
 use crate::crypto::Hash;
-use crate::storage::{UTXOSet, UTXO, UTXOKey};
+use crate::storage::{NonceSet, UTXOSet, UTXO, UTXOKey};
+use crate::validation::Transaction;
+use crate::mempool::FeeEstimator;
 use std::path::Path;
 
 /// Database wrapper
@@ -15,37 +20,640 @@ pub struct BlockChainDB {
     blocks_tree: Tree,
     utxos_tree: Tree,
     metadata_tree: Tree,
+    undo_tree: Tree,
+    /// Main chain height -> block hash
+    heights_tree: Tree,
+    /// Block hash -> index record, for every stored block
+    index_tree: Tree,
+    /// Mempool snapshot written at shutdown: position -> (transaction, arrival time)
+    mempool_tree: Tree,
+    /// Sender pubkey hash -> next confirmed nonce (absent means 0)
+    nonces_tree: Tree,
+}
+
+/// Per-block undo record
+/// 
+/// Everything needed to disconnect a block after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockUndo {
+    /// Outputs spent by the block, in spend order
+    pub spent_utxos: Vec<(UTXOKey, UTXO)>,
+    /// Total RH issued through mining after this block
+    pub total_issued: u64,
+}
+
+/// Validation status of a stored block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlockStatus {
+    /// Header validated, body not downloaded yet
+    HeaderOnly,
+    /// Passed the context-free checks and may become part of the active chain
+    Valid,
+    /// Failed full validation and must never become part of the active chain
+    Failed,
+}
+
+/// Per-block index record
+/// 
+/// Enough to rebuild the in-memory header index without reading bodies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockIndexRecord {
+    pub height: u64,
+    pub header: BlockHeader,
+    pub status: BlockStatus,
 }
 
 const TIP_KEY: &str = "tip_hash";
 const HEIGHT_KEY: &str = "height";
 const TOTAL_ISSUED_KEY: &str = "total_issued";
+/// Present once stored outputs carry the coinbase flag
+const UTXO_FORMAT_KEY: &str = "utxo_format";
+const UTXO_FORMAT_VERSION: u8 = 1;
+const FEE_ESTIMATES_KEY: &str = "fee_estimates";
+/// Present once the nonce tree covers the active chain
+const NONCE_INDEX_KEY: &str = "nonce_index";
+
+/// Output layout written before outputs carried the coinbase flag
+#[derive(Deserialize)]
+struct LegacyUtxo {
+    amount: u64,
+    pubkey_hash: Hash,
+    height: u64,
+}
+
+impl LegacyUtxo {
+    fn upgrade(self, is_coinbase: bool) -> UTXO {
+        UTXO {
+            amount: self.amount,
+            pubkey_hash: self.pubkey_hash,
+            height: self.height,
+            is_coinbase,
+        }
+    }
+}
+
+/// Undo layout written before outputs carried the coinbase flag
+#[derive(Deserialize)]
+struct LegacyBlockUndo {
+    spent_utxos: Vec<(UTXOKey, LegacyUtxo)>,
+    total_issued: u64,
+}
 
 impl BlockChainDB {
     /// Open or create the database
     pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let db = sled::open(path)?;
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Open a throwaway database that is deleted when dropped (tests, tooling)
+    pub fn open_temporary() -> std::io::Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: Db) -> std::io::Result<Self> {
         let blocks_tree = db.open_tree("blocks")?;
         let utxos_tree = db.open_tree("utxos")?;
         let metadata_tree = db.open_tree("metadata")?;
+        let undo_tree = db.open_tree("undo")?;
+        let heights_tree = db.open_tree("heights")?;
+        let index_tree = db.open_tree("block_index")?;
+        let mempool_tree = db.open_tree("mempool")?;
+        let nonces_tree = db.open_tree("nonces")?;
+
+        // A database without a tip has no records in an older layout
+        if metadata_tree.get(TIP_KEY)?.is_none() {
+            metadata_tree.insert(UTXO_FORMAT_KEY, &[UTXO_FORMAT_VERSION])?;
+            metadata_tree.insert(NONCE_INDEX_KEY, &[1])?;
+        }
 
         Ok(Self {
             db,
             blocks_tree,
             utxos_tree,
             metadata_tree,
+            undo_tree,
+            heights_tree,
+            index_tree,
+            mempool_tree,
+            nonces_tree,
         })
     }
 
-    /// Save a block
-    pub fn save_block(&self, block: &Block) -> std::io::Result<()> {
-        let key = block.hash().0;
-        let value = bincode::serialize(block).unwrap();
-        self.blocks_tree.insert(key, value)?;
+    /// Initialize a fresh database with the genesis block
+    /// 
+    /// Writes the block, its outputs, an empty undo record and the tip metadata.
+    pub fn save_genesis(&self, genesis: &Block) -> std::io::Result<()> {
+        self.connect_block(genesis, 0, &BlockUndo::default())
+    }
+
+    /// Connect a block as the new tip
+    /// 
+    /// The block, its index records, its undo record, the UTXO and nonce
+    /// deltas and the tip metadata are written in one transaction, so a crash leaves either
+    /// the old tip or the new one on disk, never a mix.
+    pub fn connect_block(&self, block: &Block, height: u64, undo: &BlockUndo) -> std::io::Result<()> {
+        let hash = block.hash();
+        let block_bytes = bincode::serialize(block).unwrap();
+        let undo_bytes = bincode::serialize(undo).unwrap();
+        let record_bytes = bincode::serialize(&BlockIndexRecord {
+            height,
+            header: block.header.clone(),
+            status: BlockStatus::Valid,
+        }).unwrap();
+        let (spent, created) = block_delta(block, height);
+        let created: Vec<_> = created.iter()
+            .map(|(key, utxo)| (utxo_key(key), bincode::serialize(utxo).unwrap()))
+            .collect();
+        let (_, nonces_after) = nonce_delta(block);
+
+        let trees = (
+            &self.blocks_tree,
+            &self.utxos_tree,
+            &self.metadata_tree,
+            &self.undo_tree,
+            &self.heights_tree,
+            &self.index_tree,
+            &self.nonces_tree,
+        );
+        trees
+            .transaction(|(blocks, utxos, metadata, undos, heights, index, nonces)| {
+                blocks.insert(hash.0.as_ref(), block_bytes.as_slice())?;
+                index.insert(hash.0.as_ref(), record_bytes.as_slice())?;
+                heights.insert(height.to_be_bytes().as_ref(), hash.0.as_ref())?;
+                undos.insert(hash.0.as_ref(), undo_bytes.as_slice())?;
+                for key in &spent {
+                    utxos.remove(utxo_key(key).as_ref())?;
+                }
+                for (key, value) in &created {
+                    utxos.insert(key.as_ref(), value.as_slice())?;
+                }
+                for (sender, nonce) in &nonces_after {
+                    nonces.insert(sender.0.as_ref(), nonce.to_le_bytes().as_ref())?;
+                }
+                metadata.insert(TIP_KEY, hash.0.as_ref())?;
+                metadata.insert(HEIGHT_KEY, height.to_le_bytes().as_ref())?;
+                metadata.insert(TOTAL_ISSUED_KEY, undo.total_issued.to_le_bytes().as_ref())?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(transaction_error)?;
+
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Disconnect the tip block at `height`, making its parent the tip
+    /// 
+    /// Removes the block's outputs, restores the outputs it spent from its
+    /// undo record and rewinds the sender nonces, height index and tip
+    /// metadata in one transaction. The block itself, its index record and its undo record
+    /// stay stored for a later reconnect.
+    pub fn disconnect_block(
+        &self,
+        block: &Block,
+        height: u64,
+        spent_utxos: &[(UTXOKey, UTXO)],
+        prev_total_issued: u64,
+    ) -> std::io::Result<()> {
+        let prev_hash = block.header.prev_hash;
+        let prev_height = height.saturating_sub(1);
+        let (spent, created) = block_delta(block, height);
+        // Outputs both created and spent by the block were never stored
+        let spent: HashSet<UTXOKey> = spent.into_iter().collect();
+        let restored: Vec<_> = spent_utxos.iter()
+            .filter(|(key, _)| spent.contains(key))
+            .map(|(key, utxo)| (utxo_key(key), bincode::serialize(utxo).unwrap()))
+            .collect();
+        let (nonces_before, _) = nonce_delta(block);
+
+        (&self.utxos_tree, &self.metadata_tree, &self.heights_tree, &self.nonces_tree)
+            .transaction(|(utxos, metadata, heights, nonces)| {
+                heights.remove(height.to_be_bytes().as_ref())?;
+                for (key, _) in &created {
+                    utxos.remove(utxo_key(key).as_ref())?;
+                }
+                for (key, value) in &restored {
+                    utxos.insert(key.as_ref(), value.as_slice())?;
+                }
+                for (sender, nonce) in &nonces_before {
+                    match nonce {
+                        0 => nonces.remove(sender.0.as_ref())?,
+                        n => nonces.insert(sender.0.as_ref(), n.to_le_bytes().as_ref())?,
+                    };
+                }
+                metadata.insert(TIP_KEY, prev_hash.0.as_ref())?;
+                metadata.insert(HEIGHT_KEY, prev_height.to_le_bytes().as_ref())?;
+                metadata.insert(TOTAL_ISSUED_KEY, prev_total_issued.to_le_bytes().as_ref())?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(transaction_error)?;
+
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Startup consistency check
+    /// 
+    /// Databases written before block connects were atomic can hold a torn
+    /// write: a UTXO delta that is only partly applied relative to the tip
+    /// metadata. Re-applies the tip's own delta and rolls back any partial
+    /// delta of a child of the tip. Returns whether anything was repaired.
+    pub fn check_consistency(&self) -> std::io::Result<bool> {
+        let Some((tip_hash, height, total_issued)) = self.load_metadata()? else {
+            return Ok(false);
+        };
+        let tip = self.get_block(&tip_hash)?.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Tip block missing from DB")
+        })?;
+        // Decided before any repair, which writes index records of its own
+        let indexed = self.has_index()?;
+        let mut repaired = false;
+
+        // Tip metadata written but its UTXO delta incomplete
+        let (spent, created) = block_delta(&tip, height);
+        let (applied, total) = self.count_applied(&spent, &created)?;
+        if applied < total {
+            let undo = match self.get_undo(&tip_hash)? {
+                Some(undo) => undo,
+                None if spent.is_empty() => BlockUndo { spent_utxos: Vec::new(), total_issued },
+                None => return Err(unrepairable(&tip_hash)),
+            };
+            self.connect_block(&tip, height, &undo)?;
+            repaired = true;
+        }
+
+        // A child's UTXO delta partly applied without the tip moving
+        for child_hash in self.connecting_children(&tip_hash, height, indexed)? {
+            let Some(block) = self.get_block(&child_hash)? else {
+                continue;
+            };
+            let (spent, created) = block_delta(&block, height + 1);
+            if self.count_applied(&spent, &created)?.0 == 0 {
+                continue;
+            }
+
+            let undo = match self.get_undo(&child_hash)? {
+                Some(undo) => undo,
+                None if spent.is_empty() => BlockUndo::default(),
+                None => return Err(unrepairable(&child_hash)),
+            };
+            self.disconnect_block(&block, height + 1, &undo.spent_utxos, total_issued)?;
+            repaired = true;
+        }
+
+        Ok(repaired)
+    }
+
+    /// Children of the tip that may have been interrupted while connecting
+    /// 
+    /// With a block index, that is only a valid child the height index
+    /// already lists above the tip. Older databases wrote the undo record
+    /// before the UTXO delta, so there it is any child with an undo record.
+    /// Blocks that were only stored, or failed validation, never qualify.
+    fn connecting_children(&self, tip_hash: &Hash, height: u64, indexed: bool) -> std::io::Result<Vec<Hash>> {
+        let child_height = height + 1;
+        if indexed {
+            let Some(bytes) = self.heights_tree.get(child_height.to_be_bytes())? else {
+                return Ok(Vec::new());
+            };
+            let Ok(hash_bytes) = <[u8; 32]>::try_from(bytes.as_ref()) else {
+                return Ok(Vec::new());
+            };
+            let hash = Hash(hash_bytes);
+            let connecting = match self.index_tree.get(hash.0)? {
+                Some(record) => {
+                    let record: BlockIndexRecord = bincode::deserialize(&record).map_err(|e| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                    })?;
+                    record.status == BlockStatus::Valid
+                        && record.height == child_height
+                        && record.header.prev_hash == *tip_hash
+                }
+                None => false,
+            };
+            return Ok(if connecting { vec![hash] } else { Vec::new() });
+        }
+
+        let mut children = Vec::new();
+        for key in self.undo_tree.iter().keys() {
+            let Ok(hash_bytes) = <[u8; 32]>::try_from(key?.as_ref()) else {
+                continue;
+            };
+            let hash = Hash(hash_bytes);
+            if self.get_block(&hash)?.is_some_and(|block| block.header.prev_hash == *tip_hash) {
+                children.push(hash);
+            }
+        }
+        Ok(children)
+    }
+
+    /// How many entries of a UTXO delta are reflected on disk, out of how many
+    /// 
+    /// Created outputs are compared by value, including their height, so an
+    /// identical coinbase in a neighbouring block is not mistaken for this one.
+    fn count_applied(&self, spent: &[UTXOKey], created: &[(UTXOKey, UTXO)]) -> std::io::Result<(usize, usize)> {
+        let mut applied = 0;
+        for key in spent {
+            if self.get_utxo(key)?.is_none() {
+                applied += 1;
+            }
+        }
+        for (key, utxo) in created {
+            if self.get_utxo(key)?.as_ref() == Some(utxo) {
+                applied += 1;
+            }
+        }
+        Ok((applied, spent.len() + created.len()))
+    }
+
+    /// Save a block that is not (yet) on the active chain, with its index record
+    pub fn save_block(&self, block: &Block, height: u64) -> std::io::Result<()> {
+        let hash = block.hash();
+        let block_bytes = bincode::serialize(block).unwrap();
+        let record_bytes = bincode::serialize(&BlockIndexRecord {
+            height,
+            header: block.header.clone(),
+            status: BlockStatus::Valid,
+        }).unwrap();
+
+        (&self.blocks_tree, &self.index_tree)
+            .transaction(|(blocks, index)| {
+                blocks.insert(hash.0.as_ref(), block_bytes.as_slice())?;
+                index.insert(hash.0.as_ref(), record_bytes.as_slice())?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(transaction_error)?;
+
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Save the index record of a header whose body has not arrived yet
+    /// 
+    /// Not flushed explicitly: headers arrive thousands at a time, and one
+    /// lost in a crash is simply downloaded again.
+    pub fn save_header(&self, header: &BlockHeader, height: u64) -> std::io::Result<()> {
+        let record = BlockIndexRecord {
+            height,
+            header: header.clone(),
+            status: BlockStatus::HeaderOnly,
+        };
+        self.index_tree.insert(header.hash().0, bincode::serialize(&record).unwrap())?;
+        Ok(())
+    }
+
+    /// Update the validation status of an indexed block
+    pub fn set_block_status(&self, hash: &Hash, status: BlockStatus) -> std::io::Result<()> {
+        let Some(bytes) = self.index_tree.get(hash.0)? else {
+            return Ok(());
+        };
+        let mut record: BlockIndexRecord = bincode::deserialize(&bytes).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        })?;
+        record.status = status;
+        self.index_tree.insert(hash.0, bincode::serialize(&record).unwrap())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Load every index record
+    pub fn load_block_index(&self) -> std::io::Result<Vec<(Hash, BlockIndexRecord)>> {
+        let mut records = Vec::with_capacity(self.index_tree.len());
+        for item in self.index_tree.iter() {
+            let (key, value) = item?;
+            if key.len() != 32 { continue; }
+
+            let mut hash_bytes = [0u8; 32];
+            hash_bytes.copy_from_slice(&key);
+            let record = bincode::deserialize(&value).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+            records.push((Hash(hash_bytes), record));
+        }
+        Ok(records)
+    }
+
+    /// Load the main chain height -> hash index
+    pub fn load_height_index(&self) -> std::io::Result<HashMap<u64, Hash>> {
+        let mut heights = HashMap::with_capacity(self.heights_tree.len());
+        for item in self.heights_tree.iter() {
+            let (key, value) = item?;
+            if key.len() != 8 || value.len() != 32 { continue; }
+
+            let mut h_bytes = [0u8; 8];
+            h_bytes.copy_from_slice(&key);
+            let mut hash_bytes = [0u8; 32];
+            hash_bytes.copy_from_slice(&value);
+            heights.insert(u64::from_be_bytes(h_bytes), Hash(hash_bytes));
+        }
+        Ok(heights)
+    }
+
+    /// Add the coinbase flag to outputs stored before it existed
+    /// 
+    /// Rewrites the UTXO set and the outputs inside undo records in one
+    /// transaction. An output is flagged if its transaction is the coinbase
+    /// of any stored block. Returns whether anything was upgraded.
+    pub fn upgrade_utxo_format(&self) -> std::io::Result<bool> {
+        if self.metadata_tree.contains_key(UTXO_FORMAT_KEY)? {
+            return Ok(false);
+        }
+
+        let mut coinbases = HashSet::new();
+        for item in self.blocks_tree.iter() {
+            let (_, bytes) = item?;
+            let block: Block = bincode::deserialize(&bytes).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+            if let Some(coinbase) = block.transactions.first() {
+                coinbases.insert(coinbase.hash());
+            }
+        }
+
+        let mut utxos = Vec::new();
+        for item in self.utxos_tree.iter() {
+            let (key, value) = item?;
+            if key.len() != 36 { continue; }
+
+            let legacy: LegacyUtxo = bincode::deserialize(&value).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+            let mut tx_hash = [0u8; 32];
+            tx_hash.copy_from_slice(&key[..32]);
+            let is_coinbase = coinbases.contains(&Hash(tx_hash));
+            utxos.push((key, bincode::serialize(&legacy.upgrade(is_coinbase)).unwrap()));
+        }
+
+        let mut undos = Vec::new();
+        for item in self.undo_tree.iter() {
+            let (key, value) = item?;
+            let legacy: LegacyBlockUndo = bincode::deserialize(&value).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+            let undo = BlockUndo {
+                spent_utxos: legacy.spent_utxos.into_iter()
+                    .map(|(key, utxo)| (key, utxo.upgrade(coinbases.contains(&key.0))))
+                    .collect(),
+                total_issued: legacy.total_issued,
+            };
+            undos.push((key, bincode::serialize(&undo).unwrap()));
+        }
+
+        (&self.utxos_tree, &self.undo_tree, &self.metadata_tree)
+            .transaction(|(utxos_tx, undo_tx, meta_tx)| {
+                for (key, value) in &utxos {
+                    utxos_tx.insert(key.as_ref(), value.as_slice())?;
+                }
+                for (key, value) in &undos {
+                    undo_tx.insert(key.as_ref(), value.as_slice())?;
+                }
+                meta_tx.insert(UTXO_FORMAT_KEY, &[UTXO_FORMAT_VERSION])?;
+                Ok::<(), ConflictableTransactionError>(())
+            })
+            .map_err(transaction_error)?;
+
+        self.db.flush()?;
+        Ok(!utxos.is_empty() || !undos.is_empty())
+    }
+
+    /// Check if the index trees have been built
+    /// 
+    /// Every indexed database lists genesis in the height index; a repair
+    /// of an older database may index its tip, but never genesis alone.
+    fn has_index(&self) -> std::io::Result<bool> {
+        Ok(self.heights_tree.contains_key(0u64.to_be_bytes())?)
+    }
+
+    /// Build the index trees for a database written before they existed
+    /// 
+    /// Walks the active chain back from the tip once. Side-chain blocks of
+    /// such databases stay unindexed. Returns whether anything was built.
+    pub fn reindex(&self) -> std::io::Result<bool> {
+        if self.has_index()? {
+            return Ok(false);
+        }
+        let Some((tip_hash, height, _)) = self.load_metadata()? else {
+            return Ok(false);
+        };
+
+        let mut hash = tip_hash;
+        for h in (0..=height).rev() {
+            let block = self.get_block(&hash)?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Block {} missing from DB", hash))
+            })?;
+            let record = BlockIndexRecord {
+                height: h,
+                header: block.header.clone(),
+                status: BlockStatus::Valid,
+            };
+            self.heights_tree.insert(h.to_be_bytes(), hash.0.as_ref())?;
+            self.index_tree.insert(hash.0, bincode::serialize(&record).unwrap())?;
+            hash = block.header.prev_hash;
+        }
+
+        self.db.flush()?;
+        Ok(true)
+    }
+
+    /// Build the nonce tree for a database written before it existed
+    ///
+    /// Replays the sender nonces of the active chain in height order.
+    /// Returns whether anything was built.
+    pub fn index_nonces(&self) -> std::io::Result<bool> {
+        if self.metadata_tree.contains_key(NONCE_INDEX_KEY)? {
+            return Ok(false);
+        }
+
+        let mut nonces = HashMap::new();
+        for item in self.heights_tree.iter() {
+            let (_, hash) = item?;
+            let mut hash_bytes = [0u8; 32];
+            hash_bytes.copy_from_slice(&hash);
+            let block = self.get_block(&Hash(hash_bytes))?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Block {} missing from DB", Hash(hash_bytes)))
+            })?;
+            nonces.extend(nonce_delta(&block).1);
+        }
+
+        let mut batch = sled::Batch::default();
+        for (sender, nonce) in &nonces {
+            batch.insert(sender.0.as_ref(), nonce.to_le_bytes().as_ref());
+        }
+        self.nonces_tree.apply_batch(batch)?;
+        self.metadata_tree.insert(NONCE_INDEX_KEY, &[1])?;
+        self.db.flush()?;
+        Ok(!nonces.is_empty())
+    }
+
+    /// Load the confirmed nonce of every sender
+    pub fn load_nonces(&self) -> std::io::Result<NonceSet> {
+        let mut nonces = NonceSet::new();
+        for item in self.nonces_tree.iter() {
+            let (key, value) = item?;
+            if key.len() != 32 || value.len() != 8 { continue; }
+
+            let mut sender = [0u8; 32];
+            sender.copy_from_slice(&key);
+            let mut nonce = [0u8; 8];
+            nonce.copy_from_slice(&value);
+            nonces.set(Hash(sender), u64::from_le_bytes(nonce));
+        }
+        Ok(nonces)
+    }
+
+    /// Replace the stored mempool snapshot
+    ///
+    /// Entries keep their order, so parents stay ahead of their children.
+    pub fn save_mempool(&self, entries: &[(Transaction, u64)]) -> std::io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for key in self.mempool_tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for (position, entry) in entries.iter().enumerate() {
+            let bytes = bincode::serialize(entry).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            })?;
+            batch.insert(&(position as u64).to_be_bytes(), bytes);
+        }
+        self.mempool_tree.apply_batch(batch)?;
         self.db.flush()?;
         Ok(())
     }
 
+    /// Load the stored mempool snapshot, in saved order
+    pub fn load_mempool(&self) -> std::io::Result<Vec<(Transaction, u64)>> {
+        self.mempool_tree.iter()
+            .values()
+            .map(|bytes| {
+                bincode::deserialize(&bytes?).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })
+            })
+            .collect()
+    }
+
+    /// Store the fee estimator's statistics
+    pub fn save_fee_estimator(&self, estimator: &FeeEstimator) -> std::io::Result<()> {
+        let bytes = bincode::serialize(estimator).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+        })?;
+        self.metadata_tree.insert(FEE_ESTIMATES_KEY, bytes)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Load the fee estimator's statistics, if any were stored
+    pub fn load_fee_estimator(&self) -> std::io::Result<Option<FeeEstimator>> {
+        match self.metadata_tree.get(FEE_ESTIMATES_KEY)? {
+            Some(bytes) => {
+                let estimator = bincode::deserialize(&bytes).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })?;
+                Ok(Some(estimator))
+            },
+            None => Ok(None),
+        }
+    }
+
     /// Get a block by hash
     pub fn get_block(&self, hash: &Hash) -> std::io::Result<Option<Block>> {
         match self.blocks_tree.get(hash.0)? {
@@ -59,28 +667,30 @@ impl BlockChainDB {
         }
     }
 
-    /// Save the UTXO set (differential update)
-    pub fn update_utxos(&self, spent: &[UTXOKey], new_utxos: &[(UTXOKey, UTXO)]) -> std::io::Result<()> {
-        // Remove spent
-        for (tx_hash, index) in spent {
-            let mut key = Vec::with_capacity(36);
-            key.extend_from_slice(&tx_hash.0);
-            key.extend_from_slice(&index.to_le_bytes());
-            self.utxos_tree.remove(key)?;
+    /// Get the undo record for a block
+    pub fn get_undo(&self, hash: &Hash) -> std::io::Result<Option<BlockUndo>> {
+        match self.undo_tree.get(hash.0)? {
+            Some(bytes) => {
+                let undo = bincode::deserialize(&bytes).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })?;
+                Ok(Some(undo))
+            },
+            None => Ok(None),
         }
+    }
 
-        // Add new
-        for ((tx_hash, index), utxo) in new_utxos {
-            let mut key = Vec::with_capacity(36);
-            key.extend_from_slice(&tx_hash.0);
-            key.extend_from_slice(&index.to_le_bytes());
-            
-            let value = bincode::serialize(utxo).unwrap();
-            self.utxos_tree.insert(key, value)?;
+    /// Get a single output from the stored UTXO set
+    pub fn get_utxo(&self, key: &UTXOKey) -> std::io::Result<Option<UTXO>> {
+        match self.utxos_tree.get(utxo_key(key))? {
+            Some(bytes) => {
+                let utxo = bincode::deserialize(&bytes).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+                })?;
+                Ok(Some(utxo))
+            },
+            None => Ok(None),
         }
-
-        self.db.flush()?;
-        Ok(())
     }
 
     /// Load the entire UTXO set
@@ -110,15 +720,6 @@ impl BlockChainDB {
         Ok(set)
     }
 
-    /// Update chain metadata
-    pub fn update_metadata(&self, tip: &Hash, height: u64, total_issued: u64) -> std::io::Result<()> {
-        self.metadata_tree.insert(TIP_KEY, tip.0.as_ref())?;
-        self.metadata_tree.insert(HEIGHT_KEY, height.to_le_bytes().as_ref())?;
-        self.metadata_tree.insert(TOTAL_ISSUED_KEY, total_issued.to_le_bytes().as_ref())?;
-        self.db.flush()?;
-        Ok(())
-    }
-
     /// Load chain metadata
     pub fn load_metadata(&self) -> std::io::Result<Option<(Hash, u64, u64)>> {
         let tip_bytes = self.metadata_tree.get(TIP_KEY)?;
@@ -145,3 +746,248 @@ impl BlockChainDB {
         }
     }
 }
+
+/// Storage key of an output: tx hash followed by the little-endian index
+fn utxo_key((tx_hash, index): &UTXOKey) -> [u8; 36] {
+    let mut key = [0u8; 36];
+    key[..32].copy_from_slice(&tx_hash.0);
+    key[32..].copy_from_slice(&index.to_le_bytes());
+    key
+}
+
+/// UTXO changes made by connecting `block` at `height`
+/// 
+/// Returns the outputs spent and the outputs created. Outputs created and
+/// spent inside the same block appear in neither list.
+fn block_delta(block: &Block, height: u64) -> (Vec<UTXOKey>, Vec<(UTXOKey, UTXO)>) {
+    let mut spent = Vec::new();
+    let mut created = Vec::new();
+
+    for tx in &block.transactions {
+        if !tx.is_coinbase() {
+            spent.extend(tx.inputs.iter().map(|input| (input.prev_tx_hash, input.output_index)));
+        }
+
+        let tx_hash = tx.hash();
+        for (i, output) in tx.outputs.iter().enumerate() {
+            created.push((
+                (tx_hash, i as u32),
+                UTXO {
+                    amount: output.amount,
+                    pubkey_hash: output.pubkey_hash,
+                    height,
+                    is_coinbase: tx.is_coinbase(),
+                }
+            ));
+        }
+    }
+
+    let spent_set: HashSet<UTXOKey> = spent.iter().copied().collect();
+    let created_set: HashSet<UTXOKey> = created.iter().map(|(key, _)| *key).collect();
+    spent.retain(|key| !created_set.contains(key));
+    created.retain(|(key, _)| !spent_set.contains(key));
+
+    (spent, created)
+}
+
+/// Sender nonces changed by connecting `block`
+///
+/// Returns each sender's next nonce before the block (its first nonce in
+/// the block) and after it (its last nonce plus one).
+fn nonce_delta(block: &Block) -> (HashMap<Hash, u64>, HashMap<Hash, u64>) {
+    let mut before = HashMap::new();
+    let mut after = HashMap::new();
+    for tx in &block.transactions {
+        if let Some(sender) = tx.sender() {
+            before.entry(sender).or_insert(tx.nonce);
+            after.insert(sender, tx.nonce.saturating_add(1));
+        }
+    }
+    (before, after)
+}
+
+fn unrepairable(hash: &Hash) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Torn write in block {} cannot be repaired without undo data", hash),
+    )
+}
+
+fn transaction_error(e: TransactionError) -> std::io::Error {
+    match e {
+        TransactionError::Storage(e) | TransactionError::Abort(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::BlockHeader;
+    use crate::crypto::{hash_bytes, PublicKey, SchnorrSignature};
+    use crate::validation::{Transaction, TxInput, TxOutput};
+
+    fn block(prev_hash: Hash, transactions: Vec<Transaction>) -> Block {
+        Block::new(
+            BlockHeader::new(1, 0x01, prev_hash, hash_bytes(b"merkle"), 1234567890, 0x207fffff, 0),
+            transactions,
+        )
+    }
+
+    /// Genesis paying `owner`, plus a child spending that output
+    fn genesis_and_spend() -> (Block, Block) {
+        let founder_tx = Transaction::coinbase(1000, hash_bytes(b"owner"));
+        let spend = Transaction::new(
+            vec![TxInput {
+                prev_tx_hash: founder_tx.hash(),
+                output_index: 0,
+                signature: SchnorrSignature([0u8; 64]),
+                public_key: PublicKey([0u8; 32]),
+            }],
+            vec![TxOutput { amount: 900, pubkey_hash: hash_bytes(b"bob") }],
+        );
+        let genesis = block(Hash::zero(), vec![founder_tx]);
+        let child = block(genesis.hash(), vec![Transaction::coinbase(50, hash_bytes(b"miner")), spend]);
+        (genesis, child)
+    }
+
+    fn founder_utxo(genesis: &Block) -> (UTXOKey, UTXO) {
+        let utxo = UTXO { amount: 1000, pubkey_hash: hash_bytes(b"owner"), height: 0, is_coinbase: true };
+        ((genesis.transactions[0].hash(), 0), utxo)
+    }
+
+    #[test]
+    fn test_connect_disconnect_roundtrip() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder.clone())], total_issued: 50 };
+        db.connect_block(&child, 1, &undo).unwrap();
+        assert_eq!(db.load_metadata().unwrap(), Some((child.hash(), 1, 50)));
+        assert!(db.get_utxo(&founder_key).unwrap().is_none());
+        assert!(db.get_utxo(&(child.transactions[1].hash(), 0)).unwrap().is_some());
+        assert!(!db.check_consistency().unwrap());
+
+        db.disconnect_block(&child, 1, &undo.spent_utxos, 0).unwrap();
+        assert_eq!(db.load_metadata().unwrap(), Some((genesis.hash(), 0, 0)));
+        assert_eq!(db.get_utxo(&founder_key).unwrap(), Some(founder));
+        assert!(db.get_utxo(&(child.transactions[1].hash(), 0)).unwrap().is_none());
+        assert!(db.get_block(&child.hash()).unwrap().is_some());
+        assert!(!db.check_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_repairs_partial_child_delta() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+
+        // Database from before the block index
+        db.index_tree.clear().unwrap();
+        db.heights_tree.clear().unwrap();
+
+        // Legacy write order: block and undo stored, crash midway through the UTXO delta
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder.clone())], total_issued: 50 };
+        let (_, created) = block_delta(&child, 1);
+        db.blocks_tree.insert(child.hash().0, bincode::serialize(&child).unwrap()).unwrap();
+        db.undo_tree.insert(child.hash().0, bincode::serialize(&undo).unwrap()).unwrap();
+        db.utxos_tree.remove(utxo_key(&founder_key)).unwrap();
+        db.utxos_tree.insert(utxo_key(&created[0].0), bincode::serialize(&created[0].1).unwrap()).unwrap();
+
+        assert!(db.check_consistency().unwrap());
+        assert_eq!(db.load_metadata().unwrap(), Some((genesis.hash(), 0, 0)));
+        assert_eq!(db.get_utxo(&founder_key).unwrap(), Some(founder));
+        assert!(db.get_utxo(&created[0].0).unwrap().is_none());
+        assert!(!db.check_consistency().unwrap());
+    }
+
+    #[test]
+    fn test_repairs_partial_indexed_child_delta() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+
+        // Child listed above the tip in the height index, tip never moved
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder.clone())], total_issued: 50 };
+        db.save_block(&child, 1).unwrap();
+        db.undo_tree.insert(child.hash().0, bincode::serialize(&undo).unwrap()).unwrap();
+        db.heights_tree.insert(1u64.to_be_bytes(), child.hash().0.as_ref()).unwrap();
+        db.utxos_tree.insert(utxo_key(&(child.transactions[1].hash(), 0)), bincode::serialize(&UTXO {
+            amount: 900, pubkey_hash: hash_bytes(b"bob"), height: 1, is_coinbase: false,
+        }).unwrap()).unwrap();
+
+        assert!(db.check_consistency().unwrap());
+        assert_eq!(db.load_metadata().unwrap(), Some((genesis.hash(), 0, 0)));
+        assert_eq!(db.get_utxo(&founder_key).unwrap(), Some(founder));
+        assert!(db.get_utxo(&(child.transactions[1].hash(), 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_stored_children_are_not_repaired() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder)], total_issued: 50 };
+        db.connect_block(&child, 1, &undo).unwrap();
+
+        // A child of the tip spending the same output again, stored but never connected
+        let double_spend = block(child.hash(), child.transactions.clone());
+        db.save_block(&double_spend, 2).unwrap();
+        assert!(!db.check_consistency().unwrap());
+        db.set_block_status(&double_spend.hash(), BlockStatus::Failed).unwrap();
+        assert!(!db.check_consistency().unwrap());
+        assert_eq!(db.load_metadata().unwrap(), Some((child.hash(), 1, 50)));
+    }
+
+    #[test]
+    fn test_repairs_incomplete_tip_delta() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder.clone())], total_issued: 50 };
+        db.connect_block(&child, 1, &undo).unwrap();
+
+        // Tip moved but the spent output and a new output were never written
+        db.utxos_tree.insert(utxo_key(&founder_key), bincode::serialize(&founder).unwrap()).unwrap();
+        db.utxos_tree.remove(utxo_key(&(child.transactions[1].hash(), 0))).unwrap();
+
+        assert!(db.check_consistency().unwrap());
+        assert_eq!(db.load_metadata().unwrap(), Some((child.hash(), 1, 50)));
+        assert!(db.get_utxo(&founder_key).unwrap().is_none());
+        assert!(db.get_utxo(&(child.transactions[1].hash(), 0)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_upgrades_legacy_utxo_format() {
+        let (genesis, child) = genesis_and_spend();
+        let (founder_key, founder) = founder_utxo(&genesis);
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let undo = BlockUndo { spent_utxos: vec![(founder_key, founder.clone())], total_issued: 50 };
+        db.connect_block(&child, 1, &undo).unwrap();
+
+        // Rewrite the records in the layout without the coinbase flag
+        let legacy = |utxo: &UTXO| (utxo.amount, utxo.pubkey_hash, utxo.height);
+        let spend_key = (child.transactions[1].hash(), 0);
+        let spend_output = db.get_utxo(&spend_key).unwrap().unwrap();
+        db.utxos_tree.insert(utxo_key(&spend_key), bincode::serialize(&legacy(&spend_output)).unwrap()).unwrap();
+        let reward_key = (child.transactions[0].hash(), 0);
+        let reward = db.get_utxo(&reward_key).unwrap().unwrap();
+        db.utxos_tree.insert(utxo_key(&reward_key), bincode::serialize(&legacy(&reward)).unwrap()).unwrap();
+        let legacy_undo = (vec![(founder_key, legacy(&founder))], 50u64);
+        db.undo_tree.insert(child.hash().0, bincode::serialize(&legacy_undo).unwrap()).unwrap();
+        db.metadata_tree.remove(UTXO_FORMAT_KEY).unwrap();
+
+        assert!(db.upgrade_utxo_format().unwrap());
+        assert!(!db.upgrade_utxo_format().unwrap());
+        assert_eq!(db.get_utxo(&spend_key).unwrap(), Some(spend_output));
+        assert_eq!(db.get_utxo(&reward_key).unwrap(), Some(reward));
+        assert_eq!(db.get_undo(&child.hash()).unwrap().unwrap().spent_utxos, vec![(founder_key, founder)]);
+    }
+}
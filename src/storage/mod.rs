@@ -1,8 +1,12 @@
 //! Storage module - UTXO set and chain state management
 
 mod utxo;
+mod nonce;
 mod state;
+mod block_cache;
 pub mod db;
 
 pub use utxo::*;
+pub use nonce::*;
 pub use state::*;
+pub use block_cache::*;
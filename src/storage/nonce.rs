@@ -0,0 +1,115 @@
+//! Confirmed sender nonces
+//!
+//! Consensus rule: every non-coinbase transaction carries the next nonce of
+//! its sender (see `Transaction::sender`). Nonces start at 0 and rise by one
+//! with each confirmed transaction, so a signed transaction can be mined at
+//! most once and a sender's transactions are mined in the order they were
+//! numbered.
+
+use std::collections::HashMap;
+use crate::consensus::Block;
+use crate::crypto::Hash;
+use crate::validation::Transaction;
+
+/// Next expected nonce of every sender with confirmed transactions
+#[derive(Debug, Clone, Default)]
+pub struct NonceSet {
+    /// Map from sender pubkey hash to its next nonce (absent means 0)
+    next: HashMap<Hash, u64>,
+}
+
+impl NonceSet {
+    /// Create a new empty nonce set
+    pub fn new() -> Self {
+        Self {
+            next: HashMap::new(),
+        }
+    }
+
+    /// Next nonce `sender` must use
+    pub fn get(&self, sender: &Hash) -> u64 {
+        self.next.get(sender).copied().unwrap_or(0)
+    }
+
+    /// Set the next nonce of `sender`
+    pub fn set(&mut self, sender: Hash, nonce: u64) {
+        if nonce == 0 {
+            self.next.remove(&sender);
+        } else {
+            self.next.insert(sender, nonce);
+        }
+    }
+
+    /// Number of senders with confirmed transactions
+    pub fn len(&self) -> usize {
+        self.next.len()
+    }
+
+    /// Check if no sender has confirmed transactions
+    pub fn is_empty(&self) -> bool {
+        self.next.is_empty()
+    }
+
+    /// Advance the sender of a confirmed transaction past its nonce
+    pub fn apply_transaction(&mut self, tx: &Transaction) {
+        if let Some(sender) = tx.sender() {
+            self.set(sender, tx.nonce.saturating_add(1));
+        }
+    }
+
+    /// Undo `apply_transaction` for every transaction of a disconnected block
+    ///
+    /// Nonces within a block are consecutive per sender, so each sender goes
+    /// back to the nonce of its first transaction in the block.
+    pub fn revert_block(&mut self, block: &Block) {
+        for tx in block.transactions.iter().rev() {
+            if let Some(sender) = tx.sender() {
+                self.set(sender, tx.nonce);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::BlockHeader;
+    use crate::crypto::{hash_bytes, PublicKey, SchnorrSignature};
+    use crate::validation::{TxInput, TxOutput};
+
+    fn tx(key: u8, nonce: u64) -> Transaction {
+        Transaction::new_with_nonce(
+            vec![TxInput {
+                prev_tx_hash: hash_bytes(&[key, nonce as u8]),
+                output_index: 0,
+                signature: SchnorrSignature([0u8; 64]),
+                public_key: PublicKey([key; 32]),
+            }],
+            vec![TxOutput { amount: 1, pubkey_hash: Hash::zero() }],
+            nonce,
+        )
+    }
+
+    #[test]
+    fn test_apply_and_revert_block() {
+        let mut nonces = NonceSet::new();
+        let alice = tx(1, 0).sender().unwrap();
+        let bob = tx(2, 0).sender().unwrap();
+        nonces.apply_transaction(&tx(1, 0));
+        assert_eq!(nonces.get(&alice), 1);
+
+        let block = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), Hash::zero(), 0, 0x207fffff, 0),
+            vec![Transaction::coinbase(1, Hash::zero()), tx(1, 1), tx(2, 0), tx(1, 2)],
+        );
+        for tx in &block.transactions {
+            nonces.apply_transaction(tx);
+        }
+        assert_eq!((nonces.get(&alice), nonces.get(&bob)), (3, 1));
+        assert_eq!(nonces.len(), 2);
+
+        nonces.revert_block(&block);
+        assert_eq!((nonces.get(&alice), nonces.get(&bob)), (1, 0));
+        assert_eq!(nonces.len(), 1);
+    }
+}
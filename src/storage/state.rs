@@ -4,48 +4,74 @@
 //! current height, total issued supply, and difficulty.
 
 use std::collections::{HashMap, HashSet};
-use crate::consensus::{Block, BlockHeader};
-use crate::crypto::Hash;
+use std::sync::{Arc, Mutex};
+use crate::consensus::{
+    Block, BlockHeader, ValidationError, calculate_work, check_block, check_checkpoint, check_header,
+    expected_difficulty, median_time, validate_block,
+};
+use crate::crypto::{Hash, SignatureCache};
 use crate::constants::PUBLIC_ISSUANCE;
+use crate::mempool::{FeeEstimator, FeeRate, Mempool};
 use crate::validation::Transaction;
-use super::{UTXOSet, UTXO, UTXOKey};
-use super::db::BlockChainDB;
+use crate::validation::policy::{check_standard, RejectCode, TxRejection};
+use super::{NonceSet, UTXOSet, UTXO, UTXOKey};
+use super::block_cache::BlockCache;
+use super::db::{BlockChainDB, BlockStatus, BlockUndo};
 
 /// Maximum mempool size in bytes (300 MB - production standard)
 const MAX_MEMPOOL_BYTES: u64 = 300 * 1024 * 1024;
 
+/// Number of full blocks kept in memory when backed by a database
+const BLOCK_CACHE_CAPACITY: usize = 128;
+
 /// Minimum relay fee in satoshis per byte (prevents dust spam)
 const MIN_RELAY_FEE: u64 = 1; // 1 sat/byte
 
+/// Mempool transactions older than this are dropped (2 weeks)
+const MEMPOOL_EXPIRY: u64 = 14 * 24 * 3600;
+
+/// Current Unix time in seconds
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
 /// Complete chain state
 #[derive(Debug)]
 pub struct ChainState {
     /// Current UTXO set
     pub utxo_set: UTXOSet,
+    /// Next nonce of every sender, as of the tip
+    pub nonces: NonceSet,
     /// Current block height
     pub height: u64,
     /// Hash of the current tip
     pub tip_hash: Hash,
     /// Total RH issued through mining (excludes founder allocation)
     pub total_issued: u64,
-    /// Current difficulty target
+    /// Difficulty target required for the next block
+    /// Recomputed every DIFFICULTY_ADJUSTMENT_INTERVAL blocks
     pub difficulty: u32,
-    /// Block index: hash -> Entry
+    /// Block index: hash -> Entry (headers only)
     block_index: HashMap<Hash, BlockIndexEntry>,
-    /// Full block storage (hash -> block)
-    full_blocks: HashMap<Hash, Block>,
+    /// Recently used block bodies; the rest are read from the database
+    block_cache: Mutex<BlockCache>,
     /// Height to Hash map (Main chain ONLY)
     height_to_hash: HashMap<u64, Hash>,
+    /// Hashes of the most-work valid header chain, by height
+    /// May run ahead of the active chain while bodies are downloaded
+    header_chain: Vec<Hash>,
     /// Unconfirmed transactions
-    pub mempool: HashMap<Hash, Transaction>,
+    pub mempool: Mempool,
+    /// Confirmation times of mempool transactions, by fee rate
+    pub fee_estimator: FeeEstimator,
+    /// Signatures already verified, shared with the network layer so blocks
+    /// can be checked before the chain state is locked
+    pub sig_cache: Arc<SignatureCache>,
     /// Database connection
     pub db: Option<BlockChainDB>,
-    /// Next expected nonce per sender (pubkey_hash -> nonce)
-    /// Used to enforce sequential nonce ordering and allow tx replacement
-    next_nonce: HashMap<Hash, u64>,
-    /// Timestamps of last 11 blocks for median time calculation
-    /// Used to validate new block timestamps
-    recent_block_timestamps: std::collections::VecDeque<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -54,6 +80,12 @@ pub struct BlockIndexEntry {
     pub height: u64,
     pub total_issued: u64,
     pub undo_data: Vec<(UTXOKey, UTXO)>,
+    /// Cumulative work of the chain ending at this block
+    pub chain_work: u128,
+    /// Block failed full validation and must never become part of the active chain
+    pub failed: bool,
+    /// Block body is stored, not just the header
+    pub has_data: bool,
 }
 
 impl ChainState {
@@ -61,22 +93,25 @@ impl ChainState {
     pub fn new(genesis_block: &Block) -> Self {
         let mut state = Self {
             utxo_set: UTXOSet::new(),
+            nonces: NonceSet::new(),
             height: 0,
             tip_hash: genesis_block.hash(),
             total_issued: 0,
             difficulty: genesis_block.header.difficulty_target,
             block_index: HashMap::new(),
-            full_blocks: HashMap::new(),
+            block_cache: Mutex::new(BlockCache::unbounded()),
             height_to_hash: HashMap::new(),
-            mempool: HashMap::new(),
+            header_chain: vec![genesis_block.hash()],
+            mempool: Mempool::new(),
+            fee_estimator: FeeEstimator::new(),
+            sig_cache: Arc::new(SignatureCache::default()),
             db: None,
-            next_nonce: HashMap::new(),
-            recent_block_timestamps: std::collections::VecDeque::with_capacity(11),
         };
 
         // Apply genesis transactions (founder allocation)
         for tx in &genesis_block.transactions {
             state.utxo_set.apply_transaction(tx, 0);
+            state.nonces.apply_transaction(tx);
         }
 
         // Index genesis block
@@ -88,9 +123,12 @@ impl ChainState {
                 height: 0,
                 total_issued: 0,
                 undo_data: Vec::new(),
+                chain_work: calculate_work(genesis_block.header.difficulty_target),
+                failed: false,
+                has_data: true,
             },
         );
-        state.full_blocks.insert(genesis_block.hash(), genesis_block.clone());
+        state.block_cache.get_mut().unwrap().insert(genesis_block.clone());
 
         state
     }
@@ -98,6 +136,15 @@ impl ChainState {
     /// Restore chain state from database
     pub fn restore(db: BlockChainDB) -> Result<Self, String> {
         println!("📂 Loading chain state from disk...");
+
+        // 0. Bring older record layouts up to date, then repair a torn
+        //    write left by an interrupted shutdown
+        if db.upgrade_utxo_format().map_err(|e| e.to_string())? {
+            println!("🗂️  Added coinbase flags to stored outputs");
+        }
+        if db.check_consistency().map_err(|e| e.to_string())? {
+            println!("🔧 Repaired an interrupted block write");
+        }
         
         // 1. Load Metadata
         let (tip_hash, height, total_issued) = match db.load_metadata().map_err(|e| e.to_string())? {
@@ -108,84 +155,206 @@ impl ChainState {
         // 2. Load UTXO Set
         let utxo_set = db.load_utxo_set().map_err(|e| e.to_string())?;
 
-        // Reconstruct Indices by walking back from tip
-        let mut block_index = HashMap::new();
-        let mut full_blocks = HashMap::new();
-        let mut height_to_hash = HashMap::new();
-
-        let mut curr_height = height;
-
-        // Load tip block first
-        let tip_block = db.get_block(&tip_hash).map_err(|e| e.to_string())?
-            .ok_or("Tip block missing from DB".to_string())?;
-            
-        let difficulty = tip_block.header.difficulty_target;
+        // 3. Load the header index (built once for databases that predate it)
+        if db.reindex().map_err(|e| e.to_string())? {
+            println!("🗂️  Built block index for existing database");
+        }
+        let mut records = db.load_block_index().map_err(|e| e.to_string())?;
+        let height_to_hash = db.load_height_index().map_err(|e| e.to_string())?;
+
+        // Parents sort before children, so chain work accumulates in one pass.
+        // Issuance depends only on height, so it is tabulated once.
+        records.sort_by_key(|(_, record)| record.height);
+        let max_height = records.last().map(|(_, record)| record.height).unwrap_or(0);
+        let mut issued_at = Vec::with_capacity(max_height as usize + 1);
+        let mut issued = 0u64;
+        for h in 0..=max_height {
+            issued = issued.saturating_add(crate::consensus::calculate_block_reward(h, issued));
+            issued_at.push(issued);
+        }
 
-        // Trace back to genesis (or as far as we have in DB)
-        let mut next_hash = tip_hash;
-        
-        loop {
-            let block = db.get_block(&next_hash).map_err(|e| e.to_string())?
-                .ok_or(format!("Block {} missing from DB", next_hash))?;
-            
-            // Reconstruct index entry (Note: undo_data is lost, so reorgs deep into history are limited)
-            block_index.insert(next_hash, BlockIndexEntry {
-                header: block.header.clone(),
-                height: curr_height,
-                total_issued: 0, // Approximate, fixed if we walk forward, but we assume valid chain
-                undo_data: Vec::new(),
+        let mut block_index: HashMap<Hash, BlockIndexEntry> = HashMap::with_capacity(records.len());
+        for (hash, record) in records {
+            let parent_work = match block_index.get(&record.header.prev_hash) {
+                Some(parent) => parent.chain_work,
+                None if record.height == 0 => 0,
+                None => continue, // Parent never indexed; cannot be weighed
+            };
+            block_index.insert(hash, BlockIndexEntry {
+                chain_work: parent_work.saturating_add(calculate_work(record.header.difficulty_target)),
+                header: record.header,
+                height: record.height,
+                total_issued: issued_at[record.height as usize],
+                undo_data: Vec::new(), // Read from disk when a block is disconnected
+                failed: record.status == BlockStatus::Failed,
+                has_data: record.status == BlockStatus::Valid,
             });
-            
-            full_blocks.insert(next_hash, block.clone());
-            height_to_hash.insert(curr_height, next_hash);
-
-            if curr_height == 0 {
-                break;
-            }
+        }
 
-            next_hash = block.header.prev_hash;
-            curr_height -= 1;
+        // 4. Load confirmed sender nonces (replayed once for databases that predate them)
+        if db.index_nonces().map_err(|e| e.to_string())? {
+            println!("🗂️  Built sender nonce index for existing database");
         }
+        let nonces = db.load_nonces().map_err(|e| e.to_string())?;
 
-        // Correct total_issued for the index (optional, but good for consistency)
-        // For now, we trust the tip metadata.
+        let tip_difficulty = block_index.get(&tip_hash)
+            .map(|entry| entry.header.difficulty_target)
+            .ok_or("Tip block missing from index".to_string())?;
 
-        println!("✅ Restored chain to height {}", height);
+        println!("✅ Restored chain to height {} ({} headers)", height, block_index.len());
 
-        Ok(Self {
+        let mut state = Self {
             utxo_set,
+            nonces,
             height,
             tip_hash,
             total_issued,
-            difficulty,
+            difficulty: tip_difficulty,
             block_index,
-            full_blocks,
+            block_cache: Mutex::new(BlockCache::new(BLOCK_CACHE_CAPACITY)),
             height_to_hash,
-            mempool: HashMap::new(),
+            header_chain: Vec::new(),
+            mempool: Mempool::new(),
+            fee_estimator: FeeEstimator::new(),
+            sig_cache: Arc::new(SignatureCache::default()),
             db: Some(db),
-            next_nonce: HashMap::new(),
-            recent_block_timestamps: std::collections::VecDeque::with_capacity(11),
-        })
+        };
+        state.difficulty = state.next_difficulty();
+        state.select_best_header();
+
+        // 5. Fee statistics (unreadable ones are discarded) and the mempool
+        //    saved at shutdown, revalidated against the restored tip
+        if let Some(estimator) = state.db.as_ref().and_then(|db| db.load_fee_estimator().ok().flatten()) {
+            state.fee_estimator = estimator;
+        }
+        let (loaded, dropped) = state.reload_mempool().map_err(|e| e.to_string())?;
+        if loaded + dropped > 0 {
+            println!("📥 Reloaded {} mempool transactions ({} no longer valid)", loaded, dropped);
+        }
+
+        Ok(state)
+    }
+
+    /// Write the mempool to the database so it survives a restart
+    ///
+    /// Returns the number of transactions saved.
+    pub fn save_mempool(&self) -> std::io::Result<usize> {
+        let Some(db) = &self.db else {
+            return Ok(0);
+        };
+        let entries = self.mempool.dump();
+        db.save_mempool(&entries)?;
+        Ok(entries.len())
+    }
+
+    /// Write the fee estimator's statistics to the database
+    pub fn save_fee_estimates(&self) -> std::io::Result<()> {
+        match &self.db {
+            Some(db) => db.save_fee_estimator(&self.fee_estimator),
+            None => Ok(()),
+        }
+    }
+
+    /// Re-admit the transactions saved by `save_mempool`
+    ///
+    /// Each one goes through full mempool validation again, keeping its
+    /// original arrival time. Returns (accepted, dropped).
+    pub fn reload_mempool(&mut self) -> std::io::Result<(usize, usize)> {
+        let Some(db) = &self.db else {
+            return Ok((0, 0));
+        };
+        let saved = db.load_mempool()?;
+        let total = saved.len();
+        for (tx, time) in saved {
+            let _ = self.add_to_mempool_at(tx, time);
+        }
+        self.expire_mempool(unix_time());
+        Ok((self.mempool.len(), total - self.mempool.len()))
     }
 
     /// Set database connection
+    /// 
+    /// Block bodies can be reloaded from disk from now on, so the cache is bounded.
     pub fn set_db(&mut self, db: BlockChainDB) {
         self.db = Some(db);
+        self.block_cache.get_mut().unwrap().set_capacity(BLOCK_CACHE_CAPACITY);
     }
 
-    /// Calculate median time of last 11 blocks
-    fn calculate_median_time(&self) -> u64 {
-        if self.recent_block_timestamps.is_empty() {
-            return 0;
+    /// Difficulty target the block after the current tip must carry
+    /// 
+    /// Equal to the tip's target, except on adjustment heights where it is
+    /// retargeted from the period start/end timestamps.
+    pub fn next_difficulty(&self) -> u32 {
+        self.difficulty_after(&self.tip_hash).unwrap_or(self.difficulty)
+    }
+
+    /// Difficulty target a child of the indexed block `prev_hash` must carry
+    /// 
+    /// Retargets never go easier than the genesis target.
+    fn difficulty_after(&self, prev_hash: &Hash) -> Option<u32> {
+        let prev = self.block_index.get(prev_hash)?;
+        let pow_limit = self.get_block_hash_at_height(0)
+            .and_then(|hash| self.get_block_header(&hash))
+            .map_or(prev.header.difficulty_target, |genesis| genesis.difficulty_target);
+
+        Some(expected_difficulty(prev.height + 1, prev.header.difficulty_target, pow_limit, |h| {
+            self.ancestor_at(prev_hash, h)
+                .and_then(|hash| self.get_block_header(&hash))
+                .map(|header| header.timestamp)
+        }))
+    }
+
+    /// Hash of the ancestor at `height` of the indexed block `hash`
+    /// 
+    /// Walks back until the chain meets the active chain, then jumps.
+    fn ancestor_at(&self, hash: &Hash, height: u64) -> Option<Hash> {
+        let mut curr_hash = *hash;
+        loop {
+            let entry = self.block_index.get(&curr_hash)?;
+            if entry.height == height {
+                return Some(curr_hash);
+            }
+            if entry.height < height {
+                return None;
+            }
+            if self.get_block_hash_at_height(entry.height) == Some(curr_hash) {
+                return self.get_block_hash_at_height(height);
+            }
+            curr_hash = entry.header.prev_hash;
         }
-        
-        let mut times: Vec<u64> = self.recent_block_timestamps.iter().copied().collect();
-        times.sort_unstable();
-        times[times.len() / 2]
+    }
+
+    /// Median timestamp of the 11 blocks ending at the indexed block `hash`
+    fn median_time_past(&self, hash: &Hash) -> u64 {
+        let mut times = Vec::with_capacity(11);
+        let mut curr_hash = *hash;
+        while times.len() < 11 {
+            let Some(entry) = self.block_index.get(&curr_hash) else {
+                break;
+            };
+            times.push(entry.header.timestamp);
+            if entry.height == 0 {
+                break;
+            }
+            curr_hash = entry.header.prev_hash;
+        }
+        median_time(&times)
+    }
+
+    /// Calculate median time of last 11 blocks
+    /// 
+    /// Read from the block index, so it is the same after a restart or a reorg.
+    pub fn calculate_median_time(&self) -> u64 {
+        self.median_time_past(&self.tip_hash)
     }
 
     /// Validate block timestamp against network time rules
-    fn validate_block_timestamp(&self, timestamp: u64) -> Result<(), String> {
+    fn validate_block_timestamp(&self, timestamp: u64) -> Result<(), ValidationError> {
+        Self::check_timestamp(timestamp, self.calculate_median_time())
+    }
+
+    /// Timestamp rules, given the median time of the preceding blocks
+    fn check_timestamp(timestamp: u64, median_time: u64) -> Result<(), ValidationError> {
         // Current time (simplified - in production, use a more robust time source)
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -195,25 +364,24 @@ impl ChainState {
         // Rule 1: Block timestamp must not be more than 2 hours in the future
         const MAX_FUTURE_TIME: u64 = 2 * 3600; // 2 hours
         if timestamp > now + MAX_FUTURE_TIME {
-            return Err(format!(
+            return Err(ValidationError::InvalidTimestamp(format!(
                 "Block timestamp {} is too far in future (now: {}, max: {})",
                 timestamp,
                 now,
                 now + MAX_FUTURE_TIME
-            ));
+            )));
         }
         
         // Rule 2: Block timestamp must not be before median time of last 11 blocks minus 1 hour
-        let median_time = self.calculate_median_time();
         if median_time > 0 {
             const MIN_PAST_TIME: u64 = 3600; // 1 hour
             if timestamp < median_time.saturating_sub(MIN_PAST_TIME) {
-                return Err(format!(
+                return Err(ValidationError::InvalidTimestamp(format!(
                     "Block timestamp {} is too old (median: {}, min: {})",
                     timestamp,
                     median_time,
                     median_time.saturating_sub(MIN_PAST_TIME)
-                ));
+                )));
             }
         }
         
@@ -222,167 +390,102 @@ impl ChainState {
 
     /// Apply a new block to the state
     /// 
-    /// Returns the spent UTXOs for potential rollback.
-    pub fn apply_block(&mut self, block: &Block) -> Result<Vec<(UTXOKey, UTXO)>, String> {
-        // 1. Validate chain_id (replay protection)
-        if block.header.chain_id != crate::constants::CHAIN_ID {
-            return Err(format!(
-                "Block has invalid chain_id: {} (expected {})",
-                block.header.chain_id,
-                crate::constants::CHAIN_ID
-            ));
-        }
-        
-        // 2. Validate timestamp against network time
+    /// Runs the full consensus pipeline (timestamp, previous hash, difficulty,
+    /// PoW, merkle root, transactions and coinbase reward) before touching
+    /// any state. The block is written to the database before memory, so a
+    /// storage error leaves the state unchanged. Returns the spent UTXOs for
+    /// potential rollback.
+    pub fn apply_block(&mut self, block: &Block) -> Result<Vec<(UTXOKey, UTXO)>, ValidationError> {
+        let new_height = self.height + 1;
+
+        // 1. Validate timestamp against network time
         self.validate_block_timestamp(block.header.timestamp)?;
         
-        // 3. Validate block header (PoW is assumed valid if it reached here, but we can check height)
-        if block.header.prev_hash != self.tip_hash && self.height > 0 {
-            return Err("Block does not connect to current tip".to_string());
-        }
-
+        // 2. Validate block against the current tip
+        validate_block(
+            block,
+            &self.tip_hash,
+            self.difficulty,
+            &self.utxo_set,
+            &self.nonces,
+            new_height,
+            self.calculate_median_time(),
+            self.total_issued,
+            &self.sig_cache,
+        )?;
+
+        // 3. Collect spent outputs for rollback, including outputs created
+        //    and spent within this block
         let mut spent_utxos = Vec::new();
-        let new_height = self.height + 1;
-
-        // 2. Validate all transactions in the block
-        let mut total_subsidy = 0u64;
-        let mut coinbase_reward = 0u64;
-        let mut block_fees = 0u64;
-
-        for tx in &block.transactions {
-            if tx.is_coinbase() {
-                if coinbase_reward > 0 {
-                    return Err("Multiple coinbase transactions in block".to_string());
-                }
-                coinbase_reward = tx.total_output_value();
-                total_subsidy = crate::consensus::calculate_block_reward(new_height, self.total_issued);
-            } else {
-                // Verify signatures
-                tx.verify_signatures(&self.utxo_set)?;
-
-                // Verify inputs exist and collect them for fees/rollback
-                let input_val = tx.total_input_value(&self.utxo_set);
-                let output_val = tx.total_output_value();
-                
-                if input_val < output_val {
-                    return Err(format!("Insufficient input in transaction {}", tx.hash()));
-                }
-                
-                let fee = input_val - output_val;
-                block_fees = block_fees.saturating_add(fee);
-
-                for input in &tx.inputs {
-                    if let Some(utxo) = self.utxo_set.get(&input.prev_tx_hash, input.output_index) {
-                        spent_utxos.push((
-                            (input.prev_tx_hash, input.output_index),
-                            utxo.clone(),
-                        ));
-                    } else {
-                        return Err(format!("UTXO missing for transaction {}", tx.hash()));
-                    }
-                }
-            }
-        }
-
-        // 3. Verify coinbase reward (subsidy + fees)
-        if coinbase_reward > total_subsidy + block_fees {
-            return Err(format!("Coinbase reward too high: {} > {} subsidy + {} fees", 
-                coinbase_reward, total_subsidy, block_fees));
-        }
-
-        // Clean mempool: Remove mined transactions and conflicting transactions
-        let mut txs_to_remove = HashSet::new();
-        let mut block_inputs = HashSet::new();
-
-        // Identify inputs spent by the new block
+        let mut block_outputs = UTXOSet::new();
         for tx in &block.transactions {
-            txs_to_remove.insert(tx.hash()); // Remove the tx itself
             if !tx.is_coinbase() {
                 for input in &tx.inputs {
-                    block_inputs.insert((input.prev_tx_hash, input.output_index));
-                }
-            }
-        }
-
-        // Identify mempool transactions that conflict with the new block
-        for (tx_hash, mempool_tx) in &self.mempool {
-            for input in &mempool_tx.inputs {
-                if block_inputs.contains(&(input.prev_tx_hash, input.output_index)) {
-                    txs_to_remove.insert(*tx_hash);
-                    break;
+                    let utxo = block_outputs.get(&input.prev_tx_hash, input.output_index)
+                        .or_else(|| self.utxo_set.get(&input.prev_tx_hash, input.output_index))
+                        .ok_or_else(|| ValidationError::InvalidTransaction(
+                            format!("UTXO missing for transaction {}", tx.hash())
+                        ))?;
+                    spent_utxos.push((
+                        (input.prev_tx_hash, input.output_index),
+                        utxo.clone(),
+                    ));
                 }
             }
+            block_outputs.apply_transaction(tx, new_height);
         }
+        let total_subsidy = crate::consensus::calculate_block_reward(new_height, self.total_issued);
+        let total_issued = self.total_issued.saturating_add(total_subsidy);
 
-        // Remove from mempool and reset nonces for removed transactions
-        for hash in &txs_to_remove {
-            if let Some(tx) = self.mempool.remove(hash) {
-                // Reset nonce for sender if no more txs from them in mempool
-                if !tx.inputs.is_empty() {
-                    let sender_pubkey = &tx.inputs[0].public_key;
-                    let sender_hash = crate::crypto::hash_bytes(&sender_pubkey.0);
-                    
-                    let has_more_txs = self.mempool.values()
-                        .any(|t| !t.inputs.is_empty() && t.inputs[0].public_key == *sender_pubkey);
-                    
-                    if !has_more_txs {
-                        self.next_nonce.remove(&sender_hash);
-                    }
-                }
-            }
+        // 4. Persist to DB if available, before any in-memory state changes
+        //    so a failed write leaves the node on its previous tip
+        if let Some(db) = &self.db {
+            let to_storage = |e: std::io::Error| ValidationError::Storage(e.to_string());
+            let undo = BlockUndo {
+                spent_utxos: spent_utxos.clone(),
+                total_issued,
+            };
+            db.connect_block(block, new_height, &undo).map_err(to_storage)?;
         }
 
-        // 4. Apply transactions to UTXO set
-        let mut new_utxos = Vec::new();
-        let spent_keys: Vec<UTXOKey> = spent_utxos.iter().map(|(k, _)| *k).collect();
+        // Clean mempool: Remove mined transactions and conflicting transactions
+        self.fee_estimator.process_block(new_height, &block.transactions);
+        let removed = self.mempool.remove_for_block(block);
+        self.forget_removed(&removed);
 
+        // 5. Apply transactions to UTXO and nonce sets
         for tx in &block.transactions {
             self.utxo_set.apply_transaction(tx, new_height);
-            
-            // Collect new UTXOs for DB update
-            for (i, output) in tx.outputs.iter().enumerate() {
-                new_utxos.push((
-                    (tx.hash(), i as u32),
-                    UTXO {
-                        amount: output.amount,
-                        pubkey_hash: output.pubkey_hash,
-                        height: new_height,
-                    }
-                ));
-            }
+            self.nonces.apply_transaction(tx);
         }
 
-        // 5. Update state
-        self.total_issued = self.total_issued.saturating_add(total_subsidy);
+        // 6. Update state
+        self.total_issued = total_issued;
         self.height = new_height;
         self.tip_hash = block.hash();
-        self.difficulty = block.header.difficulty_target;
         self.height_to_hash.insert(new_height, block.hash());
-        
-        // Track block timestamp for median time calculation
-        self.recent_block_timestamps.push_back(block.header.timestamp);
-        if self.recent_block_timestamps.len() > 11 {
-            self.recent_block_timestamps.pop_front();
-        }
+
 
         // Index block
+        let chain_work = self.work_on_top_of(&block.header);
         self.block_index.insert(
             block.hash(),
             BlockIndexEntry {
                 header: block.header.clone(),
                 height: new_height,
                 total_issued: self.total_issued,
-                undo_data: spent_utxos.clone(),
+                // With a database the undo record is read back from disk
+                undo_data: if self.db.is_some() { Vec::new() } else { spent_utxos.clone() },
+                chain_work,
+                failed: false,
+                has_data: true,
             },
         );
-        self.full_blocks.insert(block.hash(), block.clone());
+        self.block_cache.get_mut().unwrap().insert(block.clone());
+        self.consider_best_header(block.hash());
 
-        // 6. Persist to DB if available
-        if let Some(db) = &self.db {
-            db.save_block(block).map_err(|e| e.to_string())?;
-            db.update_utxos(&spent_keys, &new_utxos).map_err(|e| e.to_string())?;
-            db.update_metadata(&self.tip_hash, self.height, self.total_issued).map_err(|e| e.to_string())?;
-        }
+        // Retarget for the next block once the new tip is indexed
+        self.difficulty = self.next_difficulty();
 
         Ok(spent_utxos)
     }
@@ -390,47 +493,77 @@ impl ChainState {
     /// Revert the current tip block
     pub fn revert_tip(&mut self) -> Result<(), String> {
         let tip_hash = self.tip_hash;
-        let block = self.full_blocks.get(&tip_hash).ok_or("Tip block not found")?.clone();
-        let entry = self.block_index.get(&tip_hash).ok_or("Tip index not found")?.clone();
-        
-        self.revert_block(&block, entry.undo_data);
+        let block = self.get_block(&tip_hash).ok_or("Tip block not found")?;
+        let undo_data = self.load_undo_data(&tip_hash, &block)?;
+        let height = self.height;
+
+        // Persist first so a failed write keeps the in-memory tip in step with disk
+        if let Some(db) = &self.db {
+            let prev_total_issued = self.block_index.get(&block.header.prev_hash)
+                .map_or(self.total_issued, |entry| entry.total_issued);
+            db.disconnect_block(&block, height, &undo_data, prev_total_issued)
+                .map_err(|e| e.to_string())?;
+        }
+
+        self.revert_block(&block, undo_data);
         Ok(())
     }
 
+    /// Spent outputs needed to disconnect a block
+    /// 
+    /// Taken from the index, falling back to the persisted undo record.
+    /// Fails rather than guessing when a block that spent outputs has none,
+    /// since reverting it would silently corrupt the UTXO set.
+    fn load_undo_data(&self, hash: &Hash, block: &Block) -> Result<Vec<(UTXOKey, UTXO)>, String> {
+        let entry = self.block_index.get(hash).ok_or("Tip index not found")?;
+        if !entry.undo_data.is_empty() {
+            return Ok(entry.undo_data.clone());
+        }
+
+        if let Some(db) = &self.db {
+            if let Some(undo) = db.get_undo(hash).map_err(|e| e.to_string())? {
+                return Ok(undo.spent_utxos);
+            }
+        }
+
+        let spends_outputs = block.transactions.iter().any(|tx| !tx.is_coinbase() && !tx.inputs.is_empty());
+        if spends_outputs {
+            return Err(format!("Undo data missing for block {}", hash));
+        }
+        Ok(Vec::new())
+    }
+
     /// Revert a block from the state
     pub fn revert_block(&mut self, block: &Block, spent_utxos: Vec<(UTXOKey, UTXO)>) {
         // Revert transactions in reverse order
         for tx in block.transactions.iter().rev() {
             // Find the spent UTXOs for this transaction
             let tx_spent: Vec<_> = spent_utxos.iter()
-                .filter(|((hash, _), _)| {
-                    tx.inputs.iter().any(|i| i.prev_tx_hash == *hash)
+                .filter(|(key, _)| {
+                    tx.inputs.iter().any(|i| (i.prev_tx_hash, i.output_index) == *key)
                 })
                 .cloned()
                 .collect();
             
             self.utxo_set.revert_transaction(tx, &tx_spent);
         }
+        self.nonces.revert_block(block);
 
         // Update state
         self.height_to_hash.remove(&(self.height));
         self.height -= 1;
         self.tip_hash = block.header.prev_hash;
-        
-        // Remove timestamp from tracking (revert the most recent block timestamp)
-        self.recent_block_timestamps.pop_back();
+
 
         // Restore previous total_issued from index
         if let Some(entry) = self.block_index.get(&block.header.prev_hash) {
             self.total_issued = entry.total_issued;
-            self.difficulty = entry.header.difficulty_target;
         }
 
-        // Note: Reverts are currently NOT persisted to DB immediately.
-        // If node crashes during reorg, it might be in inconsistent state relative to DB.
-        // In full production, we'd use DB transactions or batches.
-        // For now, the DB is updated only on forward progress (apply_block).
-        // A restart would load the old tip (pre-reorg state) which is safe.
+        // The reverted block carried exactly the target now required again
+        self.difficulty = block.header.difficulty_target;
+
+        // Persisting the disconnect is left to `revert_tip`, which holds the undo record
     }
 
     /// Get block header by hash
@@ -443,6 +576,104 @@ impl ChainState {
         self.block_index.get(hash).map(|e| e.height)
     }
 
+    /// Get cumulative chain work up to and including a block
+    pub fn get_chain_work(&self, hash: &Hash) -> Option<u128> {
+        self.block_index.get(hash).map(|e| e.chain_work)
+    }
+
+    /// Cumulative chain work of the active chain
+    pub fn chain_work(&self) -> u128 {
+        self.get_chain_work(&self.tip_hash).unwrap_or(0)
+    }
+
+    /// Chain work of a block built on `header.prev_hash`
+    fn work_on_top_of(&self, header: &BlockHeader) -> u128 {
+        self.get_chain_work(&header.prev_hash)
+            .unwrap_or(0)
+            .saturating_add(calculate_work(header.difficulty_target))
+    }
+
+    /// Check whether the chain ending at `hash` contains a block that failed validation
+    fn has_failed_ancestor(&self, hash: &Hash) -> bool {
+        let mut curr_hash = *hash;
+        while let Some(entry) = self.block_index.get(&curr_hash) {
+            if entry.failed {
+                return true;
+            }
+            // Blocks on the active chain have all been validated, and the
+            // best header chain never contains a failed block
+            if self.get_block_hash_at_height(entry.height) == Some(curr_hash)
+                || self.get_header_hash_at_height(entry.height) == Some(curr_hash)
+                || entry.height == 0
+            {
+                return false;
+            }
+            curr_hash = entry.header.prev_hash;
+        }
+        false
+    }
+
+    /// Mark a block as invalid so its chain is never selected
+    fn mark_failed(&mut self, hash: &Hash) {
+        if let Some(entry) = self.block_index.get_mut(hash) {
+            entry.failed = true;
+        }
+        if let Some(db) = &self.db {
+            let _ = db.set_block_status(hash, BlockStatus::Failed);
+        }
+        if let Some(height) = self.get_block_height(hash) {
+            if self.get_header_hash_at_height(height) == Some(*hash) {
+                self.header_chain.truncate(height as usize);
+                self.select_best_header();
+            }
+        }
+    }
+
+    /// Check whether the indexed chain ending at `hash` is valid so far
+    /// and has more cumulative work than the active chain
+    pub fn has_more_work(&self, hash: &Hash) -> bool {
+        match self.get_chain_work(hash) {
+            Some(work) => work > self.chain_work() && !self.has_failed_ancestor(hash),
+            None => false,
+        }
+    }
+
+    /// Select the most-work chain
+    /// 
+    /// Makes `hash` the active tip if its chain has more cumulative work than
+    /// ours, either by extending the tip or by reorganizing. Blocks that fail
+    /// validation are marked so their chains are never selected again.
+    /// Returns whether the tip changed.
+    /// 
+    /// Only blocks whose bodies (and their ancestors' bodies) are stored can
+    /// be connected, so the chain is activated up to the last such block.
+    pub fn activate_best_chain(&mut self, hash: Hash) -> Result<bool, String> {
+        let Some(hash) = self.last_connectable(&hash) else {
+            return Ok(false);
+        };
+        if !self.has_more_work(&hash) {
+            return Ok(false);
+        }
+
+        let prev_hash = self.get_block_header(&hash)
+            .map(|h| h.prev_hash)
+            .ok_or("Block not in index")?;
+
+        if prev_hash == self.tip_hash {
+            let block = self.get_block(&hash).ok_or("Block data missing")?;
+            if let Err(e) = self.apply_block(&block) {
+                if !matches!(e, ValidationError::Storage(_)) {
+                    self.mark_failed(&hash);
+                }
+                return Err(e.to_string());
+            }
+        } else {
+            self.reorganize(hash)?;
+        }
+
+        Ok(true)
+    }
+
     /// Check if a reorg would violate checkpoint rules
     fn validate_reorg_depth(&self, common_ancestor_height: u64) -> Result<(), String> {
         let reorg_depth = self.height - common_ancestor_height;
@@ -456,9 +687,9 @@ impl ChainState {
             ));
         }
         
-        // Check against checkpoints - cannot reorg past a checkpoint
+        // Check against checkpoints - cannot reorg past a checkpoint we have connected
         for (checkpoint_height, _) in crate::constants::CHECKPOINTS {
-            if common_ancestor_height < *checkpoint_height {
+            if common_ancestor_height < *checkpoint_height && *checkpoint_height <= self.height {
                 return Err(format!(
                     "Cannot reorg past checkpoint at height {}",
                     checkpoint_height
@@ -473,6 +704,8 @@ impl ChainState {
     /// 
     /// This finds the common ancestor, reverts local blocks, and applies the new chain.
     /// Validates against checkpoint constraints and max reorg depth.
+    /// If a block on the new chain is invalid, it is marked failed and the
+    /// original chain is restored.
     pub fn reorganize(&mut self, target_hash: Hash) -> Result<(), String> {
         let mut new_chain = Vec::new();
         let mut curr_hash = target_hash;
@@ -496,200 +729,504 @@ impl ChainState {
         self.validate_reorg_depth(common_height)?;
 
         // 2. Revert blocks until common ancestor
+        let (old_height, old_median_time) = (self.height, self.calculate_median_time());
+        let mut old_chain = Vec::new();
+        let mut disconnected = Vec::new();
         while self.height > common_height {
+            old_chain.push(self.tip_hash);
+            disconnected.extend(self.get_block(&self.tip_hash));
             self.revert_tip()?;
         }
 
         // 3. Apply new blocks
         for hash in new_chain {
-            let block = self.full_blocks.get(&hash).ok_or("Block data missing during re-org")?.clone();
-            self.apply_block(&block)?;
+            let block = self.get_block(&hash).ok_or("Block data missing during re-org")?;
+            if let Err(e) = self.apply_block(&block) {
+                if !matches!(e, ValidationError::Storage(_)) {
+                    self.mark_failed(&hash);
+                }
+
+                // Roll back to the original chain
+                while self.height > common_height {
+                    disconnected.extend(self.get_block(&self.tip_hash));
+                    self.revert_tip()?;
+                }
+                for old_hash in old_chain.iter().rev() {
+                    let old_block = self.get_block(old_hash).ok_or("Block data missing during re-org")?;
+                    self.apply_block(&old_block).map_err(|e| e.to_string())?;
+                }
+                self.readmit_disconnected(disconnected, old_height, old_median_time);
+
+                return Err(format!("Reorg aborted, block {} is invalid: {}", hash, e));
+            }
         }
 
+        self.readmit_disconnected(disconnected, old_height, old_median_time);
         Ok(())
     }
 
+    /// Return transactions from disconnected blocks to the mempool
+    ///
+    /// `blocks` are in disconnect order (tip first). Their transactions go
+    /// through mempool admission in block order, so parents precede
+    /// children; those mined again or double-spent on the new chain are
+    /// dropped. Pool entries are left alone unless the new chain invalidated
+    /// them: spends of outputs that no longer exist, nonce sequences with a
+    /// gap, and, when the tip moved back from `old_height` or
+    /// `old_median_time`, immature or non-final ones.
+    fn readmit_disconnected(&mut self, blocks: Vec<Block>, old_height: u64, old_median_time: u64) {
+        let now = unix_time();
+        let disconnected: Vec<Transaction> = blocks.into_iter()
+            .rev()
+            .flat_map(|block| block.transactions)
+            .collect();
+
+        let mut returned = 0;
+        for tx in disconnected.iter().filter(|tx| !tx.is_coinbase()) {
+            if self.add_to_mempool_at(tx.clone(), now).is_ok() {
+                returned += 1;
+            }
+        }
+
+        // Pool entries built on disconnected transactions that did not return
+        let mut invalid = HashSet::new();
+        let mut senders = HashSet::new();
+        for tx in &disconnected {
+            let hash = tx.hash();
+            if self.mempool.contains(&hash) {
+                continue;
+            }
+            for index in 0..tx.outputs.len() as u32 {
+                if !self.utxo_set.contains(&hash, index) {
+                    invalid.extend(self.mempool.spender(&(hash, index)));
+                }
+            }
+            senders.extend(tx.sender());
+        }
+        for sender in senders {
+            if let Some(first) = self.mempool.first_nonce(&sender) {
+                if first != self.nonces.get(&sender) {
+                    invalid.extend(self.mempool.find_by_nonce(&sender, first));
+                }
+            }
+        }
+
+        // Maturity and lock times only tighten if the tip moved back
+        let (next_height, median_time) = (self.height + 1, self.calculate_median_time());
+        if self.height < old_height || median_time < old_median_time {
+            for entry in self.mempool.entries() {
+                let immature = entry.tx.inputs.iter().any(|input| {
+                    self.utxo_set.get(&input.prev_tx_hash, input.output_index)
+                        .is_some_and(|utxo| !utxo.is_mature(next_height))
+                });
+                if immature || !entry.tx.is_final(next_height, median_time) {
+                    invalid.insert(entry.tx.hash());
+                }
+            }
+        }
+
+        let mut evicted = 0;
+        for hash in invalid {
+            let removed = self.mempool.remove_with_descendants(&hash);
+            evicted += removed.len();
+            self.forget_removed(&removed);
+        }
+
+        let total = disconnected.iter().filter(|tx| !tx.is_coinbase()).count();
+        if total > 0 || evicted > 0 {
+            println!(
+                "♻️  Returned {} transactions from disconnected blocks to the mempool ({} dropped, {} pool transactions evicted)",
+                returned, total - returned, evicted
+            );
+        }
+    }
+
     /// Index a block without applying it (for side chains)
-    pub fn index_block(&mut self, block: &Block) {
-        if self.block_index.contains_key(&block.hash()) {
-            return;
+    /// 
+    /// Context-free checks (chain id, PoW, merkle root) run first so that
+    /// unmined blocks are never stored. The header must then pass
+    /// `accept_header`, unless it was already accepted during header sync.
+    pub fn index_block(&mut self, block: &Block) -> Result<(), ValidationError> {
+        let hash = block.hash();
+        if self.has_block_data(&hash) {
+            return Ok(());
+        }
+
+        check_block(block)?;
+        self.accept_header(&block.header)?;
+
+        let height = self.get_block_height(&hash).ok_or(ValidationError::UnknownParent)?;
+        if let Some(entry) = self.block_index.get_mut(&hash) {
+            entry.has_data = true;
+        }
+        self.block_cache.get_mut().unwrap().insert(block.clone());
+        
+        // Save to DB even if not applied yet (so we have the data)
+        if let Some(db) = &self.db {
+            db.save_block(block, height).map_err(|e| ValidationError::Storage(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Validate and index a block header without its body
+    /// 
+    /// Checks chain id, PoW, checkpoints, the retargeted difficulty and the
+    /// timestamp against the header's own branch. Headers whose parent is
+    /// unknown are rejected with `UnknownParent`. Known headers are accepted
+    /// as-is.
+    pub fn accept_header(&mut self, header: &BlockHeader) -> Result<(), ValidationError> {
+        let hash = header.hash();
+        if self.block_index.contains_key(&hash) {
+            return Ok(());
         }
 
-        // Try to derive height from parent
-        let height = self.get_block_height(&block.header.prev_hash)
-            .map(|h| h + 1)
-            .unwrap_or(0);
+        check_header(header)?;
+
+        let parent = self.block_index.get(&header.prev_hash).ok_or(ValidationError::UnknownParent)?;
+        let height = parent.height + 1;
+        let chain_work = parent.chain_work.saturating_add(calculate_work(header.difficulty_target));
+
+        check_checkpoint(height, &hash, crate::constants::CHECKPOINTS)?;
+        if self.last_checkpoint_height().is_some_and(|checkpoint| height <= checkpoint) {
+            // Any new header at this height forks below a checkpoint we already have
+            return Err(ValidationError::CheckpointMismatch(height));
+        }
+
+        if Some(header.difficulty_target) != self.difficulty_after(&header.prev_hash) {
+            return Err(ValidationError::InvalidDifficulty);
+        }
+        Self::check_timestamp(header.timestamp, self.median_time_past(&header.prev_hash))?;
 
         self.block_index.insert(
-            block.hash(),
+            hash,
             BlockIndexEntry {
-                header: block.header.clone(),
+                header: header.clone(),
                 height,
-                total_issued: 0, 
+                total_issued: 0,
                 undo_data: Vec::new(),
+                chain_work,
+                failed: false,
+                has_data: false,
             },
         );
-        self.full_blocks.insert(block.hash(), block.clone());
-        
-        // Save to DB even if not applied yet (so we have the data)
+
         if let Some(db) = &self.db {
-            let _ = db.save_block(block);
+            db.save_header(header, height).map_err(|e| ValidationError::Storage(e.to_string()))?;
         }
+
+        self.consider_best_header(hash);
+        Ok(())
     }
 
-    /// Get full block by hash
-    pub fn get_block(&self, hash: &Hash) -> Option<&Block> {
-        self.full_blocks.get(hash)
+    /// Height of the highest checkpoint whose block is in the index
+    fn last_checkpoint_height(&self) -> Option<u64> {
+        crate::constants::CHECKPOINTS.iter()
+            .filter(|(_, hash)| {
+                Hash::from_hex(hash).is_ok_and(|hash| self.block_index.contains_key(&hash))
+            })
+            .map(|(height, _)| *height)
+            .max()
     }
 
-    /// Get block hash at a given height (Main chain)
-    pub fn get_block_hash_at_height(&self, target_height: u64) -> Option<Hash> {
-        self.height_to_hash.get(&target_height).copied()
+    /// Check whether the body of a block is stored
+    pub fn has_block_data(&self, hash: &Hash) -> bool {
+        self.block_index.get(hash).is_some_and(|entry| entry.has_data)
     }
 
-    /// Calculate current mempool size in bytes
-    pub fn mempool_bytes(&self) -> u64 {
-        self.mempool.values()
-            .map(|tx| {
-                bincode::serialized_size(tx).unwrap_or(0)
-            })
-            .sum()
+    /// Hash of the most-work valid header, which may not be connected yet
+    pub fn best_header(&self) -> Hash {
+        self.header_chain.last().copied().unwrap_or(self.tip_hash)
     }
 
-    /// Calculate fee rate (satoshis per byte)
-    fn calculate_fee_rate(tx: &Transaction, fee: u64) -> u64 {
-        let tx_size = bincode::serialized_size(tx).unwrap_or(1) as u64;
-        fee / tx_size.max(1)
+    /// Height of the most-work valid header
+    pub fn best_header_height(&self) -> u64 {
+        self.header_chain.len().saturating_sub(1) as u64
     }
 
-    /// Add a transaction to the mempool
-    pub fn add_to_mempool(&mut self, tx: Transaction) -> Result<(), String> {
-        let hash = tx.hash();
-        
-        // 1. Basic checks
-        if self.mempool.contains_key(&hash) {
-            return Err("Transaction already in mempool".to_string());
-        }
-        if tx.is_coinbase() {
-            return Err("Coinbase transaction cannot be added to mempool".to_string());
+    /// Block hash at a given height on the best header chain
+    pub fn get_header_hash_at_height(&self, height: u64) -> Option<Hash> {
+        self.header_chain.get(height as usize).copied()
+    }
+
+    /// Make `hash` the best header if its chain has more work
+    fn consider_best_header(&mut self, hash: Hash) {
+        let best_work = self.get_chain_work(&self.best_header()).unwrap_or(0);
+        let work = self.get_chain_work(&hash).unwrap_or(0);
+        if work > best_work && !self.has_failed_ancestor(&hash) {
+            self.set_best_header(hash);
         }
+    }
 
-        // 2. Verify signatures and UTXO existence
-        tx.verify_signatures(&self.utxo_set)?;
+    /// Re-pick the best header from the whole index (after a failure)
+    fn select_best_header(&mut self) {
+        let mut candidates: Vec<(u128, Hash)> = self.block_index.iter()
+            .filter(|(_, entry)| !entry.failed)
+            .map(|(hash, entry)| (entry.chain_work, *hash))
+            .collect();
+        candidates.sort_unstable_by_key(|(work, _)| std::cmp::Reverse(*work));
+
+        let best = candidates.into_iter()
+            .map(|(_, hash)| hash)
+            .find(|hash| !self.has_failed_ancestor(hash))
+            .unwrap_or(self.tip_hash);
+        self.set_best_header(best);
+    }
 
-        // 3. Verify amounts (input >= output + fee)
-        let input_val = tx.total_input_value(&self.utxo_set);
-        let output_val = tx.total_output_value();
-        if input_val < output_val {
-            return Err(format!("Insufficient input: {} < {}", input_val, output_val));
+    /// Rewrite the header chain so that it ends at `hash`
+    fn set_best_header(&mut self, hash: Hash) {
+        let Some(height) = self.get_block_height(&hash) else {
+            return;
+        };
+        let height = height as usize;
+        self.header_chain.resize(height + 1, Hash::zero());
+
+        let mut curr_hash = hash;
+        let mut h = height;
+        loop {
+            if self.header_chain[h] == curr_hash {
+                break;
+            }
+            self.header_chain[h] = curr_hash;
+            match self.block_index.get(&curr_hash) {
+                Some(entry) if h > 0 => {
+                    curr_hash = entry.header.prev_hash;
+                    h -= 1;
+                }
+                _ => break,
+            }
         }
-        
-        // Calculate fee and fee rate
-        let fee = input_val - output_val;
-        let fee_rate = Self::calculate_fee_rate(&tx, fee);
+    }
 
-        // 4. Enforce minimum relay fee
-        if fee_rate < MIN_RELAY_FEE {
-            return Err(format!("Transaction fee too low: {} sat/byte < {} sat/byte minimum", 
-                fee_rate, MIN_RELAY_FEE));
+    /// Height at which the best header chain leaves the active chain
+    fn header_fork_height(&self) -> u64 {
+        let mut h = self.height.min(self.best_header_height());
+        while h > 0 && self.get_header_hash_at_height(h) != self.get_block_hash_at_height(h) {
+            h -= 1;
         }
+        h
+    }
 
-        // 5. Nonce validation: Enforce sequential nonce ordering per sender
-        // Get sender's pubkey hash from first input
-        if !tx.inputs.is_empty() {
-            let sender_pubkey = &tx.inputs[0].public_key;
-            let sender_hash = crate::crypto::hash_bytes(&sender_pubkey.0);
-            
-            let expected_nonce = self.next_nonce.get(&sender_hash).copied().unwrap_or(0);
-            
-            // Check if this is a replacement tx (same nonce) or next in sequence
-            let existing_tx_with_nonce: Option<Hash> = self.mempool.values()
-                .find(|t| !t.inputs.is_empty() && t.inputs[0].public_key == *sender_pubkey && t.nonce == tx.nonce)
-                .map(|t| t.hash());
-            
-            if let Some(existing_hash) = existing_tx_with_nonce {
-                // Replacement: newer tx must have higher fee rate
-                if let Some(existing_tx) = self.mempool.get(&existing_hash) {
-                    let existing_fee = existing_tx.total_input_value(&self.utxo_set)
-                        .saturating_sub(existing_tx.total_output_value());
-                    let existing_fee_rate = Self::calculate_fee_rate(existing_tx, existing_fee);
-                    
-                    if fee_rate <= existing_fee_rate {
-                        return Err("Replacement transaction must have higher fee rate".to_string());
-                    }
-                    // Remove old transaction
-                    self.mempool.remove(&existing_hash);
-                }
-            } else if tx.nonce < expected_nonce {
-                // Old nonce - reject
-                return Err(format!("Transaction nonce {} is too old (expected >= {})", tx.nonce, expected_nonce));
-            } else if tx.nonce > expected_nonce {
-                // Gap in nonces - reject (must maintain order)
-                return Err(format!("Transaction nonce gap: got {}, expected {}", tx.nonce, expected_nonce));
+    /// Blocks on the best header chain whose bodies are missing
+    /// 
+    /// Returns (height, hash) in height order, starting where the header
+    /// chain leaves the active chain and ending at most `window` blocks above
+    /// the tip.
+    pub fn blocks_to_download(&self, window: u64) -> Vec<(u64, Hash)> {
+        let end = self.best_header_height().min(self.height + window);
+        (self.header_fork_height() + 1..=end)
+            .filter_map(|h| self.get_header_hash_at_height(h).map(|hash| (h, hash)))
+            .filter(|(_, hash)| !self.has_block_data(hash))
+            .collect()
+    }
+
+    /// Connect downloaded blocks along the best header chain
+    /// 
+    /// Returns whether the tip changed.
+    pub fn activate_best_header(&mut self) -> Result<bool, String> {
+        let mut target = None;
+        for h in self.header_fork_height() + 1..=self.best_header_height() {
+            match self.get_header_hash_at_height(h) {
+                Some(hash) if self.has_block_data(&hash) => target = Some(hash),
+                _ => break,
             }
         }
 
-        // 6. Double spend check (mempool vs mempool, excluding replacements)
+        match target {
+            Some(hash) => self.activate_best_chain(hash),
+            None => Ok(false),
+        }
+    }
+
+    /// Highest block between the active chain and `hash` that can be
+    /// connected, i.e. whose body and all off-chain ancestors' bodies are stored
+    fn last_connectable(&self, hash: &Hash) -> Option<Hash> {
+        let mut target = None;
+        let mut curr_hash = *hash;
+        while let Some(entry) = self.block_index.get(&curr_hash) {
+            if self.get_block_hash_at_height(entry.height) == Some(curr_hash) {
+                break;
+            }
+            if !entry.has_data {
+                target = None;
+            } else if target.is_none() {
+                target = Some(curr_hash);
+            }
+            if entry.height == 0 {
+                break;
+            }
+            curr_hash = entry.header.prev_hash;
+        }
+        target
+    }
+
+    /// Get full block by hash
+    /// 
+    /// Served from the LRU cache, falling back to the database.
+    pub fn get_block(&self, hash: &Hash) -> Option<Block> {
+        let mut cache = self.block_cache.lock().unwrap();
+        if let Some(block) = cache.get(hash) {
+            return Some(block);
+        }
+
+        let block = self.db.as_ref()?.get_block(hash).ok()??;
+        cache.insert(block.clone());
+        Some(block)
+    }
+
+    /// Get block hash at a given height (Main chain)
+    pub fn get_block_hash_at_height(&self, target_height: u64) -> Option<Hash> {
+        self.height_to_hash.get(&target_height).copied()
+    }
+
+    /// Calculate current mempool size in bytes
+    pub fn mempool_bytes(&self) -> u64 {
+        self.mempool.total_bytes()
+    }
+
+    /// Add a transaction to the mempool
+    ///
+    /// Inputs may spend outputs of other mempool transactions, forming
+    /// chains of unconfirmed transactions up to the pool's package limits.
+    /// Returns the hashes of any transactions it replaced, or why it was
+    /// rejected, with the code to report to the peer that sent it.
+    pub fn add_to_mempool(&mut self, tx: Transaction) -> Result<Vec<Hash>, TxRejection> {
+        let now = unix_time();
+        self.expire_mempool(now);
+        self.add_to_mempool_at(tx, now)
+    }
+
+    /// Drop mempool transactions older than `MEMPOOL_EXPIRY` at Unix time `now`
+    ///
+    /// Returns the number of transactions removed.
+    pub fn expire_mempool(&mut self, now: u64) -> usize {
+        let expired = self.mempool.expire(now.saturating_sub(MEMPOOL_EXPIRY));
+        self.forget_removed(&expired);
+        expired.len()
+    }
+
+    /// Add a transaction to the mempool, recording it as received at Unix `time`
+    pub fn add_to_mempool_at(&mut self, tx: Transaction, time: u64) -> Result<Vec<Hash>, TxRejection> {
+        let hash = tx.hash();
+        
+        // 1. Basic checks
+        if self.mempool.contains(&hash) {
+            return Err(TxRejection::new(RejectCode::Duplicate, "Transaction already in mempool"));
+        }
+        if tx.is_coinbase() {
+            return Err(TxRejection::new(RejectCode::Invalid, "Coinbase transaction cannot be added to mempool"));
+        }
+        if tx.has_duplicate_inputs() {
+            return Err(ValidationError::DuplicateInput(hash).to_string().into());
+        }
+        // Size, input/output counts and dust (see `validation::policy`);
+        // standard transactions always fit in a block
+        check_standard(&tx)?;
+        if !tx.is_final(self.height + 1, self.calculate_median_time()) {
+            return Err(TxRejection::new(RejectCode::NonStandard, ValidationError::NonFinalTransaction(hash, tx.lock_time).to_string()));
+        }
+
+        // 2. Verify signatures and UTXO existence (confirmed or unconfirmed)
+        let inputs = self.mempool.resolve_inputs(&tx, &self.utxo_set, self.height + 1)?;
+        tx.verify_signatures(&inputs, &self.sig_cache)?;
+
+        // Coinbase outputs must mature before they can be mined in the next block
         for input in &tx.inputs {
-            for existing_tx in self.mempool.values() {
-                if existing_tx.inputs.iter().any(|i| i.prev_tx_hash == input.prev_tx_hash && i.output_index == input.output_index) {
-                    return Err("Transaction double-spends an existing mempool transaction".to_string());
+            if let Some(utxo) = inputs.get(&input.prev_tx_hash, input.output_index) {
+                if !utxo.is_mature(self.height + 1) {
+                    return Err(ValidationError::ImmatureCoinbaseSpend(input.prev_tx_hash, input.output_index).to_string().into());
                 }
             }
         }
+
+        // 3. Verify amounts (input >= output + fee)
+        let (Some(input_val), Some(output_val)) = (tx.total_input_value(&inputs), tx.total_output_value()) else {
+            return Err("Transaction amounts overflow".to_string().into());
+        };
+        if input_val < output_val {
+            return Err(format!("Insufficient input: {} < {}", input_val, output_val).into());
+        }
         
-        // 7. DoS Protection: Enforce 300MB mempool size limit
-        let tx_size = bincode::serialized_size(&tx).unwrap_or(0) as u64;
-        let current_mempool_bytes = self.mempool_bytes();
-        
-        if current_mempool_bytes + tx_size > MAX_MEMPOOL_BYTES {
-            // Evict lowest fee-rate transaction
-            if let Some((evict_hash, _)) = self.mempool.iter()
-                .min_by_key(|(_, t)| {
-                    let t_fee = t.total_input_value(&self.utxo_set)
-                        .saturating_sub(t.total_output_value());
-                    let t_size = bincode::serialized_size(t).unwrap_or(1) as u64;
-                    t_fee / t_size.max(1)
-                })
-                .map(|(h, t)| (*h, t.clone()))
-            {
-                self.mempool.remove(&evict_hash);
-                
-                // Check again - if still full, reject
-                if self.mempool_bytes() + tx_size > MAX_MEMPOOL_BYTES {
-                    return Err("Mempool full: Cannot add transaction with higher fee rate".to_string());
-                }
-            } else {
-                return Err("Mempool full".to_string());
+        // Calculate fee and fee rate
+        let fee = input_val - output_val;
+        let tx_size = bincode::serialized_size(&tx).unwrap_or(0);
+        let fee_rate = FeeRate::new(fee, tx_size);
+
+        // 4. Enforce minimum relay fee
+        if fee_rate.per_byte() < MIN_RELAY_FEE {
+            return Err(TxRejection::new(RejectCode::InsufficientFee, format!(
+                "Transaction fee too low: {} sat/byte < {} sat/byte minimum", fee_rate.per_byte(), MIN_RELAY_FEE)));
+        }
+
+        // 5. Nonce validation: the sender's nonces continue from the confirmed
+        //    one through its pool transactions, without gaps. A transaction
+        //    returned by a reorg may fill the slot below pool ones.
+        let mut conflicts = HashSet::new();
+        if let Some(sender_hash) = tx.sender() {
+            let expected_nonce = self.get_next_nonce(&sender_hash);
+            let confirmed_nonce = self.nonces.get(&sender_hash);
+            
+            // Check if this is a replacement tx (same nonce) or next in sequence
+            if let Some(existing) = self.mempool.find_by_nonce(&sender_hash, tx.nonce) {
+                conflicts.insert(existing);
+            } else if tx.nonce < confirmed_nonce {
+                // Old nonce - reject
+                return Err(TxRejection::new(RejectCode::Obsolete, format!(
+                    "Transaction nonce {} is too old (expected >= {})", tx.nonce, expected_nonce)));
+            } else if tx.nonce > confirmed_nonce && self.mempool.find_by_nonce(&sender_hash, tx.nonce - 1).is_none() {
+                // Gap in nonces - reject (must maintain order)
+                return Err(format!("Transaction nonce gap: got {}, expected {}", tx.nonce, expected_nonce).into());
             }
         }
 
-        self.mempool.insert(hash, tx.clone());
+        // 6. Conflicts with pool transactions spending the same outputs are
+        //    resolved by the replace-by-fee policy (see `mempool::rbf`)
+        conflicts.extend(tx.inputs.iter()
+            .filter_map(|input| self.mempool.spender(&(input.prev_tx_hash, input.output_index))));
+        let replaced = self.mempool.check_replacement(&tx, fee, &conflicts)
+            .map_err(|e| TxRejection::new(RejectCode::InsufficientFee, e))?;
+        self.mempool.check_limits(&tx)
+            .map_err(|e| TxRejection::new(RejectCode::NonStandard, e))?;
         
-        // Update next expected nonce for this sender
-        if !tx.inputs.is_empty() {
-            let sender_pubkey = &tx.inputs[0].public_key;
-            let sender_hash = crate::crypto::hash_bytes(&sender_pubkey.0);
-            self.next_nonce.insert(sender_hash, tx.nonce + 1);
+        // 7. DoS Protection: Enforce 300MB mempool size limit
+        // Whole packages are evicted, lowest descendant fee rate first
+        let freed: u64 = replaced.iter()
+            .filter_map(|h| self.mempool.get(h))
+            .map(|e| e.size)
+            .sum();
+        let mut protected = self.mempool.ancestors_of(&tx);
+        for hash in &replaced {
+            protected.extend(self.mempool.ancestors(hash));
         }
+        protected.extend(replaced.iter().copied());
+
+        while self.mempool.total_bytes() - freed + tx_size > MAX_MEMPOOL_BYTES {
+            let Some((worst, worst_rate)) = self.mempool.worst_package(&protected) else {
+                return Err(TxRejection::new(RejectCode::InsufficientFee, "Mempool full"));
+            };
+            if worst_rate >= fee_rate {
+                return Err(TxRejection::new(RejectCode::InsufficientFee, "Mempool full: Cannot add transaction with higher fee rate"));
+            }
+            let evicted = self.mempool.remove_with_descendants(&worst);
+            self.forget_removed(&evicted);
+        }
+
+        for hash in &conflicts {
+            let removed = self.mempool.remove_with_descendants(hash);
+            self.forget_removed(&removed);
+        }
+        self.mempool.insert(tx.clone(), fee, time)?;
+        self.fee_estimator.track(hash, fee_rate, self.height);
         
-        Ok(())
+        Ok(replaced.into_iter().collect())
     }
 
-    /// Get all transactions from the mempool, sorted by fee rate (highest first)
+    /// Get mempool transactions in block template order
+    ///
+    /// Packages with the highest ancestor fee rate come first, and every
+    /// transaction follows the unconfirmed parents it spends.
     pub fn get_mempool_transactions(&self) -> Vec<Transaction> {
-        let mut txs: Vec<_> = self.mempool.values().cloned().collect();
-        
-        // Sort by fee rate (fee / size) in descending order
-        txs.sort_by_key(|tx| {
-            let fee = tx.total_input_value(&self.utxo_set)
-                .saturating_sub(tx.total_output_value());
-            let size = bincode::serialized_size(tx).unwrap_or(1) as u64;
-            let fee_rate = fee / size.max(1);
-            std::cmp::Reverse(fee_rate) // Reverse for descending order
-        });
-        
-        txs
+        self.mempool.select_packages()
     }
 
     /// Get all mempool transaction hashes sorted by fee rate
@@ -698,35 +1235,34 @@ impl ChainState {
     }
 
     /// Get the next expected nonce for a sender (pubkey hash)
-    /// Returns 0 if sender has no transactions in mempool
+    /// Follows the sender's mempool transactions, or its confirmed nonce if it has none
     pub fn get_next_nonce(&self, sender_pubkey_hash: &Hash) -> u64 {
-        self.next_nonce.get(sender_pubkey_hash).copied().unwrap_or(0)
+        match self.mempool.last_nonce(sender_pubkey_hash) {
+            Some(nonce) => nonce + 1,
+            None => self.nonces.get(sender_pubkey_hash),
+        }
     }
 
     /// Get pending nonce for a sender (for wallet use)
     /// Returns the recommended nonce for the next transaction from this sender
     pub fn get_pending_nonce(&self, sender_pubkey: &crate::crypto::PublicKey) -> u64 {
-        let sender_hash = crate::crypto::hash_bytes(&sender_pubkey.0);
-        self.get_next_nonce(&sender_hash)
+        self.get_next_nonce(&sender_pubkey.pubkey_hash())
     }
 
-    /// Remove transactions from mempool (used when a block is applied)
+    /// Remove transactions from mempool, along with any transactions spending their outputs
     pub fn remove_from_mempool(&mut self, tx_hashes: &[Hash]) {
         for hash in tx_hashes {
-            if let Some(tx) = self.mempool.remove(hash) {
-                // Reset nonce for sender if no more txs from them in mempool
-                if !tx.inputs.is_empty() {
-                    let sender_pubkey = &tx.inputs[0].public_key;
-                    let sender_hash = crate::crypto::hash_bytes(&sender_pubkey.0);
-                    
-                    let has_more_txs = self.mempool.values()
-                        .any(|t| !t.inputs.is_empty() && t.inputs[0].public_key == *sender_pubkey);
-                    
-                    if !has_more_txs {
-                        self.next_nonce.remove(&sender_hash);
-                    }
-                }
-            }
+            let removed = self.mempool.remove_with_descendants(hash);
+            self.forget_removed(&removed);
+        }
+    }
+
+    /// Bookkeeping for transactions that left the mempool
+    ///
+    /// Tells the fee estimator that unmined ones will never confirm.
+    fn forget_removed(&mut self, removed: &[Transaction]) {
+        for tx in removed {
+            self.fee_estimator.remove(&tx.hash());
         }
     }
 
@@ -751,6 +1287,7 @@ impl ChainState {
             total_issued: self.total_issued,
             utxo_count: self.utxo_set.len(),
             difficulty: self.difficulty,
+            chain_work: self.chain_work(),
             mempool_txs: self.mempool.len(),
             mempool_bytes: self.mempool_bytes(),
         }
@@ -765,6 +1302,7 @@ pub struct ChainStats {
     pub total_issued: u64,
     pub utxo_count: usize,
     pub difficulty: u32,
+    pub chain_work: u128,
     pub mempool_txs: usize,
     pub mempool_bytes: u64,
 }
@@ -776,7 +1314,14 @@ mod tests {
     use crate::validation::Transaction;
     use crate::crypto::hash_bytes;
 
+    /// Easy compact target so tests can mine blocks instantly
+    const TEST_DIFFICULTY: u32 = 0x207fffff;
+
     fn make_genesis() -> Block {
+        make_genesis_with_difficulty(TEST_DIFFICULTY)
+    }
+
+    fn make_genesis_with_difficulty(difficulty: u32) -> Block {
         use crate::consensus::BlockHeader;
         use crate::constants::FOUNDER_ALLOCATION;
 
@@ -792,13 +1337,85 @@ mod tests {
                 Hash::zero(),
                 hash_bytes(b"merkle"),
                 1234567890,
-                0x1d00ffff,
+                difficulty,
                 0,
             ),
             vec![founder_tx],
         )
     }
 
+    /// A transaction spending `inputs` owned by `owner`, signed
+    fn signed_tx(
+        owner: &crate::wallet::KeyPair,
+        inputs: &[UTXOKey],
+        outputs: Vec<crate::validation::TxOutput>,
+        lock_time: u32,
+    ) -> Transaction {
+        let inputs = inputs.iter()
+            .map(|(prev_tx_hash, output_index)| crate::validation::TxInput {
+                prev_tx_hash: *prev_tx_hash,
+                output_index: *output_index,
+                signature: crate::crypto::SchnorrSignature([0u8; 64]),
+                public_key: owner.public_key.clone(),
+            })
+            .collect();
+        let mut tx = Transaction::new(inputs, outputs);
+        tx.lock_time = lock_time;
+        sign_inputs(owner, &mut tx);
+        tx
+    }
+
+    /// Sign every input of `tx` with `owner`'s key (after editing fields)
+    fn sign_inputs(owner: &crate::wallet::KeyPair, tx: &mut Transaction) {
+        let signature = owner.sign(&tx.signing_hash()).unwrap();
+        for input in &mut tx.inputs {
+            input.signature = signature.clone();
+        }
+    }
+
+    /// Extend the chain with coinbase-only blocks until outputs created at
+    /// genesis can be spent in the next block
+    fn mature_genesis_outputs(state: &mut ChainState) {
+        for h in 1..crate::constants::COINBASE_MATURITY {
+            let coinbase = Transaction::coinbase(1, hash_bytes(&h.to_le_bytes()));
+            let block = mine_block(state.tip_hash, 1234567890 + h, vec![coinbase]);
+            state.apply_block(&block).unwrap();
+        }
+    }
+
+    /// Build a block on top of `prev_hash` with a correct merkle root and valid PoW
+    fn mine_block(prev_hash: Hash, timestamp: u64, transactions: Vec<Transaction>) -> Block {
+        mine_block_with_difficulty(prev_hash, timestamp, TEST_DIFFICULTY, transactions)
+    }
+
+    fn mine_block_with_difficulty(
+        prev_hash: Hash,
+        timestamp: u64,
+        difficulty: u32,
+        transactions: Vec<Transaction>,
+    ) -> Block {
+        use crate::consensus::{BlockHeader, validate_pow};
+        use crate::crypto::compute_merkle_root;
+
+        let tx_hashes: Vec<Hash> = transactions.iter().map(|tx| tx.hash()).collect();
+        let mut block = Block::new(
+            BlockHeader::new(
+                1,
+                0x01,
+                prev_hash,
+                compute_merkle_root(&tx_hashes),
+                timestamp,
+                difficulty,
+                0,
+            ),
+            transactions,
+        );
+        while validate_pow(&block.header).is_err() {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
     #[test]
     fn test_genesis_initialization() {
         let genesis = make_genesis();
@@ -814,17 +1431,9 @@ mod tests {
         let mut state = ChainState::new(&genesis);
 
         // Create a simple block
-        use crate::consensus::BlockHeader;
-        let block = Block::new(
-            BlockHeader::new(
-                1,
-                0x01,
-                genesis.hash(),
-                hash_bytes(b"merkle2"),
-                1234567891,
-                0x1d00ffff,
-                123,
-            ),
+        let block = mine_block(
+            genesis.hash(),
+            1234567891,
             vec![Transaction::coinbase(5000, hash_bytes(b"miner"))],
         );
 
@@ -848,13 +1457,17 @@ mod tests {
         let keypair = wallet.generate_key();
         let miner_pubkey_hash = keypair.pubkey_hash();
 
-        // Mine a block to give the wallet some coins
-        let coinbase_tx = Transaction::coinbase(10000, miner_pubkey_hash);
-        state.utxo_set.apply_transaction(&coinbase_tx, 1);
+        // Give the wallet some (non-coinbase) coins
+        state.utxo_set.add(hash_bytes(b"funding"), 0, crate::storage::UTXO {
+            amount: 10000,
+            pubkey_hash: miner_pubkey_hash,
+            height: 0,
+            is_coinbase: false,
+        });
 
         // Create a valid transaction using the wallet
         let recipient_hash = hash_bytes(b"recipient");
-        let tx = wallet.create_transaction(&state.utxo_set, recipient_hash, 5000, 1000).unwrap();
+        let tx = wallet.create_transaction(&state.utxo_set, state.height, recipient_hash, 5000, 1000, |sender| state.get_next_nonce(sender)).unwrap();
 
         // Add to mempool
         state.add_to_mempool(tx.clone()).unwrap();
@@ -873,7 +1486,7 @@ mod tests {
     #[test]
     fn test_checkpoint_validation() {
         let genesis = make_genesis();
-        let mut state = ChainState::new(&genesis);
+        let state = ChainState::new(&genesis);
 
         // Test that reorg validation respects checkpoints
         // Since genesis is at height 0 and we have a checkpoint there,
@@ -905,7 +1518,7 @@ mod tests {
                 genesis.hash(),
                 hash_bytes(b"merkle_bad"),
                 1234567891,
-                0x1d00ffff,
+                TEST_DIFFICULTY,
                 123,
             ),
             vec![Transaction::coinbase(5000, hash_bytes(b"miner"))],
@@ -913,7 +1526,954 @@ mod tests {
 
         // Should reject due to chain_id mismatch
         let result = state.apply_block(&bad_block);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("invalid chain_id"));
+        assert!(matches!(result, Err(ValidationError::InvalidChainId { got: 0x00, .. })));
+    }
+
+    #[test]
+    fn test_undo_data_survives_restart() {
+        use crate::consensus::{BlockHeader, calculate_block_reward};
+        use crate::constants::FOUNDER_ALLOCATION;
+        use crate::wallet::Wallet;
+
+        let mut wallet = Wallet::new();
+        let owner = wallet.generate_key().pubkey_hash();
+        let founder_tx = Transaction::coinbase(FOUNDER_ALLOCATION, owner);
+        let founder_key = (founder_tx.hash(), 0);
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx],
+        );
+
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        mature_genesis_outputs(&mut state);
+        let (height, issued) = (state.height, state.total_issued);
+
+        let spend = wallet.create_transaction(&state.utxo_set, height, hash_bytes(b"bob"), 1000, 10, |sender| state.get_next_nonce(sender)).unwrap();
+        let spend_hash = spend.hash();
+        let reward = calculate_block_reward(height + 1, issued) + 10;
+        let block = mine_block(
+            state.tip_hash,
+            1234567890 + height + 1,
+            vec![Transaction::coinbase(reward, hash_bytes(b"miner")), spend],
+        );
+        state.apply_block(&block).unwrap();
+        drop(state);
+
+        // Simulate a restart, then disconnect the block
+        let mut restored = ChainState::restore(db).unwrap();
+        assert_eq!(restored.height, height + 1);
+        restored.revert_tip().unwrap();
+
+        assert_eq!(restored.height, height);
+        assert_eq!(restored.total_issued, issued);
+        assert!(restored.utxo_set.contains(&founder_key.0, founder_key.1));
+        assert!(!restored.utxo_set.contains(&spend_hash, 0));
+    }
+
+    #[test]
+    fn test_failed_child_ignored_on_restart() {
+        use crate::consensus::{BlockHeader, calculate_block_reward};
+        use crate::constants::FOUNDER_ALLOCATION;
+        use crate::wallet::Wallet;
+
+        let mut wallet = Wallet::new();
+        let owner = wallet.generate_key().pubkey_hash();
+        let founder_tx = Transaction::coinbase(FOUNDER_ALLOCATION, owner);
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx],
+        );
+
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        mature_genesis_outputs(&mut state);
+        let height = state.height;
+
+        let spend = wallet.create_transaction(&state.utxo_set, height, hash_bytes(b"bob"), 1000, 10, |sender| state.get_next_nonce(sender)).unwrap();
+        let spend_hash = spend.hash();
+        let reward = calculate_block_reward(height + 1, state.total_issued) + 10;
+        let block = mine_block(
+            state.tip_hash,
+            1234567890 + height + 1,
+            vec![Transaction::coinbase(reward, hash_bytes(b"miner")), spend.clone()],
+        );
+        state.apply_block(&block).unwrap();
+
+        // A stored child spending the same coins again fails to connect; its
+        // outputs match the live ones, so it looks half-applied to a scan
+        let double_spend = mine_block(
+            block.hash(),
+            1234567890 + height + 2,
+            vec![Transaction::coinbase(1, hash_bytes(b"thief")), spend],
+        );
+        state.index_block(&double_spend).unwrap();
+        assert!(state.activate_best_chain(double_spend.hash()).is_err());
+        let (tip, issued) = (state.tip_hash, state.total_issued);
+        drop(state);
+
+        let restored = ChainState::restore(db).unwrap();
+        assert_eq!(restored.tip_hash, tip);
+        assert_eq!(restored.height, height + 1);
+        assert_eq!(restored.total_issued, issued);
+        assert!(restored.utxo_set.contains(&spend_hash, 0));
+        assert!(!restored.has_more_work(&double_spend.hash()));
+    }
+
+    #[test]
+    fn test_mempool_survives_restart() {
+        use crate::consensus::{BlockHeader, calculate_block_reward};
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let (owner, alice) = (KeyPair::generate(), KeyPair::generate());
+        let founder_tx = Transaction::coinbase(1_000_000, owner.pubkey_hash());
+        let founder_key = (founder_tx.hash(), 0);
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx],
+        );
+
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        mature_genesis_outputs(&mut state);
+
+        let parent = signed_tx(&owner, &[founder_key], vec![TxOutput { amount: 990_000, pubkey_hash: alice.pubkey_hash() }], 0);
+        let child = signed_tx(&alice, &[(parent.hash(), 0)], vec![TxOutput { amount: 980_000, pubkey_hash: hash_bytes(b"bob") }], 0);
+        let now = unix_time();
+        state.add_to_mempool_at(parent.clone(), now - 60).unwrap();
+        state.add_to_mempool_at(child.clone(), now - 59).unwrap();
+        assert_eq!(state.save_mempool().unwrap(), 2);
+
+        // The parent confirms before the restart; only the child is still pending
+        let reward = calculate_block_reward(state.height + 1, state.total_issued) + 10_000;
+        let block = mine_block(
+            state.tip_hash,
+            1234567890 + state.height + 1,
+            vec![Transaction::coinbase(reward, hash_bytes(b"miner")), parent.clone()],
+        );
+        state.apply_block(&block).unwrap();
+        drop(state);
+
+        let restored = ChainState::restore(db).unwrap();
+        assert_eq!(restored.mempool.len(), 1);
+        assert!(!restored.mempool.contains(&parent.hash()));
+        assert_eq!(restored.mempool.get(&child.hash()).unwrap().time, now - 59);
+    }
+
+    #[test]
+    fn test_mempool_expiry() {
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let (owner, alice) = (KeyPair::generate(), KeyPair::generate());
+        state.utxo_set.add(hash_bytes(b"funding"), 0, UTXO {
+            amount: 1_000_000,
+            pubkey_hash: owner.pubkey_hash(),
+            height: 0,
+            is_coinbase: false,
+        });
+
+        let now = unix_time();
+        let stale = signed_tx(&owner, &[(hash_bytes(b"funding"), 0)], vec![TxOutput { amount: 990_000, pubkey_hash: alice.pubkey_hash() }], 0);
+        let child = signed_tx(&alice, &[(stale.hash(), 0)], vec![TxOutput { amount: 980_000, pubkey_hash: hash_bytes(b"bob") }], 0);
+        state.add_to_mempool_at(stale.clone(), now - MEMPOOL_EXPIRY - 1).unwrap();
+        state.add_to_mempool_at(child, now).unwrap();
+
+        // The stale parent takes its recent child with it, and the sender
+        // starts again from nonce 0
+        assert_eq!(state.expire_mempool(now), 2);
+        assert!(state.mempool.is_empty());
+        assert_eq!(state.get_next_nonce(&owner.pubkey_hash()), 0);
+        state.add_to_mempool(stale).unwrap();
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let owner = KeyPair::generate();
+        let (x, y) = ((hash_bytes(b"x"), 0), (hash_bytes(b"y"), 0));
+        for (hash, index) in [x, y] {
+            state.utxo_set.add(hash, index, UTXO {
+                amount: 1_000_000,
+                pubkey_hash: owner.pubkey_hash(),
+                height: 0,
+                is_coinbase: false,
+            });
+        }
+        let to = |amount| vec![TxOutput { amount, pubkey_hash: hash_bytes(b"bob") }];
+
+        let first = signed_tx(&owner, &[x], to(990_000), 0);
+        let mut second = signed_tx(&owner, &[y], to(990_000), 0);
+        second.nonce = 1;
+        sign_inputs(&owner, &mut second);
+        state.add_to_mempool(first.clone()).unwrap();
+        state.add_to_mempool(second.clone()).unwrap();
+
+        // Conflicts with `first` by nonce and outpoint and with `second` by
+        // outpoint; it must outbid both plus the incremental relay fee
+        let cheap = signed_tx(&owner, &[x, y], to(1_980_000), 0);
+        assert!(state.add_to_mempool(cheap).unwrap_err().reason.contains("too low"));
+
+        let replacement = signed_tx(&owner, &[x, y], to(1_970_000), 0);
+        let mut replaced = state.add_to_mempool(replacement.clone()).unwrap();
+        replaced.sort_by_key(|h| h.0);
+        let mut expected = vec![first.hash(), second.hash()];
+        expected.sort_by_key(|h| h.0);
+        assert_eq!(replaced, expected);
+        assert_eq!(state.mempool.len(), 1);
+        assert!(state.mempool.contains(&replacement.hash()));
+    }
+
+    #[test]
+    fn test_reorg_returns_transactions_to_mempool() {
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let (owner, other, alice) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        for (name, key) in [(b"funding", &owner), (b"contest", &other)] {
+            state.utxo_set.add(hash_bytes(name), 0, UTXO {
+                amount: 1_000_000,
+                pubkey_hash: key.pubkey_hash(),
+                height: 0,
+                is_coinbase: false,
+            });
+        }
+        let to = |amount, pubkey_hash| vec![TxOutput { amount, pubkey_hash }];
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        // Block a1 confirms a payment and a spend the fork will double-spend
+        let pay = signed_tx(&owner, &[(hash_bytes(b"funding"), 0)], to(990_000, alice.pubkey_hash()), 0);
+        let contested = signed_tx(&other, &[(hash_bytes(b"contest"), 0)], to(990_000, hash_bytes(b"carol")), 0);
+        let a1 = mine_block(genesis.hash(), 1234567891, vec![coinbase(b"a1"), pay.clone(), contested.clone()]);
+        state.index_block(&a1).unwrap();
+        state.activate_best_chain(a1.hash()).unwrap();
+
+        // Alice spends the confirmed payment
+        let child = signed_tx(&alice, &[(pay.hash(), 0)], to(980_000, hash_bytes(b"bob")), 0);
+        state.add_to_mempool(child.clone()).unwrap();
+
+        let rival = signed_tx(&other, &[(hash_bytes(b"contest"), 0)], to(990_000, hash_bytes(b"dave")), 0);
+        let b1 = mine_block(genesis.hash(), 1234567892, vec![coinbase(b"b1"), rival]);
+        let b2 = mine_block(b1.hash(), 1234567893, vec![coinbase(b"b2")]);
+        state.index_block(&b1).unwrap();
+        state.index_block(&b2).unwrap();
+        assert!(state.activate_best_chain(b2.hash()).unwrap());
+
+        // The payment is pending again with the child behind it; the
+        // double-spent transaction is gone
+        assert_eq!(state.mempool.len(), 2);
+        assert!(state.mempool.get(&child.hash()).unwrap().parents.contains(&pay.hash()));
+        assert!(!state.mempool.contains(&contested.hash()));
+        let order: Vec<Hash> = state.get_mempool_transactions().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![pay.hash(), child.hash()]);
+    }
+
+    #[test]
+    fn test_immature_coinbase_spend_rejected() {
+        use crate::consensus::{BlockHeader, calculate_block_reward};
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let owner = KeyPair::generate();
+        let founder_tx = Transaction::coinbase(1_000_000, owner.pubkey_hash());
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx.clone()],
+        );
+        let mut state = ChainState::new(&genesis);
+
+        let spend = signed_tx(
+            &owner,
+            &[(founder_tx.hash(), 0)],
+            vec![TxOutput { amount: 900_000, pubkey_hash: hash_bytes(b"bob") }],
+            0,
+        );
+
+        // One block short of maturity: rejected by the mempool and by block validation
+        for h in 1..crate::constants::COINBASE_MATURITY - 1 {
+            let block = mine_block(state.tip_hash, 1234567890 + h, vec![Transaction::coinbase(1, hash_bytes(&h.to_le_bytes()))]);
+            state.apply_block(&block).unwrap();
+        }
+        let next = state.height + 1;
+        assert!(state.add_to_mempool(spend.clone()).unwrap_err().reason.contains("before maturity"));
+
+        let coinbase = Transaction::coinbase(calculate_block_reward(next, state.total_issued), hash_bytes(b"miner"));
+        let early = mine_block(state.tip_hash, 1234567890 + next, vec![coinbase, spend.clone()]);
+        assert!(matches!(state.apply_block(&early), Err(ValidationError::ImmatureCoinbaseSpend(_, 0))));
+
+        // One more block and the output is spendable
+        let block = mine_block(state.tip_hash, 1234567890 + next, vec![Transaction::coinbase(1, hash_bytes(b"last"))]);
+        state.apply_block(&block).unwrap();
+        state.add_to_mempool(spend).unwrap();
+    }
+
+    #[test]
+    fn test_height_locked_transaction() {
+        use crate::consensus::calculate_block_reward;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let owner = KeyPair::generate();
+        state.utxo_set.add(hash_bytes(b"funding"), 0, crate::storage::UTXO {
+            amount: 1_000_000,
+            pubkey_hash: owner.pubkey_hash(),
+            height: 0,
+            is_coinbase: false,
+        });
+
+        let tx = signed_tx(
+            &owner,
+            &[(hash_bytes(b"funding"), 0)],
+            vec![TxOutput { amount: 900_000, pubkey_hash: hash_bytes(b"escrow") }],
+            2,
+        );
+
+        // Locked through height 2: neither the mempool nor block 2 accept it
+        assert!(state.add_to_mempool(tx.clone()).unwrap_err().reason.contains("not final"));
+        let block1 = mine_block(state.tip_hash, 1234567891, vec![Transaction::coinbase(1, hash_bytes(b"b1"))]);
+        state.apply_block(&block1).unwrap();
+
+        let coinbase = Transaction::coinbase(calculate_block_reward(2, state.total_issued), hash_bytes(b"miner"));
+        let early = mine_block(state.tip_hash, 1234567892, vec![coinbase.clone(), tx.clone()]);
+        assert!(matches!(state.apply_block(&early), Err(ValidationError::NonFinalTransaction(_, 2))));
+
+        // Minable in block 3
+        let block2 = mine_block(state.tip_hash, 1234567892, vec![coinbase]);
+        state.apply_block(&block2).unwrap();
+        state.add_to_mempool(tx.clone()).unwrap();
+        let coinbase = Transaction::coinbase(calculate_block_reward(3, state.total_issued), hash_bytes(b"miner3"));
+        let block3 = mine_block(state.tip_hash, 1234567893, vec![coinbase, tx]);
+        state.apply_block(&block3).unwrap();
+    }
+
+    #[test]
+    fn test_in_block_spends() {
+        use crate::consensus::calculate_block_reward;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+
+        let owner = KeyPair::generate();
+        let funding = (hash_bytes(b"funding"), 0);
+        state.utxo_set.add(funding.0, funding.1, UTXO {
+            amount: 1_000_000,
+            pubkey_hash: owner.pubkey_hash(),
+            height: 0,
+            is_coinbase: false,
+        });
+        let utxos_before = state.utxo_set.len();
+        let to = |amount, name: &[u8]| TxOutput { amount, pubkey_hash: hash_bytes(name) };
+        let coinbase = |fees| Transaction::coinbase(calculate_block_reward(1, 0) + fees, hash_bytes(b"miner"));
+
+        // Two transactions spending the same output
+        let a = signed_tx(&owner, &[funding], vec![to(900_000, b"a")], 0);
+        let mut b = signed_tx(&owner, &[funding], vec![to(800_000, b"b")], 0);
+        b.nonce = 1;
+        sign_inputs(&owner, &mut b);
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase(0), a, b]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::DoubleSpend)));
+
+        // One transaction listing the same input twice
+        let dup = signed_tx(&owner, &[funding, funding], vec![to(1_500_000, b"dup")], 0);
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase(0), dup.clone()]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::DuplicateInput(h)) if h == dup.hash()));
+        assert!(state.add_to_mempool(dup).is_err());
+
+        // A chain: the second transaction spends the first one's change,
+        // which only works in that order
+        let change = KeyPair::generate();
+        let parent = signed_tx(&owner, &[funding], vec![to(400_000, b"a"), TxOutput { amount: 590_000, pubkey_hash: change.pubkey_hash() }], 0);
+        let child = signed_tx(&change, &[(parent.hash(), 1)], vec![to(580_000, b"c")], 0);
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase(0), child.clone(), parent.clone()]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::InvalidTransaction(_))));
+
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase(20_000), parent.clone(), child.clone()]);
+        state.apply_block(&block).unwrap();
+        assert!(!state.utxo_set.contains(&parent.hash(), 1));
+        assert!(state.utxo_set.contains(&child.hash(), 0));
+
+        // Disconnecting restores the funding output without resurrecting the in-block one
+        state.revert_tip().unwrap();
+        assert_eq!(state.utxo_set.len(), utxos_before);
+        assert!(state.utxo_set.contains(&funding.0, funding.1));
+        assert!(!state.utxo_set.contains(&parent.hash(), 1));
+        assert!(db.get_utxo(&(parent.hash(), 1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_block_signatures_and_cache() {
+        use crate::consensus::calculate_block_reward;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let owner = KeyPair::generate();
+        let inputs: Vec<UTXOKey> = (0..40u8).map(|i| (hash_bytes(&[i]), 0)).collect();
+        for (hash, index) in &inputs {
+            state.utxo_set.add(*hash, *index, UTXO {
+                amount: 100_000,
+                pubkey_hash: owner.pubkey_hash(),
+                height: 0,
+                is_coinbase: false,
+            });
+        }
+        let to = vec![TxOutput { amount: 3_900_000, pubkey_hash: hash_bytes(b"bob") }];
+        let tx = signed_tx(&owner, &inputs, to, 0);
+        let coinbase = Transaction::coinbase(calculate_block_reward(1, 0) + 100_000, hash_bytes(b"miner"));
+
+        // Signatures verified on mempool entry are cached for block connect
+        state.add_to_mempool(tx.clone()).unwrap();
+        assert!(tx.signature_checks().iter().all(|check| state.sig_cache.contains(check)));
+
+        // A forged signature fails however many inputs verify around it
+        let mut forged = tx.clone();
+        forged.inputs[25].signature = KeyPair::generate().sign(&tx.signing_hash()).unwrap();
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase.clone(), forged]);
+        match state.apply_block(&block) {
+            Err(ValidationError::InvalidTransaction(e)) => assert!(e.contains("Invalid signature") && e.contains(&inputs[25].0.to_string())),
+            other => panic!("expected invalid signature, got {:?}", other),
+        }
+
+        // Without the cache the block's signatures are verified in parallel
+        state.sig_cache = Arc::new(SignatureCache::default());
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase, tx.clone()]);
+        state.apply_block(&block).unwrap();
+        assert!(tx.signature_checks().iter().all(|check| state.sig_cache.contains(check)));
+    }
+
+    #[test]
+    fn test_block_weight_and_sigop_limits() {
+        use crate::constants::{MAX_BLOCK_SIGOPS, MAX_BLOCK_WEIGHT};
+        use crate::crypto::{PublicKey, SchnorrSignature};
+        use crate::validation::{TxInput, TxOutput};
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let coinbase = Transaction::coinbase(1, hash_bytes(b"miner"));
+
+        // Checked before any transaction is looked at
+        let outputs = (MAX_BLOCK_WEIGHT / 40) as usize;
+        let huge = Transaction::new(vec![], vec![TxOutput { amount: 1, pubkey_hash: Hash::zero() }; outputs]);
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase.clone(), huge.clone()]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::BlockTooLarge(w)) if w > MAX_BLOCK_WEIGHT));
+        assert_eq!(state.add_to_mempool(huge).unwrap_err().code, RejectCode::NonStandard);
+
+        let input = |index| TxInput {
+            prev_tx_hash: hash_bytes(b"prev"),
+            output_index: index,
+            signature: SchnorrSignature([0u8; 64]),
+            public_key: PublicKey([0u8; 32]),
+        };
+        let spender = Transaction::new((0..=MAX_BLOCK_SIGOPS as u32).map(input).collect(), vec![]);
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase, spender]);
+        assert!(block.weight() <= MAX_BLOCK_WEIGHT);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::TooManySigops(n)) if n == MAX_BLOCK_SIGOPS + 1));
+        assert!(state.index_block(&block).is_err());
+        assert_eq!(state.height, 0);
+    }
+
+    #[test]
+    fn test_sender_nonces_enforced() {
+        use crate::consensus::{BlockHeader, calculate_block_reward};
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let owner = KeyPair::generate();
+        let founder_tx = Transaction::coinbase(1_000_000, owner.pubkey_hash());
+        let founder_key = (founder_tx.hash(), 0);
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx],
+        );
+
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        mature_genesis_outputs(&mut state);
+
+        let sender = owner.pubkey_hash();
+        let to_owner = |amount| TxOutput { amount, pubkey_hash: sender };
+        let numbered = |inputs: &[UTXOKey], outputs, nonce| {
+            let mut tx = signed_tx(&owner, inputs, outputs, 0);
+            tx.nonce = nonce;
+            sign_inputs(&owner, &mut tx);
+            tx
+        };
+        let tx0 = numbered(&[founder_key], vec![to_owner(400_000), to_owner(590_000)], 0);
+        let tx1 = numbered(&[(tx0.hash(), 0)], vec![to_owner(390_000)], 1);
+        let tx2 = numbered(&[(tx0.hash(), 1)], vec![to_owner(580_000)], 2);
+        let block_at = |state: &ChainState, txs: &[&Transaction]| {
+            let reward = calculate_block_reward(state.height + 1, state.total_issued);
+            let mut transactions = vec![Transaction::coinbase(reward, hash_bytes(b"miner"))];
+            transactions.extend(txs.iter().map(|tx| (*tx).clone()));
+            mine_block(state.tip_hash, 1234567890 + state.height + 1, transactions)
+        };
+
+        // Skipping a nonce is invalid, even with valid inputs
+        let gap = block_at(&state, &[&tx0, &tx2]);
+        assert!(matches!(state.apply_block(&gap), Err(ValidationError::InvalidNonce(h, 1, 2)) if h == tx2.hash()));
+
+        state.apply_block(&block_at(&state, &[&tx0, &tx1])).unwrap();
+        assert_eq!(state.nonces.get(&sender), 2);
+        assert_eq!(state.get_next_nonce(&sender), 2);
+
+        // A used nonce cannot be mined or relayed again
+        let replay = numbered(&[(tx1.hash(), 0)], vec![to_owner(380_000)], 1);
+        let block = block_at(&state, &[&replay]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::InvalidNonce(_, 2, 1))));
+        assert!(state.add_to_mempool(replay).unwrap_err().reason.contains("too old"));
+
+        // Pending transactions move the next nonce past the confirmed one
+        state.add_to_mempool(tx2.clone()).unwrap();
+        assert_eq!((state.nonces.get(&sender), state.get_next_nonce(&sender)), (2, 3));
+
+        // Confirmed nonces are persisted and rewound with the block
+        drop(state);
+        let mut state = ChainState::restore(db.clone()).unwrap();
+        assert_eq!(state.nonces.get(&sender), 2);
+        state.revert_tip().unwrap();
+        assert_eq!(state.nonces.get(&sender), 0);
+        drop(state);
+        assert!(ChainState::restore(db).unwrap().nonces.is_empty());
+    }
+
+    #[test]
+    fn test_mempool_unconfirmed_chain() {
+        use crate::consensus::calculate_block_reward;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let (owner, alice) = (KeyPair::generate(), KeyPair::generate());
+        let funding = (hash_bytes(b"funding"), 0);
+        state.utxo_set.add(funding.0, funding.1, UTXO {
+            amount: 1_000_000,
+            pubkey_hash: owner.pubkey_hash(),
+            height: 0,
+            is_coinbase: false,
+        });
+
+        // Alice spends her output before it is confirmed
+        let parent = signed_tx(&owner, &[funding], vec![TxOutput { amount: 990_000, pubkey_hash: alice.pubkey_hash() }], 0);
+        let child = signed_tx(&alice, &[(parent.hash(), 0)], vec![TxOutput { amount: 980_000, pubkey_hash: hash_bytes(b"bob") }], 0);
+        assert!(state.add_to_mempool(child.clone()).unwrap_err().reason.contains("UTXO not found"));
+        state.add_to_mempool(parent.clone()).unwrap();
+        state.add_to_mempool(child.clone()).unwrap();
+
+        let entry = state.mempool.get(&child.hash()).unwrap();
+        assert_eq!((entry.ancestor_count, entry.ancestor_fee), (2, 20_000));
+        let order: Vec<Hash> = state.get_mempool_transactions().iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), child.hash()]);
+
+        // Mining the package empties the pool
+        let coinbase = Transaction::coinbase(calculate_block_reward(1, 0) + 20_000, hash_bytes(b"miner"));
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase, parent, child]);
+        state.apply_block(&block).unwrap();
+        assert!(state.mempool.is_empty());
+        assert_eq!(state.mempool_bytes(), 0);
+    }
+
+    #[test]
+    fn test_unmined_block_rejected() {
+        use crate::consensus::validate_pow;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let mut block = mine_block(
+            genesis.hash(),
+            1234567891,
+            vec![Transaction::coinbase(5000, hash_bytes(b"miner"))],
+        );
+        while validate_pow(&block.header).is_ok() {
+            block.header.nonce += 1;
+        }
+
+        let result = state.apply_block(&block);
+        assert!(matches!(result, Err(ValidationError::InvalidPoW)));
+        assert!(matches!(state.index_block(&block), Err(ValidationError::InvalidPoW)));
+        assert_eq!(state.height, 0);
+        assert!(state.get_block(&block.hash()).is_none());
+    }
+
+    #[test]
+    fn test_bad_merkle_root_rejected() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let mut block = mine_block(
+            genesis.hash(),
+            1234567891,
+            vec![Transaction::coinbase(5000, hash_bytes(b"miner"))],
+        );
+        block.transactions[0] = Transaction::coinbase(6000, hash_bytes(b"miner"));
+
+        let result = state.apply_block(&block);
+        assert!(matches!(result, Err(ValidationError::InvalidMerkleRoot)));
+        assert_eq!(state.height, 0);
+    }
+
+    #[test]
+    fn test_wrong_difficulty_rejected() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let mut block = mine_block(
+            genesis.hash(),
+            1234567891,
+            vec![Transaction::coinbase(5000, hash_bytes(b"miner"))],
+        );
+        block.header.difficulty_target = 0x2100ffff;
+
+        let result = state.apply_block(&block);
+        assert!(matches!(result, Err(ValidationError::InvalidDifficulty)));
+    }
+
+    #[test]
+    fn test_excess_coinbase_rejected() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let subsidy = crate::consensus::calculate_block_reward(1, 0);
+        let block = mine_block(
+            genesis.hash(),
+            1234567891,
+            vec![Transaction::coinbase(subsidy + 1, hash_bytes(b"miner"))],
+        );
+
+        let result = state.apply_block(&block);
+        assert!(matches!(result, Err(ValidationError::InvalidBlockReward)));
+    }
+
+    #[test]
+    fn test_overflowing_amounts_rejected() {
+        use crate::consensus::calculate_block_reward;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let to = |amount| TxOutput { amount, pubkey_hash: hash_bytes(b"miner") };
+
+        // Coinbase outputs whose sum wraps around to a small amount
+        let mut coinbase = Transaction::coinbase(u64::MAX, hash_bytes(b"miner"));
+        coinbase.outputs.push(to(2));
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::InvalidBlockReward)));
+
+        // A spend whose outputs wrap around below its input
+        let owner = KeyPair::generate();
+        let funding = (hash_bytes(b"funding"), 0);
+        state.utxo_set.add(funding.0, funding.1, UTXO {
+            amount: 1_000_000,
+            pubkey_hash: owner.pubkey_hash(),
+            height: 0,
+            is_coinbase: false,
+        });
+        let spend = signed_tx(&owner, &[funding], vec![to(u64::MAX), to(2)], 0);
+        let coinbase = Transaction::coinbase(calculate_block_reward(1, 0), hash_bytes(b"miner"));
+        let block = mine_block(genesis.hash(), 1234567891, vec![coinbase, spend.clone()]);
+        assert!(matches!(state.apply_block(&block), Err(ValidationError::InvalidTransaction(_))));
+        assert!(state.add_to_mempool(spend).is_err());
+        assert_eq!(state.height, 0);
+    }
+
+    #[test]
+    fn test_difficulty_retargets_at_interval() {
+        use crate::consensus::{calculate_next_difficulty, compact_to_target};
+        use crate::constants::DIFFICULTY_ADJUSTMENT_INTERVAL;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        // Blocks arrive every 300s, twice as fast as the 600s target
+        let timestamp_at = |h: u64| genesis.header.timestamp + h * 300;
+
+        for h in 1..DIFFICULTY_ADJUSTMENT_INTERVAL {
+            assert_eq!(state.difficulty, TEST_DIFFICULTY);
+            let block = mine_block(
+                state.tip_hash,
+                timestamp_at(h),
+                vec![Transaction::coinbase(h, hash_bytes(b"miner"))],
+            );
+            state.apply_block(&block).expect("Failed to apply block");
+        }
+
+        let expected = calculate_next_difficulty(
+            TEST_DIFFICULTY,
+            timestamp_at(0),
+            timestamp_at(DIFFICULTY_ADJUSTMENT_INTERVAL - 1),
+            TEST_DIFFICULTY,
+        );
+        // Clamping would return the genesis target itself; faster blocks
+        // give a strictly harder one
+        assert_ne!(expected, TEST_DIFFICULTY);
+        assert!(compact_to_target(expected) < compact_to_target(TEST_DIFFICULTY));
+        assert_eq!(state.difficulty, expected);
+
+        // The miner builds its template on the retargeted difficulty
+        let miner = crate::mining::Miner::new(std::sync::Arc::new(std::sync::Mutex::new(hash_bytes(b"miner"))));
+        let template = miner.assemble_block(&state);
+        assert_eq!(template.header.difficulty_target, expected);
+
+        // A block still carrying the old target is rejected
+        let stale = mine_block(
+            state.tip_hash,
+            timestamp_at(DIFFICULTY_ADJUSTMENT_INTERVAL),
+            vec![Transaction::coinbase(1, hash_bytes(b"miner"))],
+        );
+        let result = state.apply_block(&stale);
+        assert!(matches!(result, Err(ValidationError::InvalidDifficulty)));
+
+        // Reverting the last block restores the pre-retarget target
+        state.revert_tip().unwrap();
+        assert_eq!(state.difficulty, TEST_DIFFICULTY);
+    }
+
+    #[test]
+    fn test_more_work_fork_becomes_tip() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        let a1 = mine_block(genesis.hash(), 1234567891, vec![coinbase(b"a1")]);
+        state.index_block(&a1).unwrap();
+        assert!(state.activate_best_chain(a1.hash()).unwrap());
+
+        // Equal-work fork does not displace the tip
+        let b1 = mine_block(genesis.hash(), 1234567892, vec![coinbase(b"b1")]);
+        state.index_block(&b1).unwrap();
+        assert!(!state.activate_best_chain(b1.hash()).unwrap());
+        assert_eq!(state.tip_hash, a1.hash());
+
+        // Extending the fork gives it more work
+        let b2 = mine_block(b1.hash(), 1234567893, vec![coinbase(b"b2")]);
+        state.index_block(&b2).unwrap();
+        assert!(state.has_more_work(&b2.hash()));
+        assert!(state.activate_best_chain(b2.hash()).unwrap());
+        assert_eq!(state.tip_hash, b2.hash());
+        assert_eq!(state.height, 2);
+        assert_eq!(state.chain_work(), state.get_chain_work(&b2.hash()).unwrap());
+    }
+
+    #[test]
+    fn test_restore_loads_header_index() {
+        let genesis = make_genesis();
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        let a1 = mine_block(genesis.hash(), 1234567891, vec![coinbase(b"a1")]);
+        state.index_block(&a1).unwrap();
+        state.activate_best_chain(a1.hash()).unwrap();
+        let b1 = mine_block(genesis.hash(), 1234567892, vec![coinbase(b"b1")]);
+        state.index_block(&b1).unwrap();
+        let work = state.chain_work();
+        drop(state);
+
+        // Headers of both branches come back; bodies are read on demand
+        let mut state = ChainState::restore(db).unwrap();
+        assert_eq!(state.tip_hash, a1.hash());
+        assert_eq!(state.get_block_hash_at_height(1), Some(a1.hash()));
+        assert_eq!(state.get_block_height(&b1.hash()), Some(1));
+        assert_eq!(state.chain_work(), work);
+        assert_eq!(state.get_block(&b1.hash()).unwrap().hash(), b1.hash());
+
+        // The side branch can still take over after the restart
+        let b2 = mine_block(b1.hash(), 1234567893, vec![coinbase(b"b2")]);
+        state.index_block(&b2).unwrap();
+        assert!(state.activate_best_chain(b2.hash()).unwrap());
+        assert_eq!(state.get_block_hash_at_height(1), Some(b1.hash()));
+        assert_eq!(state.height, 2);
+    }
+
+    #[test]
+    fn test_longer_low_work_fork_rejected() {
+        const HARD_DIFFICULTY: u32 = 0x1f7fffff;
+
+        let genesis = make_genesis_with_difficulty(HARD_DIFFICULTY);
+        let mut state = ChainState::new(&genesis);
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        let a1 = mine_block_with_difficulty(genesis.hash(), 1234567891, HARD_DIFFICULTY, vec![coinbase(b"a1")]);
+        state.index_block(&a1).unwrap();
+        assert!(state.activate_best_chain(a1.hash()).unwrap());
+
+        // Easy blocks carry less work each, and off a retarget height their
+        // target is wrong, so the fork is refused before it is even indexed
+        let easy = mine_block(genesis.hash(), 1234567892, vec![coinbase(b"easy")]);
+        assert!(calculate_work(TEST_DIFFICULTY) < calculate_work(HARD_DIFFICULTY));
+        assert!(matches!(state.index_block(&easy), Err(ValidationError::InvalidDifficulty)));
+        assert!(!state.activate_best_chain(easy.hash()).unwrap());
+        assert_eq!(state.tip_hash, a1.hash());
+    }
+
+    #[test]
+    fn test_invalid_fork_restores_original_chain() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let coinbase = |amount: u64, tag: &[u8]| Transaction::coinbase(amount, hash_bytes(tag));
+
+        let a1 = mine_block(genesis.hash(), 1234567891, vec![coinbase(1, b"a1")]);
+        state.index_block(&a1).unwrap();
+        state.activate_best_chain(a1.hash()).unwrap();
+
+        // Fork whose second block claims too much reward
+        let b1 = mine_block(genesis.hash(), 1234567892, vec![coinbase(1, b"b1")]);
+        let b2 = mine_block(b1.hash(), 1234567893, vec![coinbase(u64::MAX, b"b2")]);
+        state.index_block(&b1).unwrap();
+        state.index_block(&b2).unwrap();
+
+        assert!(state.activate_best_chain(b2.hash()).is_err());
+        assert_eq!(state.tip_hash, a1.hash());
+        assert_eq!(state.height, 1);
+
+        // The failed chain is never selected again, even when extended
+        let b3 = mine_block(b2.hash(), 1234567894, vec![coinbase(1, b"b3")]);
+        state.index_block(&b3).unwrap();
+        assert!(!state.has_more_work(&b3.hash()));
+    }
+
+    #[test]
+    fn test_orphan_block_not_indexed() {
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+
+        let orphan = mine_block(hash_bytes(b"unknown"), 1234567891, vec![Transaction::coinbase(1, hash_bytes(b"miner"))]);
+        assert!(matches!(state.index_block(&orphan), Err(ValidationError::UnknownParent)));
+        assert!(state.get_block(&orphan.hash()).is_none());
+    }
+
+    #[test]
+    fn test_reorged_mempool_survives_restart() {
+        use crate::consensus::BlockHeader;
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let (owner, alice) = (KeyPair::generate(), KeyPair::generate());
+        let founder_tx = Transaction::coinbase(1_000_000, owner.pubkey_hash());
+        let founder_key = (founder_tx.hash(), 0);
+        let genesis = Block::new(
+            BlockHeader::new(1, 0x01, Hash::zero(), hash_bytes(b"merkle"), 1234567890, TEST_DIFFICULTY, 0),
+            vec![founder_tx],
+        );
+
+        let db = BlockChainDB::open_temporary().unwrap();
+        db.save_genesis(&genesis).unwrap();
+        let mut state = ChainState::new(&genesis);
+        state.set_db(db.clone());
+        mature_genesis_outputs(&mut state);
+        let (fork_point, height) = (state.tip_hash, state.height);
+        let time = |h: u64| 1234567890 + h;
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        // A payment confirms and Alice spends it from the pool
+        let pay = signed_tx(&owner, &[founder_key], vec![TxOutput { amount: 990_000, pubkey_hash: alice.pubkey_hash() }], 0);
+        let a1 = mine_block(fork_point, time(height + 1), vec![coinbase(b"a1"), pay.clone()]);
+        state.index_block(&a1).unwrap();
+        state.activate_best_chain(a1.hash()).unwrap();
+        let child = signed_tx(&alice, &[(pay.hash(), 0)], vec![TxOutput { amount: 980_000, pubkey_hash: hash_bytes(b"bob") }], 0);
+        state.add_to_mempool_at(child.clone(), unix_time() - 60).unwrap();
+
+        // A longer fork returns the payment to the pool, newer than its child
+        let b1 = mine_block(fork_point, time(height + 2), vec![coinbase(b"b1")]);
+        let b2 = mine_block(b1.hash(), time(height + 3), vec![coinbase(b"b2")]);
+        state.index_block(&b1).unwrap();
+        state.index_block(&b2).unwrap();
+        assert!(state.activate_best_chain(b2.hash()).unwrap());
+        assert!(state.mempool.get(&pay.hash()).unwrap().time > state.mempool.get(&child.hash()).unwrap().time);
+
+        assert_eq!(state.save_mempool().unwrap(), 2);
+        drop(state);
+
+        let restored = ChainState::restore(db).unwrap();
+        assert!(restored.mempool.contains(&pay.hash()));
+        assert!(restored.mempool.contains(&child.hash()));
+    }
+
+    #[test]
+    fn test_reorg_keeps_surviving_pool_entries() {
+        use crate::validation::TxOutput;
+        use crate::wallet::KeyPair;
+
+        let genesis = make_genesis();
+        let mut state = ChainState::new(&genesis);
+        let (owner, other, alice, carol, bystander) =
+            (KeyPair::generate(), KeyPair::generate(), KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        for (name, key) in [(b"funding", &owner), (b"contest", &other), (b"savings", &bystander)] {
+            state.utxo_set.add(hash_bytes(name), 0, UTXO {
+                amount: 1_000_000,
+                pubkey_hash: key.pubkey_hash(),
+                height: 0,
+                is_coinbase: false,
+            });
+        }
+        let to = |amount, pubkey_hash| vec![TxOutput { amount, pubkey_hash }];
+        let coinbase = |tag: &[u8]| Transaction::coinbase(1, hash_bytes(tag));
+
+        let pay = signed_tx(&owner, &[(hash_bytes(b"funding"), 0)], to(990_000, alice.pubkey_hash()), 0);
+        let contested = signed_tx(&other, &[(hash_bytes(b"contest"), 0)], to(990_000, carol.pubkey_hash()), 0);
+        let a1 = mine_block(genesis.hash(), 1234567891, vec![coinbase(b"a1"), pay.clone(), contested.clone()]);
+        state.index_block(&a1).unwrap();
+        state.activate_best_chain(a1.hash()).unwrap();
+
+        // Pool entries on top of both payments, and one unrelated to them
+        let child = signed_tx(&alice, &[(pay.hash(), 0)], to(980_000, hash_bytes(b"bob")), 0);
+        let carol_spend = signed_tx(&carol, &[(contested.hash(), 0)], to(980_000, hash_bytes(b"erin")), 0);
+        let unrelated = signed_tx(&bystander, &[(hash_bytes(b"savings"), 0)], to(990_000, hash_bytes(b"frank")), 0);
+        for tx in [&child, &carol_spend, &unrelated] {
+            state.add_to_mempool(tx.clone()).unwrap();
+        }
+        let unrelated_time = state.mempool.get(&unrelated.hash()).unwrap().time;
+
+        let rival = signed_tx(&other, &[(hash_bytes(b"contest"), 0)], to(990_000, hash_bytes(b"dave")), 0);
+        let b1 = mine_block(genesis.hash(), 1234567892, vec![coinbase(b"b1"), rival]);
+        let b2 = mine_block(b1.hash(), 1234567893, vec![coinbase(b"b2")]);
+        state.index_block(&b1).unwrap();
+        state.index_block(&b2).unwrap();
+        assert!(state.activate_best_chain(b2.hash()).unwrap());
+
+        // The payment returns beneath its pool child; the spend of the
+        // double-spent payment goes with it
+        assert_eq!(state.mempool.len(), 3);
+        assert!(!state.mempool.contains(&carol_spend.hash()));
+        let (pay_entry, child_entry) = (state.mempool.get(&pay.hash()).unwrap(), state.mempool.get(&child.hash()).unwrap());
+        assert!(child_entry.parents.contains(&pay.hash()));
+        assert_eq!((pay_entry.descendant_count, child_entry.ancestor_count), (2, 2));
+        assert_eq!(child_entry.ancestor_fee, pay_entry.fee + child_entry.fee);
+
+        // Surviving entries keep their arrival time and fee tracking
+        assert_eq!(state.mempool.get(&unrelated.hash()).unwrap().time, unrelated_time);
+        assert_eq!(state.fee_estimator.seen_height(&unrelated.hash()), Some(1));
+        assert_eq!(state.fee_estimator.seen_height(&child.hash()), Some(1));
+        assert_eq!(state.fee_estimator.seen_height(&carol_spend.hash()), None);
     }
 }
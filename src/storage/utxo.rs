@@ -11,7 +11,7 @@ use serde::{Serialize, Deserialize};
 pub type UTXOKey = (Hash, u32);
 
 /// Unspent Transaction Output
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UTXO {
     /// Amount in base units
     pub amount: u64,
@@ -19,6 +19,17 @@ pub struct UTXO {
     pub pubkey_hash: Hash,
     /// Height at which this UTXO was created
     pub height: u64,
+    /// Created by a coinbase transaction (subject to maturity)
+    pub is_coinbase: bool,
+}
+
+impl UTXO {
+    /// Check if this output may be spent in a block at `spend_height`
+    /// 
+    /// Coinbase outputs must be buried `COINBASE_MATURITY` blocks deep.
+    pub fn is_mature(&self, spend_height: u64) -> bool {
+        !self.is_coinbase || spend_height >= self.height + crate::constants::COINBASE_MATURITY
+    }
 }
 
 /// Set of all unspent transaction outputs
@@ -78,6 +89,7 @@ impl UTXOSet {
                     amount: output.amount,
                     pubkey_hash: output.pubkey_hash,
                     height,
+                    is_coinbase: tx.is_coinbase(),
                 },
             );
         }
@@ -151,6 +163,7 @@ mod tests {
             amount: 100,
             pubkey_hash: make_hash("owner"),
             height: 1,
+            is_coinbase: false,
         });
 
         assert!(set.contains(&tx_hash, 0));
@@ -169,6 +182,7 @@ mod tests {
             amount: 100,
             pubkey_hash: make_hash("owner"),
             height: 1,
+            is_coinbase: false,
         });
 
         assert!(set.contains(&tx_hash, 0));
@@ -187,18 +201,21 @@ mod tests {
             amount: 100,
             pubkey_hash: owner,
             height: 1,
+            is_coinbase: false,
         });
 
         set.add(make_hash("tx2"), 0, UTXO {
             amount: 200,
             pubkey_hash: owner,
             height: 2,
+            is_coinbase: false,
         });
 
         set.add(make_hash("tx3"), 0, UTXO {
             amount: 50,
             pubkey_hash: make_hash("other"),
             height: 3,
+            is_coinbase: false,
         });
 
         assert_eq!(set.get_balance(&owner), 300);
@@ -215,5 +232,23 @@ mod tests {
         let tx_hash = coinbase.hash();
         assert!(set.contains(&tx_hash, 0));
         assert_eq!(set.get_balance(&miner), 5000);
+        assert!(set.get(&tx_hash, 0).unwrap().is_coinbase);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let mut set = UTXOSet::new();
+        let coinbase = Transaction::coinbase(5000, make_hash("miner"));
+        set.apply_transaction(&coinbase, 10);
+
+        let utxo = set.get(&coinbase.hash(), 0).unwrap();
+        let maturity = crate::constants::COINBASE_MATURITY;
+        assert!(!utxo.is_mature(11));
+        assert!(!utxo.is_mature(10 + maturity - 1));
+        assert!(utxo.is_mature(10 + maturity));
+
+        // Regular outputs are spendable immediately
+        let regular = UTXO { is_coinbase: false, ..utxo.clone() };
+        assert!(regular.is_mature(11));
     }
 }
@@ -1,5 +1,6 @@
 //! Validation module - Transaction structure and validation
 
 mod transaction;
+pub mod policy;
 
 pub use transaction::*;
@@ -0,0 +1,185 @@
+//! Transaction standardness policy
+//!
+//! Rules this node applies before relaying a transaction or keeping it in
+//! the mempool, on top of consensus validity. A non-standard transaction is
+//! still valid in a block; it just never enters the pool, so it cannot be
+//! used to bloat the pool or the UTXO set cheaply.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use super::Transaction;
+
+/// Largest standard transaction (bytes, serialized)
+pub const MAX_STANDARD_TX_SIZE: u64 = 100_000;
+
+/// Most inputs a standard transaction may have
+pub const MAX_STANDARD_INPUTS: usize = 500;
+
+/// Most outputs a standard transaction may have
+pub const MAX_STANDARD_OUTPUTS: usize = 500;
+
+/// Fee rate used to price dust (sat/byte)
+///
+/// An output is dust when spending it at this rate would cost more than it
+/// is worth.
+pub const DUST_RELAY_FEE: u64 = 3;
+
+/// Serialized size of one output (amount + pubkey hash)
+const OUTPUT_SIZE: u64 = 8 + 32;
+
+/// Serialized size of the input spending an output
+/// (outpoint + length-prefixed signature and public key)
+const SPENDING_INPUT_SIZE: u64 = 32 + 4 + (8 + 64) + (8 + 32);
+
+/// Smallest standard output amount
+pub fn dust_threshold() -> u64 {
+    DUST_RELAY_FEE * (OUTPUT_SIZE + SPENDING_INPUT_SIZE)
+}
+
+/// Rejection codes sent to peers in a `reject` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectCode {
+    Malformed = 0x01,
+    Invalid = 0x10,
+    Obsolete = 0x11,
+    Duplicate = 0x12,
+    NonStandard = 0x40,
+    Dust = 0x41,
+    InsufficientFee = 0x42,
+    Checkpoint = 0x43,
+}
+
+/// Standardness failures
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    #[error("Transaction size {0} exceeds the standard maximum of {MAX_STANDARD_TX_SIZE} bytes")]
+    TooLarge(u64),
+    #[error("Transaction has {0} inputs, more than the standard {MAX_STANDARD_INPUTS}")]
+    TooManyInputs(usize),
+    #[error("Transaction has {0} outputs, more than the standard {MAX_STANDARD_OUTPUTS}")]
+    TooManyOutputs(usize),
+    #[error("Output {0} has a zero amount")]
+    ZeroAmount(usize),
+    #[error("Output {0} of {1} is dust (minimum {2})")]
+    Dust(usize, u64, u64),
+}
+
+impl PolicyError {
+    /// Code to report to the peer that sent the transaction
+    pub fn reject_code(&self) -> RejectCode {
+        match self {
+            PolicyError::Dust(..) => RejectCode::Dust,
+            _ => RejectCode::NonStandard,
+        }
+    }
+}
+
+/// Check a transaction against the standardness rules
+pub fn check_standard(tx: &Transaction) -> Result<(), PolicyError> {
+    let size = bincode::serialized_size(tx).unwrap_or(u64::MAX);
+    if size > MAX_STANDARD_TX_SIZE {
+        return Err(PolicyError::TooLarge(size));
+    }
+    if tx.inputs.len() > MAX_STANDARD_INPUTS {
+        return Err(PolicyError::TooManyInputs(tx.inputs.len()));
+    }
+    if tx.outputs.len() > MAX_STANDARD_OUTPUTS {
+        return Err(PolicyError::TooManyOutputs(tx.outputs.len()));
+    }
+
+    let dust = dust_threshold();
+    for (index, output) in tx.outputs.iter().enumerate() {
+        if output.amount == 0 {
+            return Err(PolicyError::ZeroAmount(index));
+        }
+        if output.amount < dust {
+            return Err(PolicyError::Dust(index, output.amount, dust));
+        }
+    }
+
+    Ok(())
+}
+
+/// Why a transaction was kept out of the mempool
+#[derive(Debug, Clone, Error)]
+#[error("{reason}")]
+pub struct TxRejection {
+    /// Code to report to the peer that sent it
+    pub code: RejectCode,
+    /// Human-readable reason
+    pub reason: String,
+}
+
+impl TxRejection {
+    /// Rejection with an explicit code
+    pub fn new(code: RejectCode, reason: impl Into<String>) -> Self {
+        Self { code, reason: reason.into() }
+    }
+}
+
+/// Failures without a more specific code make the transaction invalid
+impl From<String> for TxRejection {
+    fn from(reason: String) -> Self {
+        Self::new(RejectCode::Invalid, reason)
+    }
+}
+
+impl From<PolicyError> for TxRejection {
+    fn from(e: PolicyError) -> Self {
+        Self::new(e.reject_code(), e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Hash, PublicKey, SchnorrSignature};
+    use crate::validation::{TxInput, TxOutput};
+
+    fn tx(inputs: usize, amounts: &[u64]) -> Transaction {
+        let input = TxInput {
+            prev_tx_hash: Hash::zero(),
+            output_index: 0,
+            signature: SchnorrSignature([0u8; 64]),
+            public_key: PublicKey([0u8; 32]),
+        };
+        Transaction::new(
+            vec![input; inputs],
+            amounts.iter().map(|&amount| TxOutput { amount, pubkey_hash: Hash::zero() }).collect(),
+        )
+    }
+
+    #[test]
+    fn test_sizes_match_serialization() {
+        let one = bincode::serialized_size(&tx(1, &[1])).unwrap();
+        assert_eq!(bincode::serialized_size(&tx(2, &[1])).unwrap() - one, SPENDING_INPUT_SIZE);
+        assert_eq!(bincode::serialized_size(&tx(1, &[1, 1])).unwrap() - one, OUTPUT_SIZE);
+    }
+
+    #[test]
+    fn test_standard_rules() {
+        let dust = dust_threshold();
+        assert!(check_standard(&tx(1, &[dust, 50_000])).is_ok());
+
+        let err = check_standard(&tx(1, &[50_000, dust - 1])).unwrap_err();
+        assert!(matches!(err, PolicyError::Dust(1, _, _)));
+        assert_eq!(err.reject_code(), RejectCode::Dust);
+
+        let err = check_standard(&tx(1, &[0])).unwrap_err();
+        assert!(matches!(err, PolicyError::ZeroAmount(0)));
+        assert_eq!(err.reject_code(), RejectCode::NonStandard);
+
+        assert!(matches!(
+            check_standard(&tx(1, &vec![dust; MAX_STANDARD_OUTPUTS + 1])),
+            Err(PolicyError::TooManyOutputs(_))
+        ));
+        assert!(matches!(
+            check_standard(&tx(MAX_STANDARD_INPUTS + 1, &[dust])),
+            Err(PolicyError::TooManyInputs(_))
+        ));
+        assert!(matches!(
+            check_standard(&tx(MAX_STANDARD_TX_SIZE as usize / 100, &[dust])),
+            Err(PolicyError::TooLarge(_))
+        ));
+    }
+}
@@ -3,7 +3,7 @@
 //! UTXO-based transactions with Schnorr signatures.
 
 use serde::{Deserialize, Serialize};
-use crate::crypto::{Hash, hash_bytes, PublicKey, SchnorrSignature};
+use crate::crypto::{Hash, hash_bytes, PublicKey, SchnorrSignature, SignatureCache, SignatureCheck};
 use crate::storage::UTXOSet;
 
 /// A transaction input referencing a previous output
@@ -39,8 +39,9 @@ pub struct Transaction {
     pub outputs: Vec<TxOutput>,
     /// Lock time (block height or timestamp)
     pub lock_time: u32,
-    /// Transaction nonce (per-sender sequence number for ordering)
-    /// Allows users to replace pending transactions with higher fee
+    /// Per-sender sequence number, see `Transaction::sender`
+    /// Consensus requires a sender's transactions to be mined with nonces
+    /// 0, 1, 2, ... in order, so a signed transaction is mined at most once
     pub nonce: u64,
 }
 
@@ -86,6 +87,23 @@ impl Transaction {
         }
     }
 
+    /// Check if the lock time allows inclusion in a block
+    /// 
+    /// `height` is the height of that block and `median_time_past` the
+    /// median timestamp of the 11 blocks before it. A lock time of 0 never
+    /// locks; below `LOCKTIME_THRESHOLD` it is the last height the
+    /// transaction is locked at, otherwise the last locked timestamp.
+    pub fn is_final(&self, height: u64, median_time_past: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        if self.lock_time < crate::constants::LOCKTIME_THRESHOLD {
+            (self.lock_time as u64) < height
+        } else {
+            (self.lock_time as u64) < median_time_past
+        }
+    }
+
     /// Check if this is a coinbase transaction
     pub fn is_coinbase(&self) -> bool {
         self.inputs.len() == 1 
@@ -93,6 +111,31 @@ impl Transaction {
             && self.inputs[0].output_index == 0xFFFFFFFF
     }
 
+    /// Pubkey hash (address form) of the sender, whose nonce this transaction uses
+    ///
+    /// The sender is the signer of the first input. `None` for coinbases.
+    pub fn sender(&self) -> Option<Hash> {
+        if self.is_coinbase() {
+            return None;
+        }
+        self.inputs.first().map(|input| input.public_key.pubkey_hash())
+    }
+
+    /// Check if any output is listed as an input more than once
+    pub fn has_duplicate_inputs(&self) -> bool {
+        let mut seen = std::collections::HashSet::with_capacity(self.inputs.len());
+        !self.inputs.iter().all(|input| seen.insert((input.prev_tx_hash, input.output_index)))
+    }
+
+    /// Signature verifications this transaction costs (one per input)
+    pub fn sigops(&self) -> usize {
+        if self.is_coinbase() {
+            0
+        } else {
+            self.inputs.len()
+        }
+    }
+
     /// Calculate transaction hash
     pub fn hash(&self) -> Hash {
         let bytes = self.to_bytes_for_signing();
@@ -139,28 +182,31 @@ impl Transaction {
         bytes
     }
 
-    /// Verify all input signatures
-    pub fn verify_signatures(&self, utxo_set: &UTXOSet) -> Result<(), String> {
+    /// Signatures to verify, one per input (none for a coinbase)
+    pub fn signature_checks(&self) -> Vec<SignatureCheck> {
         if self.is_coinbase() {
-            return Ok(());
+            return Vec::new();
         }
 
         let signing_hash = self.signing_hash();
+        self.inputs.iter()
+            .map(|input| SignatureCheck {
+                public_key: input.public_key.clone(),
+                message: signing_hash,
+                signature: input.signature.clone(),
+            })
+            .collect()
+    }
 
-        for input in &self.inputs {
-            // Verify signature
-            if !input.public_key.verify(&signing_hash, &input.signature) {
-                return Err(format!("Invalid signature for input {}:{}", input.prev_tx_hash, input.output_index));
-            }
+    /// Verify that every input's public key owns the UTXO it spends
+    pub fn verify_input_owners(&self, utxo_set: &UTXOSet) -> Result<(), String> {
+        if self.is_coinbase() {
+            return Ok(());
+        }
 
-            // Verify public key matches the UTXO
+        for input in &self.inputs {
             if let Some(utxo) = utxo_set.get(&input.prev_tx_hash, input.output_index) {
-                // Derive the 20-byte address-format hash from the signer's public key
-                let full_hash = hash_bytes(&input.public_key.0);
-                let mut pubkey_hash = [0u8; 32];
-                pubkey_hash[0..20].copy_from_slice(&full_hash.0[0..20]);
-                
-                if pubkey_hash != utxo.pubkey_hash.0 {
+                if input.public_key.pubkey_hash() != utxo.pubkey_hash {
                     return Err(format!("Public key mismatch for UTXO {}:{}", input.prev_tx_hash, input.output_index));
                 }
             } else {
@@ -171,26 +217,45 @@ impl Transaction {
         Ok(())
     }
 
+    /// Verify all input signatures and UTXO ownership
+    ///
+    /// Signatures found in `sig_cache` are not verified again; newly verified
+    /// ones are added to it.
+    pub fn verify_signatures(&self, utxo_set: &UTXOSet, sig_cache: &SignatureCache) -> Result<(), String> {
+        for (input, check) in self.inputs.iter().zip(self.signature_checks()) {
+            if !sig_cache.verify(&check) {
+                return Err(format!("Invalid signature for input {}:{}", input.prev_tx_hash, input.output_index));
+            }
+        }
+
+        self.verify_input_owners(utxo_set)
+    }
+
     /// Calculate total input value (requires UTXO lookup)
-    pub fn total_input_value(&self, utxo_set: &UTXOSet) -> u64 {
+    /// 
+    /// Returns None if the sum overflows.
+    pub fn total_input_value(&self, utxo_set: &UTXOSet) -> Option<u64> {
         self.inputs.iter()
             .filter_map(|input| {
                 utxo_set.get(&input.prev_tx_hash, input.output_index)
                     .map(|utxo| utxo.amount)
             })
-            .sum()
+            .try_fold(0u64, |total, amount| total.checked_add(amount))
     }
 
     /// Calculate total output value
-    pub fn total_output_value(&self) -> u64 {
-        self.outputs.iter().map(|o| o.amount).sum()
+    /// 
+    /// Returns None if the sum overflows.
+    pub fn total_output_value(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, o| total.checked_add(o.amount))
     }
 
-    /// Calculate transaction fee
+    /// Calculate transaction fee (zero if either total overflows)
     pub fn fee(&self, utxo_set: &UTXOSet) -> u64 {
-        let input_value = self.total_input_value(utxo_set);
-        let output_value = self.total_output_value();
-        input_value.saturating_sub(output_value)
+        match (self.total_input_value(utxo_set), self.total_output_value()) {
+            (Some(input_value), Some(output_value)) => input_value.saturating_sub(output_value),
+            _ => 0,
+        }
     }
 }
 
@@ -207,6 +272,34 @@ mod tests {
         assert!(!regular.is_coinbase());
     }
 
+    #[test]
+    fn test_lock_time_finality() {
+        let mut tx = Transaction::new(vec![], vec![]);
+        assert!(tx.is_final(0, 0));
+
+        // Height lock: minable from the block after the lock height
+        tx.lock_time = 100;
+        assert!(!tx.is_final(100, u64::MAX));
+        assert!(tx.is_final(101, 0));
+
+        // Time lock: minable once the median time past has passed it
+        tx.lock_time = 1_700_000_000;
+        assert!(!tx.is_final(u64::MAX, 1_700_000_000));
+        assert!(tx.is_final(0, 1_700_000_001));
+    }
+
+    #[test]
+    fn test_duplicate_inputs_detected() {
+        let input = |index| TxInput {
+            prev_tx_hash: hash_bytes(b"prev"),
+            output_index: index,
+            signature: SchnorrSignature([0u8; 64]),
+            public_key: PublicKey([0u8; 32]),
+        };
+        assert!(!Transaction::new(vec![input(0), input(1)], vec![]).has_duplicate_inputs());
+        assert!(Transaction::new(vec![input(0), input(1), input(0)], vec![]).has_duplicate_inputs());
+    }
+
     #[test]
     fn test_transaction_hash_deterministic() {
         let tx = Transaction::coinbase(5000, Hash::zero());
@@ -224,7 +317,16 @@ mod tests {
                 TxOutput { amount: 200, pubkey_hash: Hash::zero() },
             ],
         );
-        assert_eq!(tx.total_output_value(), 300);
+        assert_eq!(tx.total_output_value(), Some(300));
+
+        let overflowing = Transaction::new(
+            vec![],
+            vec![
+                TxOutput { amount: u64::MAX, pubkey_hash: Hash::zero() },
+                TxOutput { amount: 1, pubkey_hash: Hash::zero() },
+            ],
+        );
+        assert_eq!(overflowing.total_output_value(), None);
     }
 
     #[test]
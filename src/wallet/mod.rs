@@ -1,5 +1,6 @@
 //! Wallet module - Key management and transaction signing
 
+#[allow(clippy::module_inception)]
 mod wallet;
 
 pub use wallet::*;
@@ -3,7 +3,8 @@
 //! Handles key generation, UTXO tracking, and transaction signing.
 //! The wallet does NOT affect consensus - bugs here cannot affect supply.
 
-use crate::crypto::{Hash, PrivateKey, PublicKey, hash_bytes};
+use crate::crypto::{Hash, PrivateKey, PublicKey};
+use crate::mempool::FeeEstimator;
 use crate::storage::{UTXOSet, UTXO, UTXOKey};
 use crate::validation::{Transaction, TxInput, TxOutput};
 use thiserror::Error;
@@ -13,6 +14,25 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
 
+/// Confirmation target for the wallet's default fee (blocks)
+pub const DEFAULT_CONFIRM_TARGET: usize = 6;
+
+/// Fee rate paid while the node has too little data to estimate (sat/byte)
+pub const FALLBACK_FEE_RATE: u64 = 2;
+
+/// Serialized size of a signed transaction with the given shape
+pub fn estimate_tx_size(inputs: usize, outputs: usize) -> u64 {
+    let input = TxInput {
+        prev_tx_hash: Hash::zero(),
+        output_index: 0,
+        signature: crate::crypto::SchnorrSignature([0u8; 64]),
+        public_key: PublicKey([0u8; 32]),
+    };
+    let output = TxOutput { amount: 0, pubkey_hash: Hash::zero() };
+    let tx = Transaction::new(vec![input; inputs], vec![output; outputs]);
+    bincode::serialized_size(&tx).unwrap_or(0)
+}
+
 /// Wallet errors
 #[derive(Debug, Error)]
 pub enum WalletError {
@@ -26,6 +46,35 @@ pub enum WalletError {
     InvalidAddress,
 }
 
+/// Wallet balance split by spendability
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    /// Funds that can be spent in the next block
+    pub spendable: u64,
+    /// Coinbase rewards still waiting for maturity
+    pub immature: u64,
+}
+
+impl Balance {
+    /// Balance of any pubkey hash with the chain at `chain_height`
+    pub fn of(pubkey_hash: &Hash, utxo_set: &UTXOSet, chain_height: u64) -> Self {
+        let mut balance = Balance::default();
+        for (_, utxo) in utxo_set.get_by_pubkey_hash(pubkey_hash) {
+            if utxo.is_mature(chain_height + 1) {
+                balance.spendable += utxo.amount;
+            } else {
+                balance.immature += utxo.amount;
+            }
+        }
+        balance
+    }
+
+    /// Spendable plus immature funds
+    pub fn total(&self) -> u64 {
+        self.spendable + self.immature
+    }
+}
+
 /// A wallet key pair
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KeyPair {
@@ -74,10 +123,7 @@ impl KeyPair {
     /// Returns the first 20 bytes of the BLAKE3 hash, padded to 32 bytes.
     /// This matches the address encoding format.
     pub fn pubkey_hash(&self) -> Hash {
-        let full_hash = hash_bytes(&self.public_key.0);
-        let mut addr_hash = [0u8; 32];
-        addr_hash[0..20].copy_from_slice(&full_hash.0[0..20]);
-        Hash(addr_hash)
+        self.public_key.pubkey_hash()
     }
 
     /// Sign a message
@@ -140,16 +186,19 @@ impl Wallet {
         self.keys.keys().copied().collect()
     }
 
-    /// Get total balance across all keys
-    pub fn get_balance(&self, utxo_set: &UTXOSet) -> u64 {
+    /// Get balance across all keys with the chain at `chain_height`
+    pub fn get_balance(&self, utxo_set: &UTXOSet, chain_height: u64) -> Balance {
         self.keys.keys()
-            .map(|pkh| utxo_set.get_balance(pkh))
-            .sum()
+            .map(|pkh| self.get_balance_for(pkh, utxo_set, chain_height))
+            .fold(Balance::default(), |acc, b| Balance {
+                spendable: acc.spendable + b.spendable,
+                immature: acc.immature + b.immature,
+            })
     }
 
-    /// Get balance for a specific pubkey hash
-    pub fn get_balance_for(&self, pubkey_hash: &Hash, utxo_set: &UTXOSet) -> u64 {
-        utxo_set.get_balance(pubkey_hash)
+    /// Get balance for a specific pubkey hash with the chain at `chain_height`
+    pub fn get_balance_for(&self, pubkey_hash: &Hash, utxo_set: &UTXOSet, chain_height: u64) -> Balance {
+        Balance::of(pubkey_hash, utxo_set, chain_height)
     }
 
     /// Get all UTXOs owned by this wallet
@@ -165,19 +214,64 @@ impl Wallet {
         result
     }
 
-    /// Create and sign a transaction
+    /// Fee rate (sat/byte) the wallet pays when none is given
+    ///
+    /// The node's estimate for `DEFAULT_CONFIRM_TARGET` blocks, or
+    /// `FALLBACK_FEE_RATE` while it has too little data.
+    pub fn default_fee_rate(estimator: &FeeEstimator) -> u64 {
+        estimator.estimate(DEFAULT_CONFIRM_TARGET).unwrap_or(FALLBACK_FEE_RATE)
+    }
+
+    /// Create and sign a transaction paying a fixed `fee`
+    /// 
+    /// Only outputs spendable in the block after `chain_height` are selected.
+    /// `next_nonce` gives the nonce a sender (pubkey hash) must use next,
+    /// see `ChainState::get_next_nonce`.
     pub fn create_transaction(
         &self,
         utxo_set: &UTXOSet,
+        chain_height: u64,
         recipient_pubkey_hash: Hash,
         amount: u64,
         fee: u64,
+        next_nonce: impl Fn(&Hash) -> u64,
+    ) -> Result<Transaction, WalletError> {
+        self.build_transaction(utxo_set, chain_height, recipient_pubkey_hash, amount, |_| fee, next_nonce)
+    }
+
+    /// Create and sign a transaction paying `fee_rate` sat/byte
+    ///
+    /// Use `Wallet::default_fee_rate` for the node's current estimate.
+    pub fn create_transaction_at_rate(
+        &self,
+        utxo_set: &UTXOSet,
+        chain_height: u64,
+        recipient_pubkey_hash: Hash,
+        amount: u64,
+        fee_rate: u64,
+        next_nonce: impl Fn(&Hash) -> u64,
     ) -> Result<Transaction, WalletError> {
-        let total_needed = amount + fee;
+        self.build_transaction(utxo_set, chain_height, recipient_pubkey_hash, amount, |inputs| {
+            fee_rate * estimate_tx_size(inputs, 2)
+        }, next_nonce)
+    }
 
+    /// Select inputs, add change and sign
+    ///
+    /// `fee_for` gives the fee for a given number of inputs.
+    fn build_transaction(
+        &self,
+        utxo_set: &UTXOSet,
+        chain_height: u64,
+        recipient_pubkey_hash: Hash,
+        amount: u64,
+        fee_for: impl Fn(usize) -> u64,
+        next_nonce: impl Fn(&Hash) -> u64,
+    ) -> Result<Transaction, WalletError> {
         // Collect UTXOs until we have enough
         let mut selected_utxos: Vec<(UTXOKey, UTXO, &KeyPair)> = Vec::new();
         let mut total_input: u64 = 0;
+        let mut total_needed = amount + fee_for(0);
 
         for pubkey_hash in self.keys.keys() {
             if total_input >= total_needed {
@@ -190,8 +284,12 @@ impl Wallet {
                 if total_input >= total_needed {
                     break;
                 }
+                if !utxo.is_mature(chain_height + 1) {
+                    continue;
+                }
                 selected_utxos.push((key, utxo.clone(), keypair));
                 total_input += utxo.amount;
+                total_needed = amount + fee_for(selected_utxos.len());
             }
         }
 
@@ -208,9 +306,9 @@ impl Wallet {
             pubkey_hash: recipient_pubkey_hash,
         }];
 
-        // Add change output if needed
+        // Add change output if needed; change too small to relay goes to the fee
         let change = total_input - total_needed;
-        if change > 0 {
+        if change >= crate::validation::policy::dust_threshold() {
             // Send change to first key
             let change_pubkey_hash = *self.keys.keys().next()
                 .ok_or(WalletError::NoUTXOs)?;
@@ -231,8 +329,11 @@ impl Wallet {
             })
             .collect();
 
-        // Create unsigned transaction
-        let mut tx = Transaction::new(inputs, outputs);
+        // Create unsigned transaction, numbered after the first input's owner
+        let sender = selected_utxos.first()
+            .map(|(_, _, keypair)| keypair.pubkey_hash())
+            .ok_or(WalletError::NoUTXOs)?;
+        let mut tx = Transaction::new_with_nonce(inputs, outputs, next_nonce(&sender));
 
         // Sign each input
         let signing_hash = tx.signing_hash();
@@ -248,7 +349,7 @@ impl Wallet {
     /// Save wallet to file
     pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
         let bytes = bincode::serialize(self)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
         let mut file = File::create(path)?;
         file.write_all(&bytes)
     }
@@ -259,7 +360,7 @@ impl Wallet {
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
         let wallet = bincode::deserialize(&bytes)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
         Ok(wallet)
     }
 }
@@ -267,6 +368,7 @@ impl Wallet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::hash_bytes;
 
     #[test]
     fn test_keypair_generation() {
@@ -307,10 +409,31 @@ mod tests {
                 amount: 1000,
                 pubkey_hash,
                 height: 1,
+                is_coinbase: false,
             },
         );
 
-        assert_eq!(wallet.get_balance(&utxo_set), 1000);
+        assert_eq!(wallet.get_balance(&utxo_set, 1).spendable, 1000);
+    }
+
+    #[test]
+    fn test_immature_coinbase_balance() {
+        let mut wallet = Wallet::new();
+        let pubkey_hash = wallet.generate_key().pubkey_hash();
+
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.apply_transaction(&Transaction::coinbase(5000, pubkey_hash), 1);
+
+        let balance = wallet.get_balance(&utxo_set, 1);
+        assert_eq!(balance, Balance { spendable: 0, immature: 5000 });
+        assert!(matches!(
+            wallet.create_transaction(&utxo_set, 1, Hash::zero(), 1000, 10, |_| 0),
+            Err(WalletError::InsufficientFunds { .. })
+        ));
+
+        let mature_height = crate::constants::COINBASE_MATURITY;
+        assert_eq!(wallet.get_balance(&utxo_set, mature_height).spendable, 5000);
+        assert!(wallet.create_transaction(&utxo_set, mature_height, Hash::zero(), 1000, 10, |_| 0).is_ok());
     }
 
     #[test]
@@ -322,11 +445,31 @@ mod tests {
 
         let result = wallet.create_transaction(
             &utxo_set,
+            0,
             Hash::zero(),
             1000,
             10,
+            |_| 0,
         );
 
         assert!(matches!(result, Err(WalletError::InsufficientFunds { .. })));
     }
+
+    #[test]
+    fn test_fee_rate_transaction() {
+        let mut wallet = Wallet::new();
+        let pubkey_hash = wallet.generate_key().pubkey_hash();
+        let mut utxo_set = UTXOSet::new();
+        utxo_set.apply_transaction(&Transaction::coinbase(100_000, pubkey_hash), 1);
+        let height = crate::constants::COINBASE_MATURITY;
+
+        // Without estimates the fallback rate applies
+        let fee_rate = Wallet::default_fee_rate(&FeeEstimator::new());
+        assert_eq!(fee_rate, FALLBACK_FEE_RATE);
+
+        let tx = wallet.create_transaction_at_rate(&utxo_set, height, Hash::zero(), 1000, fee_rate, |_| 0).unwrap();
+        let size = bincode::serialized_size(&tx).unwrap();
+        assert_eq!(size, estimate_tx_size(1, 2));
+        assert_eq!(tx.fee(&utxo_set), fee_rate * size);
+    }
 }
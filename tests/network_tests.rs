@@ -0,0 +1,439 @@
+//! Multi-node network tests
+//!
+//! Runs several in-memory nodes in one process, connected over loopback
+//! sockets through `NetworkService`, and checks that chains propagate.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rh_core::consensus::{Block, BlockHeader};
+use rh_core::crypto::{compute_merkle_root, hash_bytes, Hash};
+use rh_core::mining::{Miner, MiningResult};
+use rh_core::p2p::{
+    read_message, Message, NetAddress, NetworkService, PeerManager, PeerState, RejectCode,
+    SyncManager, VersionMessage, MIN_PROTOCOL_VERSION, NODE_NETWORK, PROTOCOL_VERSION,
+};
+use rh_core::storage::ChainState;
+use rh_core::validation::Transaction;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Easiest compact target, so blocks mine instantly
+const TEST_DIFFICULTY: u32 = 0x207fffff;
+
+/// How long a test waits for the network to converge
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Genesis block shared by every test node
+fn test_genesis() -> Block {
+    let coinbase = Transaction::coinbase(rh_core::constants::FOUNDER_ALLOCATION, hash_bytes(b"founder"));
+    let merkle_root = compute_merkle_root(&[coinbase.hash()]);
+    Block::new(
+        BlockHeader::new(1, 0x01, Hash::zero(), merkle_root, 1_700_000_000, TEST_DIFFICULTY, 0),
+        vec![coinbase],
+    )
+}
+
+/// An in-process node listening on a loopback port
+struct TestNode {
+    chain_state: Arc<Mutex<ChainState>>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    network: NetworkService,
+    miner: Miner,
+    addr: SocketAddr,
+}
+
+impl TestNode {
+    async fn start(genesis: &Block) -> Self {
+        Self::start_with(genesis, |network| network).await
+    }
+
+    /// Start a node, adjusting its network service first
+    async fn start_with(genesis: &Block, configure: impl FnOnce(NetworkService) -> NetworkService) -> Self {
+        Self::start_with_peers(genesis, PeerManager::new(8), configure).await
+    }
+
+    /// Start a node with its own peer manager settings
+    async fn start_with_peers(
+        genesis: &Block,
+        peer_manager: PeerManager,
+        configure: impl FnOnce(NetworkService) -> NetworkService,
+    ) -> Self {
+        let chain_state = Arc::new(Mutex::new(ChainState::new(genesis)));
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let sync_manager = Arc::new(Mutex::new(SyncManager::new()));
+        let miner = Miner::new(Arc::new(Mutex::new(hash_bytes(b"miner"))));
+
+        let network = configure(
+            NetworkService::new(chain_state.clone(), peer_manager.clone(), sync_manager)
+                .with_miner(miner.clone()),
+        );
+        network.start_sync();
+        let addr = network.listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        Self { chain_state, peer_manager, network, miner, addr }
+    }
+
+    /// Dial another node and wait for the handshake to finish
+    async fn connect(&self, other: &TestNode) {
+        self.network.connect(other.addr).await.unwrap();
+        wait_until(|| self.peer_count() > 0 && other.peer_count() > 0).await;
+    }
+
+    /// Mine a block on our tip, connect it and announce it
+    fn mine(&self) -> Block {
+        let template = self.miner.assemble_block(&self.chain_state.lock().unwrap());
+        self.miner.reset();
+        let MiningResult::Success(block) = self.miner.mine_block(template) else {
+            panic!("mining failed");
+        };
+        self.chain_state.lock().unwrap().apply_block(&block).unwrap();
+        self.network.announce_block(block.hash());
+        block
+    }
+
+    fn tip(&self) -> (u64, Hash) {
+        let state = self.chain_state.lock().unwrap();
+        (state.height, state.tip_hash)
+    }
+
+    fn peer_count(&self) -> usize {
+        self.peer_manager.lock().unwrap().connected_count()
+    }
+
+    /// Check if `other`'s listening address is in our address table
+    fn knows(&self, other: &TestNode) -> bool {
+        self.peer_manager.lock().unwrap().addrman.get(&other.addr).is_some()
+    }
+
+    /// Put `other`'s listening address in our address table
+    fn learn(&self, other: &TestNode) {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let address = NetAddress { addr: other.addr, services: NODE_NETWORK, timestamp: now };
+        self.peer_manager.lock().unwrap().addrman.add(address, other.addr.ip(), now);
+    }
+
+    fn outbound_count(&self) -> usize {
+        self.peer_manager.lock().unwrap().outbound_count()
+    }
+}
+
+/// A hand-driven peer on a raw socket, after reading the node's version
+async fn raw_peer(node: &TestNode) -> TcpStream {
+    let mut stream = TcpStream::connect(node.addr).await.unwrap();
+    assert!(matches!(recv(&mut stream).await, Some(Message::Version(v)) if v.services & NODE_NETWORK != 0));
+    stream
+}
+
+fn version(version: u32) -> Message {
+    Message::Version(VersionMessage {
+        version,
+        services: 0,
+        best_height: 0,
+        from_addr: "127.0.0.1:1".parse().unwrap(),
+        to_addr: "127.0.0.1:2".parse().unwrap(),
+        nonce: 42,
+        user_agent: "raw".to_string(),
+    })
+}
+
+async fn send(stream: &mut TcpStream, msg: Message) {
+    stream.write_all(&msg.to_bytes()).await.unwrap();
+}
+
+/// Next message from the node, or None once it closed the connection
+async fn recv(stream: &mut TcpStream) -> Option<Message> {
+    tokio::time::timeout(CONVERGE_TIMEOUT, read_message(stream)).await
+        .expect("node did not answer in time")
+        .ok()
+}
+
+/// Complete the handshake from a raw peer
+async fn handshake(stream: &mut TcpStream) {
+    send(stream, version(PROTOCOL_VERSION)).await;
+    assert!(matches!(recv(stream).await, Some(Message::VerAck)));
+    send(stream, Message::VerAck).await;
+}
+
+/// Read the reject that precedes a disconnect
+async fn expect_reject_and_close(stream: &mut TcpStream, command: &str, code: RejectCode) {
+    match recv(stream).await {
+        Some(Message::Reject(reject)) => {
+            assert_eq!(reject.message_type, command);
+            assert_eq!(reject.code, code);
+        }
+        other => panic!("expected reject, got {:?}", other),
+    }
+    assert!(recv(stream).await.is_none(), "connection left open");
+}
+
+/// Poll `condition` until it holds, failing the test after `CONVERGE_TIMEOUT`
+async fn wait_until(condition: impl Fn() -> bool) {
+    tokio::time::timeout(CONVERGE_TIMEOUT, async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("network did not converge in time");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_new_node_syncs_existing_chain() {
+    let genesis = test_genesis();
+    let a = TestNode::start(&genesis).await;
+    for _ in 0..5 {
+        a.mine();
+    }
+
+    let b = TestNode::start(&genesis).await;
+    b.connect(&a).await;
+    wait_until(|| b.tip() == a.tip()).await;
+    assert_eq!(b.tip().0, 5);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_blocks_relay_across_nodes() {
+    let genesis = test_genesis();
+    let a = TestNode::start(&genesis).await;
+    let b = TestNode::start(&genesis).await;
+    let c = TestNode::start(&genesis).await;
+
+    // a <-> b <-> c: c only hears about a's blocks through b
+    b.connect(&a).await;
+    c.connect(&b).await;
+
+    a.mine();
+    let tip = a.mine();
+    wait_until(|| c.tip() == (2, tip.hash())).await;
+    assert_eq!(b.tip(), c.tip());
+
+    // Blocks mined further down the line travel back
+    let tip = c.mine();
+    wait_until(|| a.tip() == (3, tip.hash())).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_handshake_is_enforced() {
+    let node = TestNode::start(&test_genesis()).await;
+
+    // Anything before the handshake ends the connection
+    let mut early = raw_peer(&node).await;
+    send(&mut early, Message::GetAddr).await;
+    expect_reject_and_close(&mut early, "getaddr", RejectCode::Malformed).await;
+
+    let mut early = raw_peer(&node).await;
+    send(&mut early, version(PROTOCOL_VERSION)).await;
+    assert!(matches!(recv(&mut early).await, Some(Message::VerAck)));
+    send(&mut early, Message::GetAddr).await;
+    expect_reject_and_close(&mut early, "getaddr", RejectCode::Malformed).await;
+
+    // So does an incompatible protocol version
+    let mut old = raw_peer(&node).await;
+    send(&mut old, version(MIN_PROTOCOL_VERSION - 1)).await;
+    expect_reject_and_close(&mut old, "version", RejectCode::Obsolete).await;
+    assert_eq!(node.peer_count(), 0);
+
+    // A complete handshake registers the peer
+    let mut peer = raw_peer(&node).await;
+    handshake(&mut peer).await;
+    wait_until(|| node.peer_count() == 1).await;
+
+    // A second version is a protocol violation
+    send(&mut peer, version(PROTOCOL_VERSION)).await;
+    expect_reject_and_close(&mut peer, "version", RejectCode::Duplicate).await;
+    wait_until(|| node.peer_count() == 0).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_handshake_timeout() {
+    let timeout = Duration::from_millis(300);
+    let node = TestNode::start_with(&test_genesis(), |network| network.with_handshake_timeout(timeout)).await;
+
+    let mut silent = raw_peer(&node).await;
+    let mut peer = raw_peer(&node).await;
+    handshake(&mut peer).await;
+
+    // The silent peer is dropped; the handshaked one stays past the deadline
+    assert!(recv(&mut silent).await.is_none());
+    tokio::time::sleep(timeout * 2).await;
+    assert_eq!(node.peer_count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_self_connection_is_dropped() {
+    let node = TestNode::start(&test_genesis()).await;
+    node.network.connect(node.addr).await.unwrap();
+
+    wait_until(|| {
+        let pm = node.peer_manager.lock().unwrap();
+        pm.get_peer(&node.addr).is_some_and(|peer| peer.state == PeerState::Disconnected)
+    }).await;
+    assert_eq!(node.peer_count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_keepalive_pings() {
+    let (interval, timeout) = (Duration::from_millis(100), Duration::from_millis(300));
+    let node = TestNode::start_with(&test_genesis(), |network| network.with_keepalive(interval, timeout)).await;
+    let mut peer = raw_peer(&node).await;
+    handshake(&mut peer).await;
+
+    // Answered pings are timed
+    let Some(Message::Ping(nonce)) = recv(&mut peer).await else {
+        panic!("expected ping");
+    };
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    send(&mut peer, Message::Pong(nonce)).await;
+    wait_until(|| {
+        let pm = node.peer_manager.lock().unwrap();
+        pm.get_connected_peers().first().and_then(|p| p.ping_rtt).is_some_and(|rtt| rtt >= Duration::from_millis(20))
+    }).await;
+
+    // A peer that stops answering is dropped
+    assert!(matches!(recv(&mut peer).await, Some(Message::Ping(_))));
+    assert!(recv(&mut peer).await.is_none());
+    wait_until(|| node.peer_count() == 0).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_addresses_gossip() {
+    let genesis = test_genesis();
+    let a = TestNode::start(&genesis).await;
+    let b = TestNode::start(&genesis).await;
+    let c = TestNode::start(&genesis).await;
+
+    // Dialed peers are tried; inbound peers advertise their listening port
+    b.connect(&a).await;
+    {
+        let pm = b.peer_manager.lock().unwrap();
+        assert!(pm.addrman.get(&a.addr).is_some_and(|info| info.tried));
+    }
+    wait_until(|| a.knows(&b)).await;
+
+    // A newcomer's address is relayed to the rest of the network
+    c.connect(&b).await;
+    wait_until(|| a.knows(&c)).await;
+
+    // And a node asking for addresses learns everyone its peer knows
+    let d = TestNode::start(&genesis).await;
+    d.connect(&b).await;
+    wait_until(|| d.knows(&a) && d.knows(&c)).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peers_file_survives_restart() {
+    let genesis = test_genesis();
+    let path = std::env::temp_dir().join(format!("rh_network_peers_{}.dat", rand::random::<u64>()));
+    let remote = TestNode::start(&genesis).await;
+
+    let node = TestNode::start_with(&genesis, |network| network.with_peers_file(&path)).await;
+    node.connect(&remote).await;
+    assert!(node.network.save_peers().unwrap() >= 1);
+
+    let restarted = TestNode::start_with(&genesis, |network| network.with_peers_file(&path)).await;
+    std::fs::remove_file(&path).unwrap();
+    let pm = restarted.peer_manager.lock().unwrap();
+    assert!(pm.addrman.get(&remote.addr).is_some_and(|info| info.tried));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connection_manager_fills_outbound_slots() {
+    let genesis = test_genesis();
+    let added = TestNode::start(&genesis).await;
+    let mut others = Vec::new();
+    for _ in 0..3 {
+        others.push(TestNode::start(&genesis).await);
+    }
+
+    let node = TestNode::start_with_peers(&genesis, PeerManager::new(8).with_max_outbound(2), |network| network).await;
+    node.peer_manager.lock().unwrap().add_node(added.addr);
+    for other in &others {
+        node.learn(other);
+    }
+    node.network.start_connection_manager();
+
+    // The added node has its own slot next to the outbound target
+    wait_until(|| added.peer_count() == 1 && node.peer_count() == 3).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(node.outbound_count(), 2);
+    assert_eq!(others.iter().map(TestNode::peer_count).sum::<usize>(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_refused_dial_frees_outbound_slot() {
+    let genesis = test_genesis();
+    let banned = TestNode::start(&genesis).await;
+    let node = TestNode::start(&genesis).await;
+    {
+        let mut pm = node.peer_manager.lock().unwrap();
+        pm.add_peer(banned.addr);
+        pm.report_misbehavior(&banned.addr, 100);
+    }
+
+    // The dial goes through, but the banned peer is refused right away
+    node.network.connect(banned.addr).await.unwrap();
+    let pm = node.peer_manager.lock().unwrap();
+    assert_eq!(pm.outbound_count(), 0);
+    assert!(!pm.get_peer(&banned.addr).unwrap().is_active());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_inbound_connections_are_capped() {
+    let node = TestNode::start_with_peers(&test_genesis(), PeerManager::new(2).with_max_outbound(1), |network| network).await;
+    assert_eq!(node.peer_manager.lock().unwrap().max_inbound(), 1);
+
+    let mut first = raw_peer(&node).await;
+    handshake(&mut first).await;
+
+    // No free slot: closed before the node even sends its version
+    let mut refused = TcpStream::connect(node.addr).await.unwrap();
+    assert!(recv(&mut refused).await.is_none());
+
+    // Once the slot frees up, it is available again, to exactly one of a
+    // burst of connections
+    drop(first);
+    wait_until(|| node.peer_manager.lock().unwrap().accepts_inbound()).await;
+    let mut burst = Vec::new();
+    for _ in 0..4 {
+        burst.push(TcpStream::connect(node.addr).await.unwrap());
+    }
+    let mut admitted = Vec::new();
+    for mut stream in burst {
+        if let Some(msg) = recv(&mut stream).await {
+            assert!(matches!(msg, Message::Version(_)));
+            admitted.push(stream);
+        }
+    }
+    assert_eq!(admitted.len(), 1);
+    handshake(&mut admitted[0]).await;
+    wait_until(|| node.peer_count() == 1).await;
+    assert_eq!(node.peer_manager.lock().unwrap().inbound_count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_poor_outbound_peer_is_rotated_out() {
+    let genesis = test_genesis();
+    let good = TestNode::start(&genesis).await;
+    let node = TestNode::start_with_peers(&genesis, PeerManager::new(8).with_max_outbound(1), |network| {
+        network.with_rotation_interval(Duration::from_millis(200))
+    }).await;
+
+    // A raw peer takes the only outbound slot, then misbehaves
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let poor_addr = listener.local_addr().unwrap();
+    node.network.connect(poor_addr).await.unwrap();
+    let (mut poor, _) = listener.accept().await.unwrap();
+    assert!(matches!(recv(&mut poor).await, Some(Message::Version(_))));
+    handshake(&mut poor).await;
+    wait_until(|| node.peer_count() == 1).await;
+    node.peer_manager.lock().unwrap().report_misbehavior(&poor_addr, 10);
+
+    node.learn(&good);
+    node.network.start_connection_manager();
+
+    // It is dropped and its slot goes to another peer
+    while recv(&mut poor).await.is_some() {}
+    wait_until(|| good.peer_count() == 1).await;
+    assert_eq!(node.outbound_count(), 1);
+}
@@ -105,7 +105,7 @@ proptest! {
 /// The difficulty adjustment should limit changes to 4x per period.
 #[test]
 fn test_time_warp_attack_resistance() {
-    use rh_core::consensus::calculate_next_difficulty;
+    use rh_core::consensus::{POW_LIMIT, calculate_next_difficulty, compact_to_target};
     use rh_core::constants::{BLOCK_TIME_TARGET, DIFFICULTY_ADJUSTMENT_INTERVAL};
     
     let current_difficulty = 0x1c00ffff;
@@ -114,21 +114,21 @@ fn test_time_warp_attack_resistance() {
     // Attack: Claim blocks took 0 seconds (instant)
     // Algorithm should clamp to minimum time (expected_time / 4)
     let attack_time = 0u64;
-    let new_difficulty = calculate_next_difficulty(current_difficulty, 0, attack_time);
+    let new_difficulty = calculate_next_difficulty(current_difficulty, 0, attack_time, POW_LIMIT);
     
     // Difficulty should change but algorithm handles the extreme input
     // The key verification is that it doesn't panic or produce invalid values
     assert!(new_difficulty != 0, "Difficulty should not be zero");
-    assert!(new_difficulty <= 0x1d00ffff, "Should not exceed MIN_DIFFICULTY");
+    assert!(compact_to_target(new_difficulty) <= compact_to_target(POW_LIMIT), "Should not exceed POW_LIMIT");
     
     // Attack: Claim blocks took 100 years
     // Algorithm should clamp to maximum time (expected_time * 4)
     let attack_time = expected_time * 100;
-    let new_difficulty_slow = calculate_next_difficulty(current_difficulty, 0, attack_time);
+    let new_difficulty_slow = calculate_next_difficulty(current_difficulty, 0, attack_time, POW_LIMIT);
     
     // Algorithm should handle the extreme input gracefully
     assert!(new_difficulty_slow != 0, "Difficulty should not be zero");
-    assert!(new_difficulty_slow <= 0x1d00ffff, "Should not exceed MIN_DIFFICULTY");
+    assert!(compact_to_target(new_difficulty_slow) <= compact_to_target(POW_LIMIT), "Should not exceed POW_LIMIT");
 }
 
 /// Test: Double-spend detection
@@ -148,6 +148,7 @@ fn test_double_spend_in_block_rejected() {
         amount: 1000,
         pubkey_hash: owner_hash,
         height: 0,
+        is_coinbase: false,
     });
     
     // Try to spend same UTXO twice
@@ -227,7 +228,7 @@ fn test_genesis_determinism() {
 /// Attacker alternates between fast and slow blocks to lower difficulty.
 #[test]
 fn test_difficulty_oscillation_resistance() {
-    use rh_core::consensus::calculate_next_difficulty;
+    use rh_core::consensus::{POW_LIMIT, calculate_next_difficulty};
     use rh_core::constants::{BLOCK_TIME_TARGET, DIFFICULTY_ADJUSTMENT_INTERVAL};
     
     let initial_difficulty = 0x1c00ffff;
@@ -235,11 +236,11 @@ fn test_difficulty_oscillation_resistance() {
     
     // Period 1: Very fast blocks (4x faster, max allowed)
     let fast_time = expected_time / 4;
-    let diff_after_fast = calculate_next_difficulty(initial_difficulty, 0, fast_time);
+    let diff_after_fast = calculate_next_difficulty(initial_difficulty, 0, fast_time, POW_LIMIT);
     
     // Period 2: Very slow blocks (4x slower, max allowed)
     let slow_time = expected_time * 4;
-    let diff_after_slow = calculate_next_difficulty(diff_after_fast, 0, slow_time);
+    let diff_after_slow = calculate_next_difficulty(diff_after_fast, 0, slow_time, POW_LIMIT);
     
     // After one fast and one slow period, difficulty should oscillate
     // but the 4x cap prevents extreme manipulation